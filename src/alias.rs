@@ -0,0 +1,122 @@
+use crate::tokenize::{Op, Token, TokenKind, tokenize};
+use std::collections::HashMap;
+
+/// Alias names mapped to their replacement text.
+pub type AliasMap = HashMap<String, String>;
+
+/// Maximum number of nested alias expansions applied to a single word.
+const MAX_ALIAS_DEPTH: usize = 32;
+
+/// Operators after which the next word is in command position.
+const COMMAND_SEPARATORS: &[Op] = &[
+    Op::Pipe,
+    Op::Semicolon,
+    Op::DoubleSemicolon,
+    Op::And,
+    Op::Or,
+];
+
+/// Replaces aliased words in command position with their tokenized definitions.
+/// The replacement tokens take the span of the word they replace.
+pub fn expand_aliases<'a>(tokens: Vec<Token<'a>>, aliases: &'a AliasMap) -> Vec<Token<'a>> {
+    if aliases.is_empty() {
+        return tokens;
+    }
+
+    let mut expanded = Vec::with_capacity(tokens.len());
+    let mut command_position = true;
+
+    for token in tokens {
+        let next_is_command =
+            matches!(token.kind, TokenKind::Operator(op) if COMMAND_SEPARATORS.contains(&op));
+        if command_position {
+            expanded.extend(expand_word(token, aliases, &mut Vec::new()));
+        } else {
+            expanded.push(token);
+        }
+        command_position = next_is_command;
+    }
+
+    expanded
+}
+
+/// Expands a single command word, refusing to re-expand an alias that is already
+/// being expanded (so `alias ls='ls -la'` terminates) or to nest too deeply.
+/// Quoting any part of the word, as in `\ls`, suppresses expansion.
+fn expand_word<'a>(
+    token: Token<'a>,
+    aliases: &'a AliasMap,
+    active: &mut Vec<String>,
+) -> Vec<Token<'a>> {
+    let TokenKind::Word(word) = &token.kind else {
+        return vec![token];
+    };
+    if token.quoted {
+        return vec![token];
+    }
+    let Some(value) = aliases.get(word.as_ref()) else {
+        return vec![token];
+    };
+    if active.iter().any(|name| name == word) || active.len() >= MAX_ALIAS_DEPTH {
+        return vec![token];
+    }
+
+    // A definition that does not tokenize cleanly is left unexpanded
+    let Ok(tokens) = tokenize(value) else {
+        return vec![token];
+    };
+    let mut tokens = tokens.into_iter().map(|replacement| Token {
+        span: token.span.clone(),
+        ..replacement
+    });
+    let Some(first) = tokens.next() else {
+        return Vec::new();
+    };
+
+    active.push(word.to_string());
+    let mut expanded = expand_word(first, aliases, active);
+    active.pop();
+
+    expanded.extend(tokens);
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(input: &str, aliases: &AliasMap) -> Vec<String> {
+        let tokens = expand_aliases(tokenize(input).unwrap(), aliases);
+        tokens.iter().map(Token::to_string).collect()
+    }
+
+    #[test]
+    fn test_expands_command_position_only() {
+        let aliases = AliasMap::from([("ll".to_string(), "ls -la".to_string())]);
+        assert_eq!(
+            expand("ll ll | ll", &aliases),
+            vec!["ls", "-la", "ll", "|", "ls", "-la"]
+        );
+    }
+
+    #[test]
+    fn test_self_and_mutual_recursion_terminate() {
+        let aliases = AliasMap::from([
+            ("ls".to_string(), "ls --color".to_string()),
+            ("a".to_string(), "b x".to_string()),
+            ("b".to_string(), "a y".to_string()),
+        ]);
+        assert_eq!(expand("ls", &aliases), vec!["ls", "--color"]);
+        assert_eq!(expand("a", &aliases), vec!["a", "y", "x"]);
+    }
+
+    #[test]
+    fn test_quoted_word_is_not_expanded() {
+        let aliases = AliasMap::from([("ll".to_string(), "ls -la".to_string())]);
+        assert_eq!(expand("\\ll 'll'", &aliases), vec!["ll", "ll"]);
+        assert_eq!(
+            expand("\"ll\" | ll", &aliases),
+            vec!["ll", "|", "ls", "-la"]
+        );
+    }
+}
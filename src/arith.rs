@@ -0,0 +1,223 @@
+use std::fmt;
+
+/// Evaluates the expression of a `$(( ... ))` arithmetic expansion.
+/// Supports `+ - * / % **`, unary minus and plus, parentheses, integer literals
+/// and variable names (optionally written `$NAME`), whose values `lookup` gives;
+/// unset or empty variables count as 0.
+pub fn evaluate(
+    expression: &str,
+    mut lookup: impl FnMut(&str) -> Option<String>,
+) -> Result<i64, String> {
+    let mut parser = Parser {
+        terms: lex(expression)?,
+        position: 0,
+        lookup: &mut lookup,
+    };
+    let value = parser.additive()?;
+    match parser.terms.get(parser.position) {
+        None => Ok(value),
+        Some(term) => Err(syntax_error(term)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Term {
+    Number(i64),
+    Name(String),
+    Operator(&'static str),
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Term::Number(n) => write!(f, "{}", n),
+            Term::Name(name) => f.write_str(name),
+            Term::Operator(op) => f.write_str(op),
+        }
+    }
+}
+
+const OPERATORS: &[&str] = &["**", "+", "-", "*", "/", "%", "(", ")"];
+
+fn lex(expression: &str) -> Result<Vec<Term>, String> {
+    let mut terms = Vec::new();
+    let mut rest = expression.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        let length = if c.is_ascii_digit() {
+            let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            let number = rest[..digits]
+                .parse()
+                .map_err(|_| format!("{}: value too great for base", &rest[..digits]))?;
+            terms.push(Term::Number(number));
+            digits
+        } else if c == '$' || c == '_' || c.is_ascii_alphabetic() {
+            let sigil = usize::from(c == '$');
+            let name = rest[sigil..]
+                .split(|c: char| c != '_' && !c.is_ascii_alphanumeric())
+                .next()
+                .unwrap_or_default();
+            if name.is_empty() {
+                return Err(format!(
+                    "syntax error: operand expected (error token is \"{}\")",
+                    rest
+                ));
+            }
+            terms.push(Term::Name(name.to_string()));
+            sigil + name.len()
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            terms.push(Term::Operator(op));
+            op.len()
+        } else {
+            return Err(format!(
+                "syntax error: invalid arithmetic operator (error token is \"{}\")",
+                rest
+            ));
+        };
+        rest = rest[length..].trim_start();
+    }
+
+    Ok(terms)
+}
+
+fn syntax_error(term: &Term) -> String {
+    format!("syntax error in expression (error token is \"{}\")", term)
+}
+
+/// Recursive-descent evaluator; each method handles one precedence level.
+struct Parser<'a> {
+    terms: Vec<Term>,
+    position: usize,
+    lookup: &'a mut dyn FnMut(&str) -> Option<String>,
+}
+
+impl Parser<'_> {
+    fn eat(&mut self, op: &str) -> bool {
+        let found = matches!(self.terms.get(self.position), Some(Term::Operator(o)) if *o == op);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn additive(&mut self) -> Result<i64, String> {
+        let mut value = self.multiplicative()?;
+        loop {
+            if self.eat("+") {
+                value = value.wrapping_add(self.multiplicative()?);
+            } else if self.eat("-") {
+                value = value.wrapping_sub(self.multiplicative()?);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<i64, String> {
+        let mut value = self.power()?;
+        loop {
+            let op = if self.eat("*") {
+                "*"
+            } else if self.eat("/") {
+                "/"
+            } else if self.eat("%") {
+                "%"
+            } else {
+                return Ok(value);
+            };
+            let rhs = self.power()?;
+            value = match op {
+                "*" => value.wrapping_mul(rhs),
+                _ if rhs == 0 => return Err("division by 0".to_string()),
+                "/" => value.wrapping_div(rhs),
+                _ => value.wrapping_rem(rhs),
+            };
+        }
+    }
+
+    /// `**` is right-associative and binds more loosely than unary minus, as in bash.
+    fn power(&mut self) -> Result<i64, String> {
+        let base = self.unary()?;
+        if !self.eat("**") {
+            return Ok(base);
+        }
+        let exponent = self.power()?;
+        let exponent = u32::try_from(exponent).map_err(|_| "exponent less than 0".to_string())?;
+        Ok(base.wrapping_pow(exponent))
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        if self.eat("-") {
+            Ok(self.unary()?.wrapping_neg())
+        } else if self.eat("+") {
+            self.unary()
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, String> {
+        let Some(term) = self.terms.get(self.position).cloned() else {
+            return Err("syntax error: operand expected".to_string());
+        };
+        self.position += 1;
+        match term {
+            Term::Number(n) => Ok(n),
+            Term::Name(name) => variable((self.lookup)(&name)),
+            Term::Operator("(") => {
+                let value = self.additive()?;
+                if self.eat(")") {
+                    Ok(value)
+                } else {
+                    Err("missing `)'".to_string())
+                }
+            }
+            term => Err(syntax_error(&term)),
+        }
+    }
+}
+
+fn variable(value: Option<String>) -> Result<i64, String> {
+    match value {
+        Some(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse()
+            .map_err(|_| format!("{}: syntax error: invalid arithmetic operator", value)),
+        _ => Ok(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn evaluate(expression: &str) -> Result<i64, String> {
+        super::evaluate(expression, |name| {
+            (name == "ARITH_TEST_N").then(|| "6".to_string())
+        })
+    }
+
+    #[test]
+    fn test_precedence_and_associativity() {
+        assert_eq!(evaluate("2 + 3 * 4"), Ok(14));
+        assert_eq!(evaluate("(2 + 3) * 4"), Ok(20));
+        assert_eq!(evaluate("10 - 4 - 3"), Ok(3));
+        assert_eq!(evaluate("2 ** 3 ** 2"), Ok(512));
+        assert_eq!(evaluate("-2 ** 2"), Ok(4));
+        assert_eq!(evaluate("-7 / 2 + 7 % 3"), Ok(-2));
+    }
+
+    #[test]
+    fn test_variables() {
+        assert_eq!(evaluate("ARITH_TEST_N * 2 + $ARITH_TEST_N"), Ok(18));
+        assert_eq!(evaluate("ARITH_TEST_UNSET + 1"), Ok(1));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(evaluate("1 / 0"), Err("division by 0".to_string()));
+        assert_eq!(evaluate("5 % (2 - 2)"), Err("division by 0".to_string()));
+        assert_eq!(evaluate("2 ** -1"), Err("exponent less than 0".to_string()));
+        assert!(evaluate("1 +").is_err());
+        assert!(evaluate("(1").is_err());
+        assert!(evaluate("1 2").is_err());
+    }
+}
@@ -0,0 +1,1160 @@
+use crate::redirection::{ParseError, array_assignment_name, parse_pipeline};
+use crate::tokenize::{Op, Token, TokenKind, join_continued_lines, tokenize};
+
+/// Commands run one after another, as separated by `;`, `&` or newlines.
+pub type List = Vec<Command>;
+
+/// One entry of a command list.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// A pipeline, kept as source text so its expansions happen only when it runs.
+    Pipeline(String),
+    /// `if list; then list; [elif list; then list;]... [else list;] fi [redirection...]`
+    If {
+        /// Each condition with the list it guards, `if` first and then every `elif`.
+        branches: Vec<(List, List)>,
+        otherwise: Option<List>,
+        /// Source text of the redirections after `fi`, applied to the whole command.
+        redirects: String,
+    },
+    /// `while list; do list; done [redirection...]`, or with `until` the body runs while
+    /// the condition fails.
+    Loop {
+        until: bool,
+        condition: List,
+        body: List,
+        /// Source text of the redirections after `done`, applied to the whole loop.
+        redirects: String,
+    },
+    /// `for name [in word...]; do list; done [redirection...]`
+    For {
+        name: String,
+        /// Source text of the words after `in`, expanded each time the loop starts.
+        /// `None` without `in`, when the loop goes over the positional parameters.
+        words: Option<String>,
+        body: List,
+        redirects: String,
+    },
+    /// `select name [in word...]; do list; done [redirection...]`, with the words as
+    /// in `For`.
+    Select {
+        name: String,
+        words: Option<String>,
+        body: List,
+        redirects: String,
+    },
+    /// `case word in [(]pattern[|pattern]...) list;; ... esac [redirection...]`
+    Case {
+        /// Source text of the word to match, expanded when the command runs.
+        word: String,
+        /// The patterns of each arm, as source text, with the list they select.
+        arms: Vec<(Vec<String>, List)>,
+        redirects: String,
+    },
+    /// `name() { list; } [redirection...]` or `function name [()] { list; }`, defining
+    /// a function.
+    Function {
+        name: String,
+        body: List,
+        /// Source text of the redirections after the body, applied on every call.
+        redirects: String,
+    },
+    /// `[[ expression ]]`, with the words of the expression as source text. The
+    /// operators `&&`, `||`, `(`, `)`, `<` and `>` are words of their own.
+    Conditional(Vec<String>),
+    /// `first && second`: `second` runs only if `first` succeeds. Chains group to the
+    /// left, so `a && b || c` is `(a && b) || c`.
+    And(Box<Command>, Box<Command>),
+    /// `first || second`: `second` runs only if `first` fails.
+    Or(Box<Command>, Box<Command>),
+    /// `( list ) [redirection...]`, run in a copy of the shell so that nothing it
+    /// changes reaches this one.
+    Subshell {
+        body: List,
+        /// Source text of the redirections after `)`, applied to the whole list.
+        redirects: String,
+    },
+    /// `{ list; } [redirection...]`, run in this shell with the redirections applied
+    /// to the whole list. As a stage of a pipeline it runs in a forked copy instead.
+    Group { body: List, redirects: String },
+    /// A pipeline with a compound command among its stages, such as `(cd dir; ls) | wc`,
+    /// or a compound command run in the background with `&`. Each stage is a `Pipeline`
+    /// of one simple command, or a compound command, and runs in a process of its own.
+    Stages {
+        stages: Vec<Command>,
+        /// Set when the stages end with `&`.
+        background: bool,
+        /// Source text without the `&`, which names the job.
+        text: String,
+    },
+}
+
+impl Command {
+    /// Removes the redirections after a compound command and returns them, for running
+    /// it with redirections already applied. Other commands have none to give up.
+    pub fn take_redirects(&mut self) -> String {
+        match self {
+            Command::If { redirects, .. }
+            | Command::Loop { redirects, .. }
+            | Command::For { redirects, .. }
+            | Command::Select { redirects, .. }
+            | Command::Case { redirects, .. }
+            | Command::Subshell { redirects, .. }
+            | Command::Group { redirects, .. } => std::mem::take(redirects),
+            _ => String::new(),
+        }
+    }
+}
+
+/// Words that end a list inside a compound command.
+const CLOSING_WORDS: &[&str] = &["then", "elif", "else", "fi", "do", "done", "esac", "}"];
+
+/// Parses shell input, tokenized without expansions, into a command list.
+/// Fails with `ParseError::UnexpectedEof` if a compound command is left open.
+pub fn parse_list<'a>(input: &'a str, tokens: Vec<Token<'a>>) -> Result<List, ParseError> {
+    let mut parser = Parser {
+        input,
+        tokens,
+        position: 0,
+    };
+    let list = parser.list(&[])?;
+    match parser.peek() {
+        None => Ok(list),
+        Some(token) => Err(ParseError::unexpected(token)),
+    }
+}
+
+/// Returns true if `input` opens a compound command, such as `if`, that it does not close.
+pub fn is_incomplete(input: &str) -> bool {
+    tokenize(input).is_ok_and(|tokens| parse_list(input, tokens) == Err(ParseError::UnexpectedEof))
+}
+
+/// Appends lines from `next_line` to `line` until it ends neither in a line
+/// continuation nor inside a compound command.
+pub fn join_command_lines(line: String, next_line: &mut dyn FnMut() -> Option<String>) -> String {
+    let mut line = join_continued_lines(line, next_line);
+    while is_incomplete(&line) {
+        let Some(more) = next_line() else {
+            break;
+        };
+        line.push('\n');
+        line.push_str(&more);
+        line = join_continued_lines(line, next_line);
+    }
+    line
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<Token<'a>>,
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token<'_>> {
+        self.tokens.get(self.position)
+    }
+
+    /// Returns the reserved word at the current position, if there is one.
+    /// Quoting a reserved word makes it an ordinary word.
+    fn keyword(&self) -> Option<&str> {
+        match self.peek() {
+            Some(Token {
+                kind: TokenKind::Word(word),
+                quoted: false,
+                ..
+            }) => Some(word),
+            _ => None,
+        }
+    }
+
+    fn at_separator(&self, ops: &[Op]) -> bool {
+        matches!(self.peek(), Some(Token { kind: TokenKind::Operator(op), .. }) if ops.contains(op))
+    }
+
+    /// Consumes the reserved word `word`, or fails on whatever is there instead.
+    fn expect(&mut self, word: &str) -> Result<(), ParseError> {
+        if self.keyword() == Some(word) {
+            self.position += 1;
+            return Ok(());
+        }
+        Err(match self.peek() {
+            Some(token) => ParseError::unexpected(token),
+            None => ParseError::UnexpectedEof,
+        })
+    }
+
+    /// Returns true at one of the reserved words in `terminators`, or at `;;` if that is
+    /// among them.
+    fn at_terminator(&self, terminators: &[&str]) -> bool {
+        self.keyword()
+            .is_some_and(|word| terminators.contains(&word))
+            || (terminators.contains(&";;") && self.at_separator(&[Op::DoubleSemicolon]))
+            || (terminators.contains(&")") && self.at_separator(&[Op::RightParen]))
+    }
+
+    /// Parses commands up to one of the reserved words in `terminators`, or the end of input.
+    fn list(&mut self, terminators: &[&str]) -> Result<List, ParseError> {
+        let mut list = Vec::new();
+        loop {
+            while self.at_separator(&[Op::Newline]) {
+                self.position += 1;
+            }
+            if self.peek().is_none() || self.at_terminator(terminators) {
+                return Ok(list);
+            }
+            list.push(self.and_or()?);
+
+            let after_background = matches!(
+                self.tokens[self.position - 1].kind,
+                TokenKind::Operator(Op::Background)
+            );
+            if self.at_separator(&[Op::Semicolon, Op::Newline]) {
+                self.position += 1;
+            } else if let Some(token) = self.peek()
+                && !after_background
+                && !self.at_terminator(terminators)
+            {
+                return Err(ParseError::unexpected(token));
+            }
+        }
+    }
+
+    /// Parses commands joined by `&&` and `||`, which may be followed by newlines.
+    fn and_or(&mut self) -> Result<Command, ParseError> {
+        let mut command = self.command()?;
+        while let Some(Token {
+            kind: TokenKind::Operator(op @ (Op::And | Op::Or)),
+            ..
+        }) = self.peek()
+        {
+            let and = *op == Op::And;
+            self.position += 1;
+            while self.at_separator(&[Op::Newline]) {
+                self.position += 1;
+            }
+            let second = Box::new(self.command()?);
+            command = if and {
+                Command::And(Box::new(command), second)
+            } else {
+                Command::Or(Box::new(command), second)
+            };
+        }
+        Ok(command)
+    }
+
+    /// Parses one command: a function definition, or a pipeline, whose stages may be
+    /// compound commands.
+    fn command(&mut self) -> Result<Command, ParseError> {
+        let Some(token) = self.peek() else {
+            return Err(ParseError::UnexpectedEof);
+        };
+        match self.keyword() {
+            Some(word) if CLOSING_WORDS.contains(&word) => Err(ParseError::unexpected(token)),
+            Some("function") => {
+                self.position += 1;
+                self.function_definition()
+            }
+            _ if self.at_function_definition() => self.function_definition(),
+            _ if self.at_separator(&[
+                Op::Semicolon,
+                Op::DoubleSemicolon,
+                Op::Background,
+                Op::And,
+                Op::Or,
+                Op::RightParen,
+            ]) =>
+            {
+                Err(ParseError::unexpected(token))
+            }
+            _ => self.pipeline(),
+        }
+    }
+
+    /// Parses stages joined by `|`, which may be followed by newlines, and a trailing `&`.
+    /// Simple commands alone make a `Pipeline`, which keeps the `&`, and a compound
+    /// command alone is itself; anything else makes `Stages`.
+    fn pipeline(&mut self) -> Result<Command, ParseError> {
+        let start = self.position;
+        let mut stages = Vec::new();
+        loop {
+            stages.push(match self.keyword() {
+                Some("if") => self.if_command()?,
+                Some("while" | "until") => self.loop_command()?,
+                Some("for" | "select") => self.for_command()?,
+                Some("case") => self.case_command()?,
+                Some("[[") => self.conditional_command()?,
+                Some("{") => self.group()?,
+                _ if self.at_separator(&[Op::LeftParen]) => self.subshell()?,
+                _ => self.simple_command()?,
+            });
+            if !self.at_separator(&[Op::Pipe]) {
+                break;
+            }
+            self.position += 1;
+            while self.at_separator(&[Op::Newline]) {
+                self.position += 1;
+            }
+            if self.peek().is_none() {
+                return Err(ParseError::UnexpectedEof);
+            }
+        }
+        let background = self.at_separator(&[Op::Background]);
+        let end = self.tokens[self.position - 1].span.end;
+        if background {
+            self.position += 1;
+        }
+
+        let text = self.input[self.tokens[start].span.start..end].to_string();
+        if stages
+            .iter()
+            .all(|stage| matches!(stage, Command::Pipeline(_)))
+        {
+            let end = self.tokens[self.position - 1].span.end;
+            return Ok(Command::Pipeline(
+                self.input[self.tokens[start].span.start..end].to_string(),
+            ));
+        }
+        if stages.len() == 1 && !background {
+            return Ok(stages.remove(0));
+        }
+        Ok(Command::Stages {
+            stages,
+            background,
+            text,
+        })
+    }
+
+    /// Takes the tokens up to the next `|`, `;`, `&`, `&&`, `||` or newline as the source
+    /// of a simple command. A `)` ends it too, closing a subshell. A `(` is a syntax
+    /// error, and so are misplaced operators, which are found before any command on the
+    /// line runs.
+    fn simple_command(&mut self) -> Result<Command, ParseError> {
+        let start = self.position;
+        while let Some(token) = self.peek() {
+            if self.at_separator(&[
+                Op::Pipe,
+                Op::Semicolon,
+                Op::DoubleSemicolon,
+                Op::Background,
+                Op::And,
+                Op::Or,
+                Op::Newline,
+                Op::RightParen,
+            ]) {
+                break;
+            }
+            if self.at_array_assignment() {
+                self.array_words()?;
+                continue;
+            }
+            if self.at_separator(&[Op::LeftParen]) {
+                return Err(ParseError::unexpected(token));
+            }
+            self.position += 1;
+        }
+        if self.position == start {
+            return Err(match self.peek() {
+                Some(token) => ParseError::unexpected(token),
+                None => ParseError::UnexpectedEof,
+            });
+        }
+        // Other errors may go away once the words are expanded
+        if let Err(e @ (ParseError::UnexpectedToken { .. } | ParseError::UnexpectedEof)) =
+            parse_pipeline(self.tokens[start..self.position].to_vec())
+        {
+            return Err(e);
+        }
+        let span = self.tokens[start].span.start..self.tokens[self.position - 1].span.end;
+        Ok(Command::Pipeline(self.input[span].to_string()))
+    }
+
+    /// Parses `( list )` and the redirections that follow it.
+    fn subshell(&mut self) -> Result<Command, ParseError> {
+        self.expect_operator(Op::LeftParen)?;
+        let body = self.non_empty_list(&[")"])?;
+        self.expect_operator(Op::RightParen)?;
+        let redirects = self.redirections()?;
+        Ok(Command::Subshell { body, redirects })
+    }
+
+    /// Parses `{ list; }` and the redirections that follow it. The `}` is only reserved
+    /// where a command could start, so the list must end with `;` or a newline.
+    fn group(&mut self) -> Result<Command, ParseError> {
+        self.expect("{")?;
+        let body = self.non_empty_list(&["}"])?;
+        self.expect("}")?;
+        let redirects = self.redirections()?;
+        Ok(Command::Group { body, redirects })
+    }
+
+    /// Takes the redirections after a compound command as source text.
+    fn redirections(&mut self) -> Result<String, ParseError> {
+        let start = self.position;
+        while let Some(Token {
+            kind: TokenKind::Operator(op),
+            ..
+        }) = self.peek()
+        {
+            if !matches!(
+                op,
+                Op::Output { .. }
+                    | Op::Clobber { .. }
+                    | Op::OutputAll { .. }
+                    | Op::Input { .. }
+                    | Op::ReadWrite { .. }
+                    | Op::DuplicateOutput { .. }
+                    | Op::DuplicateInput { .. }
+                    | Op::HereString { .. }
+                    | Op::HereDoc { .. }
+            ) {
+                break;
+            }
+            self.position += 1;
+            self.word()?;
+        }
+        Ok(match self.tokens[start..self.position] {
+            [ref first, .., ref last] => self.input[first.span.start..last.span.end].to_string(),
+            _ => String::new(),
+        })
+    }
+
+    fn if_command(&mut self) -> Result<Command, ParseError> {
+        let mut branches = Vec::new();
+        let mut otherwise = None;
+
+        self.expect("if")?;
+        loop {
+            let condition = self.non_empty_list(&["then"])?;
+            self.expect("then")?;
+            let body = self.non_empty_list(&["elif", "else", "fi"])?;
+            branches.push((condition, body));
+            if self.keyword() != Some("elif") {
+                break;
+            }
+            self.position += 1;
+        }
+        if self.keyword() == Some("else") {
+            self.position += 1;
+            otherwise = Some(self.non_empty_list(&["fi"])?);
+        }
+        self.expect("fi")?;
+        let redirects = self.redirections()?;
+
+        Ok(Command::If {
+            branches,
+            otherwise,
+            redirects,
+        })
+    }
+
+    fn loop_command(&mut self) -> Result<Command, ParseError> {
+        let until = self.keyword() == Some("until");
+        self.position += 1;
+        let condition = self.non_empty_list(&["do"])?;
+        self.expect("do")?;
+        let body = self.non_empty_list(&["done"])?;
+        self.expect("done")?;
+        let redirects = self.redirections()?;
+        Ok(Command::Loop {
+            until,
+            condition,
+            body,
+            redirects,
+        })
+    }
+
+    /// Parses a `for` loop, or a `select` loop, which has the same form.
+    fn for_command(&mut self) -> Result<Command, ParseError> {
+        let select = self.keyword() == Some("select");
+        self.position += 1;
+        let name = match self.peek() {
+            Some(Token {
+                kind: TokenKind::Word(name),
+                ..
+            }) => name.to_string(),
+            Some(token) => return Err(ParseError::unexpected(token)),
+            None => return Err(ParseError::UnexpectedEof),
+        };
+        self.position += 1;
+
+        while self.at_separator(&[Op::Newline]) {
+            self.position += 1;
+        }
+        let mut words = None;
+        if self.keyword() == Some("in") {
+            self.position += 1;
+            let start = self.position;
+            while let Some(Token {
+                kind: TokenKind::Word(_),
+                ..
+            }) = self.peek()
+            {
+                self.position += 1;
+            }
+            let listed = &self.tokens[start..self.position];
+            words = Some(match (listed.first(), listed.last()) {
+                (Some(first), Some(last)) => {
+                    self.input[first.span.start..last.span.end].to_string()
+                }
+                _ => String::new(),
+            });
+            // The word list must end with `;` or a newline
+            match self.peek() {
+                None => return Err(ParseError::UnexpectedEof),
+                Some(token) if !self.at_separator(&[Op::Semicolon, Op::Newline]) => {
+                    return Err(ParseError::unexpected(token));
+                }
+                _ => self.position += 1,
+            }
+        } else if self.at_separator(&[Op::Semicolon]) {
+            self.position += 1;
+        }
+        while self.at_separator(&[Op::Newline]) {
+            self.position += 1;
+        }
+
+        self.expect("do")?;
+        let body = self.non_empty_list(&["done"])?;
+        self.expect("done")?;
+        let redirects = self.redirections()?;
+        Ok(if select {
+            Command::Select {
+                name,
+                words,
+                body,
+                redirects,
+            }
+        } else {
+            Command::For {
+                name,
+                words,
+                body,
+                redirects,
+            }
+        })
+    }
+
+    fn case_command(&mut self) -> Result<Command, ParseError> {
+        self.expect("case")?;
+        let word = self.word()?;
+        while self.at_separator(&[Op::Newline]) {
+            self.position += 1;
+        }
+        self.expect("in")?;
+
+        let mut arms = Vec::new();
+        loop {
+            while self.at_separator(&[Op::Newline]) {
+                self.position += 1;
+            }
+            if self.keyword() == Some("esac") {
+                break;
+            }
+            if self.at_separator(&[Op::LeftParen]) {
+                self.position += 1;
+            }
+            let mut patterns = vec![self.word()?];
+            while self.at_separator(&[Op::Pipe]) {
+                self.position += 1;
+                patterns.push(self.word()?);
+            }
+            self.expect_operator(Op::RightParen)?;
+            let body = self.list(&[";;", "esac"])?;
+            arms.push((patterns, body));
+            if !self.at_separator(&[Op::DoubleSemicolon]) {
+                break;
+            }
+            self.position += 1;
+        }
+        self.expect("esac")?;
+        let redirects = self.redirections()?;
+
+        Ok(Command::Case {
+            word,
+            arms,
+            redirects,
+        })
+    }
+
+    /// Parses `[[ ... ]]`, which may span lines, keeping the words up to `]]`.
+    fn conditional_command(&mut self) -> Result<Command, ParseError> {
+        self.expect("[[")?;
+        let mut words = Vec::new();
+        while self.keyword() != Some("]]") {
+            let Some(token) = self.peek() else {
+                return Err(ParseError::UnexpectedEof);
+            };
+            // The regular expression after `=~` is one word, even with `(`, `)` or `|` in it
+            if words.last().is_some_and(|word| word == "=~") {
+                let start = token.span.start;
+                let mut end = start;
+                while let Some(token) = self.peek()
+                    && token.span.start == end
+                    && matches!(
+                        token.kind,
+                        TokenKind::Word(_)
+                            | TokenKind::Operator(Op::LeftParen | Op::RightParen | Op::Pipe)
+                    )
+                {
+                    end = token.span.end;
+                    self.position += 1;
+                }
+                if start == end {
+                    return Err(ParseError::unexpected(&self.tokens[self.position]));
+                }
+                words.push(self.input[start..end].to_string());
+                continue;
+            }
+            match token.kind {
+                TokenKind::Operator(Op::Newline) => {}
+                TokenKind::Word(_)
+                | TokenKind::Operator(
+                    Op::And
+                    | Op::Or
+                    | Op::LeftParen
+                    | Op::RightParen
+                    | Op::Input { fd: None }
+                    | Op::Output {
+                        fd: None,
+                        append: false,
+                    },
+                ) => words.push(self.input[token.span.clone()].to_string()),
+                _ => return Err(ParseError::unexpected(token)),
+            }
+            self.position += 1;
+        }
+        if words.is_empty() {
+            return Err(ParseError::unexpected(&self.tokens[self.position]));
+        }
+        self.position += 1;
+        Ok(Command::Conditional(words))
+    }
+
+    /// Returns true at the `(` of `NAME=(word...)` or `NAME+=(word...)`.
+    fn at_array_assignment(&self) -> bool {
+        let Some(paren) = self.peek() else {
+            return false;
+        };
+        paren.kind == TokenKind::Operator(Op::LeftParen)
+            && self.position > 0
+            && matches!(
+                &self.tokens[self.position - 1],
+                Token { kind: TokenKind::Word(word), span, .. }
+                    if span.end == paren.span.start && array_assignment_name(word).is_some()
+            )
+    }
+
+    /// Skips the parenthesized words of an array assignment, which may span lines.
+    fn array_words(&mut self) -> Result<(), ParseError> {
+        self.position += 1;
+        loop {
+            match self.peek() {
+                Some(Token {
+                    kind: TokenKind::Word(_) | TokenKind::Operator(Op::Newline),
+                    ..
+                }) => self.position += 1,
+                Some(Token {
+                    kind: TokenKind::Operator(Op::RightParen),
+                    ..
+                }) => {
+                    self.position += 1;
+                    return Ok(());
+                }
+                Some(token) => return Err(ParseError::unexpected(token)),
+                None => return Err(ParseError::UnexpectedEof),
+            }
+        }
+    }
+
+    /// Returns true at `name()`, the start of a function definition.
+    fn at_function_definition(&self) -> bool {
+        // `NAME=()` is an empty array instead
+        self.keyword()
+            .is_some_and(|word| array_assignment_name(word).is_none())
+            && matches!(
+                self.tokens.get(self.position + 1..self.position + 3),
+                Some([
+                    Token {
+                        kind: TokenKind::Operator(Op::LeftParen),
+                        ..
+                    },
+                    Token {
+                        kind: TokenKind::Operator(Op::RightParen),
+                        ..
+                    },
+                ])
+            )
+    }
+
+    /// Parses a function's name, the optional `()` and its `{ ... }` body.
+    fn function_definition(&mut self) -> Result<Command, ParseError> {
+        let name = match self.keyword() {
+            Some(name) => name.to_string(),
+            None => {
+                return Err(match self.peek() {
+                    Some(token) => ParseError::unexpected(token),
+                    None => ParseError::UnexpectedEof,
+                });
+            }
+        };
+        self.position += 1;
+        if self.at_separator(&[Op::LeftParen]) {
+            self.position += 1;
+            self.expect_operator(Op::RightParen)?;
+        }
+        while self.at_separator(&[Op::Newline]) {
+            self.position += 1;
+        }
+
+        self.expect("{")?;
+        let body = self.non_empty_list(&["}"])?;
+        self.expect("}")?;
+        let redirects = self.redirections()?;
+        Ok(Command::Function {
+            name,
+            body,
+            redirects,
+        })
+    }
+
+    /// Consumes a word, returning its source text so it can be expanded later.
+    fn word(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some(Token {
+                kind: TokenKind::Word(_) | TokenKind::ProcessSubstitution { .. },
+                span,
+                ..
+            }) => {
+                let text = self.input[span.clone()].to_string();
+                self.position += 1;
+                Ok(text)
+            }
+            Some(token) => Err(ParseError::unexpected(token)),
+            None => Err(ParseError::UnexpectedEof),
+        }
+    }
+
+    fn expect_operator(&mut self, op: Op) -> Result<(), ParseError> {
+        if self.at_separator(&[op]) {
+            self.position += 1;
+            return Ok(());
+        }
+        Err(match self.peek() {
+            Some(token) => ParseError::unexpected(token),
+            None => ParseError::UnexpectedEof,
+        })
+    }
+
+    /// Like `list`, but at least one command is required, as in `if` conditions and bodies.
+    fn non_empty_list(&mut self, terminators: &[&str]) -> Result<List, ParseError> {
+        let list = self.list(terminators)?;
+        if list.is_empty() {
+            return Err(match self.peek() {
+                Some(token) => ParseError::unexpected(token),
+                None => ParseError::UnexpectedEof,
+            });
+        }
+        Ok(list)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Result<List, ParseError> {
+        parse_list(input, tokenize(input).unwrap())
+    }
+
+    fn unexpected(token: &str, column: usize) -> Result<List, ParseError> {
+        Err(ParseError::UnexpectedToken {
+            token: token.to_string(),
+            column,
+        })
+    }
+
+    fn pipeline(text: &str) -> Command {
+        Command::Pipeline(text.to_string())
+    }
+
+    #[test]
+    fn test_parse_and_or() {
+        let and = |a, b| Command::And(Box::new(a), Box::new(b));
+        let or = |a, b| Command::Or(Box::new(a), Box::new(b));
+        assert_eq!(
+            parse("false || echo a && echo b; c").unwrap(),
+            vec![
+                and(
+                    or(pipeline("false"), pipeline("echo a")),
+                    pipeline("echo b")
+                ),
+                pipeline("c"),
+            ]
+        );
+        assert_eq!(
+            parse("if a; then b; fi &&\n\n c | d").unwrap(),
+            vec![and(
+                Command::If {
+                    branches: vec![(vec![pipeline("a")], vec![pipeline("b")])],
+                    otherwise: None,
+                    redirects: String::new(),
+                },
+                pipeline("c | d")
+            )]
+        );
+        assert!(is_incomplete("a ||"));
+        assert_eq!(parse("&& a"), unexpected("&&", 1));
+        assert_eq!(parse("a && || b"), unexpected("||", 6));
+        assert_eq!(parse("a; && b"), unexpected("&&", 4));
+    }
+
+    #[test]
+    fn test_parse_sequence() {
+        assert_eq!(
+            parse("echo a; echo b | cat\nsleep 1 & echo $(x; y)").unwrap(),
+            vec![
+                pipeline("echo a"),
+                pipeline("echo b | cat"),
+                pipeline("sleep 1 &"),
+                pipeline("echo $(x; y)"),
+            ]
+        );
+        assert_eq!(parse("\n\n").unwrap(), vec![]);
+        // A line ending in `|` continues the pipeline on the next one
+        assert_eq!(
+            parse("echo a |\n\ncat; b").unwrap(),
+            vec![pipeline("echo a |\n\ncat"), pipeline("b")]
+        );
+        assert!(is_incomplete("echo a |"));
+        assert!(is_incomplete("echo a |\n"));
+    }
+
+    #[test]
+    fn test_parse_if() {
+        assert_eq!(
+            parse("if a; then b; elif c\nthen d; e\nelse f; fi; g").unwrap(),
+            vec![
+                Command::If {
+                    branches: vec![
+                        (vec![pipeline("a")], vec![pipeline("b")]),
+                        (vec![pipeline("c")], vec![pipeline("d"), pipeline("e")]),
+                    ],
+                    otherwise: Some(vec![pipeline("f")]),
+                    redirects: String::new(),
+                },
+                pipeline("g"),
+            ]
+        );
+        // Reserved words only count at the start of a command and when unquoted
+        assert_eq!(
+            parse("if echo then; then echo fi 'fi'; fi").unwrap(),
+            vec![Command::If {
+                branches: vec![(vec![pipeline("echo then")], vec![pipeline("echo fi 'fi'")])],
+                otherwise: None,
+                redirects: String::new(),
+            }]
+        );
+        let if_a = |redirects: &str| Command::If {
+            branches: vec![(vec![pipeline("a")], vec![pipeline("b")])],
+            otherwise: None,
+            redirects: redirects.to_string(),
+        };
+        assert_eq!(
+            parse("if a; then b; fi > out 2>&1").unwrap(),
+            vec![if_a("> out 2>&1")]
+        );
+        assert_eq!(
+            parse("if a; then b; fi | cat").unwrap(),
+            vec![Command::Stages {
+                stages: vec![if_a(""), pipeline("cat")],
+                background: false,
+                text: "if a; then b; fi | cat".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_loops() {
+        assert_eq!(
+            parse("while a; do b\nuntil c\ndo d; done; done").unwrap(),
+            vec![Command::Loop {
+                until: false,
+                condition: vec![pipeline("a")],
+                body: vec![
+                    pipeline("b"),
+                    Command::Loop {
+                        until: true,
+                        condition: vec![pipeline("c")],
+                        body: vec![pipeline("d")],
+                        redirects: String::new(),
+                    },
+                ],
+                redirects: String::new(),
+            }]
+        );
+        assert!(is_incomplete("while true; do"));
+        assert_eq!(parse("while true; do; done"), unexpected(";", 15));
+        assert_eq!(
+            parse("while read l; do echo $l; done < in > out").unwrap(),
+            vec![Command::Loop {
+                until: false,
+                condition: vec![pipeline("read l")],
+                body: vec![pipeline("echo $l")],
+                redirects: "< in > out".to_string(),
+            }]
+        );
+        assert_eq!(parse("while a; do b; done c"), unexpected("c", 21));
+        assert_eq!(
+            parse("while read l; do echo $l; done <<-EOF")
+                .unwrap()
+                .first()
+                .map(|command| command.clone().take_redirects()),
+            Some("<<-EOF".to_string())
+        );
+    }
+
+    #[test]
+    fn test_incomplete_and_invalid_if() {
+        assert!(is_incomplete("if true; then"));
+        assert!(is_incomplete("if true\nthen echo\nelse"));
+        assert!(!is_incomplete("if true; then echo; fi"));
+        assert!(!is_incomplete("echo 'if"));
+
+        assert_eq!(parse("fi"), unexpected("fi", 1));
+        assert_eq!(parse("if true; then fi"), unexpected("fi", 15));
+        assert_eq!(parse("; echo"), unexpected(";", 1));
+        assert_eq!(parse("if a; then b; fi c"), unexpected("c", 18));
+    }
+
+    #[test]
+    fn test_parse_for() {
+        assert_eq!(
+            parse("for x in a 'b c' *.rs; do echo $x; done").unwrap(),
+            vec![Command::For {
+                name: "x".to_string(),
+                words: Some("a 'b c' *.rs".to_string()),
+                body: vec![pipeline("echo $x")],
+                redirects: String::new(),
+            }]
+        );
+        assert_eq!(
+            parse("for x\ndo :; done; for y in; do :; done 2>&1").unwrap(),
+            vec![
+                Command::For {
+                    name: "x".to_string(),
+                    words: None,
+                    body: vec![pipeline(":")],
+                    redirects: String::new(),
+                },
+                Command::For {
+                    name: "y".to_string(),
+                    words: Some(String::new()),
+                    body: vec![pipeline(":")],
+                    redirects: "2>&1".to_string(),
+                },
+            ]
+        );
+        assert!(is_incomplete("for x in a b"));
+        assert_eq!(parse("for x in a | b; do :; done"), unexpected("|", 12));
+        assert_eq!(
+            parse("select x in a b; do break; done").unwrap(),
+            vec![Command::Select {
+                name: "x".to_string(),
+                words: Some("a b".to_string()),
+                body: vec![pipeline("break")],
+                redirects: String::new(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_case() {
+        assert_eq!(
+            parse("case $x in\n(a|'b c') echo 1;;\n*.rs) ;; *) echo 2\nesac").unwrap(),
+            vec![Command::Case {
+                word: "$x".to_string(),
+                arms: vec![
+                    (
+                        vec!["a".to_string(), "'b c'".to_string()],
+                        vec![pipeline("echo 1")]
+                    ),
+                    (vec!["*.rs".to_string()], vec![]),
+                    (vec!["*".to_string()], vec![pipeline("echo 2")]),
+                ],
+                redirects: String::new(),
+            }]
+        );
+        assert_eq!(
+            parse("case x in esac >&2").unwrap(),
+            vec![Command::Case {
+                word: "x".to_string(),
+                arms: vec![],
+                redirects: ">&2".to_string(),
+            }]
+        );
+        assert!(is_incomplete("case x in a) echo;;"));
+        assert_eq!(parse("case x in a echo;; esac"), unexpected("echo", 13));
+    }
+
+    #[test]
+    fn test_parse_conditional() {
+        let words =
+            |words: &[&str]| Command::Conditional(words.iter().map(|w| w.to_string()).collect());
+        assert_eq!(
+            parse("[[ $a < \"b c\" && ( -f x ||\n! y ) ]]; z").unwrap(),
+            vec![
+                words(&[
+                    "$a", "<", "\"b c\"", "&&", "(", "-f", "x", "||", "!", "y", ")"
+                ]),
+                pipeline("z"),
+            ]
+        );
+        assert_eq!(
+            parse("[[ $x =~ ^(a|b)+$ ]]").unwrap(),
+            vec![words(&["$x", "=~", "^(a|b)+$"])]
+        );
+        assert!(parse("[[ ]]").is_err());
+        assert!(parse("[[ a ; ]]").is_err());
+        assert_eq!(parse("[[ a"), Err(ParseError::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_parse_array_assignment() {
+        assert_eq!(
+            parse("a=(1\n2) b+=(); e=()").unwrap(),
+            vec![pipeline("a=(1\n2) b+=()"), pipeline("e=()")]
+        );
+        assert_eq!(parse("a=(1"), Err(ParseError::UnexpectedEof));
+        assert!(parse("echo (1)").is_err());
+    }
+
+    #[test]
+    fn test_parse_function() {
+        let greet = Command::Function {
+            name: "greet".to_string(),
+            body: vec![pipeline("echo hi $1"), pipeline("return 2")],
+            redirects: String::new(),
+        };
+        assert_eq!(
+            parse("greet() { echo hi $1; return 2; }; greet x").unwrap(),
+            vec![greet.clone(), pipeline("greet x")]
+        );
+        assert_eq!(
+            parse("function greet\n{\necho hi $1\nreturn 2\n}").unwrap(),
+            vec![greet]
+        );
+        assert!(is_incomplete("f() {"));
+        assert!(is_incomplete("function f() { echo }"));
+        assert_eq!(parse("f() echo"), unexpected("echo", 5));
+        assert_eq!(
+            parse("f() { cat; } <<E 2>&1; f").unwrap(),
+            vec![
+                Command::Function {
+                    name: "f".to_string(),
+                    body: vec![pipeline("cat")],
+                    redirects: "<<E 2>&1".to_string(),
+                },
+                pipeline("f")
+            ]
+        );
+        assert_eq!(parse("f() { cat; } > out cat"), unexpected("cat", 20));
+    }
+
+    #[test]
+    fn test_parentheses_and_braces() {
+        for (input, token, column) in [
+            ("echo (x)", "(", 6),
+            ("ls )", ")", 4),
+            ("()", ")", 2),
+            ("(ls) | | cat", "|", 8),
+            ("(ls) cat", "cat", 6),
+        ] {
+            assert_eq!(parse(input), unexpected(token, column));
+        }
+        let list = |source| Command::Subshell {
+            body: vec![pipeline("cd /tmp"), pipeline(source)],
+            redirects: String::new(),
+        };
+        assert_eq!(
+            parse("(cd /tmp; ls) | wc -l").unwrap(),
+            vec![Command::Stages {
+                stages: vec![list("ls"), pipeline("wc -l")],
+                background: false,
+                text: "(cd /tmp; ls) | wc -l".to_string(),
+            }]
+        );
+        assert_eq!(
+            parse("echo a |\n(cd /tmp; cat) &").unwrap(),
+            vec![Command::Stages {
+                stages: vec![pipeline("echo a"), list("cat")],
+                background: true,
+                text: "echo a |\n(cd /tmp; cat)".to_string(),
+            }]
+        );
+        assert_eq!(
+            parse("(cd /tmp; sleep 1) & echo x").unwrap(),
+            vec![
+                Command::Stages {
+                    stages: vec![list("sleep 1")],
+                    background: true,
+                    text: "(cd /tmp; sleep 1)".to_string(),
+                },
+                pipeline("echo x")
+            ]
+        );
+        assert!(is_incomplete("(ls) |"));
+        assert_eq!(
+            parse("(cd /; ls) > out 2>&1 && (\npwd\n)").unwrap(),
+            vec![Command::And(
+                Box::new(Command::Subshell {
+                    body: vec![pipeline("cd /"), pipeline("ls")],
+                    redirects: "> out 2>&1".to_string(),
+                }),
+                Box::new(Command::Subshell {
+                    body: vec![pipeline("pwd")],
+                    redirects: String::new(),
+                }),
+            )]
+        );
+        assert!(is_incomplete("(echo a"));
+        assert_eq!(
+            parse("{ cd /; ls; } >> out").unwrap(),
+            vec![Command::Group {
+                body: vec![pipeline("cd /"), pipeline("ls")],
+                redirects: ">> out".to_string(),
+            }]
+        );
+        assert_eq!(
+            parse("{ echo a; echo b; } | sort").unwrap(),
+            vec![Command::Stages {
+                stages: vec![
+                    Command::Group {
+                        body: vec![pipeline("echo a"), pipeline("echo b")],
+                        redirects: String::new(),
+                    },
+                    pipeline("sort")
+                ],
+                background: false,
+                text: "{ echo a; echo b; } | sort".to_string(),
+            }]
+        );
+        assert!(is_incomplete("{ echo a }"));
+        assert_eq!(parse("{ }"), unexpected("}", 3));
+        // Braces are only reserved as whole words at the start of a command
+        assert_eq!(
+            parse("echo {a,b} }; {x}").unwrap(),
+            vec![pipeline("echo {a,b} }"), pipeline("{x}")]
+        );
+    }
+
+    #[test]
+    fn test_join_command_lines() {
+        let mut rest = ["then echo \\", "yes", "fi", "after"]
+            .map(String::from)
+            .into_iter();
+        let joined = join_command_lines("if true".to_string(), &mut || rest.next());
+        assert_eq!(joined, "if true\nthen echo \\\nyes\nfi");
+        assert_eq!(rest.next().as_deref(), Some("after"));
+    }
+}
@@ -0,0 +1,57 @@
+use crate::flags::bit_flags;
+
+bit_flags! {
+    /// Attributes given to a variable with `declare -i` and `-r`, one bit each. Whether a
+    /// variable is exported or an array is kept in `Shell::exported` and `Shell::arrays`.
+    pub struct Attributes {
+        /// `-i`: values assigned are evaluated as arithmetic expressions.
+        const INTEGER = 0;
+        /// `-r`: the variable can no longer be assigned or unset.
+        const READONLY = 1;
+    }
+}
+
+impl Attributes {
+    /// Each attribute with its `declare` flag letter.
+    const ALL: [(Attributes, char); 2] = [(Self::INTEGER, 'i'), (Self::READONLY, 'r')];
+
+    /// Looks up the attribute for a flag letter, as in `declare -i`.
+    pub fn from_flag(flag: char) -> Option<Attributes> {
+        Self::ALL
+            .iter()
+            .find(|(_, c)| *c == flag)
+            .map(|(attribute, _)| *attribute)
+    }
+
+    /// The flag letters of the attributes that are set, as `declare -p` shows them.
+    pub fn flags(self) -> String {
+        Self::ALL
+            .iter()
+            .filter(|(attribute, _)| self.contains(*attribute))
+            .map(|(_, c)| *c)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_clear() {
+        let mut attributes = Attributes::default();
+        attributes.set(Attributes::READONLY, true);
+        attributes.set(Attributes::INTEGER, true);
+        assert_eq!(attributes.flags(), "ir");
+        attributes.set(Attributes::INTEGER, false);
+        assert!(!attributes.contains(Attributes::INTEGER));
+        assert_eq!(attributes.flags(), "r");
+    }
+
+    #[test]
+    fn test_from_flag() {
+        assert_eq!(Attributes::from_flag('i'), Some(Attributes::INTEGER));
+        assert_eq!(Attributes::from_flag('r'), Some(Attributes::READONLY));
+        assert_eq!(Attributes::from_flag('q'), None);
+    }
+}
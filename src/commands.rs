@@ -1,80 +1,1745 @@
+use crate::ast::join_command_lines;
+use crate::attributes::Attributes;
+use crate::encoding;
+use crate::execute::{execute_line, run_line};
+use crate::options::ShellOptions;
+use crate::redirection::{Io, io_error_message};
+use crate::shell::{Array, LoopControl, Shell};
+use crate::tokenize::{split_subscript, strip_carriage_return};
+use crate::trap::{self, Condition};
+use crate::ulimit::{LIMITS, Limit};
 use std::env;
+use std::ffi::OsStr;
+use std::io::Read;
+use std::os::fd::AsFd;
+use std::path::PathBuf;
 
 /// List of builtin commands
-pub const BUILTINS: &[&str] = &["echo", "exit", "type", "pwd", "cd", "clear", "history"];
+pub const BUILTINS: &[&str] = &[
+    "echo", "exit", "type", "pwd", "cd", "clear", "history", "export", "unset", "alias", "unalias",
+    "source", ".", "wait", "jobs", "fg", "bg", "break", "continue", "return", "set", "read",
+    "printf", "test", "[", "declare", "typeset", "readonly", "local", "pushd", "popd", "dirs",
+    "trap", "kill", "exec", "umask", "ulimit", "getopts", "eval", "command", "builtin", "true",
+    "false", ":", "newgrp",
+];
 
-/// Executes a builtin command and returns the output or error.
-pub fn execute_builtin(cmd: &str, args: &[String]) -> Result<String, String> {
-    match cmd {
+/// The `PATH` that `command -p` searches, which finds the standard utilities whatever
+/// the user's `PATH` is.
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Executes a builtin command, writing its output and any error to `io`, and returns
+/// its exit status: 1 after an error, unless the builtin set another.
+pub fn execute_builtin(cmd: &str, args: &[String], shell: &mut Shell, io: &mut Io) -> i32 {
+    let result = match cmd {
         "pwd" => env::current_dir()
-            .map(|p| format!("{}\n", p.display()))
+            .map(|p| io.print(&format!("{}\n", encoding::decode(p.as_os_str()))))
             .map_err(|e| format!("Error getting current directory: {}", e)),
-        "cd" => execute_cd(args),
-        "type" => execute_type(args),
-        "echo" => Ok(args[1..].join(" ") + "\n"),
-        "clear" => Ok("\x1b[2J\x1b[H".to_string()),
+        "cd" => execute_cd(args, shell, io),
+        "pushd" => execute_pushd(args, shell, io),
+        "popd" => execute_popd(args, shell, io),
+        "dirs" => execute_dirs(args, shell, io),
+        "trap" => execute_trap(args, shell, io),
+        "kill" => execute_kill(args, shell, io),
+        "umask" => execute_umask(args, io),
+        "ulimit" => execute_ulimit(args, shell, io),
+        "type" => execute_type(args, shell, io),
+        "echo" => {
+            io.print(&(args[1..].join(" ") + "\n"));
+            Ok(())
+        }
+        "true" | ":" => Ok(()),
+        "false" => {
+            shell.builtin_status = Some(1);
+            Ok(())
+        }
+        "clear" => {
+            io.print("\x1b[2J\x1b[H");
+            Ok(())
+        }
+        "export" => execute_export(args, shell, io),
+        "unset" => execute_unset(args, shell),
+        "alias" => execute_alias(args, shell, io),
+        "unalias" => execute_unalias(args, shell),
+        "history" => execute_history(args, shell, io),
+        "exit" => execute_exit(args, shell),
+        "source" | "." => execute_source(args, shell),
+        "eval" => execute_eval(args, shell),
+        "command" => execute_command(args, shell, io),
+        "builtin" => execute_named_builtin(args, shell, io),
+        "newgrp" => execute_newgrp(args, shell),
+        "jobs" => execute_jobs(args, shell, io),
+        "break" => execute_loop_control(args, shell, LoopControl::Break),
+        "continue" => execute_loop_control(args, shell, LoopControl::Continue),
+        "return" => execute_return(args, shell),
+        "set" => execute_set(args, shell, io),
+        "read" => execute_read(args, shell),
+        "getopts" => execute_getopts(args, shell, io),
+        "printf" => execute_printf(args, shell, io),
+        "test" | "[" => execute_test(args, shell),
+        "declare" | "typeset" => execute_declare(args, shell, io),
+        "readonly" => execute_readonly(args, shell, io),
+        "local" => execute_local(args, shell, io),
+        "fg" => {
+            let status = shell.jobs.foreground(args.get(1).map(String::as_str));
+            shell.builtin_status = status.as_ref().ok().copied();
+            status.map(|_| ()).map_err(|e| format!("fg: {}", e))
+        }
+        "bg" => shell
+            .jobs
+            .background(args.get(1).map(String::as_str))
+            .map(|output| io.print(&output))
+            .map_err(|e| format!("bg: {}", e)),
+        "wait" => execute_wait(args, shell),
+        // Only a stage of a pipeline gets here, where there is no shell to replace and
+        // a command is run as any other stage
+        "exec" => Ok(()),
         _ => Err(format!("{}: command not found", cmd)),
+    };
+    if let Err(message) = &result {
+        io.eprintln(message);
+    }
+    let status = if result.is_ok() { 0 } else { 1 };
+    shell.builtin_status.take().unwrap_or(status)
+}
+
+/// `cd [dir]`: changes to `dir`, or to `$HOME` without one, keeping `$PWD` and
+/// `$OLDPWD` up to date. `cd -` returns to `$OLDPWD` and prints where it went.
+/// A `~` in `dir` has already been expanded with the rest of the command line.
+fn execute_cd(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    let name = shell.name();
+    let variable = |name: &str| shell.var(name).map(str::to_string);
+    let previous = args.get(1).is_some_and(|arg| arg == "-");
+    let target = match args.get(1) {
+        Some(_) if previous => variable("OLDPWD").ok_or(format!("{}: cd: OLDPWD not set", name))?,
+        Some(dir) => dir.clone(),
+        None => variable("HOME").ok_or(format!("{}: cd: HOME not set", name))?,
+    };
+
+    let cwd = change_directory(shell, &target)
+        .map_err(|e| format!("{}: cd: {}: {}", name, target, io_error_message(&e)))?;
+    if previous {
+        io.print(&format!("{}\n", cwd));
+    }
+    Ok(())
+}
+
+/// Changes the working directory to `target`, updating `$PWD` and `$OLDPWD`, and
+/// returns the new directory.
+fn change_directory(shell: &mut Shell, target: &str) -> std::io::Result<String> {
+    let old = env::current_dir().ok();
+    env::set_current_dir(encoding::path(target))?;
+    if let Some(old) = old {
+        shell.set_var("OLDPWD", &encoding::decode(old.as_os_str()));
+    }
+    let cwd =
+        env::current_dir().map_or(target.to_string(), |cwd| encoding::decode(cwd.as_os_str()));
+    shell.set_var("PWD", &cwd);
+    Ok(cwd)
+}
+
+/// The directory stack as `dirs` shows it: the current directory first, then the
+/// directories saved by `pushd` from the top down.
+fn directory_list(shell: &Shell) -> Vec<PathBuf> {
+    let cwd = env::current_dir().unwrap_or_default();
+    std::iter::once(cwd)
+        .chain(shell.dir_stack.iter().rev().cloned())
+        .collect()
+}
+
+/// Changes to the first directory of `list` and saves the rest as the stack, then
+/// prints the new stack as `dirs` does.
+fn set_directory_list(
+    cmd: &str,
+    mut list: Vec<PathBuf>,
+    shell: &mut Shell,
+    io: &mut Io,
+) -> Result<(), String> {
+    let target = encoding::decode(list[0].as_os_str());
+    let cwd = change_directory(shell, &target)
+        .map_err(|e| format!("{}: {}: {}", cmd, target, io_error_message(&e)))?;
+    list[0] = PathBuf::from(encoding::encode(&cwd));
+    shell.dir_stack = list[1..].iter().rev().cloned().collect();
+    io.print(&format_directories(shell, &list, false, false, false));
+    Ok(())
+}
+
+/// The entry that `+N` (counting from the left of `dirs`) or `-N` (from the right)
+/// names in a stack of `len` entries, or `None` if `arg` is not of that form.
+fn stack_index(cmd: &str, arg: &str, len: usize) -> Result<Option<usize>, String> {
+    let (from_right, digits) = match arg.split_at_checked(1) {
+        Some(("+", digits)) => (false, digits),
+        Some(("-", digits)) => (true, digits),
+        _ => return Ok(None),
+    };
+    let Ok(n) = digits.parse::<usize>() else {
+        return Ok(None);
+    };
+    let index = if from_right {
+        len.checked_sub(n + 1)
+    } else {
+        Some(n).filter(|&n| n < len)
+    };
+    index
+        .map(Some)
+        .ok_or_else(|| format!("{}: {}: directory stack index out of range", cmd, arg))
+}
+
+/// Formats the directory stack for `dirs`: on one line, or one per line, optionally
+/// numbered. Unless `long`, the home directory is shown as `~`.
+fn format_directories(
+    shell: &Shell,
+    list: &[PathBuf],
+    long: bool,
+    per_line: bool,
+    numbered: bool,
+) -> String {
+    let home = shell.var("HOME").unwrap_or_default();
+    let names = list.iter().map(|path| {
+        let name = encoding::decode(path.as_os_str());
+        match name.strip_prefix(home) {
+            Some(rest)
+                if !long && !home.is_empty() && (rest.is_empty() || rest.starts_with('/')) =>
+            {
+                format!("~{}", rest)
+            }
+            _ => name,
+        }
+    });
+    if numbered {
+        names
+            .enumerate()
+            .map(|(i, name)| format!("{:2}  {}\n", i, name))
+            .collect()
+    } else if per_line {
+        names.map(|name| name + "\n").collect()
+    } else {
+        names.collect::<Vec<_>>().join(" ") + "\n"
+    }
+}
+
+/// `pushd [dir | +N | -N]`: saves the current directory on the stack and changes to
+/// `dir`. Without an argument the top two entries swap; `+N` and `-N` rotate the
+/// stack so that entry comes to the top.
+fn execute_pushd(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    let mut list = directory_list(shell);
+    let Some(arg) = args.get(1) else {
+        if list.len() < 2 {
+            return Err("pushd: no other directory".to_string());
+        }
+        list.swap(0, 1);
+        return set_directory_list("pushd", list, shell, io);
+    };
+    if let Some(index) = stack_index("pushd", arg, list.len())? {
+        list.rotate_left(index);
+        return set_directory_list("pushd", list, shell, io);
+    }
+
+    let cwd = change_directory(shell, arg)
+        .map_err(|e| format!("pushd: {}: {}", arg, io_error_message(&e)))?;
+    list.insert(0, PathBuf::from(encoding::encode(&cwd)));
+    shell.dir_stack = list[1..].iter().rev().cloned().collect();
+    io.print(&format_directories(shell, &list, false, false, false));
+    Ok(())
+}
+
+/// `popd [+N | -N]`: removes the top of the stack and changes to the directory below
+/// it, or removes the entry `+N` or `-N` names.
+fn execute_popd(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    let mut list = directory_list(shell);
+    if list.len() < 2 {
+        return Err("popd: directory stack empty".to_string());
+    }
+    let index = match args.get(1) {
+        Some(arg) => stack_index("popd", arg, list.len())?.ok_or_else(|| {
+            shell.builtin_status = Some(2);
+            format!("popd: {}: invalid argument", arg)
+        })?,
+        None => 0,
+    };
+    list.remove(index);
+    if index == 0 {
+        return set_directory_list("popd", list, shell, io);
+    }
+    shell.dir_stack = list[1..].iter().rev().cloned().collect();
+    io.print(&format_directories(shell, &list, false, false, false));
+    Ok(())
+}
+
+/// `trap [action] condition...`: runs `action` when a signal arrives, on `EXIT` or
+/// on `ERR`. An empty action ignores the signal and `-` restores the default. Without
+/// an action, or with `-p`, lists the traps that are set; `-l` lists the signals.
+fn execute_trap(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    let list = |io: &mut Io, conditions: &[Condition]| {
+        let traps = shell
+            .traps
+            .iter()
+            .filter(|(condition, _)| conditions.is_empty() || conditions.contains(condition));
+        for (condition, command) in traps {
+            io.print(&format!(
+                "trap -- '{}' {}\n",
+                command.replace('\'', "'\\''"),
+                condition.name()
+            ));
+        }
+    };
+    let mut rest = &args[1..];
+    match rest.first().map(String::as_str) {
+        None => {
+            list(io, &[]);
+            return Ok(());
+        }
+        Some("-l") => {
+            io.print(&trap::signal_list());
+            return Ok(());
+        }
+        Some("--") => rest = &rest[1..],
+        _ => {}
+    }
+
+    let mut conditions = Vec::new();
+    let mut errors = Vec::new();
+    let names = match rest.first().map(String::as_str) {
+        Some("-p") => &rest[1..],
+        // A lone condition is reset, as with `-`
+        _ if rest.len() == 1 => rest,
+        _ => &rest[1..],
+    };
+    for name in names {
+        match Condition::parse(name) {
+            Some(condition) => conditions.push(condition),
+            None => errors.push(format!("trap: {}: invalid signal specification", name)),
+        }
+    }
+
+    match rest.first().map(String::as_str) {
+        Some("-p") => list(io, &conditions),
+        Some(action) => {
+            for condition in conditions {
+                let result = if rest.len() == 1 || action == "-" {
+                    shell.traps.reset(condition)
+                } else {
+                    shell.traps.set(condition, action)
+                };
+                errors.extend(result.err());
+            }
+        }
+        None => return Err("trap: usage: trap [-lp] [[arg] signal_spec ...]".to_string()),
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+/// `wait [%job | pid ...]`: waits for the given jobs, or for all of them, and sets
+/// the status to that of the last one. A pid that is not one of the shell's jobs
+/// gives status 127.
+fn execute_wait(args: &[String], shell: &mut Shell) -> Result<(), String> {
+    if args.len() < 2 {
+        shell.builtin_status = Some(shell.jobs.wait_all());
+        return Ok(());
+    }
+    let mut errors = Vec::new();
+    let mut status = 0;
+    for target in &args[1..] {
+        status = match shell.jobs.wait_for(target) {
+            Ok(code) => code,
+            Err(e) => {
+                errors.push(format!("wait: {}", e));
+                127
+            }
+        };
+    }
+    shell.builtin_status = Some(status);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+/// `kill [-s sig | -n num | -sig] pid | %job ...`: sends a signal, `SIGTERM` unless
+/// another is named, to processes and jobs. `kill -l [sig | status]` lists the signals,
+/// or converts between a signal's name and number.
+fn execute_kill(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    use nix::sys::signal::Signal;
+    use nix::unistd::Pid;
+
+    const USAGE: &str = "kill: usage: kill [-s sigspec | -n signum | -sigspec] pid | jobspec ... or kill -l [sigspec]";
+    let invalid = |name: &str| format!("kill: {}: invalid signal specification", name);
+
+    if args.get(1).is_some_and(|arg| arg == "-l" || arg == "-L") {
+        if args.len() == 2 {
+            io.print(&trap::signal_list());
+        }
+        for name in &args[2..] {
+            // An exit status of 128 + N stands for the signal N that ended a command
+            let signal = match name.parse::<i32>() {
+                Ok(status) if status > 128 => trap::parse_signal(&(status - 128).to_string()),
+                _ => trap::parse_signal(name),
+            }
+            .ok_or_else(|| invalid(name))?;
+            match name.parse::<i32>() {
+                Ok(_) => io.print(&format!("{}\n", signal.as_str().trim_start_matches("SIG"))),
+                Err(_) => io.print(&format!("{}\n", signal as i32)),
+            }
+        }
+        return Ok(());
+    }
+
+    // `None` only checks that the processes exist, as signal 0 does
+    let mut signal = Some(Signal::SIGTERM);
+    let mut rest = &args[1..];
+    match rest.first().map(String::as_str) {
+        Some("-s" | "-n") => {
+            let name = rest.get(1).ok_or(USAGE)?;
+            signal = match name.as_str() {
+                "0" => None,
+                name => Some(trap::parse_signal(name).ok_or_else(|| invalid(name))?),
+            };
+            rest = &rest[2..];
+        }
+        Some("--") => rest = &rest[1..],
+        Some(option) if option.len() > 1 && option.starts_with('-') => {
+            signal = match &option[1..] {
+                "0" => None,
+                name => Some(trap::parse_signal(name).ok_or_else(|| invalid(name))?),
+            };
+            rest = &rest[1..];
+        }
+        _ => {}
+    }
+    if rest.is_empty() {
+        shell.builtin_status = Some(2);
+        return Err(USAGE.to_string());
+    }
+
+    let mut errors = Vec::new();
+    for target in rest {
+        let result = if target.starts_with('%') {
+            shell.jobs.signal(target, signal)
+        } else {
+            match target.parse::<i32>() {
+                Ok(pid) => nix::sys::signal::kill(Pid::from_raw(pid), signal)
+                    .map_err(|e| format!("({}) - {}", pid, e.desc())),
+                Err(_) => Err(format!("{}: arguments must be process or job IDs", target)),
+            }
+        };
+        if let Err(e) = result {
+            errors.push(format!("kill: {}", e));
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+/// `umask [-p] [-S] [mode]`: sets the file creation mask to the octal `mode`, or
+/// prints it, in octal or with `-S` as the permissions it leaves. `-p` prints it as a
+/// command that sets it again.
+fn execute_umask(args: &[String], io: &mut Io) -> Result<(), String> {
+    use nix::sys::stat::{Mode, umask};
+
+    let mut symbolic = false;
+    let mut reusable = false;
+    let mut rest = &args[1..];
+    while let Some(option) = rest.first().filter(|arg| arg.starts_with('-')) {
+        for flag in option.chars().skip(1) {
+            match flag {
+                'S' => symbolic = true,
+                'p' => reusable = true,
+                _ => return Err(format!("umask: -{}: invalid option", flag)),
+            }
+        }
+        rest = &rest[1..];
+    }
+
+    if let Some(mode) = rest.first() {
+        let mask = u32::from_str_radix(mode, 8)
+            .ok()
+            .filter(|mask| *mask <= 0o777)
+            .ok_or_else(|| format!("umask: {}: octal number out of range", mode))?;
+        umask(Mode::from_bits_truncate(mask));
+        return Ok(());
+    }
+
+    // The mask can only be read by setting it, so put it straight back
+    let mask = umask(Mode::empty());
+    umask(mask);
+    let mask = mask.bits() as u32;
+    let text = if symbolic {
+        let allowed = |shift: u32| {
+            let bits = !mask >> shift;
+            [(0o4, 'r'), (0o2, 'w'), (0o1, 'x')]
+                .iter()
+                .filter(|(bit, _)| bits & bit != 0)
+                .map(|(_, c)| *c)
+                .collect::<String>()
+        };
+        format!("u={},g={},o={}", allowed(6), allowed(3), allowed(0))
+    } else {
+        format!("{:04o}", mask)
+    };
+    io.print(&match (reusable, symbolic) {
+        (true, true) => format!("umask -S {}\n", text),
+        (true, false) => format!("umask {}\n", text),
+        _ => format!("{}\n", text),
+    });
+    Ok(())
+}
+
+/// `ulimit [-SH] [-a | -cdfnstuv] [limit]`: shows resource limits, the soft ones
+/// unless `-H` is given, or sets them, both soft and hard unless `-S` or `-H` picks
+/// one. Without a resource option it is about the file size limit.
+fn execute_ulimit(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    let (mut soft, mut hard, mut all) = (false, false, false);
+    let mut limits = Vec::new();
+    let mut value = None;
+    for arg in &args[1..] {
+        let Some(flags) = arg.strip_prefix('-').filter(|flags| !flags.is_empty()) else {
+            if value.replace(arg).is_some() {
+                shell.builtin_status = Some(2);
+                return Err("ulimit: too many arguments".to_string());
+            }
+            continue;
+        };
+        for flag in flags.chars() {
+            match flag {
+                'S' => soft = true,
+                'H' => hard = true,
+                'a' => all = true,
+                _ => match Limit::from_option(flag) {
+                    Some(limit) => limits.push(limit),
+                    None => {
+                        shell.builtin_status = Some(2);
+                        return Err(format!("ulimit: -{}: invalid option", flag));
+                    }
+                },
+            }
+        }
+    }
+    let show_hard = hard && !soft;
+
+    if all {
+        for limit in LIMITS {
+            let value = limit.get(show_hard).map_err(|e| format!("ulimit: {}", e))?;
+            io.print(&format!("{}{}\n", limit.label(), value));
+        }
+        return Ok(());
+    }
+    if limits.is_empty() {
+        limits.extend(Limit::from_option('f'));
+    }
+    if let Some(value) = value {
+        let (soft, hard) = if soft || hard {
+            (soft, hard)
+        } else {
+            (true, true)
+        };
+        for limit in &limits {
+            limit
+                .set(value, soft, hard)
+                .map_err(|e| format!("ulimit: {}", e))?;
+        }
+        return Ok(());
+    }
+    for limit in &limits {
+        let value = limit.get(show_hard).map_err(|e| format!("ulimit: {}", e))?;
+        let label = if limits.len() > 1 {
+            limit.label()
+        } else {
+            String::new()
+        };
+        io.print(&format!("{}{}\n", label, value));
+    }
+    Ok(())
+}
+
+/// `dirs [-clpv] [+N | -N]`: shows the directory stack, starting with the current
+/// directory. `-c` empties it instead.
+fn execute_dirs(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    let mut list = directory_list(shell);
+    let (mut long, mut per_line, mut numbered) = (false, false, false);
+    let mut only = None;
+    for arg in &args[1..] {
+        match arg.as_str() {
+            "-c" => {
+                shell.dir_stack.clear();
+                return Ok(());
+            }
+            "-l" => long = true,
+            "-p" => per_line = true,
+            "-v" => numbered = true,
+            arg => match stack_index("dirs", arg, list.len())? {
+                Some(index) => only = Some(index),
+                None => {
+                    shell.builtin_status = Some(2);
+                    return Err(format!("dirs: {}: invalid option", arg));
+                }
+            },
+        }
+    }
+    if let Some(index) = only {
+        list = vec![list.remove(index)];
+        numbered = false;
     }
+    io.print(&format_directories(shell, &list, long, per_line, numbered));
+    Ok(())
 }
 
-fn execute_cd(args: &[String]) -> Result<String, String> {
-    let target = args.get(1).map_or_else(
-        || env::var("HOME").ok(),
-        |arg| {
-            if *arg == "~" {
-                env::var("HOME").ok()
-            } else if let Some(rest) = arg.strip_prefix("~/") {
-                env::var("HOME").map(|h| format!("{}/{}", h, rest)).ok()
-            } else {
-                Some(arg.to_string())
+fn execute_exit(args: &[String], shell: &mut Shell) -> Result<(), String> {
+    match args.get(1) {
+        None => {
+            shell.exit_code = Some(shell.last_status);
+            Ok(())
+        }
+        Some(arg) => match arg.parse::<i32>() {
+            Ok(code) => {
+                shell.exit_code = Some(code);
+                Ok(())
+            }
+            Err(_) => {
+                shell.exit_code = Some(2);
+                Err(format!("exit: {}: numeric argument required", arg))
             }
         },
+    }
+}
+
+/// `break [N]` and `continue [N]`: asks the innermost N running loops to stop, or to
+/// go on with the next iteration of the Nth. N beyond the loop depth means the outermost.
+fn execute_loop_control(
+    args: &[String],
+    shell: &mut Shell,
+    control: fn(usize) -> LoopControl,
+) -> Result<(), String> {
+    let name = &args[0];
+    let count = match args.get(1) {
+        None => 1,
+        Some(arg) => match arg.parse::<i64>() {
+            Ok(count) if count >= 1 => usize::try_from(count).unwrap_or(usize::MAX),
+            Ok(_) => return Err(format!("{}: {}: loop count out of range", name, arg)),
+            Err(_) => return Err(format!("{}: {}: numeric argument required", name, arg)),
+        },
+    };
+    if shell.loop_depth == 0 {
+        shell.builtin_status = Some(0);
+        return Err(format!(
+            "{}: only meaningful in a `for', `while', or `until' loop",
+            name
+        ));
+    }
+    shell.loop_control = Some(control(count.min(shell.loop_depth)));
+    Ok(())
+}
+
+/// `return [N]`: leaves the running function or sourced file with status N, by default
+/// that of the last command.
+fn execute_return(args: &[String], shell: &mut Shell) -> Result<(), String> {
+    if shell.function_depth == 0 && shell.source_depth == 0 {
+        return Err("return: can only `return' from a function or sourced script".to_string());
+    }
+    let status = match args.get(1) {
+        None => shell.last_status,
+        Some(arg) => arg
+            .parse::<i64>()
+            .map(|n| (n & 0xff) as i32)
+            .map_err(|_| format!("return: {}: numeric argument required", arg))?,
+    };
+    shell.builtin_status = Some(status);
+    shell.loop_control = Some(LoopControl::Return);
+    Ok(())
+}
+
+/// Runs each line of a file in the current shell, so aliases, exports, and `cd`
+/// persist afterwards. A `return` stops the file, and the status is that of the last
+/// command run.
+fn execute_source(args: &[String], shell: &mut Shell) -> Result<(), String> {
+    let Some(path) = args.get(1) else {
+        return Err(format!("{}: filename argument required", args[0]));
+    };
+    let content = std::fs::read(encoding::path(path))
+        .map(|bytes| encoding::from_bytes(&bytes))
+        .map_err(|e| format!("{}: {}", path, io_error_message(&e)))?;
+
+    let saved_arg0 = std::mem::replace(&mut shell.arg0, path.clone());
+    shell.source_depth += 1;
+    shell.last_status = 0;
+    let mut lines = content
+        .lines()
+        .map(|line| strip_carriage_return(line.to_string()));
+    while let Some(line) = lines.next() {
+        let line = join_command_lines(line, &mut || lines.next());
+        let returned = execute_line(shell, &line, &mut || lines.next()) == Err(LoopControl::Return);
+        if returned || shell.exit_code.is_some() {
+            break;
+        }
+    }
+    shell.source_depth -= 1;
+    shell.arg0 = saved_arg0;
+    shell.builtin_status = Some(shell.last_status);
+    Ok(())
+}
+
+/// `eval [arg...]`: joins the arguments with spaces and runs the result as a command
+/// line in the current shell, with the status of what it ran. The line is run exactly
+/// as written, so handing `eval` untrusted input lets it run anything; POSIX requires
+/// the builtin all the same.
+fn execute_eval(args: &[String], shell: &mut Shell) -> Result<(), String> {
+    let line = args[1..].join(" ");
+    if line.trim().is_empty() {
+        return Ok(());
+    }
+    run_line(shell, &line, &mut || None);
+    shell.builtin_status = Some(shell.last_status);
+    Ok(())
+}
+
+/// What `command` does with the names it is given.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandLookup {
+    /// Runs the command, as a builtin or external program but never as a function.
+    Run,
+    /// `-v`: prints how each name would be found.
+    Show,
+    /// `-V`: describes each name, as `type` does.
+    Describe,
+}
+
+/// Splits the words of `command [-pvV] [--] [name [args...]]` into what to do, whether
+/// `-p` asked for the default `PATH`, and the name with its arguments.
+pub fn command_arguments(args: &[String]) -> Result<(CommandLookup, bool, &[String]), String> {
+    let mut lookup = CommandLookup::Run;
+    let mut default_path = false;
+    let mut rest = &args[1..];
+    while let Some(arg) = rest
+        .first()
+        .filter(|arg| arg.starts_with('-') && *arg != "-")
+    {
+        rest = &rest[1..];
+        if arg == "--" {
+            break;
+        }
+        for flag in arg[1..].chars() {
+            match flag {
+                'p' => default_path = true,
+                'v' => lookup = CommandLookup::Show,
+                'V' => lookup = CommandLookup::Describe,
+                _ => return Err(format!("command: -{}: invalid option", flag)),
+            }
+        }
+    }
+    Ok((lookup, default_path, rest))
+}
+
+/// `command [-pvV] name [args...]`: runs `name` skipping any function of that name.
+/// External commands are run by the caller; only builtins get here to run.
+fn execute_command(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    let (lookup, default_path, rest) = command_arguments(args).inspect_err(|_| {
+        shell.builtin_status = Some(2);
+    })?;
+    let Some(name) = rest.first() else {
+        return Ok(());
+    };
+    match lookup {
+        CommandLookup::Run if BUILTINS.contains(&name.as_str()) => {
+            let status = execute_builtin(name, rest, shell, io);
+            shell.builtin_status = Some(status);
+            Ok(())
+        }
+        CommandLookup::Run => {
+            shell.builtin_status = Some(127);
+            Err(format!("{}: command not found", name))
+        }
+        CommandLookup::Show | CommandLookup::Describe => {
+            for name in rest {
+                let path = if default_path {
+                    search_path(name, OsStr::new(DEFAULT_PATH))
+                } else {
+                    full_path(shell, name)
+                };
+                let found = shell.aliases.contains_key(name)
+                    || shell.functions.contains_key(name)
+                    || BUILTINS.contains(&name.as_str())
+                    || path.is_some();
+                if !found {
+                    if lookup == CommandLookup::Describe {
+                        io.eprintln(&format!("command: {}: not found", name));
+                    }
+                    shell.builtin_status = Some(1);
+                } else if lookup == CommandLookup::Describe {
+                    execute_type(&[args[0].clone(), name.clone()], shell, io)?;
+                } else if let Some(value) = shell.aliases.get(name) {
+                    io.print(&format_alias(name, value));
+                } else if let Some(path) = path.filter(|_| {
+                    !shell.functions.contains_key(name) && !BUILTINS.contains(&name.as_str())
+                }) {
+                    io.print(&format!("{}\n", encoding::decode(path.as_os_str())));
+                } else {
+                    io.print(&format!("{}\n", name));
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// `builtin name [args...]`: runs the builtin `name` even if a function has its name.
+fn execute_named_builtin(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    let Some(name) = args.get(1) else {
+        return Ok(());
+    };
+    if !BUILTINS.contains(&name.as_str()) {
+        return Err(format!("builtin: {}: not a shell builtin", name));
+    }
+    let status = execute_builtin(name, &args[1..], shell, io);
+    shell.builtin_status = Some(status);
+    Ok(())
+}
+
+/// `newgrp group`: replaces the shell with a new one whose group ID is `group`, a name
+/// or number. The working directory and environment carry over, and the history is
+/// saved first so that the new shell reads it from the same `$HISTFILE`.
+#[cfg(target_os = "linux")]
+fn execute_newgrp(args: &[String], shell: &mut Shell) -> Result<(), String> {
+    use crate::execute::replace_shell;
+    use nix::unistd::{Gid, Group, User, getgid, getgroups, getuid, initgroups, setgid, setgroups};
+
+    let [_, name] = args else {
+        shell.builtin_status = Some(2);
+        return Err("newgrp: usage: newgrp group".to_string());
+    };
+    let group = match name.parse::<u32>() {
+        Ok(gid) => Group::from_gid(Gid::from_raw(gid)),
+        Err(_) => Group::from_name(name),
+    };
+    let group = group
+        .ok()
+        .flatten()
+        .ok_or_else(|| format!("newgrp: {}: no such group", name))?;
+    let user = User::from_uid(getuid())
+        .ok()
+        .flatten()
+        .ok_or("newgrp: cannot find the current user")?;
+    let user_name = std::ffi::CString::new(user.name).map_err(|e| format!("newgrp: {}", e))?;
+
+    shell.save_history();
+    let program = env::current_exe().map_err(|e| format!("newgrp: {}", io_error_message(&e)))?;
+    let mut command = std::process::Command::new(program);
+    command.env_clear().envs(shell.environment());
+    // The new shell carries on with the same history file
+    if let Some(path) = shell.history_file() {
+        command.env("HISTFILE", path);
+    }
+
+    // Only the new shell gets the group: the shell's own are put back if it cannot start
+    let saved = (
+        getgid(),
+        getgroups().map_err(|e| format!("newgrp: {}", e.desc()))?,
     );
-    match target {
-        Some(dir) => env::set_current_dir(&dir)
-            .map(|_| String::new())
-            .map_err(|_| format!("cd: {}: No such file or directory", dir)),
-        None => Err("cd: HOME not set".to_string()),
+    let restore = || {
+        let _ = setgroups(&saved.1);
+        let _ = setgid(saved.0);
+    };
+    // The supplementary groups go first, as changing the group ID can drop the privilege
+    if let Err(e) = initgroups(&user_name, group.gid).and_then(|()| setgid(group.gid)) {
+        restore();
+        return Err(format!("newgrp: {}: {}", name, e.desc()));
     }
+    let e = replace_shell(&mut command);
+    restore();
+    Err(format!("newgrp: {}", io_error_message(&e)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn execute_newgrp(_args: &[String], _shell: &mut Shell) -> Result<(), String> {
+    Err("newgrp: not supported on this system".to_string())
 }
 
-fn execute_type(args: &[String]) -> Result<String, String> {
+/// `test expression` or `[ expression ]`: see `conditional::evaluate`. The status is 0
+/// when the expression is true, 1 when it is false and 2 when it is malformed.
+fn execute_test(args: &[String], shell: &mut Shell) -> Result<(), String> {
+    let name = &args[0];
+    let mut expression = &args[1..];
+    if name == "[" {
+        match expression.split_last() {
+            Some((last, rest)) if last == "]" => expression = rest,
+            _ => {
+                shell.builtin_status = Some(2);
+                return Err("[: missing `]'".to_string());
+            }
+        }
+    }
+    match crate::conditional::evaluate(expression) {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            shell.builtin_status = Some(1);
+            Ok(())
+        }
+        Err(e) => {
+            shell.builtin_status = Some(2);
+            Err(format!("{}: {}", name, e))
+        }
+    }
+}
+
+/// `set [-+efuxC] [-+o name] [--] [arg...]`: `-` turns options on and `+` turns them off.
+/// Any other arguments replace the positional parameters; with no arguments at all,
+/// lists the shell's variables.
+fn execute_set(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
     if args.len() < 2 {
-        return Ok("type: missing argument\n".to_string());
+        let mut variables: Vec<(String, String)> = shell
+            .variables
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        variables.extend(
+            shell
+                .arrays
+                .iter()
+                .map(|(name, elements)| (name.clone(), quote_array(elements))),
+        );
+        variables.sort();
+        for (name, value) in variables {
+            io.print(&format!("{}={}\n", name, value));
+        }
+        return Ok(());
     }
 
-    let arg = &args[1];
-    if BUILTINS.contains(&arg.as_str()) {
-        Ok(format!("{} is a shell builtin\n", arg))
+    let mut rest = args[1..].iter();
+    let mut positional = None;
+    while let Some(arg) = rest.next() {
+        if arg == "--" {
+            positional = Some(rest.cloned().collect());
+            break;
+        }
+        let enabled = match arg.chars().next() {
+            Some('-') => true,
+            Some('+') => false,
+            _ => {
+                positional = Some(std::iter::once(arg).chain(rest).cloned().collect());
+                break;
+            }
+        };
+        for flag in arg[1..].chars() {
+            let option = match flag {
+                'o' => match rest.next() {
+                    None => {
+                        io.print(&shell.options.describe());
+                        return Ok(());
+                    }
+                    Some(name) => ShellOptions::from_name(name)
+                        .ok_or_else(|| format!("set: {}: invalid option name", name))?,
+                },
+                flag => match ShellOptions::from_flag(flag) {
+                    Some(option) => option,
+                    None => {
+                        shell.builtin_status = Some(2);
+                        return Err(format!("set: {}{}: invalid option", &arg[..1], flag));
+                    }
+                },
+            };
+            shell.options.set(option, enabled);
+        }
+    }
+
+    if let Some(positional) = positional {
+        shell.positional = positional;
+    }
+    Ok(())
+}
+
+/// `printf format [argument...]`: see `printf::format`. Invalid numbers are reported
+/// without stopping the output, but make the status 1.
+fn execute_printf(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    let mut rest = &args[1..];
+    if rest.first().is_some_and(|arg| arg == "--") {
+        rest = &rest[1..];
+    }
+    let Some((format, arguments)) = rest.split_first() else {
+        shell.builtin_status = Some(2);
+        return Err("printf: usage: printf format [arguments]".to_string());
+    };
+    let (output, errors) =
+        crate::printf::format(format, arguments).map_err(|e| format!("printf: {}", e))?;
+    for error in &errors {
+        io.eprintln(&format!("printf: {}", error));
+    }
+    if !errors.is_empty() {
+        shell.builtin_status = Some(1);
+    }
+    io.print(&output);
+    Ok(())
+}
+
+/// `read [-r] [-p prompt] [name...]`: reads a line and splits it at `$IFS` into the
+/// variables named, the last taking the rest of the line. Without `-r` a backslash escapes
+/// the next character and joins lines. Fails at end of input.
+fn execute_read(args: &[String], shell: &mut Shell) -> Result<(), String> {
+    let mut raw = false;
+    let mut names = Vec::new();
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "-r" => raw = true,
+            "-p" => {
+                let prompt = rest.next().ok_or("read: -p: option requires an argument")?;
+                eprint!("{}", prompt);
+            }
+            option if option.starts_with('-') && names.is_empty() => {
+                shell.builtin_status = Some(2);
+                return Err(format!("read: {}: invalid option", option));
+            }
+            name if !is_valid_name(name) => {
+                return Err(format!("read: `{}': not a valid identifier", name));
+            }
+            name => names.push(name.to_string()),
+        }
+    }
+    if names.is_empty() {
+        names.push("REPLY".to_string());
+    }
+
+    let read_line = |line: &mut String| -> std::io::Result<usize> {
+        let mut bytes = Vec::new();
+        let fd = match shell.stdin.as_ref() {
+            Some(fd) => fd.try_clone()?,
+            None => std::io::stdin().as_fd().try_clone_to_owned()?,
+        };
+        // Byte by byte, so nothing after the line is taken from a shared descriptor
+        let mut file = std::fs::File::from(fd);
+        let mut byte = [0];
+        while file.read(&mut byte)? == 1 {
+            bytes.push(byte[0]);
+            if byte[0] == b'\n' {
+                break;
+            }
+        }
+        line.push_str(&encoding::from_bytes(&bytes));
+        Ok(bytes.len())
+    };
+    let mut line = String::new();
+    let complete = loop {
+        let mut more = String::new();
+        read_line(&mut more).map_err(|e| format!("read: {}", io_error_message(&e)))?;
+        let complete = more.ends_with('\n');
+        let more = more.strip_suffix('\n').unwrap_or(&more);
+        line.push_str(more);
+        // An odd number of trailing backslashes continues the line
+        let backslashes = line.len() - line.trim_end_matches('\\').len();
+        if raw || !complete || backslashes.is_multiple_of(2) {
+            break complete;
+        }
+        line.pop();
+    };
+
+    let ifs = shell.var("IFS").unwrap_or(" \t\n").to_string();
+    for (name, value) in names
+        .iter()
+        .zip(split_fields(&line, &ifs, names.len(), raw))
+    {
+        shell
+            .set_variable(name, &value, false)
+            .map_err(|e| format!("read: {}", e))?;
+    }
+    if !complete {
+        shell.builtin_status = Some(1);
+    }
+    Ok(())
+}
+
+/// Splits `line` at the characters of `ifs` into exactly `count` fields, the last of which
+/// takes the rest of the line. Runs of IFS whitespace count as one separator and are
+/// trimmed from both ends. Unless `raw`, a backslash makes the next character literal.
+fn split_fields(line: &str, ifs: &str, count: usize, raw: bool) -> Vec<String> {
+    let mut chars: Vec<(char, bool)> = Vec::new();
+    let mut iter = line.chars();
+    while let Some(c) = iter.next() {
+        if c != '\\' || raw {
+            chars.push((c, false));
+        } else if let Some(escaped) = iter.next() {
+            chars.push((escaped, true));
+        }
+    }
+    let separator = |&(c, escaped): &(char, bool)| !escaped && ifs.contains(c);
+    let blank = |&(c, escaped): &(char, bool)| !escaped && ifs.contains(c) && c.is_whitespace();
+    let text = |chars: &[(char, bool)]| chars.iter().map(|&(c, _)| c).collect::<String>();
+
+    let mut fields = Vec::new();
+    let mut i = chars.iter().take_while(|c| blank(c)).count();
+    while fields.len() + 1 < count && i < chars.len() {
+        let end = i + chars[i..].iter().take_while(|c| !separator(c)).count();
+        fields.push(text(&chars[i..end]));
+        i = end + chars[end..].iter().take_while(|c| blank(c)).count();
+        // At most one separator that is not whitespace, with any whitespace around it
+        if i < chars.len() && separator(&chars[i]) && !blank(&chars[i]) {
+            i += 1;
+            i += chars[i..].iter().take_while(|c| blank(c)).count();
+        }
+    }
+    let end = chars.len() - chars[i..].iter().rev().take_while(|c| blank(c)).count();
+    fields.push(text(&chars[i..end]));
+    fields.resize(count, String::new());
+    fields
+}
+
+/// `getopts optstring name [arg...]`: parses the next option in the arguments, or in the
+/// positional parameters, and stores its letter in `name`, its argument in `OPTARG` and
+/// the index of the next argument in `OPTIND`. Fails once the options run out. A bad
+/// option sets `name` to `?` and is reported, unless `optstring` starts with `:`, which
+/// instead puts the letter in `OPTARG` and a missing argument as `:` in `name`.
+fn execute_getopts(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    let [_, optstring, name, rest @ ..] = args else {
+        shell.builtin_status = Some(2);
+        return Err("getopts: usage: getopts optstring name [arg ...]".to_string());
+    };
+    if !is_valid_name(name) {
+        return Err(format!("getopts: `{}': not a valid identifier", name));
+    }
+    let params = if rest.is_empty() {
+        shell.positional.clone()
+    } else {
+        rest.to_vec()
+    };
+    let (silent, optstring) = match optstring.strip_prefix(':') {
+        Some(optstring) => (true, optstring),
+        None => (false, optstring.as_str()),
+    };
+    let mut optind = shell
+        .var("OPTIND")
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(1)
+        .max(1);
+    let mut offset = match shell.getopts_position {
+        (index, offset) if index == optind => offset,
+        _ => 0,
+    };
+
+    let set = |shell: &mut Shell, letter: &str, optind: usize, offset: usize| {
+        shell.getopts_position = (optind, offset);
+        shell.set_variable("OPTIND", &optind.to_string(), false)?;
+        shell.set_variable(name, letter, false)
+    };
+    let arg: Vec<char> = match params.get(optind - 1) {
+        Some(arg) if offset > 0 => arg.chars().collect(),
+        Some(arg) if arg == "--" => {
+            set(shell, "?", optind + 1, 0)?;
+            shell.builtin_status = Some(1);
+            return Ok(());
+        }
+        Some(arg) if arg.len() > 1 && arg.starts_with('-') => {
+            offset = 1;
+            arg.chars().collect()
+        }
+        _ => {
+            set(shell, "?", optind, 0)?;
+            shell.builtin_status = Some(1);
+            return Ok(());
+        }
+    };
+
+    let letter = arg[offset];
+    offset += 1;
+    if offset >= arg.len() {
+        optind += 1;
+        offset = 0;
+    }
+    let spec = optstring.find(letter).filter(|_| letter != ':');
+    let takes_argument = spec.is_some_and(|i| optstring[i + letter.len_utf8()..].starts_with(':'));
+    let mut optarg = None;
+    let mut result = letter.to_string();
+    if spec.is_none() {
+        if silent {
+            optarg = Some(letter.to_string());
+        } else {
+            io.eprintln(&format!("{}: illegal option -- {}", shell.name(), letter));
+        }
+        result = "?".to_string();
+    } else if takes_argument {
+        if offset > 0 {
+            optarg = Some(arg[offset..].iter().collect());
+            optind += 1;
+            offset = 0;
+        } else if let Some(value) = params.get(optind - 1) {
+            optarg = Some(value.clone());
+            optind += 1;
+        } else if silent {
+            optarg = Some(letter.to_string());
+            result = ":".to_string();
+        } else {
+            io.eprintln(&format!(
+                "{}: option requires an argument -- {}",
+                shell.name(),
+                letter
+            ));
+            result = "?".to_string();
+        }
+    }
+    match optarg {
+        Some(value) => shell.set_variable("OPTARG", &value, false)?,
+        None => shell.unset_var("OPTARG"),
+    }
+    set(shell, &result, optind, offset)?;
+    Ok(())
+}
+
+fn execute_jobs(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    let mut pids_only = false;
+    let mut long = false;
+    for arg in &args[1..] {
+        match arg.as_str() {
+            "-p" => pids_only = true,
+            "-l" => long = true,
+            _ => return Err(format!("jobs: {}: invalid option", arg)),
+        }
+    }
+    io.print(&shell.jobs.list(pids_only, long));
+    Ok(())
+}
+
+/// `export [-p] [name[=value]...]`: marks the variables named for export, assigning
+/// any values. With `-p`, or without names, lists the exported variables.
+fn execute_export(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    if args.len() < 2 || args[1..] == ["-p"] {
+        let mut names: Vec<&String> = shell.exported.iter().collect();
+        names.sort();
+        for name in names {
+            io.print(&match shell.var(name) {
+                Some(value) => format!("declare -x {}=\"{}\"\n", name, escape_double_quoted(value)),
+                None => format!("declare -x {}\n", name),
+            });
+        }
+        return Ok(());
+    }
+
+    let mut invalid = Vec::new();
+    for arg in &args[1..] {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if !is_valid_name(name) {
+            invalid.push(format!("export: `{}': not a valid identifier", arg));
+            continue;
+        }
+        if let Some(value) = value
+            && let Err(e) = shell.set_variable(name, value, false)
+        {
+            invalid.push(format!("export: {}", e));
+            continue;
+        }
+        shell.exported.insert(name.to_string());
+    }
+
+    if invalid.is_empty() {
+        Ok(())
     } else {
-        match full_path(arg) {
-            Some(path) => Ok(format!("{} is {}\n", arg, path)),
-            None => Ok(format!("{}: not found\n", arg)),
+        Err(invalid.join("\n"))
+    }
+}
+
+/// `declare [-+airx] [-p] [name[=value]...]`, also called `typeset`: gives the variables
+/// named the attributes flagged with `-`, or takes them away with `+`, then assigns any
+/// values. Integer attributes apply before the value is assigned and readonly ones after.
+/// With `-p`, or without names, prints the variables in a form that recreates them.
+fn execute_declare(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    let command = &args[0];
+    let mut print = false;
+    let mut array = false;
+    let mut export = None;
+    let mut changes = Vec::new();
+    let mut rest = args[1..].iter().peekable();
+    while let Some(arg) = rest.next_if(|arg| arg.len() > 1 && arg.starts_with(['-', '+'])) {
+        if arg == "--" {
+            break;
+        }
+        let enabled = arg.starts_with('-');
+        for flag in arg[1..].chars() {
+            match flag {
+                'p' => print = true,
+                'a' => array = enabled,
+                'x' => export = Some(enabled),
+                flag => match Attributes::from_flag(flag) {
+                    Some(attribute) => changes.push((attribute, enabled)),
+                    None => {
+                        shell.builtin_status = Some(2);
+                        return Err(format!(
+                            "{}: {}{}: invalid option",
+                            command,
+                            &arg[..1],
+                            flag
+                        ));
+                    }
+                },
+            }
+        }
+    }
+    let names: Vec<&String> = rest.collect();
+
+    if names.is_empty() {
+        let mut all: Vec<&String> = shell
+            .arrays
+            .keys()
+            .chain(shell.attributes.keys())
+            .chain(shell.variables.keys())
+            .collect();
+        all.sort();
+        all.dedup();
+        let listed = all.into_iter().filter(|name| {
+            let attributes = shell.attributes(name);
+            changes
+                .iter()
+                .all(|&(attribute, enabled)| !enabled || attributes.contains(attribute))
+                && (!array || shell.arrays.contains_key(*name))
+                && (export != Some(true) || shell.exported.contains(*name))
+        });
+        for line in listed.filter_map(|name| declaration(shell, name)) {
+            io.print(&line);
+        }
+        return Ok(());
+    }
+    if print {
+        for name in names {
+            match declaration(shell, name) {
+                Some(line) => io.print(&line),
+                None => {
+                    io.eprintln(&format!("{}: {}: not found", command, name));
+                    shell.builtin_status = Some(1);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    let mut invalid = Vec::new();
+    for arg in names {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        let (name, append) = match name.strip_suffix('+') {
+            Some(name) if value.is_some() => (name, true),
+            _ => (name, false),
+        };
+        if !is_valid_name(name) {
+            invalid.push(format!("{}: `{}': not a valid identifier", command, arg));
+            continue;
+        }
+        let mut attributes = shell.attributes(name);
+        let removing = changes.iter().any(|&(_, enabled)| !enabled);
+        if attributes.contains(Attributes::READONLY) && (value.is_some() || removing) {
+            invalid.push(format!("{}: {}: readonly variable", command, name));
+            continue;
+        }
+        for &(attribute, enabled) in &changes {
+            if attribute != Attributes::READONLY {
+                attributes.set(attribute, enabled);
+            }
+        }
+        shell.attributes.insert(name.to_string(), attributes);
+        if array && !shell.arrays.contains_key(name) {
+            // A variable's value becomes the first element of the array
+            let elements = shell.variables.remove(name).map(|value| (0, value));
+            let elements = elements.into_iter().collect();
+            shell.arrays.insert(name.to_string(), elements);
+        }
+        if let Some(value) = value
+            && let Err(e) = shell.set_variable(name, value, append)
+        {
+            invalid.push(format!("{}: {}", command, e));
+            continue;
+        }
+        if changes.contains(&(Attributes::READONLY, true)) {
+            attributes.set(Attributes::READONLY, true);
+            shell.attributes.insert(name.to_string(), attributes);
+        }
+        match export {
+            Some(true) => shell.exported.insert(name.to_string()),
+            Some(false) => shell.exported.remove(name),
+            None => false,
+        };
+    }
+
+    if invalid.is_empty() {
+        Ok(())
+    } else {
+        Err(invalid.join("\n"))
+    }
+}
+
+/// `readonly [-ap] [name[=value]...]`: assigns any values, then marks the variables named
+/// readonly. With `-p`, or without names, lists the readonly variables.
+fn execute_readonly(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    let options = args[1..]
+        .iter()
+        .take_while(|arg| arg.len() > 1 && arg.starts_with('-') && *arg != "--");
+    for option in options {
+        if let Some(flag) = option[1..].chars().find(|flag| !matches!(flag, 'a' | 'p')) {
+            shell.builtin_status = Some(2);
+            return Err(format!("readonly: -{}: invalid option", flag));
+        }
+    }
+    let mut declare = vec![args[0].clone(), "-r".to_string()];
+    declare.extend(args[1..].iter().cloned());
+    execute_declare(&declare, shell, io)
+}
+
+/// `local [-airx] [name[=value]...]`: declares the variables named like `declare`, but
+/// only until the running function returns, when they go back to what they were.
+/// Without names, lists the function's local variables.
+fn execute_local(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    let Some(frame) = shell.locals.last() else {
+        return Err("local: can only be used in a function".to_string());
+    };
+    let options = args[1..]
+        .iter()
+        .take_while(|arg| arg.len() > 1 && arg.starts_with(['-', '+']) && *arg != "--")
+        .count();
+    let names = &args[1 + options..];
+    if names.is_empty() {
+        let mut locals: Vec<&String> = frame.keys().collect();
+        locals.sort();
+        for line in locals
+            .into_iter()
+            .filter_map(|name| declaration(shell, name))
+        {
+            io.print(&line);
+        }
+        return Ok(());
+    }
+
+    let mut declare = args[..1 + options].to_vec();
+    let mut invalid = Vec::new();
+    for arg in names {
+        let name = arg.split_once('=').map_or(arg.as_str(), |(name, _)| name);
+        let name = name.strip_suffix('+').unwrap_or(name);
+        if shell.attributes(name).contains(Attributes::READONLY) {
+            invalid.push(format!("local: {}: readonly variable", name));
+            continue;
+        }
+        // A variable is only saved the first time the function makes it local
+        if is_valid_name(name) && !shell.locals.last().is_some_and(|f| f.contains_key(name)) {
+            let saved = shell.take_variable(name);
+            if let Some(frame) = shell.locals.last_mut() {
+                frame.insert(name.to_string(), saved);
+            }
+        }
+        declare.push(arg.clone());
+    }
+    if declare.len() > 1 + options {
+        let result = execute_declare(&declare, shell, io);
+        invalid.extend(result.err());
+    }
+
+    if invalid.is_empty() {
+        Ok(())
+    } else {
+        Err(invalid.join("\n"))
+    }
+}
+
+/// Returns the `declare` command that recreates the variable `name` with its attributes,
+/// or None if the shell knows nothing of it.
+fn declaration(shell: &Shell, name: &str) -> Option<String> {
+    let mut flags = String::new();
+    let value = match shell.arrays.get(name) {
+        Some(elements) => {
+            flags.push('a');
+            Some(quote_array(elements))
+        }
+        None => shell
+            .var(name)
+            .map(|value| format!("\"{}\"", escape_double_quoted(value))),
+    };
+    let exported = shell.exported.contains(name);
+    if value.is_none() && !exported && !shell.attributes.contains_key(name) {
+        return None;
+    }
+    flags.push_str(&shell.attributes(name).flags());
+    if exported {
+        flags.push('x');
+    }
+    if flags.is_empty() {
+        flags.push('-');
+    }
+    Some(match value {
+        Some(value) => format!("declare -{} {}={}\n", flags, name, value),
+        None => format!("declare -{} {}\n", flags, name),
+    })
+}
+
+/// Renders the elements of an array as `([0]="a" [1]="b")`.
+fn quote_array(elements: &Array) -> String {
+    let elements: Vec<String> = elements
+        .iter()
+        .map(|(i, element)| format!("[{}]=\"{}\"", i, escape_double_quoted(element)))
+        .collect();
+    format!("({})", elements.join(" "))
+}
+
+/// `unset [-fv] name...`: removes the variables, or with `-f` the functions, of those
+/// names. Without either option, a name that is not a variable removes a function.
+/// `name[index]` removes one element of an array.
+fn execute_unset(args: &[String], shell: &mut Shell) -> Result<(), String> {
+    // Some(true) for `-f`, Some(false) for `-v`; the last one given wins
+    let mut functions = None;
+    let mut names = &args[1..];
+    while let Some(option) = names
+        .first()
+        .filter(|arg| arg.len() > 1 && arg.starts_with('-'))
+    {
+        names = &names[1..];
+        if option == "--" {
+            break;
+        }
+        for flag in option[1..].chars() {
+            match flag {
+                'f' => functions = Some(true),
+                'v' => functions = Some(false),
+                _ => {
+                    shell.builtin_status = Some(2);
+                    return Err(format!("unset: -{}: invalid option", flag));
+                }
+            }
+        }
+    }
+
+    let mut invalid = Vec::new();
+    for name in names {
+        if functions == Some(true) {
+            shell.functions.remove(name);
+            continue;
+        }
+        if let Some((array, index, "")) = split_subscript(name)
+            && is_valid_name(array)
+        {
+            if let Err(e) = shell.unset_element(array, index) {
+                invalid.push(format!("unset: {}", e));
+            }
+            continue;
+        }
+        if !is_valid_name(name) {
+            invalid.push(format!("unset: `{}': not a valid identifier", name));
+            continue;
+        }
+        if shell.attributes(name).contains(Attributes::READONLY) {
+            invalid.push(format!("unset: {}: cannot unset: readonly variable", name));
+            continue;
+        }
+        let variable = shell.variables.contains_key(name)
+            || shell.arrays.contains_key(name)
+            || shell.exported.contains(name)
+            || shell.attributes.contains_key(name);
+        if !variable && functions.is_none() {
+            shell.functions.remove(name);
+            continue;
+        }
+        // Unsetting a variable that was never set is not an error
+        shell.variables.remove(name);
+        shell.arrays.remove(name);
+        shell.exported.remove(name);
+        shell.attributes.remove(name);
+    }
+
+    if invalid.is_empty() {
+        Ok(())
+    } else {
+        Err(invalid.join("\n"))
+    }
+}
+
+fn execute_alias(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    if args.len() < 2 {
+        let mut names: Vec<&String> = shell.aliases.keys().collect();
+        names.sort();
+        for name in names {
+            io.print(&format_alias(name, &shell.aliases[name]));
+        }
+        return Ok(());
+    }
+
+    for arg in &args[1..] {
+        match arg.split_once('=') {
+            Some((name, value)) => {
+                shell.aliases.insert(name.to_string(), value.to_string());
+            }
+            None => match shell.aliases.get(arg) {
+                Some(value) => io.print(&format_alias(arg, value)),
+                None => {
+                    io.eprintln(&format!("alias: {}: not found", arg));
+                    shell.builtin_status = Some(1);
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+fn execute_unalias(args: &[String], shell: &mut Shell) -> Result<(), String> {
+    if args.len() < 2 {
+        return Err("unalias: usage: unalias [-a] name [name ...]".to_string());
+    }
+    if args[1] == "-a" {
+        shell.aliases.clear();
+        return Ok(());
+    }
+
+    let missing: Vec<String> = args[1..]
+        .iter()
+        .filter(|name| shell.aliases.remove(*name).is_none())
+        .map(|name| format!("unalias: {}: not found", name))
+        .collect();
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(missing.join("\n"))
+    }
+}
+
+fn execute_history(args: &[String], shell: &mut Shell, io: &mut Io) -> Result<(), String> {
+    match args.get(1).map(|s| s.as_str()) {
+        Some("-c") => {
+            shell.history.clear();
+            shell.history_written = 0;
+            shell.history_modified = true;
+            Ok(())
+        }
+        Some("-r") => {
+            let path = history_path_arg(args, "-r")?;
+            let content = std::fs::read_to_string(path)
+                .map_err(|_| format!("history: {}: No such file or directory", path))?;
+            shell.append_history_lines(&content);
+            shell.history_modified = true;
+            Ok(())
+        }
+        Some("-w") => {
+            let path = history_path_arg(args, "-w")?;
+            std::fs::write(path, shell.history_content(0))
+                .map_err(|e| format!("history: {}: {}", path, e))?;
+            shell.history_written = shell.history.len();
+            Ok(())
+        }
+        Some("-a") => {
+            use std::io::Write;
+
+            let path = history_path_arg(args, "-a")?;
+            let content = shell.history_content(shell.history_written);
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(content.as_bytes()))
+                .map_err(|e| format!("history: {}: {}", path, e))?;
+            shell.history_written = shell.history.len();
+            Ok(())
+        }
+        Some(n) => match n.parse::<usize>() {
+            Ok(limit) => {
+                display_history(&shell.history, Some(limit), io);
+                Ok(())
+            }
+            Err(_) => Err(format!("history: {}: numeric argument required", n)),
+        },
+        None => {
+            display_history(&shell.history, None, io);
+            Ok(())
+        }
+    }
+}
+
+/// Returns the file argument following a `history` flag.
+fn history_path_arg<'a>(args: &'a [String], flag: &str) -> Result<&'a str, String> {
+    args.get(2)
+        .map(|s| s.as_str())
+        .ok_or_else(|| format!("history: {}: filename argument required", flag))
+}
+
+/// Prints numbered history entries, limited to the last `limit` entries if given.
+fn display_history(entries: &[String], limit: Option<usize>, io: &mut Io) {
+    let start = limit.map_or(0, |n| entries.len().saturating_sub(n));
+    for (i, entry) in entries.iter().enumerate().skip(start) {
+        io.print(&format!("{:>4}  {}\n", i + 1, entry));
+    }
+}
+
+/// Formats an alias definition so it can be read back by `alias`.
+fn format_alias(name: &str, value: &str) -> String {
+    format!("alias {}='{}'\n", name, value.replace('\'', "'\\''"))
+}
+
+/// Escapes characters that are special inside double quotes.
+fn escape_double_quoted(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            escaped.push('\\');
         }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Returns true if `name` is a valid shell variable name (`[A-Za-z_][A-Za-z0-9_]*`).
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn execute_type(args: &[String], shell: &Shell, io: &mut Io) -> Result<(), String> {
+    if args.len() < 2 {
+        io.print("type: missing argument\n");
+        return Ok(());
     }
+
+    let arg = &args[1];
+    io.print(&if let Some(value) = shell.aliases.get(arg) {
+        format!("{} is aliased to `{}'\n", arg, value)
+    } else if shell.functions.contains_key(arg) {
+        format!("{} is a function\n", arg)
+    } else if BUILTINS.contains(&arg.as_str()) {
+        format!("{} is a shell builtin\n", arg)
+    } else {
+        match full_path(shell, arg) {
+            Some(path) => format!("{} is {}\n", arg, encoding::decode(path.as_os_str())),
+            None => format!("{}: not found\n", arg),
+        }
+    });
+    Ok(())
 }
 
 /// Finds the full path of a command by searching PATH.
-pub fn full_path(command: &str) -> Option<String> {
+pub fn full_path(shell: &Shell, command: &str) -> Option<PathBuf> {
+    search_path(command, &encoding::encode(shell.var("PATH")?))
+}
+
+/// Finds the full path of a command in the directories of `path`, a list in the
+/// format of `PATH`.
+pub fn search_path(command: &str, path: &OsStr) -> Option<PathBuf> {
     #[cfg(unix)]
     use std::os::unix::fs::PermissionsExt;
 
-    env::var("PATH").ok()?.split(':').find_map(|path| {
-        let full = format!("{}/{}", path, command);
-        std::fs::metadata(&full)
-            .ok()
-            .filter(|m| {
-                m.is_file() && {
-                    #[cfg(unix)]
-                    {
-                        m.permissions().mode() & 0o111 != 0
-                    }
-                    #[cfg(not(unix))]
-                    {
-                        true
-                    }
+    let command = encoding::encode(command);
+    env::split_paths(path).find_map(|path| {
+        let full = path.join(&command);
+        std::fs::metadata(&full).ok().filter(|m| {
+            m.is_file() && {
+                #[cfg(unix)]
+                {
+                    m.permissions().mode() & 0o111 != 0
                 }
-            })?;
+                #[cfg(not(unix))]
+                {
+                    true
+                }
+            }
+        })?;
         Some(full)
     })
 }
@@ -82,16 +1747,392 @@ pub fn full_path(command: &str) -> Option<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::TempDir;
+
+    fn args(words: &[&str]) -> Vec<String> {
+        words.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// Runs the builtin `words` calls for with its output and errors written to memory,
+    /// and returns its status, output and errors.
+    fn run(shell: &mut Shell, words: &[&str]) -> (i32, String, String) {
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let mut io = Io::new(&mut out, Some(&mut err));
+        let status = execute_builtin(words[0], &args(words), shell, &mut io);
+        let text = |bytes| String::from_utf8(bytes).unwrap();
+        (status, text(out), text(err))
+    }
+
+    /// Like `run`, giving the output if the builtin succeeded and the errors if not.
+    fn builtin(shell: &mut Shell, words: &[&str]) -> Result<String, String> {
+        match run(shell, words) {
+            (0, out, _) => Ok(out),
+            (_, _, err) => Err(err.trim_end().to_string()),
+        }
+    }
 
     #[test]
     fn test_echo() {
-        let args = vec!["echo".to_string(), "hello".to_string(), "world".to_string()];
-        assert_eq!(execute_builtin("echo", &args), Ok("hello world\n".to_string()));
+        let mut shell = Shell::new();
+        assert_eq!(
+            builtin(&mut shell, &["echo", "hello", "world"]),
+            Ok("hello world\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_output_and_errors_interleave() {
+        let mut shell = Shell::new();
+        builtin(&mut shell, &["alias", "a=x", "b=y"]).unwrap();
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        // With errors going to the output, each line is where it happened
+        let mut io = Io::new(&mut out, None);
+        let status = execute_builtin(
+            "alias",
+            &args(&["alias", "a", "c", "b"]),
+            &mut shell,
+            &mut io,
+        );
+        assert_eq!(status, 1);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "alias a='x'\nalias: c: not found\nalias b='y'\n"
+        );
+        let mut io = Io::new(&mut err, None);
+        assert_eq!(
+            execute_builtin("printf", &args(&["printf", "%d", "z"]), &mut shell, &mut io),
+            1
+        );
+        assert_eq!(
+            String::from_utf8(err).unwrap(),
+            "printf: z: invalid number\n0"
+        );
+    }
+
+    #[test]
+    fn test_builtin_runs_builtins_only() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            builtin(&mut shell, &["builtin", "echo", "hi"]),
+            Ok("hi\n".to_string())
+        );
+        assert_eq!(
+            run(&mut shell, &["builtin", "test", "a", "=", "b"]),
+            (1, String::new(), String::new())
+        );
+        assert_eq!(
+            builtin(&mut shell, &["builtin", "ls"]),
+            Err("builtin: ls: not a shell builtin".to_string())
+        );
+    }
+
+    #[test]
+    fn test_newgrp_errors() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            builtin(&mut shell, &["newgrp", "no_such_group_x"]),
+            Err("newgrp: no_such_group_x: no such group".to_string())
+        );
+        assert_eq!(run(&mut shell, &["newgrp"]).0, 2);
+    }
+
+    #[test]
+    fn test_umask() {
+        let mut shell = Shell::new();
+        let mut umask = |words: &[&str]| builtin(&mut shell, words);
+        let original = umask(&["umask"]).unwrap();
+        umask(&["umask", "027"]).unwrap();
+        assert_eq!(umask(&["umask"]), Ok("0027\n".to_string()));
+        assert_eq!(umask(&["umask", "-S"]), Ok("u=rwx,g=rx,o=\n".to_string()));
+        assert_eq!(umask(&["umask", "-p"]), Ok("umask 0027\n".to_string()));
+        assert!(umask(&["umask", "8"]).is_err());
+        assert!(umask(&["umask", "1000"]).is_err());
+        assert_eq!(umask(&["umask"]), Ok("0027\n".to_string()));
+        umask(&["umask", original.trim()]).unwrap();
+    }
+
+    #[test]
+    fn test_ulimit() {
+        let mut shell = Shell::new();
+        let mut ulimit = |words: &[&str]| builtin(&mut shell, words);
+        let files = ulimit(&["ulimit", "-n"]).unwrap();
+        assert!(files.trim().parse::<u64>().is_ok());
+        let both = ulimit(&["ulimit", "-Sn", "-t"]).unwrap();
+        assert!(both.starts_with(&format!(
+            "open files                          (-n) {}",
+            files
+        )));
+        assert_eq!(
+            ulimit(&["ulimit", "-a"]).unwrap().lines().count(),
+            LIMITS.len()
+        );
+        assert_eq!(
+            ulimit(&["ulimit", "-n", "lots"]),
+            Err("ulimit: lots: invalid number".to_string())
+        );
+        assert_eq!(run(&mut shell, &["ulimit", "-z"]).0, 2);
     }
 
     #[test]
     fn test_type_builtin() {
-        let args = vec!["type".to_string(), "echo".to_string()];
-        assert!(execute_builtin("type", &args).unwrap().contains("builtin"));
+        let mut shell = Shell::new();
+        assert!(
+            builtin(&mut shell, &["type", "echo"])
+                .unwrap()
+                .contains("builtin")
+        );
+        assert_eq!(
+            builtin(&mut shell, &["type", "false"]),
+            Ok("false is a shell builtin\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_true_and_false() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run(&mut shell, &["true", "x"]),
+            (0, String::new(), String::new())
+        );
+        assert_eq!(
+            run(&mut shell, &[":", "x"]),
+            (0, String::new(), String::new())
+        );
+        assert_eq!(
+            run(&mut shell, &["false"]),
+            (1, String::new(), String::new())
+        );
+    }
+
+    #[test]
+    fn test_export_sets_and_lists_variable() {
+        let mut shell = Shell::new();
+        builtin(&mut shell, &["export", "EXPORT_TEST_VAR=a\"b"]).unwrap();
+        assert_eq!(shell.var("EXPORT_TEST_VAR"), Some("a\"b"));
+
+        let listing = builtin(&mut shell, &["export"]).unwrap();
+        assert!(listing.contains("declare -x EXPORT_TEST_VAR=\"a\\\"b\"\n"));
+        assert_eq!(builtin(&mut shell, &["export", "-p"]), Ok(listing));
+    }
+
+    #[test]
+    fn test_export_rejects_invalid_name() {
+        let mut shell = Shell::new();
+        let result = builtin(&mut shell, &["export", "1X=y"]);
+        assert_eq!(
+            result,
+            Err("export: `1X=y': not a valid identifier".to_string())
+        );
+    }
+
+    #[test]
+    fn test_unset_removes_variable() {
+        let mut shell = Shell::new();
+        builtin(&mut shell, &["export", "UNSET_TEST_VAR=1"]).unwrap();
+        builtin(&mut shell, &["unset", "UNSET_TEST_VAR"]).unwrap();
+        assert!(shell.var("UNSET_TEST_VAR").is_none());
+        assert!(!shell.exported.contains("UNSET_TEST_VAR"));
+    }
+
+    #[test]
+    fn test_unset_functions() {
+        let mut shell = Shell::new();
+        run_line(&mut shell, "UNSET_F=1; UNSET_F() { :; }", &mut || None);
+        builtin(&mut shell, &["unset", "-f", "UNSET_F"]).unwrap();
+        assert!(!shell.functions.contains_key("UNSET_F"));
+        assert_eq!(shell.var("UNSET_F"), Some("1"));
+
+        // Without -f the variable goes first, then the function
+        run_line(&mut shell, "UNSET_F() { :; }", &mut || None);
+        builtin(&mut shell, &["unset", "UNSET_F"]).unwrap();
+        assert!(shell.var("UNSET_F").is_none());
+        assert!(shell.functions.contains_key("UNSET_F"));
+        builtin(&mut shell, &["unset", "-v", "UNSET_F"]).unwrap();
+        assert!(shell.functions.contains_key("UNSET_F"));
+        builtin(&mut shell, &["unset", "UNSET_F"]).unwrap();
+        assert!(!shell.functions.contains_key("UNSET_F"));
+
+        assert_eq!(
+            run(&mut shell, &["unset", "-q", "x"]),
+            (2, String::new(), "unset: -q: invalid option\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_unset_undefined_variable_succeeds() {
+        let mut shell = Shell::new();
+        let result = builtin(&mut shell, &["unset", "NEVER_SET_VAR"]);
+        assert_eq!(result, Ok(String::new()));
+    }
+
+    #[test]
+    fn test_declare_attributes() {
+        let mut shell = Shell::new();
+        let declare = |shell: &mut Shell, words: &[&str]| builtin(shell, words);
+        declare(&mut shell, &["declare", "-i", "DECLARE_N=40+2"]).unwrap();
+        declare(&mut shell, &["declare", "-rx", "DECLARE_R=a\"b"]).unwrap();
+        declare(&mut shell, &["declare", "-a", "DECLARE_A"]).unwrap();
+        assert_eq!(shell.var("DECLARE_N"), Some("42"));
+        assert_eq!(
+            declare(
+                &mut shell,
+                &["declare", "-p", "DECLARE_N", "DECLARE_R", "DECLARE_A"]
+            ),
+            Ok("declare -i DECLARE_N=\"42\"\n\
+                declare -rx DECLARE_R=\"a\\\"b\"\n\
+                declare -a DECLARE_A=()\n"
+                .to_string())
+        );
+        assert_eq!(
+            declare(&mut shell, &["declare", "DECLARE_R=c"]),
+            Err("declare: DECLARE_R: readonly variable".to_string())
+        );
+        assert_eq!(
+            builtin(&mut shell, &["unset", "DECLARE_R"]),
+            Err("unset: DECLARE_R: cannot unset: readonly variable".to_string())
+        );
+        assert_eq!(shell.var("DECLARE_R"), Some("a\"b"));
+    }
+
+    #[test]
+    fn test_readonly_builtin() {
+        let mut shell = Shell::new();
+        let readonly = |shell: &mut Shell, words: &[&str]| builtin(shell, words);
+        readonly(&mut shell, &["readonly", "READONLY_A=1", "READONLY_B"]).unwrap();
+        assert_eq!(
+            builtin(&mut shell, &["export", "READONLY_A=2"]),
+            Err("export: READONLY_A: readonly variable".to_string())
+        );
+        assert_eq!(shell.var("READONLY_A"), Some("1"));
+        assert_eq!(
+            readonly(&mut shell, &["readonly", "READONLY_A=3"]),
+            Err("readonly: READONLY_A: readonly variable".to_string())
+        );
+
+        let listing = readonly(&mut shell, &["readonly", "-p"]).unwrap();
+        assert!(listing.contains("declare -r READONLY_A=\"1\"\ndeclare -r READONLY_B\n"));
+        assert!(listing.contains(" PPID=") && listing.contains(" UID="));
+        assert!(!listing.contains("PATH="));
+        assert_eq!(run(&mut shell, &["readonly", "-i", "READONLY_C"]).0, 2);
+    }
+
+    #[test]
+    fn test_declare_lists_by_attribute() {
+        let mut shell = Shell::new();
+        builtin(&mut shell, &["typeset", "-i", "TYPESET_I=7"]).unwrap();
+        let listing = builtin(&mut shell, &["declare", "-i"]).unwrap();
+        assert!(listing.contains("declare -i TYPESET_I=\"7\"\n"));
+        assert!(!listing.contains("PATH="));
+        assert_eq!(run(&mut shell, &["declare", "-q"]).0, 2);
+    }
+
+    #[test]
+    fn test_alias_define_list_and_type() {
+        let mut shell = Shell::new();
+        builtin(&mut shell, &["alias", "ll=ls -la"]).unwrap();
+        assert_eq!(
+            builtin(&mut shell, &["alias"]),
+            Ok("alias ll='ls -la'\n".to_string())
+        );
+        assert_eq!(
+            builtin(&mut shell, &["type", "ll"]),
+            Ok("ll is aliased to `ls -la'\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_unalias() {
+        let mut shell = Shell::new();
+        builtin(&mut shell, &["alias", "a=x", "b=y"]).unwrap();
+        builtin(&mut shell, &["unalias", "a"]).unwrap();
+        assert!(!shell.aliases.contains_key("a"));
+        assert_eq!(
+            builtin(&mut shell, &["unalias", "a"]),
+            Err("unalias: a: not found".to_string())
+        );
+        builtin(&mut shell, &["unalias", "-a"]).unwrap();
+        assert!(shell.aliases.is_empty());
+    }
+
+    #[test]
+    fn test_history_listing_and_limit() {
+        let mut shell = Shell::new();
+        shell.history = args(&["echo a", "echo b", "history 2"]);
+        assert_eq!(
+            builtin(&mut shell, &["history", "2"]),
+            Ok("   2  echo b\n   3  history 2\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_history_clear() {
+        let mut shell = Shell::new();
+        shell.history = args(&["echo a", "history -c"]);
+        builtin(&mut shell, &["history", "-c"]).unwrap();
+        assert!(shell.history.is_empty());
+        assert!(shell.history_modified);
+    }
+
+    #[test]
+    fn test_source_runs_in_current_shell() {
+        let dir = TempDir::new("source_test");
+        let path = dir.join("script.sh");
+        std::fs::write(&path, "export SOURCE_TEST_VAR=1\nalias hi='echo hi'\n").unwrap();
+
+        let mut shell = Shell::new();
+        let script = path.to_string_lossy().to_string();
+        builtin(&mut shell, &[".", &script]).unwrap();
+
+        assert_eq!(shell.var("SOURCE_TEST_VAR"), Some("1"));
+        assert_eq!(shell.aliases.get("hi").map(String::as_str), Some("echo hi"));
+    }
+
+    #[test]
+    fn test_source_status_and_return() {
+        let dir = TempDir::new("source_return");
+        let path = dir.join("script.sh");
+        let script = path.to_string_lossy().to_string();
+        let mut shell = Shell::new();
+
+        std::fs::write(&path, "true\nfalse\n").unwrap();
+        run_line(&mut shell, &format!(". {}", script), &mut || None);
+        assert_eq!(shell.last_status, 1);
+
+        std::fs::write(
+            &path,
+            "SOURCE_RETURN=1\nif true; then return 3; fi\nSOURCE_RETURN=2\n",
+        )
+        .unwrap();
+        run_line(&mut shell, &format!(". {}", script), &mut || None);
+        assert_eq!(shell.last_status, 3);
+        assert_eq!(shell.var("SOURCE_RETURN"), Some("1"));
+
+        // The file's `return` leaves only the file, not a function sourcing it
+        run_line(
+            &mut shell,
+            &format!("f() {{ . {}; SOURCE_RETURN=after; }}; f", script),
+            &mut || None,
+        );
+        assert_eq!(shell.var("SOURCE_RETURN"), Some("after"));
+    }
+
+    #[test]
+    fn test_source_missing_file() {
+        let mut shell = Shell::new();
+        let result = builtin(&mut shell, &["source", "/nonexistent.sh"]);
+        assert_eq!(
+            result,
+            Err("/nonexistent.sh: No such file or directory".to_string())
+        );
+    }
+
+    #[test]
+    fn test_split_fields() {
+        let split = |line, ifs, count, raw| split_fields(line, ifs, count, raw);
+        assert_eq!(split("  a  b c  ", " \t\n", 2, false), ["a", "b c"]);
+        assert_eq!(split("a", " \t\n", 3, false), ["a", "", ""]);
+        assert_eq!(split("a::b:c", ":", 3, false), ["a", "", "b:c"]);
+        assert_eq!(split("a\\ b c", " ", 2, false), ["a b", "c"]);
+        assert_eq!(split("a\\ b c", " ", 2, true), ["a\\", "b c"]);
     }
 }
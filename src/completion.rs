@@ -1,5 +1,7 @@
+use crate::encoding;
+use crate::shell::Shell;
 use rustyline::Helper;
-use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::completion::{Completer, Pair};
 use rustyline::error::ReadlineError;
 use rustyline::highlight::Highlighter;
 use rustyline::hint::Hinter;
@@ -9,16 +11,26 @@ use std::env;
 /// Shell completer for tab completion.
 pub struct ShellCompleter {
     builtins: Vec<String>,
-    filename_completer: FilenameCompleter,
+    /// The shell's `PATH`, searched for command names.
+    path: String,
+    /// The shell's `HOME`, which a leading `~/` stands for.
+    home: String,
 }
 
 impl ShellCompleter {
     pub fn new(builtins: Vec<String>) -> Self {
         Self {
             builtins,
-            filename_completer: FilenameCompleter::new(),
+            path: String::new(),
+            home: String::new(),
         }
     }
+
+    /// Picks up the `PATH` and `HOME` the shell has now.
+    pub fn update(&mut self, shell: &Shell) {
+        self.path = shell.var("PATH").unwrap_or_default().to_string();
+        self.home = shell.var("HOME").unwrap_or_default().to_string();
+    }
 }
 
 impl Completer for ShellCompleter {
@@ -28,7 +40,7 @@ impl Completer for ShellCompleter {
         &self,
         line: &str,
         pos: usize,
-        ctx: &rustyline::Context<'_>,
+        _ctx: &rustyline::Context<'_>,
     ) -> Result<(usize, Vec<Self::Candidate>), ReadlineError> {
         let (start, word) = extract_word(line, pos);
         // Check if we're completing the first word (command)
@@ -55,53 +67,82 @@ impl Completer for ShellCompleter {
                 });
 
             // Complete PATH binaries
-            if let Ok(path) = env::var("PATH") {
-                for dir in path.split(':') {
-                    if let Ok(entries) = std::fs::read_dir(dir) {
-                        entries
-                            .flatten()
-                            .filter_map(|e| e.file_name().into_string().ok())
-                            .filter(|name| name.starts_with(&word))
-                            .for_each(|name| {
-                                candidates.push(Pair {
-                                    display: name.clone(),
-                                    replacement: format!("{} ", name),
-                                });
-                            });
-                    }
-                }
+            for name in path_commands(&word, &self.path) {
+                candidates.push(Pair {
+                    display: name.clone(),
+                    replacement: format!("{} ", escape(&name)),
+                });
             }
 
             candidates.sort_by(|a, b| a.display.cmp(&b.display));
             candidates.dedup_by(|a, b| a.display == b.display);
             Ok((start, candidates))
         } else {
-            // Use filename completer: directories get '/', files get ' '
-            let (start, candidates) = self.filename_completer.complete(line, pos, ctx)?;
-            let candidates_with_space: Vec<Pair> = candidates
-                .into_iter()
-                .map(|c| {
-                    // rustyline adds '/' to replacement for directories
-                    let is_dir = c.replacement.ends_with('/');
-                    let replacement = if is_dir {
-                        c.replacement
-                    } else {
-                        c.replacement + " "
-                    };
-                    // Display shows '/' for directories, no suffix for files
-                    let display = if is_dir {
-                        c.display + "/"
-                    } else {
-                        c.display
-                    };
-                    Pair { display, replacement }
-                })
-                .collect();
-            Ok((start, candidates_with_space))
+            Ok((start, complete_filename(&word, &self.home)))
         }
     }
 }
 
+/// The names in the directories of `path`, a list in the format of `PATH`, that start
+/// with `prefix`. Names that are not valid UTF-8 keep their raw bytes (see `encoding`),
+/// so they can still be run.
+fn path_commands(prefix: &str, path: &str) -> Vec<String> {
+    env::split_paths(&encoding::encode(path))
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.flatten())
+        .map(|entry| encoding::decode(&entry.file_name()))
+        .filter(|name| name.starts_with(prefix))
+        .collect()
+}
+
+/// Completes `word` as a path, with a leading `~/` standing for `home`: directories get
+/// '/' and files get ' '. Hidden files are offered only once the name being completed
+/// starts with a dot.
+fn complete_filename(word: &str, home: &str) -> Vec<Pair> {
+    let (dir, prefix) = match word.rfind('/') {
+        Some(i) => word.split_at(i + 1),
+        None => ("", word),
+    };
+    let search = match dir.strip_prefix('~') {
+        Some(rest) if rest.starts_with('/') => home.to_string() + rest,
+        _ if dir.is_empty() => ".".to_string(),
+        _ => dir.to_string(),
+    };
+    let Ok(entries) = std::fs::read_dir(encoding::path(&search)) else {
+        return Vec::new();
+    };
+
+    let mut candidates: Vec<Pair> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = encoding::decode(&entry.file_name());
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let is_dir = entry.path().is_dir();
+            let (display_suffix, suffix) = if is_dir { ("/", "/") } else { ("", " ") };
+            Some(Pair {
+                display: format!("{}{}", name, display_suffix),
+                replacement: format!("{}{}{}", dir, escape(&name), suffix),
+            })
+        })
+        .collect();
+    candidates.sort_by(|a, b| a.display.cmp(&b.display));
+    candidates
+}
+
+/// Backslash-escapes the characters the shell would otherwise treat specially.
+fn escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_whitespace() || "\\'\"$`&|;<>()*?[]{}!#".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
 fn extract_word(line: &str, pos: usize) -> (usize, String) {
     let before = &line[..pos];
     let start = before
@@ -116,3 +157,44 @@ impl Hinter for ShellCompleter {
 }
 impl Highlighter for ShellCompleter {}
 impl Validator for ShellCompleter {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn test_complete_filename() {
+        let dir = TempDir::new("complete");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a b"), "").unwrap();
+        std::fs::write(dir.join(".hidden"), "").unwrap();
+        let base = format!("{}/", dir.to_string_lossy());
+
+        let candidates = complete_filename(&base, "");
+        let displays: Vec<&str> = candidates.iter().map(|c| c.display.as_str()).collect();
+        assert_eq!(displays, vec!["a b", "sub/"]);
+        assert_eq!(candidates[0].replacement, format!("{}a\\ b ", base));
+        assert_eq!(candidates[1].replacement, format!("{}sub/", base));
+        assert_eq!(
+            complete_filename(&format!("{}.h", base), "")[0].display,
+            ".hidden"
+        );
+    }
+
+    #[test]
+    fn test_complete_non_utf8_filename() {
+        let dir = TempDir::new("complete_raw");
+        let raw = dir.join(OsStr::from_bytes(b"f\xFF"));
+        std::fs::write(&raw, "").unwrap();
+        let base = format!("{}/", dir.to_string_lossy());
+
+        // The completed word still names the file once encoded again
+        let candidates = complete_filename(&format!("{}f", base), "");
+        assert_eq!(candidates.len(), 1);
+        let word = candidates[0].replacement.trim_end();
+        assert_eq!(encoding::path(word), raw);
+    }
+}
@@ -0,0 +1,434 @@
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+
+use nix::unistd::{AccessFlags, access};
+use regex::Regex;
+
+use crate::encoding;
+use crate::glob::{escape, match_pattern};
+
+/// Evaluates the expression of a `test` or `[` command, given as its separate arguments.
+/// Supports the file, string and integer tests of POSIX `test`, combined with `!`,
+/// `-a`, `-o` and parentheses. An empty expression is false.
+pub fn evaluate(args: &[String]) -> Result<bool, String> {
+    let operands: Vec<Operand> = args
+        .iter()
+        .map(|arg| Operand {
+            text: arg.clone(),
+            pattern: escape(arg),
+            quoted: false,
+        })
+        .collect();
+    Parser::new(&operands, false).evaluate()
+}
+
+/// Evaluates the expression of `[[ ... ]]`. Unlike `test`, it combines tests with `&&`
+/// and `||`, `==` and `!=` match a glob pattern, and `=~` matches the regular expression
+/// given by the text of its right operand, in the syntax of the `regex` crate. That
+/// covers POSIX extended regular expressions, except that of alternatives the first to
+/// match is taken rather than the longest. Returns the result with, after a successful
+/// `=~`, the text matched by the expression and by each of its groups.
+pub fn evaluate_extended(operands: &[Operand]) -> Result<(bool, Option<Vec<String>>), String> {
+    let mut parser = Parser::new(operands, true);
+    let value = parser.evaluate()?;
+    Ok((value, parser.captures))
+}
+
+/// One argument of a conditional expression. Only unquoted arguments act as operators.
+#[derive(Debug, Clone)]
+pub struct Operand {
+    pub text: String,
+    /// The argument as a glob pattern, with its quoted parts escaped.
+    pub pattern: String,
+    pub quoted: bool,
+}
+
+const BINARY_OPERATORS: &[&str] = &[
+    "=", "==", "!=", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef",
+];
+
+const UNARY_OPERATORS: &[&str] = &[
+    "-e", "-f", "-d", "-r", "-w", "-x", "-s", "-L", "-h", "-b", "-c", "-p", "-S", "-g", "-u", "-k",
+    "-z", "-n",
+];
+
+struct Parser<'a> {
+    operands: &'a [Operand],
+    position: usize,
+    /// Set for `[[ ... ]]`.
+    extended: bool,
+    /// Set while parsing the right side of a `&&` or `||` whose result is already
+    /// decided, whose tests are then not evaluated.
+    skipping: bool,
+    captures: Option<Vec<String>>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(operands: &'a [Operand], extended: bool) -> Self {
+        Self {
+            operands,
+            position: 0,
+            extended,
+            skipping: false,
+            captures: None,
+        }
+    }
+
+    fn evaluate(&mut self) -> Result<bool, String> {
+        let operands = self.operands;
+        if operands.is_empty() {
+            return Ok(false);
+        }
+        let value = self.or()?;
+        match operands.get(self.position) {
+            None => Ok(value),
+            Some(_) if operands.len() == 2 => {
+                Err(format!("{}: unary operator expected", operands[0].text))
+            }
+            Some(_) if operands.len() == 3 => {
+                Err(format!("{}: binary operator expected", operands[1].text))
+            }
+            Some(_) => Err("too many arguments".to_string()),
+        }
+    }
+
+    fn peek(&self, offset: usize) -> Option<&'a Operand> {
+        self.operands.get(self.position + offset)
+    }
+
+    /// Returns the operator at `offset` from the current position, if there is one there.
+    fn operator(&self, offset: usize) -> Option<&'a str> {
+        self.peek(offset)
+            .filter(|operand| !operand.quoted)
+            .map(|operand| operand.text.as_str())
+    }
+
+    fn is_binary_operator(&self, offset: usize) -> bool {
+        self.operator(offset).is_some_and(|operator| {
+            BINARY_OPERATORS.contains(&operator) || self.extended && operator == "=~"
+        })
+    }
+
+    fn next(&mut self) -> Result<&'a Operand, String> {
+        let operand = self.peek(0).ok_or("argument expected")?;
+        self.position += 1;
+        Ok(operand)
+    }
+
+    fn or(&mut self) -> Result<bool, String> {
+        let or = if self.extended { "||" } else { "-o" };
+        let mut value = self.and()?;
+        while self.operator(0) == Some(or) {
+            self.position += 1;
+            // Like bash, `test` evaluates both sides, but `[[` stops once the result is known
+            if value && self.extended {
+                self.skip(Self::and)?;
+            } else {
+                value |= self.and()?;
+            }
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<bool, String> {
+        let and = if self.extended { "&&" } else { "-a" };
+        let mut value = self.not()?;
+        while self.operator(0) == Some(and) {
+            self.position += 1;
+            if !value && self.extended {
+                self.skip(Self::not)?;
+            } else {
+                value &= self.not()?;
+            }
+        }
+        Ok(value)
+    }
+
+    /// Parses an operand with `parse` without evaluating its tests.
+    fn skip(&mut self, parse: fn(&mut Self) -> Result<bool, String>) -> Result<(), String> {
+        let skipping = std::mem::replace(&mut self.skipping, true);
+        let result = parse(self);
+        self.skipping = skipping;
+        result.map(|_| ())
+    }
+
+    fn not(&mut self) -> Result<bool, String> {
+        // A `!` followed by a binary operator is the string "!" being compared
+        if self.operator(0) == Some("!") && !self.is_binary_operator(1) {
+            self.position += 1;
+            return Ok(!self.not()?);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<bool, String> {
+        if self.is_binary_operator(1) && self.peek(2).is_some() {
+            let left = self.next()?;
+            let operator = self.next()?;
+            let right = self.next()?;
+            if self.skipping {
+                return Ok(false);
+            }
+            return self.binary(left, &operator.text, right);
+        }
+        if self.operator(0) == Some("(") && self.peek(1).is_some() {
+            self.position += 1;
+            let value = self.or()?;
+            return match self.operator(0) {
+                Some(")") => {
+                    self.position += 1;
+                    Ok(value)
+                }
+                _ => Err("`)' expected".to_string()),
+            };
+        }
+        if let Some(operator) = self.operator(0)
+            && UNARY_OPERATORS.contains(&operator)
+            && self.peek(1).is_some()
+        {
+            self.position += 1;
+            let operand = self.next()?;
+            return Ok(!self.skipping && unary(operator, &operand.text));
+        }
+        Ok(!self.next()?.text.is_empty())
+    }
+
+    fn binary(&mut self, left: &Operand, operator: &str, right: &Operand) -> Result<bool, String> {
+        if !self.extended {
+            return binary(&left.text, operator, &right.text);
+        }
+        match operator {
+            "=" | "==" => Ok(match_pattern(&right.pattern, &left.text)),
+            "!=" => Ok(!match_pattern(&right.pattern, &left.text)),
+            "=~" => {
+                let captures = regex_captures(&right.text, &left.text)?;
+                let matched = captures.is_some();
+                if matched {
+                    self.captures = captures;
+                }
+                Ok(matched)
+            }
+            _ => binary(&left.text, operator, &right.text),
+        }
+    }
+}
+
+fn unary(operator: &str, operand: &str) -> bool {
+    let path = encoding::path(operand);
+    let metadata = || fs::metadata(&path);
+    let permission = |flag| !operand.is_empty() && access(&path, flag).is_ok();
+    match operator {
+        "-z" => operand.is_empty(),
+        "-n" => !operand.is_empty(),
+        "-e" => metadata().is_ok(),
+        "-f" => metadata().is_ok_and(|m| m.is_file()),
+        "-d" => metadata().is_ok_and(|m| m.is_dir()),
+        "-s" => metadata().is_ok_and(|m| m.len() > 0),
+        "-b" => metadata().is_ok_and(|m| m.file_type().is_block_device()),
+        "-c" => metadata().is_ok_and(|m| m.file_type().is_char_device()),
+        "-p" => metadata().is_ok_and(|m| m.file_type().is_fifo()),
+        "-S" => metadata().is_ok_and(|m| m.file_type().is_socket()),
+        "-g" => metadata().is_ok_and(|m| m.permissions().mode() & 0o2000 != 0),
+        "-u" => metadata().is_ok_and(|m| m.permissions().mode() & 0o4000 != 0),
+        "-k" => metadata().is_ok_and(|m| m.permissions().mode() & 0o1000 != 0),
+        "-L" | "-h" => fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink()),
+        "-r" => permission(AccessFlags::R_OK),
+        "-w" => permission(AccessFlags::W_OK),
+        "-x" => permission(AccessFlags::X_OK),
+        _ => false,
+    }
+}
+
+fn binary(left: &str, operator: &str, right: &str) -> Result<bool, String> {
+    let modified = |path: &str| {
+        fs::metadata(encoding::path(path))
+            .and_then(|m| m.modified())
+            .ok()
+    };
+    Ok(match operator {
+        "=" | "==" => left == right,
+        "!=" => left != right,
+        "<" => left < right,
+        ">" => left > right,
+        "-nt" => match (modified(left), modified(right)) {
+            (Some(left), Some(right)) => left > right,
+            (left, right) => left.is_some() && right.is_none(),
+        },
+        "-ot" => match (modified(left), modified(right)) {
+            (Some(left), Some(right)) => left < right,
+            (left, right) => left.is_none() && right.is_some(),
+        },
+        "-ef" => match (
+            fs::metadata(encoding::path(left)),
+            fs::metadata(encoding::path(right)),
+        ) {
+            (Ok(left), Ok(right)) => left.dev() == right.dev() && left.ino() == right.ino(),
+            _ => false,
+        },
+        _ => {
+            let (left, right) = (integer(left)?, integer(right)?);
+            match operator {
+                "-eq" => left == right,
+                "-ne" => left != right,
+                "-lt" => left < right,
+                "-le" => left <= right,
+                "-gt" => left > right,
+                _ => left >= right,
+            }
+        }
+    })
+}
+
+/// Matches `text` against the regular expression `pattern`. If it matches, returns the
+/// matched text followed by what each group matched, which is empty for a group that
+/// took no part in the match.
+fn regex_captures(pattern: &str, text: &str) -> Result<Option<Vec<String>>, String> {
+    let regex =
+        Regex::new(pattern).map_err(|_| format!("{}: invalid regular expression", pattern))?;
+    Ok(regex.captures(text).map(|captures| {
+        captures
+            .iter()
+            .map(|group| group.map_or_else(String::new, |m| m.as_str().to_string()))
+            .collect()
+    }))
+}
+
+/// Escapes the characters that are special in a regular expression.
+pub fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\.[]()*+?{}|^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn integer(text: &str) -> Result<i64, String> {
+    text.trim()
+        .parse()
+        .map_err(|_| format!("{}: integer expression expected", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test(expression: &str) -> Result<bool, String> {
+        let args: Vec<String> = expression.split_whitespace().map(String::from).collect();
+        evaluate(&args)
+    }
+
+    #[test]
+    fn test_strings_and_integers() {
+        assert_eq!(test(""), Ok(false));
+        assert_eq!(test("x"), Ok(true));
+        assert_eq!(test("-z"), Ok(true));
+        assert_eq!(test("-n x"), Ok(true));
+        assert_eq!(test("a = a"), Ok(true));
+        assert_eq!(test("a != a"), Ok(false));
+        assert_eq!(test("10 -gt 9"), Ok(true));
+        assert_eq!(test("-3 -ge -2"), Ok(false));
+        assert_eq!(
+            test("a -eq 1"),
+            Err("a: integer expression expected".to_string())
+        );
+    }
+
+    #[test]
+    fn test_logical_operators_and_grouping() {
+        assert_eq!(test("! a = b"), Ok(true));
+        assert_eq!(test("! = !"), Ok(true));
+        assert_eq!(test("a -a -z b"), Ok(false));
+        assert_eq!(test("a -o -z b -a -z c"), Ok(true));
+        assert_eq!(test("( a -o -z b ) -a -z c"), Ok(false));
+        assert_eq!(test("! ( 1 -eq 2 )"), Ok(true));
+        assert!(test("( a").is_err());
+        assert_eq!(test("a b c d"), Err("too many arguments".to_string()));
+        assert_eq!(
+            test("a b c"),
+            Err("b: binary operator expected".to_string())
+        );
+        assert_eq!(test("-q x"), Err("-q: unary operator expected".to_string()));
+    }
+
+    fn extended(words: &[&str]) -> Result<(bool, Option<Vec<String>>), String> {
+        let operands: Vec<Operand> = words
+            .iter()
+            .map(|word| Operand {
+                text: word.to_string(),
+                pattern: word.to_string(),
+                quoted: false,
+            })
+            .collect();
+        evaluate_extended(&operands)
+    }
+
+    #[test]
+    fn test_extended_expressions() {
+        assert_eq!(extended(&["abc", "==", "a*"]), Ok((true, None)));
+        assert_eq!(extended(&["abc", "!=", "a?c"]), Ok((false, None)));
+        assert_eq!(
+            extended(&[
+                "b", ">", "a", "&&", "(", "-z", "x", "||", "1", "-lt", "2", ")"
+            ]),
+            Ok((true, None))
+        );
+        // `-a` is an ordinary string in `[[`
+        assert!(extended(&["x", "-a", "y"]).is_err());
+    }
+
+    #[test]
+    fn test_extended_operators_short_circuit() {
+        // The side not needed is parsed, but not evaluated
+        assert_eq!(
+            extended(&["x", "==", "x", "||", "1", "-eq", "zz"]),
+            Ok((true, None))
+        );
+        assert_eq!(
+            extended(&["x", "==", "y", "&&", "(", "1", "-eq", "zz", ")"]),
+            Ok((false, None))
+        );
+        assert_eq!(
+            extended(&["ab", "=~", "(a)", "||", "ab", "=~", "(b)"])
+                .unwrap()
+                .1,
+            Some(vec!["a".to_string(), "a".to_string()])
+        );
+        assert!(extended(&["x", "||", "(", "y"]).is_err());
+        // `test` evaluates both sides, as in bash
+        assert!(test("x -o 1 -eq zz").is_err());
+    }
+
+    #[test]
+    fn test_regex_match() {
+        let captures = |words: &[&str]| extended(words).unwrap().1;
+        assert_eq!(
+            captures(&["2024-10", "=~", "^([0-9]+)-([0-9]+)(x)?$"]),
+            Some(vec![
+                "2024-10".to_string(),
+                "2024".to_string(),
+                "10".to_string(),
+                String::new()
+            ])
+        );
+        assert_eq!(captures(&["abc", "=~", "^b"]), None);
+        assert_eq!(captures(&["a(b", "=~", "[(]b"]).unwrap()[0], "(b");
+        assert_eq!(captures(&["ab12", "=~", "[[:digit:]]+"]).unwrap()[0], "12");
+        assert!(extended(&["a", "=~", "("]).is_err());
+        assert_eq!(escape_regex("a.b*"), "a\\.b\\*");
+    }
+
+    #[test]
+    fn test_file_tests() {
+        let dir = std::env::temp_dir();
+        let dir = dir.to_str().unwrap();
+        assert_eq!(test(&format!("-d {}", dir)), Ok(true));
+        assert_eq!(test(&format!("-f {}", dir)), Ok(false));
+        assert_eq!(test(&format!("-e {} -a -r {}", dir, dir)), Ok(true));
+        assert_eq!(test("-e /nonexistent/file"), Ok(false));
+        assert_eq!(test("-x /bin/sh"), Ok(true));
+        assert_eq!(test("-L /nonexistent"), Ok(false));
+    }
+}
@@ -0,0 +1,130 @@
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+
+/// Bytes that are not valid UTF-8 are kept in a `String` as the characters
+/// `U+10FF80..=U+10FFFF`, one per byte, so they survive the round trip back to the OS.
+/// Those characters are reserved: one that really occurs in the input is kept as the
+/// raw bytes of its UTF-8 encoding instead, so every reserved character is a raw byte.
+const RAW_BASE: u32 = 0x10FF00;
+
+/// The character that stands for `byte`: ASCII bytes are themselves, the rest are
+/// mapped into the private use area.
+pub fn raw_byte(byte: u8) -> char {
+    if byte.is_ascii() {
+        return byte as char;
+    }
+    char::from_u32(RAW_BASE + byte as u32).expect("private use characters are valid")
+}
+
+/// The byte a character from [`raw_byte`] stands for, if it is one.
+fn raw_value(c: char) -> Option<u8> {
+    (c as u32)
+        .checked_sub(RAW_BASE)
+        .filter(|value| *value >= 0x80)
+        .map(|value| value as u8)
+}
+
+/// Whether `c` is one of the reserved characters that stand for raw bytes.
+pub fn is_reserved(c: char) -> bool {
+    raw_value(c).is_some()
+}
+
+/// The text for the character `c`: itself, or the raw bytes of its UTF-8 encoding if
+/// it is reserved, which encode back to the same character.
+pub fn escape(c: char) -> String {
+    if !is_reserved(c) {
+        return c.to_string();
+    }
+    let mut buffer = [0; 4];
+    c.encode_utf8(&mut buffer).bytes().map(raw_byte).collect()
+}
+
+/// Converts an OS string such as a filename or argument to text without losing the
+/// bytes that are not valid UTF-8.
+pub fn decode(s: &OsStr) -> String {
+    from_bytes(s.as_bytes())
+}
+
+/// Like [`decode`], for bytes read from a file or pipe.
+pub fn from_bytes(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        let valid = chunk.valid();
+        if valid.chars().any(is_reserved) {
+            text.extend(valid.chars().map(escape));
+        } else {
+            text.push_str(valid);
+        }
+        text.extend(chunk.invalid().iter().map(|&byte| raw_byte(byte)));
+    }
+    text
+}
+
+/// Converts text back to the bytes the OS sees, the reverse of [`decode`].
+pub fn encode(text: &str) -> OsString {
+    if !text.chars().any(is_reserved) {
+        return OsString::from(text);
+    }
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut buffer = [0; 4];
+    for c in text.chars() {
+        match raw_value(c) {
+            Some(byte) => bytes.push(byte),
+            None => bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes()),
+        }
+    }
+    OsString::from_vec(bytes)
+}
+
+/// Like [`encode`], for text written to a file or pipe.
+pub fn to_bytes(text: &str) -> Vec<u8> {
+    encode(text).into_vec()
+}
+
+/// The path named by `text`, with any raw bytes restored.
+pub fn path(text: &str) -> PathBuf {
+    PathBuf::from(encode(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_invalid_bytes() {
+        let name = OsStr::from_bytes(b"caf\xFF\xC3.txt");
+        let text = decode(name);
+        assert_eq!(text.chars().count(), 9);
+        assert!(text.starts_with("caf") && text.ends_with(".txt"));
+        assert_eq!(encode(&text), name);
+    }
+
+    #[test]
+    fn test_valid_text_is_unchanged() {
+        assert_eq!(decode(OsStr::new("héllo")), "héllo");
+        assert_eq!(encode("héllo"), OsStr::new("héllo"));
+        assert_eq!(raw_byte(b'a'), 'a');
+    }
+
+    #[test]
+    fn test_raw_bytes_forming_utf8() {
+        // Raw bytes that together spell a valid character decode to that character
+        let text: String = [0xC3, 0xA9].into_iter().map(raw_byte).collect();
+        assert_eq!(decode(&encode(&text)), "é");
+    }
+
+    #[test]
+    fn test_reserved_characters_are_not_raw_bytes() {
+        // U+10FFAA is a valid character, not the raw byte 0xAA
+        let name = OsStr::new("a\u{10FFAA}b");
+        let text = decode(name);
+        assert_eq!(text.chars().count(), 6);
+        assert_eq!(encode(&text), name);
+        assert_eq!(encode(&escape('\u{10FFAA}')), OsStr::new("\u{10FFAA}"));
+        assert_ne!(
+            decode(OsStr::from_bytes(b"\xAA")),
+            decode(OsStr::new("\u{10FFAA}"))
+        );
+    }
+}
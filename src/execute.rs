@@ -0,0 +1,3432 @@
+use crate::alias::expand_aliases;
+use crate::ast::{Command as ShellCommand, List, parse_list};
+use crate::attributes::Attributes;
+use crate::commands::{
+    BUILTINS, CommandLookup, DEFAULT_PATH, command_arguments, execute_builtin, is_valid_name,
+    search_path,
+};
+use crate::conditional::{Operand, escape_regex, evaluate_extended};
+use crate::encoding;
+use crate::glob::{escape, expand_globs, match_pattern};
+use crate::jobs;
+use crate::options::ShellOptions;
+use crate::redirection::{
+    ArrayAssignment, Assignment, Input, Io, Output, OutputFiles, ParseError, ParsedCommand,
+    Pipeline, RedirTarget, io_error_message, open_input, parse_command, parse_pipeline,
+    read_heredoc_body, render_syntax_error, with_io,
+};
+use crate::shell::{Array, Function, LoopControl, SavedVariable, Shell};
+use crate::tokenize::{
+    Token, TokenKind, TokenizeError, expand_heredoc, tokenize, tokenize_expanded,
+};
+use crate::trap;
+use nix::errno::Errno;
+use nix::sys::signal::{SigHandler, Signal, signal};
+use nix::sys::wait::{WaitStatus, waitpid};
+use nix::unistd::{ForkResult, Pid, setpgid};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{PipeWriter, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus, Stdio};
+
+/// Exit status for a command that could not be found.
+const STATUS_NOT_FOUND: i32 = 127;
+/// Exit status for a command that was found but could not be executed.
+const STATUS_NOT_EXECUTABLE: i32 = 126;
+/// Exit status for a syntax error.
+const STATUS_SYNTAX_ERROR: i32 = 2;
+
+/// Runs one line of input, which may hold several commands and compound commands.
+/// `next_line` supplies the input lines that follow, for here-document bodies.
+/// The exit status of the line is recorded in `shell.last_status`. Nothing on the line
+/// runs if it has a syntax error, which an interactive shell points out with a caret.
+pub fn run_line(shell: &mut Shell, input: &str, next_line: &mut dyn FnMut() -> Option<String>) {
+    // A `break` or `continue` outside a loop has nothing left to interrupt
+    let _ = execute_line(shell, input, next_line);
+}
+
+/// Runs one line of input like `run_line`, handing back a `return` or other control that
+/// left the line early, as a sourced file needs to stop at one.
+pub fn execute_line(
+    shell: &mut Shell,
+    input: &str,
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> Result<(), LoopControl> {
+    let list = tokenize(input)
+        .map_err(|e| (e.to_string(), None))
+        .and_then(|tokens| parse_list(input, tokens).map_err(|e| (e.to_string(), e.column())));
+    match list {
+        Ok(list) => execute_list(shell, &list, next_line),
+        Err((message, column)) => {
+            let column = column.filter(|_| shell.interactive);
+            let name = shell.name();
+            eprintln!("{}", render_syntax_error(&name, &message, input, column));
+            shell.last_status = STATUS_SYNTAX_ERROR;
+            Ok(())
+        }
+    }
+}
+
+/// Runs each command of `list` in turn, stopping early if the shell is exiting.
+/// A `break` or `continue` stops it too, and is handed up to the enclosing loops.
+fn execute_list(
+    shell: &mut Shell,
+    list: &[ShellCommand],
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> Result<(), LoopControl> {
+    for command in list {
+        execute_command(shell, command, next_line)?;
+        // Compound commands have already checked the commands they ran
+        if matches!(
+            command,
+            ShellCommand::Pipeline(_) | ShellCommand::Subshell { .. } | ShellCommand::Stages { .. }
+        ) && shell.last_status != 0
+            && shell.condition_depth == 0
+        {
+            if shell.options.contains(ShellOptions::ERREXIT) {
+                shell.exit_code = Some(shell.last_status);
+            } else {
+                trap::run_err(shell);
+            }
+        }
+        trap::run_pending(shell);
+        if let Some(control) = shell.loop_control.take() {
+            return Err(control);
+        }
+        if shell.exit_code.is_some() {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+fn execute_command(
+    shell: &mut Shell,
+    command: &ShellCommand,
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> Result<(), LoopControl> {
+    match command {
+        ShellCommand::Pipeline(source) => {
+            run_pipeline(shell, source, next_line);
+            Ok(())
+        }
+        ShellCommand::If {
+            branches,
+            otherwise,
+            redirects,
+        } => with_redirects(shell, redirects, next_line, |shell, next_line| {
+            execute_if(shell, branches, otherwise.as_ref(), next_line)
+        }),
+        ShellCommand::Loop {
+            until,
+            condition,
+            body,
+            redirects,
+        } => with_redirects(shell, redirects, next_line, |shell, next_line| {
+            in_loop(shell, |shell| {
+                execute_loop(shell, *until, condition, body, next_line)
+            })
+        }),
+        ShellCommand::For {
+            name,
+            words,
+            body,
+            redirects,
+        } => with_redirects(shell, redirects, next_line, |shell, next_line| {
+            in_loop(shell, |shell| {
+                execute_for(shell, name, words.as_deref(), body, next_line)
+            })
+        }),
+        ShellCommand::Select {
+            name,
+            words,
+            body,
+            redirects,
+        } => with_redirects(shell, redirects, next_line, |shell, next_line| {
+            in_loop(shell, |shell| {
+                execute_select(shell, name, words.as_deref(), body, next_line)
+            })
+        }),
+        ShellCommand::Case {
+            word,
+            arms,
+            redirects,
+        } => with_redirects(shell, redirects, next_line, |shell, next_line| {
+            execute_case(shell, word, arms, next_line)
+        }),
+        ShellCommand::Conditional(words) => {
+            execute_conditional(shell, words);
+            Ok(())
+        }
+        ShellCommand::Function {
+            name,
+            body,
+            redirects,
+        } => {
+            let function = Function {
+                body: body.clone(),
+                redirects: redirects.clone(),
+                heredoc_lines: read_heredoc_lines(redirects, next_line),
+            };
+            shell.functions.insert(name.clone(), function);
+            shell.last_status = 0;
+            Ok(())
+        }
+        ShellCommand::And(first, second) => execute_and_or(shell, first, true, second, next_line),
+        ShellCommand::Or(first, second) => execute_and_or(shell, first, false, second, next_line),
+        ShellCommand::Subshell { body, redirects } => {
+            shell.last_status = execute_subshell(shell, body, redirects, next_line);
+            Ok(())
+        }
+        ShellCommand::Group { body, redirects } => {
+            with_redirects(shell, redirects, next_line, |shell, next_line| {
+                execute_list(shell, body, next_line)
+            })
+        }
+        ShellCommand::Stages {
+            stages,
+            background,
+            text,
+        } => {
+            shell.last_status = execute_stages(shell, stages, *background, text, next_line);
+            Ok(())
+        }
+    }
+}
+
+/// Reads the lines holding the bodies of any here-documents among the redirections of
+/// a function definition, which follow the definition, for every call to read again.
+fn read_heredoc_lines(
+    redirects: &str,
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> Vec<String> {
+    let mut lines = Vec::new();
+    let Some(parsed) = tokenize(redirects)
+        .ok()
+        .and_then(|tokens| parse_command(tokens).ok())
+    else {
+        return lines;
+    };
+    for redirection in parsed.redirects {
+        let RedirTarget::HereDoc(mut heredoc) = redirection.target else {
+            continue;
+        };
+        let read = read_heredoc_body(&mut heredoc, || {
+            let line = next_line()?;
+            lines.push(line.clone());
+            Some(line)
+        });
+        if let Err(warning) = read {
+            eprintln!("{}", warning);
+            // A call then finds the body ending in the same place, without a warning
+            lines.push(heredoc.delimiter);
+        }
+    }
+    lines
+}
+
+/// Runs `first`, then `second` if `first` succeeded with `and`, or failed without.
+/// The status is that of the last command run. Only a failure of `second` counts for
+/// `set -e` and the `ERR` trap.
+fn execute_and_or(
+    shell: &mut Shell,
+    first: &ShellCommand,
+    and: bool,
+    second: &ShellCommand,
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> Result<(), LoopControl> {
+    shell.condition_depth += 1;
+    let result = execute_command(shell, first, next_line);
+    shell.condition_depth -= 1;
+    result?;
+    if shell.exit_code.is_some() || shell.loop_control.is_some() {
+        return Ok(());
+    }
+    if (shell.last_status == 0) == and {
+        execute_list(shell, std::slice::from_ref(second), next_line)?;
+    }
+    Ok(())
+}
+
+/// Runs a loop, counting it among the running loops that `break` and `continue` can reach.
+fn in_loop(
+    shell: &mut Shell,
+    run: impl FnOnce(&mut Shell) -> Result<(), LoopControl>,
+) -> Result<(), LoopControl> {
+    shell.loop_depth += 1;
+    let result = run(shell);
+    shell.loop_depth -= 1;
+    result
+}
+
+/// Decides what a loop does once an iteration has run: `Ok(true)` to stop after a `break`,
+/// `Ok(false)` to go on, or `Err` with the control left for the loops around it.
+fn stop_loop(result: Result<(), LoopControl>) -> Result<bool, LoopControl> {
+    match result {
+        Ok(()) => Ok(false),
+        Err(control) => match control.outer() {
+            Some(outer) => Err(outer),
+            None => Ok(matches!(control, LoopControl::Break(_))),
+        },
+    }
+}
+
+/// Runs an `if` command: the body of the first branch whose condition succeeds,
+/// else the `else` list. With no branch taken the status is 0.
+fn execute_if(
+    shell: &mut Shell,
+    branches: &[(List, List)],
+    otherwise: Option<&List>,
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> Result<(), LoopControl> {
+    for (condition, body) in branches {
+        execute_condition(shell, condition, next_line)?;
+        if shell.exit_code.is_some() {
+            return Ok(());
+        }
+        if shell.last_status == 0 {
+            return execute_list(shell, body, next_line);
+        }
+    }
+    match otherwise {
+        Some(list) => execute_list(shell, list, next_line),
+        None => {
+            shell.last_status = 0;
+            Ok(())
+        }
+    }
+}
+
+/// Runs the condition of an `if`, `while` or `until`, where a failing command does not
+/// make `set -e` exit.
+fn execute_condition(
+    shell: &mut Shell,
+    condition: &List,
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> Result<(), LoopControl> {
+    shell.condition_depth += 1;
+    let result = execute_list(shell, condition, next_line);
+    shell.condition_depth -= 1;
+    result
+}
+
+/// Runs a `while` (or `until`) loop. Its status is that of the last body command
+/// run, or 0 if the body never ran.
+fn execute_loop(
+    shell: &mut Shell,
+    until: bool,
+    condition: &List,
+    body: &List,
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> Result<(), LoopControl> {
+    let mut status = 0;
+    loop {
+        let mut result = execute_condition(shell, condition, next_line);
+        if result.is_ok() && shell.exit_code.is_none() {
+            if (shell.last_status == 0) == until {
+                break;
+            }
+            result = execute_list(shell, body, next_line);
+        }
+        status = shell.last_status;
+        if shell.exit_code.is_some() || stop_loop(result)? {
+            break;
+        }
+    }
+    shell.last_status = status;
+    Ok(())
+}
+
+/// Runs a `for` loop, setting `name` to each word of the expanded list in turn.
+/// Without a list the loop goes over the positional parameters.
+fn execute_for(
+    shell: &mut Shell,
+    name: &str,
+    words: Option<&str>,
+    body: &List,
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> Result<(), LoopControl> {
+    let Some(values) = loop_words(shell, name, words) else {
+        return Ok(());
+    };
+
+    let mut status = 0;
+    for value in values {
+        if let Err(e) = shell.set_variable(name, &value, false) {
+            eprintln!("{}: {}", shell.name(), e);
+            shell.last_status = 1;
+            return Ok(());
+        }
+        let result = execute_list(shell, body, next_line);
+        status = shell.last_status;
+        if shell.exit_code.is_some() || stop_loop(result)? {
+            break;
+        }
+    }
+    shell.last_status = status;
+    Ok(())
+}
+
+/// The words a `for` or `select` loop goes over: the expanded `words`, or the
+/// positional parameters without any. `None` if the loop cannot run, which has been
+/// reported.
+fn loop_words(shell: &mut Shell, name: &str, words: Option<&str>) -> Option<Vec<String>> {
+    if !is_valid_name(name) {
+        eprintln!("`{}': not a valid identifier", name);
+        shell.last_status = 1;
+        return None;
+    }
+    let Some(words) = words else {
+        return Some(shell.positional.clone());
+    };
+    let tokens = expand(shell, words)?;
+    Some(
+        expand_filenames(shell, tokens)
+            .into_iter()
+            .filter_map(|token| match token.kind {
+                TokenKind::Word(word) => Some(word.into_owned()),
+                TokenKind::Operator(_) | TokenKind::ProcessSubstitution { .. } => None,
+            })
+            .collect(),
+    )
+}
+
+/// Runs a `select` loop: shows the words as a numbered menu on stderr, then reads
+/// choices after the `$PS3` prompt until end of input. For each the body runs with
+/// `name` set to the word chosen, or empty for anything else, and `REPLY` to the
+/// line read. An empty line shows the menu again.
+fn execute_select(
+    shell: &mut Shell,
+    name: &str,
+    words: Option<&str>,
+    body: &List,
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> Result<(), LoopControl> {
+    let Some(values) = loop_words(shell, name, words) else {
+        return Ok(());
+    };
+    let menu: String = values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| format!("{}) {}\n", i + 1, value))
+        .collect();
+    // Commands in the body must not take over the loop's input
+    let input = shell.stdin.take();
+
+    let mut status = 0;
+    let mut show_menu = true;
+    while !values.is_empty() {
+        if show_menu {
+            eprint!("{}", menu);
+        }
+        eprint!("{}", shell.var("PS3").unwrap_or("#? "));
+        shell.stdin = input.as_ref().and_then(|fd| fd.try_clone().ok());
+        let read = ["read".to_string(), "-r".to_string()];
+        let (mut sink, mut stderr) = (std::io::sink(), std::io::stderr());
+        let mut io = Io::new(&mut sink, Some(&mut stderr));
+        let eof = execute_builtin("read", &read, shell, &mut io) != 0;
+        shell.stdin = None;
+        if eof {
+            break;
+        }
+        let reply = shell.var("REPLY").unwrap_or_default().to_string();
+        show_menu = reply.is_empty();
+        if show_menu {
+            continue;
+        }
+        let chosen = reply
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| values.get(n.checked_sub(1)?));
+        if let Err(e) = shell.set_variable(name, chosen.map_or("", String::as_str), false) {
+            eprintln!("{}: {}", shell.name(), e);
+            status = 1;
+            break;
+        }
+        let result = execute_list(shell, body, next_line);
+        status = shell.last_status;
+        if shell.exit_code.is_some() || stop_loop(result)? {
+            break;
+        }
+    }
+    shell.last_status = status;
+    Ok(())
+}
+
+/// Runs one pipeline from source: expansions, alias and filename expansion, parsing,
+/// here-document bodies, execution.
+fn run_pipeline(shell: &mut Shell, input: &str, next_line: &mut dyn FnMut() -> Option<String>) {
+    let Some(pipeline) = prepare_pipeline(shell, input, next_line) else {
+        return;
+    };
+    if pipeline.commands.is_empty() {
+        return;
+    }
+    shell.last_status = if pipeline.commands.len() == 1 && !pipeline.background {
+        execute_single_command(shell, &pipeline.commands[0])
+    } else {
+        let text = input.trim_end();
+        let text = text.strip_suffix('&').unwrap_or(text).trim_end();
+        let stages: Vec<Stage> = pipeline.commands.iter().map(Stage::Simple).collect();
+        execute_pipeline(shell, &stages, pipeline.background, text)
+    };
+}
+
+/// Expands and parses a pipeline from source, reads its here-document bodies and
+/// traces it. On failure the error has been reported, the status set, and `None`
+/// is returned.
+fn prepare_pipeline(
+    shell: &mut Shell,
+    input: &str,
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> Option<Pipeline> {
+    shell.substitution_status = None;
+    let tokens = expand(shell, input)?;
+    let tokens = expand_filenames(shell, expand_aliases(tokens, &shell.aliases));
+    let mut pipeline = match parse_pipeline(tokens) {
+        Ok(pipeline) => pipeline,
+        Err(e) => {
+            shell.last_status = report_parse_error(shell, &e);
+            return None;
+        }
+    };
+    if !read_heredocs(shell, &mut pipeline, next_line) {
+        return None;
+    }
+    if shell.options.contains(ShellOptions::XTRACE) {
+        for parsed in &pipeline.commands {
+            let words: Vec<String> = parsed
+                .assignments
+                .iter()
+                .map(|assignment| {
+                    let operator = if assignment.append { "+=" } else { "=" };
+                    let index = match &assignment.index {
+                        Some(index) => format!("[{}]", index),
+                        None => String::new(),
+                    };
+                    format!(
+                        "{}{}{}{}",
+                        assignment.name, index, operator, assignment.value
+                    )
+                })
+                .chain(parsed.args.iter().cloned())
+                .collect();
+            eprintln!("+ {}", trace(&words));
+        }
+    }
+    Some(pipeline)
+}
+
+/// Runs a pipeline with compound commands among its stages, or a compound command in
+/// the background, as a job under `text`. Every stage's redirections are prepared
+/// first, in order, so their here-documents are read the way they appear and before
+/// any stage is forked.
+fn execute_stages(
+    shell: &mut Shell,
+    stages: &[ShellCommand],
+    background: bool,
+    text: &str,
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> i32 {
+    let mut prepared = Vec::new();
+    for stage in stages {
+        prepared.push(match stage {
+            ShellCommand::Pipeline(source) => match prepare_pipeline(shell, source, next_line) {
+                Some(pipeline) => PreparedStage::Simple(pipeline),
+                None => return shell.last_status,
+            },
+            stage => {
+                let mut command = stage.clone();
+                match prepare_redirects(shell, &command.take_redirects(), next_line) {
+                    Ok(redirects) => PreparedStage::Compound(command, redirects.map(Box::new)),
+                    Err(status) => return status,
+                }
+            }
+        });
+    }
+    let stages: Vec<Stage> = prepared
+        .iter()
+        .flat_map(|prepared| match prepared {
+            PreparedStage::Simple(pipeline) => {
+                pipeline.commands.iter().map(Stage::Simple).collect()
+            }
+            PreparedStage::Compound(command, redirects) => {
+                vec![Stage::Compound(command, redirects.as_deref())]
+            }
+        })
+        .collect();
+    execute_pipeline(shell, &stages, background, text)
+}
+
+/// A stage of `execute_stages` once its redirections have been prepared.
+enum PreparedStage {
+    Simple(Pipeline),
+    /// A compound command with its redirections taken out, and those redirections.
+    Compound(ShellCommand, Option<Box<ParsedCommand>>),
+}
+
+/// Runs `body` in a forked copy of the shell, with `redirects` applied to it, and
+/// returns its status. Nothing the list changes, such as variables or the working
+/// directory, reaches this shell.
+fn execute_subshell(
+    shell: &mut Shell,
+    body: &List,
+    redirects: &str,
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> i32 {
+    // Here-documents are read here, as the input lines belong to this shell
+    let redirects = match prepare_redirects(shell, redirects, next_line) {
+        Ok(redirects) => redirects,
+        Err(status) => return status,
+    };
+    // Output still buffered would otherwise be written by both processes
+    let _ = std::io::stdout().flush();
+    // SAFETY: the child only goes on to run the shell, and then exits.
+    match unsafe { nix::unistd::fork() } {
+        Ok(ForkResult::Child) => {
+            leave_job_control(shell);
+            let status = run_subshell(shell, body, redirects.as_ref());
+            let _ = std::io::stdout().flush();
+            std::process::exit(status);
+        }
+        Ok(ForkResult::Parent { child }) => wait_process(child),
+        Err(e) => {
+            eprintln!("fork: {}", e.desc());
+            1
+        }
+    }
+}
+
+/// Waits for the child `pid` to finish and returns its status; death by signal N
+/// makes it 128 + N.
+fn wait_process(pid: Pid) -> i32 {
+    loop {
+        match waitpid(pid, None) {
+            Ok(WaitStatus::Exited(_, code)) => return code,
+            Ok(WaitStatus::Signaled(_, signal, _)) => return 128 + signal as i32,
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(e) => {
+                eprintln!("waitpid: {}", e.desc());
+                return 1;
+            }
+        }
+    }
+}
+
+/// Turns job control off in a forked child of the shell, which runs its commands
+/// without process groups of their own and with the terminal's signals acted on.
+fn leave_job_control(shell: &mut Shell) {
+    if std::mem::take(&mut shell.job_control) {
+        jobs::restore_terminal_signals();
+    }
+}
+
+/// Runs the list of a subshell in the forked child, with its prepared `redirects`
+/// applied, and returns the status to exit with.
+fn run_subshell(shell: &mut Shell, body: &List, redirects: Option<&ParsedCommand>) -> i32 {
+    // The child's own stdout can then be redirected like any other
+    if let Some(writer) = shell.stdout.take()
+        && let Err(e) = nix::unistd::dup2_stdout(writer)
+    {
+        eprintln!("{}: {}", shell.name(), e.desc());
+        return 1;
+    }
+    let status = redirects.map_or(0, |parsed| redirect_shell(shell, parsed));
+    if status != 0 {
+        return status;
+    }
+    // `break` and `continue` cannot reach loops outside the subshell
+    let _ = execute_list(shell, body, &mut || None);
+    shell.exit_code.unwrap_or(shell.last_status)
+}
+
+/// Runs a compound command in this shell, or in the forked copy running a pipeline
+/// stage, such as a `{ list; }` group or a loop. Its redirections replace the shell's
+/// own streams while `run` runs, and the streams are put back afterwards.
+fn with_redirects(
+    shell: &mut Shell,
+    redirects: &str,
+    next_line: &mut dyn FnMut() -> Option<String>,
+    run: impl FnOnce(&mut Shell, &mut dyn FnMut() -> Option<String>) -> Result<(), LoopControl>,
+) -> Result<(), LoopControl> {
+    let redirects = match prepare_redirects(shell, redirects, next_line) {
+        Ok(Some(redirects)) => redirects,
+        Ok(None) => return run(shell, next_line),
+        Err(status) => {
+            shell.last_status = status;
+            return Ok(());
+        }
+    };
+    match with_shell_streams(shell, None, &redirects, |shell| run(shell, next_line)) {
+        Ok(result) => result,
+        Err(status) => {
+            shell.last_status = status;
+            Ok(())
+        }
+    }
+}
+
+/// Points the shell's own stdin at `stdin`, if given, and applies the redirections of
+/// `redirects` to its streams while `run` runs, putting the streams back afterwards.
+/// If they cannot be applied, the failure has been reported and its status is
+/// returned instead.
+fn with_shell_streams<T>(
+    shell: &mut Shell,
+    stdin: Option<OwnedFd>,
+    redirects: &ParsedCommand,
+    run: impl FnOnce(&mut Shell) -> T,
+) -> Result<T, i32> {
+    let saved = (
+        nix::unistd::dup(std::io::stdin()),
+        nix::unistd::dup(std::io::stdout()),
+        nix::unistd::dup(std::io::stderr()),
+    );
+    let (Ok(saved_stdin), Ok(saved_stdout), Ok(saved_stderr)) = saved else {
+        eprintln!("{}: cannot save the shell's streams", shell.name());
+        return Err(1);
+    };
+    let capture = shell.stdout.take();
+    // Output being captured goes through the shell's stdout, where redirections apply
+    let mut status = match capture.as_ref().map(|writer| writer.try_clone()) {
+        Some(Ok(writer)) => nix::unistd::dup2_stdout(writer).map_or(1, |()| 0),
+        Some(Err(_)) => 1,
+        None => 0,
+    };
+    if status == 0
+        && let Some(stdin) = stdin
+    {
+        status = nix::unistd::dup2_stdin(stdin).map_or(1, |()| 0);
+    }
+    if status == 0 {
+        status = redirect_shell(shell, redirects);
+    }
+    let result = match status {
+        0 => Ok(run(shell)),
+        status => Err(status),
+    };
+    let _ = std::io::stdout().flush();
+    let _ = nix::unistd::dup2_stdin(saved_stdin);
+    let _ = nix::unistd::dup2_stdout(saved_stdout);
+    let _ = nix::unistd::dup2_stderr(saved_stderr);
+    shell.stdout = capture;
+    result
+}
+
+/// Expands and parses the redirections after a compound command, reading the bodies
+/// of its here-documents from `next_line`, for `redirect_shell` to apply. There are
+/// none to apply if it has no redirections. A failure has been reported, and gives
+/// the status instead.
+fn prepare_redirects(
+    shell: &mut Shell,
+    redirects: &str,
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> Result<Option<ParsedCommand>, i32> {
+    if redirects.is_empty() {
+        return Ok(None);
+    }
+    let Some(tokens) = expand(shell, redirects) else {
+        return Err(shell.last_status);
+    };
+    let mut pipeline = parse_pipeline(expand_filenames(shell, tokens))
+        .map_err(|e| report_parse_error(shell, &e))?;
+    if !read_heredocs(shell, &mut pipeline, next_line) {
+        return Err(shell.last_status);
+    }
+    Ok(pipeline.commands.pop())
+}
+
+/// Runs a `case` command: the list of the first arm with a pattern matching the
+/// expanded word. With no arm taken the status is 0.
+fn execute_case(
+    shell: &mut Shell,
+    word: &str,
+    arms: &[(Vec<String>, List)],
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> Result<(), LoopControl> {
+    let Some(word) = expand(shell, word) else {
+        return Ok(());
+    };
+    let word = join_words(word, |text, _| text);
+
+    for (patterns, body) in arms {
+        for pattern in patterns {
+            let Some(pattern) = expand(shell, pattern) else {
+                return Ok(());
+            };
+            // Quoted parts of a pattern match literally
+            let pattern = join_words(pattern, |text, glob| glob.unwrap_or_else(|| escape(&text)));
+            if match_pattern(&pattern, &word) {
+                shell.last_status = 0;
+                return execute_list(shell, body, next_line);
+            }
+        }
+    }
+    shell.last_status = 0;
+    Ok(())
+}
+
+/// Runs `[[ ... ]]`. Each word is expanded without splitting or filename expansion,
+/// and a successful `=~` leaves what it matched in `BASH_REMATCH`.
+fn execute_conditional(shell: &mut Shell, words: &[String]) {
+    let mut operands: Vec<Operand> = Vec::new();
+    for word in words {
+        if operands
+            .last()
+            .is_some_and(|last| !last.quoted && last.text == "=~")
+        {
+            let Some(regex) = expand_regex(shell, word) else {
+                return;
+            };
+            operands.push(Operand {
+                pattern: escape(&regex),
+                text: regex,
+                quoted: false,
+            });
+            continue;
+        }
+        if matches!(word.as_str(), "&&" | "||" | "(" | ")" | "<" | ">") {
+            operands.push(Operand {
+                text: word.clone(),
+                pattern: escape(word),
+                quoted: false,
+            });
+            continue;
+        }
+        let Some(tokens) = expand(shell, word) else {
+            return;
+        };
+        let quoted = tokens.iter().any(|token| token.quoted);
+        operands.push(Operand {
+            text: join_words(tokens.clone(), |text, _| text),
+            pattern: join_words(tokens, |text, glob| glob.unwrap_or_else(|| escape(&text))),
+            quoted,
+        });
+    }
+    shell.last_status = match evaluate_extended(&operands) {
+        Ok((value, captures)) => {
+            if let Some(captures) = captures {
+                let captures = captures.into_iter().enumerate().collect();
+                shell.arrays.insert("BASH_REMATCH".to_string(), captures);
+            }
+            if value { 0 } else { 1 }
+        }
+        Err(e) => {
+            eprintln!("[[: {}", e);
+            2
+        }
+    };
+}
+
+/// Expands the regular expression on the right of `=~`. The `(`, `)` and `|` in it are
+/// kept, and words with quotes in them match literally.
+fn expand_regex(shell: &mut Shell, word: &str) -> Option<String> {
+    let mut regex = String::new();
+    for token in tokenize(word).ok()? {
+        let source = &word[token.span.clone()];
+        if !matches!(token.kind, TokenKind::Word(_)) {
+            regex.push_str(source);
+            continue;
+        }
+        let tokens = expand(shell, source)?;
+        let quoted = tokens.iter().any(|token| token.quoted);
+        let text = join_words(tokens, |text, _| text);
+        regex.push_str(&if quoted { escape_regex(&text) } else { text });
+    }
+    Some(regex)
+}
+
+/// Joins expanded words back together with spaces, since `case` does not split
+/// words, using `text` to pick what each word contributes.
+fn join_words(tokens: Vec<Token>, text: impl Fn(String, Option<String>) -> String) -> String {
+    let words: Vec<String> = tokens
+        .into_iter()
+        .filter_map(|token| match token.kind {
+            TokenKind::Word(word) => Some(text(word.into_owned(), token.glob)),
+            TokenKind::Operator(_) | TokenKind::ProcessSubstitution { .. } => None,
+        })
+        .collect();
+    words.join(" ")
+}
+
+/// Expands glob patterns to filenames, unless `set -f` is on.
+fn expand_filenames<'a>(shell: &Shell, tokens: Vec<Token<'a>>) -> Vec<Token<'a>> {
+    if shell.options.contains(ShellOptions::NOGLOB) {
+        tokens
+    } else {
+        expand_globs(tokens)
+    }
+}
+
+/// Renders a command for `set -x`, quoting arguments that would not read back as one word.
+fn trace(args: &[String]) -> String {
+    let quoted: Vec<String> = args
+        .iter()
+        .map(|arg| {
+            let plain = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_./=:,+-@%".contains(c));
+            if plain {
+                arg.clone()
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect();
+    quoted.join(" ")
+}
+
+/// Tokenizes `input` with its `$` expansions, reporting any error and setting the status.
+fn expand<'a>(shell: &mut Shell, input: &'a str) -> Option<Vec<Token<'a>>> {
+    match tokenize_expanded(input, shell) {
+        Ok(tokens) => Some(tokens),
+        Err(e) => {
+            eprintln!("{}: {}", shell.name(), e);
+            shell.last_status = match e {
+                TokenizeError::Arithmetic { .. }
+                | TokenizeError::BadSubstitution(_)
+                | TokenizeError::Unbound(_) => 1,
+                _ => STATUS_SYNTAX_ERROR,
+            };
+            None
+        }
+    }
+}
+
+/// Reads the bodies of any here-documents in the pipeline from the following input lines,
+/// one after the other in the order they appear, expanding those whose delimiter was
+/// not quoted. Returns false if an expansion failed.
+fn read_heredocs(
+    shell: &mut Shell,
+    pipeline: &mut Pipeline,
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> bool {
+    let heredocs = pipeline
+        .commands
+        .iter_mut()
+        .flat_map(|parsed| &mut parsed.redirects)
+        .filter_map(|redirection| match &mut redirection.target {
+            RedirTarget::HereDoc(heredoc) => Some(heredoc),
+            _ => None,
+        });
+    for heredoc in heredocs {
+        if let Err(warning) = read_heredoc_body(heredoc, &mut *next_line) {
+            eprintln!("{}", warning);
+        }
+        if heredoc.expand {
+            match expand_heredoc(&heredoc.body, shell) {
+                Ok(body) => heredoc.body = body,
+                Err(e) => {
+                    eprintln!("{}: {}", shell.name(), e);
+                    shell.last_status = 1;
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+fn execute_single_command(shell: &mut Shell, parsed: &ParsedCommand) -> i32 {
+    if parsed.args.is_empty() {
+        for assignment in &parsed.assignments {
+            if let Err(e) = assign(shell, assignment) {
+                eprintln!("{}: {}", shell.name(), e);
+                return 1;
+            }
+        }
+        for array in &parsed.arrays {
+            if let Err(e) = assign_array(shell, array) {
+                eprintln!("{}: {}", shell.name(), e);
+                return 1;
+            }
+        }
+        // As in bash, `x=$(cmd)` gives the status of `cmd`
+        return shell.substitution_status.take().unwrap_or(0);
+    }
+
+    match parsed.args[0].as_str() {
+        "exec" if !shell.functions.contains_key("exec") => execute_exec(shell, parsed),
+        "command" if !shell.functions.contains_key("command") => {
+            match command_target(&parsed.args) {
+                Some((_, [name, ..])) => execute_external(shell, name, parsed),
+                _ => execute_builtin_command(shell, parsed, None),
+            }
+        }
+        cmd if BUILTINS.contains(&cmd) || shell.functions.contains_key(cmd) => {
+            execute_builtin_command(shell, parsed, None)
+        }
+        cmd => execute_external(shell, cmd, parsed),
+    }
+}
+
+/// Runs a builtin or function in the shell itself, with its redirections applied.
+/// Unless redirected, it reads `stdin` if given, as the last stage of a pipeline does.
+fn execute_builtin_command(
+    shell: &mut Shell,
+    parsed: &ParsedCommand,
+    stdin: Option<OwnedFd>,
+) -> i32 {
+    if shell.functions.contains_key(&parsed.args[0]) {
+        return execute_function(shell, parsed, stdin);
+    }
+    let mut files = match OutputFiles::open(parsed, shell.noclobber()) {
+        Ok(files) => files,
+        Err(e) => {
+            report_redirection_error(&shell.name(), &e);
+            return 1;
+        }
+    };
+    shell.stdin = match input_fd(std::mem::replace(&mut files.stdin, Input::Stream)) {
+        Ok(fd) => fd.or(stdin),
+        Err(e) => {
+            report_redirection_error(&shell.name(), &e);
+            return 1;
+        }
+    };
+    let mut stdout: Box<dyn Write> = match shell.stdout.as_ref().and_then(|w| w.try_clone().ok()) {
+        Some(writer) => Box::new(writer),
+        None => Box::new(std::io::stdout()),
+    };
+    let status = with_io(files, &mut stdout, |io| {
+        with_assignments(shell, parsed, |shell| {
+            execute_in_shell(shell, &parsed.args, io)
+        })
+    });
+    shell.stdin = None;
+    status
+}
+
+/// Runs a function in this shell with its redirections, and `stdin` if given, applied
+/// to the shell's own streams while it runs, so that every command in its body sees
+/// them.
+fn execute_function(shell: &mut Shell, parsed: &ParsedCommand, stdin: Option<OwnedFd>) -> i32 {
+    let call = |shell: &mut Shell| {
+        with_assignments(shell, parsed, |shell| call_function(shell, &parsed.args))
+    };
+    if stdin.is_none() && parsed.redirects.is_empty() {
+        return call(shell);
+    }
+    with_shell_streams(shell, stdin, parsed, call).unwrap_or_else(|status| status)
+}
+
+/// Runs a builtin or function with the assignments in front of it made in the shell's
+/// variables, exported for the commands it starts, and puts the variables back
+/// afterwards. Nothing runs if one cannot be assigned. External commands get their
+/// assignments in their environment instead (see `external_command`).
+fn with_assignments(
+    shell: &mut Shell,
+    parsed: &ParsedCommand,
+    run: impl FnOnce(&mut Shell) -> i32,
+) -> i32 {
+    let mut saved: Vec<(&str, SavedVariable)> = Vec::new();
+    let mut result = Ok(());
+    for assignment in &parsed.assignments {
+        if !saved.iter().any(|(name, _)| *name == assignment.name) {
+            saved.push((&assignment.name, shell.save_variable(&assignment.name)));
+        }
+        result = assign(shell, assignment);
+        if result.is_err() {
+            break;
+        }
+        shell.exported.insert(assignment.name.clone());
+    }
+    let status = match result {
+        Ok(()) => run(shell),
+        Err(e) => {
+            eprintln!("{}: {}", shell.name(), e);
+            1
+        }
+    };
+    for (name, variable) in saved.into_iter().rev() {
+        shell.restore_variable(name, variable);
+    }
+    status
+}
+
+/// Makes the assignment `NAME=value`, or `NAME[index]=value`, in the shell's variables.
+fn assign(shell: &mut Shell, assignment: &Assignment) -> Result<(), String> {
+    let (name, value, append) = (&assignment.name, &assignment.value, assignment.append);
+    match &assignment.index {
+        Some(index) => shell.set_element(name, index, value, append),
+        None => shell.set_variable(name, value, append),
+    }
+}
+
+/// Stores the words of `NAME=(...)` in the array `NAME`, which replaces a variable of
+/// that name. With `+=` they are added to its elements, or to its value.
+/// Fails for a readonly variable.
+fn assign_array(shell: &mut Shell, array: &ArrayAssignment) -> Result<(), String> {
+    if shell.attributes(&array.name).contains(Attributes::READONLY) {
+        return Err(format!("{}: readonly variable", array.name));
+    }
+    let mut elements = match shell.arrays.remove(&array.name) {
+        Some(elements) if array.append => elements,
+        Some(_) => Array::new(),
+        None if array.append => shell
+            .variables
+            .remove(&array.name)
+            .map(|value| (0, value))
+            .into_iter()
+            .collect(),
+        None => Array::new(),
+    };
+    shell.unset_var(&array.name);
+    // Appended words go after the highest index in use
+    let start = elements.keys().next_back().map_or(0, |last| last + 1);
+    elements.extend((start..).zip(array.values.iter().cloned()));
+    shell.arrays.insert(array.name.clone(), elements);
+    Ok(())
+}
+
+/// Runs a builtin with its output written to `io`, and returns its status. Output that
+/// cannot be written is reported and makes the status 1.
+fn execute_in_shell(shell: &mut Shell, args: &[String], io: &mut Io) -> i32 {
+    let status = execute_builtin(&args[0], args, shell, io);
+    match io.finish() {
+        Some(e) => {
+            io.eprintln(&format!(
+                "{}: write error: {}",
+                args[0],
+                io_error_message(&e)
+            ));
+            1
+        }
+        None => status,
+    }
+}
+
+/// Runs the function named by `args[0]` in the current shell, with the rest of `args`
+/// as its positional parameters and the redirections after its body applied, and
+/// returns its status.
+fn call_function(shell: &mut Shell, args: &[String]) -> i32 {
+    let Some(function) = shell.functions.get(&args[0]).cloned() else {
+        return 127;
+    };
+    let outer_positional = std::mem::replace(&mut shell.positional, args[1..].to_vec());
+    // Loops around the call are out of reach of `break` and `continue` in the body
+    let outer_loop_depth = std::mem::take(&mut shell.loop_depth);
+    shell.function_depth += 1;
+    shell.locals.push(HashMap::new());
+    let mut heredoc_lines = function.heredoc_lines.into_iter();
+    // The only control that can leave the body is `return`, which ends the call
+    let _ = with_redirects(
+        shell,
+        &function.redirects,
+        &mut || heredoc_lines.next(),
+        |shell, _| execute_list(shell, &function.body, &mut || None),
+    );
+    for (name, saved) in shell.locals.pop().unwrap_or_default() {
+        shell.restore_variable(&name, saved);
+    }
+    shell.function_depth -= 1;
+    shell.loop_depth = outer_loop_depth;
+    shell.positional = outer_positional;
+    shell.last_status
+}
+
+/// Builds the process for an external command, turning its arguments back into the
+/// bytes they stood for. `exec [-a name]` or `command [-p]` in front of the command is
+/// dropped, keeping `name` as its `argv[0]`.
+fn external_command(shell: &Shell, parsed: &ParsedCommand) -> Command {
+    let (arg0, default_path, args) =
+        external_prefix(&parsed.args).unwrap_or((None, false, parsed.args.as_slice()));
+    let program = match default_path {
+        true => search_path(&args[0], OsStr::new(DEFAULT_PATH)),
+        false => None,
+    };
+    let mut command = match program {
+        Some(path) => Command::new(path),
+        None => Command::new(encoding::encode(&args[0])),
+    };
+    // The child sees the exported variables, and the assignments in front of the
+    // command, which are for its environment only
+    command.env_clear().envs(shell.environment());
+    // An array element has no place in the environment
+    for assignment in parsed.assignments.iter().filter(|a| a.index.is_none()) {
+        let value = match shell.var(&assignment.name) {
+            Some(current) if assignment.append => current.to_string() + &assignment.value,
+            _ => assignment.value.clone(),
+        };
+        command.env(&assignment.name, encoding::encode(&value));
+    }
+    command.args(args[1..].iter().map(|arg| encoding::encode(arg)));
+    if let Some(name) = arg0 {
+        command.arg0(encoding::encode(name));
+    }
+    if shell.job_control {
+        // SAFETY: the hook only changes signal actions, which is async-signal-safe.
+        unsafe {
+            command.pre_exec(|| {
+                jobs::restore_terminal_signals();
+                Ok(())
+            });
+        }
+    }
+    command
+}
+
+/// Strips `exec [-a name]` or `command [-p]` from the front of `args` when what follows
+/// is an external command to run, returning `name`, whether `-p` asked for the default
+/// `PATH`, and the command with its arguments.
+fn external_prefix(args: &[String]) -> Option<(Option<&String>, bool, &[String])> {
+    if let Ok((arg0, rest @ [_, ..])) = exec_arguments(args) {
+        return Some((arg0, false, rest));
+    }
+    let (default_path, rest) = command_target(args)?;
+    Some((None, default_path, rest))
+}
+
+/// The external command of `command [-p] name [args...]`, with whether to find it in
+/// the default `PATH`. `None` for anything else, including a builtin `name`, which
+/// the `command` builtin runs itself.
+fn command_target(args: &[String]) -> Option<(bool, &[String])> {
+    if args.first().is_none_or(|cmd| cmd != "command") {
+        return None;
+    }
+    match command_arguments(args) {
+        Ok((CommandLookup::Run, default_path, rest @ [name, ..]))
+            if !BUILTINS.contains(&name.as_str()) =>
+        {
+            Some((default_path, rest))
+        }
+        _ => None,
+    }
+}
+
+/// Splits the words of `exec [-a name] [command [args...]]` into `name`, if given,
+/// and the command with its arguments. Anything other than `exec` has no command.
+fn exec_arguments(args: &[String]) -> Result<(Option<&String>, &[String]), String> {
+    if args.first().is_none_or(|cmd| cmd != "exec") {
+        return Ok((None, &[]));
+    }
+    let mut arg0 = None;
+    let mut rest = &args[1..];
+    loop {
+        match rest.first().map(String::as_str) {
+            Some("--") => return Ok((arg0, &rest[1..])),
+            Some("-a") => {
+                let name = rest.get(1).ok_or("exec: -a: option requires an argument")?;
+                arg0 = Some(name);
+                rest = &rest[2..];
+            }
+            Some(option) if option.starts_with('-') && option.len() > 1 => {
+                return Err(format!("exec: {}: invalid option", option));
+            }
+            _ => return Ok((arg0, rest)),
+        }
+    }
+}
+
+/// `exec [-a name] [command [args...]]`: replaces the shell with `command`, giving it
+/// the command's redirections. If it cannot be started the error is reported and the
+/// shell carries on. Without a command, the redirections are applied to the shell
+/// itself and stay in effect for the commands after it.
+fn execute_exec(shell: &mut Shell, parsed: &ParsedCommand) -> i32 {
+    let args = match exec_arguments(&parsed.args) {
+        Ok((_, args)) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    if args.is_empty() {
+        return redirect_shell(shell, parsed);
+    }
+
+    let mut command = external_command(shell, parsed);
+    capture_stdout(&mut command, shell);
+    if let Err(e) = apply_redirections(
+        &mut command,
+        parsed,
+        shell.noclobber(),
+        shell.stdout.as_ref(),
+    ) {
+        report_redirection_error(&shell.name(), &e);
+        return 1;
+    }
+    let e = replace_shell(&mut command);
+    report_spawn_failure(&args[0], &e)
+}
+
+/// Replaces the shell with `command`, for `exec` and `newgrp`, once the output it has
+/// buffered is written. Only returns if the command could not be started.
+pub fn replace_shell(command: &mut Command) -> std::io::Error {
+    let _ = std::io::stdout().flush();
+    let e = command.exec();
+    // Getting ready to run the command gave SIGPIPE its default action back, which
+    // would now let a closed pipe kill the shell
+    // SAFETY: ignoring a signal installs no handler code.
+    let _ = unsafe { signal(Signal::SIGPIPE, SigHandler::SigIgn) };
+    e
+}
+
+/// Applies the redirections of a bare `exec` to the shell's own stdin, stdout and
+/// stderr. Nothing is changed unless every file can be opened.
+fn redirect_shell(shell: &Shell, parsed: &ParsedCommand) -> i32 {
+    let mut files = match OutputFiles::open(parsed, shell.noclobber()) {
+        Ok(files) => files,
+        Err(e) => {
+            report_redirection_error(&shell.name(), &e);
+            return 1;
+        }
+    };
+    let stdin = match input_fd(std::mem::replace(&mut files.stdin, Input::Stream)) {
+        Ok(stdin) => stdin,
+        Err(e) => {
+            report_redirection_error(&shell.name(), &e);
+            return 1;
+        }
+    };
+    let replaced = stdin
+        .map_or(Ok(()), nix::unistd::dup2_stdin)
+        .map_err(std::io::Error::from)
+        .and_then(|()| files.replace_shell_streams());
+    match replaced {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("exec: {}", io_error_message(&e));
+            1
+        }
+    }
+}
+
+/// Runs an external command to completion and returns its exit status. With job
+/// control it runs as a job of its own, in the foreground.
+fn execute_external(shell: &mut Shell, cmd: &str, parsed: &ParsedCommand) -> i32 {
+    let mut command = external_command(shell, parsed);
+    capture_stdout(&mut command, shell);
+    if let Err(e) = apply_redirections(
+        &mut command,
+        parsed,
+        shell.noclobber(),
+        shell.stdout.as_ref(),
+    ) {
+        report_redirection_error(&shell.name(), &e);
+        return 1;
+    }
+
+    if shell.job_control {
+        command.process_group(0);
+        return match command.spawn() {
+            Ok(child) => shell
+                .jobs
+                .wait_foreground(&[child.id()], parsed.args.join(" ")),
+            Err(e) => report_spawn_failure(cmd, &e),
+        };
+    }
+    match command.status() {
+        Ok(status) => exit_code(status),
+        Err(e) => report_spawn_failure(cmd, &e),
+    }
+}
+
+/// Runs `command` as a command substitution, in a forked copy of the shell, and returns
+/// everything it wrote to stdout. Nothing the command changes, such as variables or the
+/// working directory, reaches this shell, and `exit` ends only the substitution.
+pub fn capture_output(shell: &mut Shell, command: &str) -> String {
+    let (mut reader, writer) = match std::io::pipe() {
+        Ok(pipe) => pipe,
+        Err(e) => {
+            eprintln!("pipe: {}", e);
+            return String::new();
+        }
+    };
+    // Output still buffered would otherwise be written by both processes
+    let _ = std::io::stdout().flush();
+    // SAFETY: the child only goes on to run the shell, and then exits.
+    match unsafe { nix::unistd::fork() } {
+        Ok(ForkResult::Child) => {
+            drop(reader);
+            leave_job_control(shell);
+            // Everything the command writes to stdout, however it writes it, is captured
+            shell.stdout = None;
+            let status = match nix::unistd::dup2_stdout(writer) {
+                Ok(()) => {
+                    run_line(shell, command, &mut || None);
+                    shell.exit_code.unwrap_or(shell.last_status)
+                }
+                Err(e) => {
+                    eprintln!("{}: {}", shell.name(), e.desc());
+                    1
+                }
+            };
+            let _ = std::io::stdout().flush();
+            std::process::exit(status);
+        }
+        Ok(ForkResult::Parent { child }) => {
+            // The child's copy of the writer is then the only one, and ends the output
+            drop(writer);
+            let mut output = Vec::new();
+            let _ = reader.read_to_end(&mut output);
+            shell.last_status = wait_process(child);
+            shell.substitution_status = Some(shell.last_status);
+            encoding::from_bytes(&output)
+        }
+        Err(e) => {
+            eprintln!("fork: {}", e.desc());
+            shell.last_status = 1;
+            shell.substitution_status = Some(1);
+            String::new()
+        }
+    }
+}
+
+/// Sends an external command's stdout to where the shell's output is captured, if anywhere.
+fn capture_stdout(command: &mut Command, shell: &Shell) {
+    if let Some(writer) = &shell.stdout
+        && let Ok(clone) = writer.try_clone()
+    {
+        command.stdout(clone);
+    }
+}
+
+/// Converts a child's exit status to a shell status; death by signal N becomes 128 + N.
+fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+/// Reports a redirection that could not be applied on the shell's stderr, under the
+/// shell's name as bash does. Redirections after the failed one, including the
+/// command's own stderr, were never applied.
+fn report_redirection_error(name: &str, message: &str) {
+    eprintln!("{}: {}", name, message);
+}
+
+/// Reports an error in a pipeline whose syntax was only checked once its words were
+/// expanded, such as a bad redirection, and returns its status: 2 for a syntax error
+/// and 1 for the rest, as in bash.
+fn report_parse_error(shell: &Shell, error: &ParseError) -> i32 {
+    report_redirection_error(&shell.name(), &error.to_string());
+    match error {
+        ParseError::UnexpectedToken { .. } | ParseError::UnexpectedEof => STATUS_SYNTAX_ERROR,
+        _ => 1,
+    }
+}
+
+/// Prints why `cmd` could not be started and returns the matching exit status.
+fn report_spawn_failure(cmd: &str, e: &std::io::Error) -> i32 {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        eprintln!("{}: command not found", cmd);
+        STATUS_NOT_FOUND
+    } else {
+        eprintln!("{}: {}", cmd, io_error_message(e));
+        STATUS_NOT_EXECUTABLE
+    }
+}
+
+/// Points the child's stdin/stdout/stderr at any redirection targets of the command.
+/// `stdout` is the pipe the command writes to, if any, for `2>&1`.
+/// Fails if a file cannot be opened, in which case the command must not run.
+fn apply_redirections(
+    command: &mut Command,
+    parsed: &ParsedCommand,
+    noclobber: bool,
+    stdout: Option<&PipeWriter>,
+) -> Result<(), String> {
+    let files = OutputFiles::open(parsed, noclobber)?;
+    match files.stdin {
+        Input::File(file) => {
+            command.stdin(file);
+        }
+        Input::Closed => {
+            command.stdin(Stdio::null());
+        }
+        Input::Text(text) => {
+            let (reader, writer) = std::io::pipe().map_err(|e| e.to_string())?;
+            // The feeder finishes once the child has read everything or closed its stdin
+            feed_pipe(writer, text);
+            command.stdin(reader);
+        }
+        Input::Stream => {}
+    }
+    match files.stdout {
+        Output::File(file) => {
+            command.stdout(file);
+        }
+        Output::Closed => {
+            command.stdout(Stdio::null());
+        }
+        Output::Stream(2) => {
+            command.stdout(copy_stream(std::io::stderr().as_fd())?);
+        }
+        Output::Stream(_) => {}
+    }
+    match files.stderr {
+        Output::File(file) => {
+            command.stderr(file);
+        }
+        Output::Closed => {
+            command.stderr(Stdio::null());
+        }
+        Output::Stream(1) => {
+            let stream = match stdout {
+                Some(writer) => copy_stream(writer.as_fd())?,
+                None => copy_stream(std::io::stdout().as_fd())?,
+            };
+            command.stderr(stream);
+        }
+        Output::Stream(_) => {}
+    }
+
+    Ok(())
+}
+
+fn copy_stream(fd: BorrowedFd) -> Result<Stdio, String> {
+    fd.try_clone_to_owned()
+        .map(Stdio::from)
+        .map_err(|e| e.to_string())
+}
+
+/// One stage of a pipeline to run.
+enum Stage<'a> {
+    /// A simple command, expanded and parsed.
+    Simple(&'a ParsedCommand),
+    /// A compound command, which always runs in a forked copy of the shell, and the
+    /// redirections to apply there in place of its own.
+    Compound(&'a ShellCommand, Option<&'a ParsedCommand>),
+}
+
+/// Runs every stage of the pipeline concurrently, connecting each stage's stdout
+/// to the next stage's stdin with an OS pipe. The last stage writes to the shell's
+/// stdout (or where that is captured) unless redirected; stderr of every stage is left untouched.
+/// Builtins and functions before the last stage run in forked copies of the shell, so
+/// their output streams into the next stage as it is written. A last one runs in the
+/// shell itself, where `read` can set its variables. Compound commands always run in
+/// a forked copy.
+/// Returns the exit status of the last stage.
+///
+/// A `background` pipeline is started without waiting for it, and every stage runs
+/// in a child process. The children share a process group of their own, led by the
+/// first, and are recorded as one job under `text`. With job control a pipeline in
+/// the foreground gets a group too, unless its last stage runs in the shell.
+fn execute_pipeline(shell: &mut Shell, stages: &[Stage], background: bool, text: &str) -> i32 {
+    let last_index = stages.len().saturating_sub(1);
+    let job = background
+        || (shell.job_control
+            && !matches!(stages.last(), Some(Stage::Simple(parsed)) if runs_in_shell(shell, parsed)));
+    let mut children: Vec<(usize, Pid)> = Vec::new();
+    // Background jobs must not compete with the shell for terminal input
+    let mut next_stdin: Option<OwnedFd> = match background {
+        true => open_input("/dev/null").ok().map(OwnedFd::from),
+        false => None,
+    };
+    let mut last_status = 0;
+
+    for (i, stage) in stages.iter().enumerate() {
+        let stdin = next_stdin.take();
+        let stdout = if i < last_index {
+            match std::io::pipe() {
+                Ok((reader, writer)) => {
+                    next_stdin = Some(reader.into());
+                    Some(writer)
+                }
+                Err(e) => {
+                    eprintln!("pipe: {}", e);
+                    last_status = 1;
+                    break;
+                }
+            }
+        } else {
+            shell
+                .stdout
+                .as_ref()
+                .and_then(|writer| writer.try_clone().ok())
+        };
+
+        // Pid 0 starts the group, which the others then join
+        let group = job.then(|| children.first().map_or(Pid::from_raw(0), |(_, pid)| *pid));
+
+        let started = match stage {
+            Stage::Simple(parsed) if parsed.args.is_empty() => continue,
+            Stage::Simple(parsed) if runs_in_shell(shell, parsed) => {
+                if i == last_index && !background {
+                    last_status = execute_builtin_command(shell, parsed, stdin);
+                    continue;
+                }
+                fork_stage(shell, stdin, stdout, next_stdin.as_ref(), group, |shell| {
+                    execute_single_command(shell, parsed)
+                })
+            }
+            Stage::Simple(parsed) => spawn_pipeline_stage(shell, parsed, stdin, stdout, group)
+                .map(|child| Pid::from_raw(child.id() as i32)),
+            Stage::Compound(command, redirects) => {
+                fork_stage(shell, stdin, stdout, next_stdin.as_ref(), group, |shell| {
+                    match command {
+                        ShellCommand::Subshell { body, .. } => {
+                            run_subshell(shell, body, *redirects)
+                        }
+                        // `break` and `continue` cannot reach loops outside the stage
+                        command => {
+                            let status =
+                                redirects.map_or(0, |parsed| redirect_shell(shell, parsed));
+                            if status != 0 {
+                                return status;
+                            }
+                            let _ = execute_command(shell, command, &mut || None);
+                            shell.last_status
+                        }
+                    }
+                })
+            }
+        };
+        match started {
+            Ok(pid) => children.push((i, pid)),
+            Err(status) => last_status = status,
+        }
+    }
+
+    let pids: Vec<u32> = children
+        .iter()
+        .map(|(_, pid)| pid.as_raw() as u32)
+        .collect();
+    if background {
+        if !pids.is_empty() {
+            let job = shell.jobs.add(&pids, text.to_string());
+            println!("[{}] {}", job.id, job.pid());
+            shell.last_background_pid = Some(job.pid());
+        }
+        return 0;
+    }
+    if job {
+        let status = shell.jobs.wait_foreground(&pids, text.to_string());
+        // Unless the last stage could not be started, it gives the status
+        return match children.last() {
+            Some((i, _)) if *i == last_index => status,
+            _ => last_status,
+        };
+    }
+    for (i, pid) in children {
+        let status = wait_process(pid);
+        if i == last_index {
+            last_status = status;
+        }
+    }
+
+    last_status
+}
+
+/// Returns true if the command is a builtin or function, which runs in the shell or a
+/// forked copy of it. `exec` with a command runs that command instead.
+fn runs_in_shell(shell: &Shell, parsed: &ParsedCommand) -> bool {
+    let Some(cmd) = parsed.args.first() else {
+        return false;
+    };
+    (BUILTINS.contains(&cmd.as_str()) && external_prefix(&parsed.args).is_none())
+        || shell.functions.contains_key(cmd)
+}
+
+/// Runs one stage of a pipeline in a forked copy of the shell, reading `stdin` and
+/// writing `stdout` where given, and returns the child's pid. The child exits with
+/// the status `run` returns. `read_end` is the other end of the pipe `stdout` writes
+/// to: the child lets go of it, so that it sees the pipe break once the next stage
+/// is gone. With a `group` the child is put in that process group, or in a new one
+/// of its own for pid 0. On failure the error has been reported and the status is
+/// returned instead.
+fn fork_stage(
+    shell: &mut Shell,
+    stdin: Option<OwnedFd>,
+    stdout: Option<std::io::PipeWriter>,
+    read_end: Option<&OwnedFd>,
+    group: Option<Pid>,
+    run: impl FnOnce(&mut Shell) -> i32,
+) -> Result<Pid, i32> {
+    // Output still buffered would otherwise be written by both processes
+    let _ = std::io::stdout().flush();
+    // SAFETY: the child only goes on to run the shell, and then exits.
+    match unsafe { nix::unistd::fork() } {
+        Ok(ForkResult::Child) => {
+            if let Some(group) = group {
+                let _ = setpgid(Pid::from_raw(0), group);
+            }
+            leave_job_control(shell);
+            if let Some(reader) = read_end {
+                // The parent still owns it, and the child never returns to drop it
+                let _ = nix::unistd::close(reader.as_raw_fd());
+            }
+            // Like an external command, the stage is ended by writing to a closed pipe
+            // SAFETY: restoring the default action installs no handler code.
+            let _ = unsafe { signal(Signal::SIGPIPE, SigHandler::SigDfl) };
+            let streams = stdin
+                .map_or(Ok(()), nix::unistd::dup2_stdin)
+                .and_then(|()| stdout.map_or(Ok(()), nix::unistd::dup2_stdout));
+            shell.stdin = None;
+            shell.stdout = None;
+            let status = match streams {
+                Ok(()) => run(shell),
+                Err(e) => {
+                    eprintln!("{}: {}", shell.name(), e.desc());
+                    1
+                }
+            };
+            let _ = std::io::stdout().flush();
+            std::process::exit(shell.exit_code.unwrap_or(status));
+        }
+        Ok(ForkResult::Parent { child }) => {
+            // Also done here, so that the group exists before the next stage joins it
+            if let Some(group) = group {
+                let _ = setpgid(child, group);
+            }
+            Ok(child)
+        }
+        Err(e) => {
+            eprintln!("fork: {}", e.desc());
+            Err(1)
+        }
+    }
+}
+
+/// The descriptor to read the redirected input of a builtin or the shell from, or
+/// `None` if stdin is not redirected. Text is fed to it through a pipe.
+fn input_fd(input: Input) -> Result<Option<OwnedFd>, String> {
+    match input {
+        Input::Stream => Ok(None),
+        Input::File(file) => Ok(Some(file.into())),
+        Input::Closed => open_input("/dev/null").map(|file| Some(file.into())),
+        Input::Text(text) => {
+            let (reader, writer) = std::io::pipe().map_err(|e| e.to_string())?;
+            feed_pipe(writer, text);
+            Ok(Some(reader.into()))
+        }
+    }
+}
+
+/// Writes a builtin's output into a pipe from a separate thread, so a reader
+/// that is slow to start (or never reads) cannot block the shell.
+fn feed_pipe(mut writer: std::io::PipeWriter, content: String) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        // The reader may exit early (e.g. `head`); a broken pipe is not an error here.
+        let _ = writer.write_all(&encoding::to_bytes(&content));
+    })
+}
+
+/// Starts one external stage of a pipeline, in the process group `group` if given, as
+/// `fork_stage` takes it. On failure the error has already been reported and the
+/// stage's exit status is returned instead.
+fn spawn_pipeline_stage(
+    shell: &Shell,
+    parsed: &ParsedCommand,
+    stdin: Option<OwnedFd>,
+    stdout: Option<std::io::PipeWriter>,
+    group: Option<Pid>,
+) -> Result<Child, i32> {
+    let cmd = external_prefix(&parsed.args).map_or(&parsed.args[0], |(_, _, args)| &args[0]);
+    let mut command = external_command(shell, parsed);
+
+    if let Some(reader) = stdin {
+        command.stdin(Stdio::from(reader));
+    }
+    if let Some(group) = group {
+        command.process_group(group.as_raw());
+    }
+    if let Some(writer) = &stdout
+        && let Ok(clone) = writer.try_clone()
+    {
+        command.stdout(Stdio::from(clone));
+    }
+    // Explicit redirections on a stage take precedence over the pipe, as in bash.
+    if let Err(e) = apply_redirections(&mut command, parsed, shell.noclobber(), stdout.as_ref()) {
+        report_redirection_error(&shell.name(), &e);
+        return Err(1);
+    }
+
+    command.spawn().map_err(|e| report_spawn_failure(cmd, &e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn run(shell: &mut Shell, input: &str) {
+        run_line(shell, input, &mut || None);
+    }
+
+    /// Calls `run` with the shell's stdout going into a pipe, and returns everything written to it.
+    fn capture(shell: &mut Shell, run: impl FnOnce(&mut Shell)) -> String {
+        let (mut reader, writer) = match std::io::pipe() {
+            Ok(pipe) => pipe,
+            Err(e) => {
+                eprintln!("pipe: {}", e);
+                return String::new();
+            }
+        };
+        // Drain the pipe while the command runs so a large output cannot block it
+        let collector = std::thread::spawn(move || {
+            let mut output = Vec::new();
+            let _ = reader.read_to_end(&mut output);
+            encoding::from_bytes(&output)
+        });
+
+        let outer_stdout = shell.stdout.replace(writer);
+        // As in a subshell, the commands are not jobs of their own
+        let job_control = std::mem::take(&mut shell.job_control);
+        run(shell);
+        shell.job_control = job_control;
+        // Dropping the capture writer lets the collector see end of file
+        shell.stdout = outer_stdout;
+        collector.join().unwrap_or_default()
+    }
+
+    /// Runs `input` in the shell itself, unlike a command substitution, and returns
+    /// what it wrote to stdout. `exit` does not end the shell.
+    fn run_captured(shell: &mut Shell, input: &str) -> String {
+        capture(shell, |shell| {
+            let outer_exit_code = shell.exit_code.take();
+            run(shell, input);
+            shell.exit_code = outer_exit_code;
+        })
+    }
+
+    /// Runs a test that changes the working directory, one at a time, and changes
+    /// back afterwards.
+    fn with_cwd_restored<T>(test: impl FnOnce() -> T) -> T {
+        static CWD: std::sync::Mutex<()> = std::sync::Mutex::new(());
+        let _guard = CWD.lock().unwrap_or_else(|e| e.into_inner());
+        let outer = std::env::current_dir().unwrap();
+        let result = test();
+        std::env::set_current_dir(&outer).unwrap();
+        result
+    }
+
+    #[test]
+    fn test_last_status_success_and_failure() {
+        let mut shell = Shell::new();
+        run(&mut shell, "sh -c 'exit 3'");
+        assert_eq!(shell.last_status, 3);
+        assert_eq!(run_captured(&mut shell, "echo $?"), "3\n");
+
+        run(&mut shell, "echo ok > /dev/null");
+        assert_eq!(run_captured(&mut shell, "echo $?"), "0\n");
+    }
+
+    #[test]
+    fn test_status_of_missing_command_and_syntax_error() {
+        let mut shell = Shell::new();
+        run(&mut shell, "definitely-not-a-command-xyz");
+        assert_eq!(shell.last_status, STATUS_NOT_FOUND);
+        run(&mut shell, "echo a; fi");
+        assert_eq!(shell.last_status, STATUS_SYNTAX_ERROR);
+        for input in ["echo >", "| foo", "echo a; echo b >> | c"] {
+            // Nothing on the line runs
+            assert_eq!(run_captured(&mut shell, input), "");
+            assert_eq!(shell.last_status, STATUS_SYNTAX_ERROR);
+        }
+    }
+
+    #[test]
+    fn test_eval() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "EVAL_CMD='echo a;'; eval \"$EVAL_CMD\" echo '$EVAL_CMD' b"
+            ),
+            "a\necho a; b\n"
+        );
+        run(&mut shell, "eval EVAL_SET=1 \"&& sh -c 'exit 4'\"");
+        assert_eq!(shell.var("EVAL_SET"), Some("1"));
+        assert_eq!(shell.last_status, 4);
+        run(&mut shell, "false; eval '' ' '");
+        assert_eq!(shell.last_status, 0);
+    }
+
+    #[test]
+    fn test_command_skips_functions() {
+        let mut shell = Shell::new();
+        run(
+            &mut shell,
+            "echo() { printf 'fn\\n'; }; sh() { printf 'fn\\n'; }",
+        );
+        assert_eq!(
+            run_captured(&mut shell, "echo a; command echo b"),
+            "fn\nb\n"
+        );
+        assert_eq!(
+            run_captured(&mut shell, "command sh -c 'echo ext' | command -p cat"),
+            "ext\n"
+        );
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "command -v cd no_such_command_x; command echo $?"
+            ),
+            "cd\n1\n"
+        );
+        assert!(run_captured(&mut shell, "command -p -v cat").ends_with("/cat\n"));
+        run(&mut shell, "command -x");
+        assert_eq!(shell.last_status, 2);
+    }
+
+    #[test]
+    fn test_colon_expands_its_arguments() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                ": ${COLON_VAR:=default} $(echo ignored); echo $COLON_VAR"
+            ),
+            "default\n"
+        );
+        run(&mut shell, "false; : nothing here");
+        assert_eq!(shell.last_status, 0);
+    }
+
+    #[test]
+    fn test_subshell() {
+        with_cwd_restored(|| {
+            let mut shell = Shell::new();
+            let cwd = std::env::current_dir().unwrap();
+            assert_eq!(
+                run_captured(&mut shell, "(cd / && pwd); pwd"),
+                format!("/\n{}\n", cwd.display())
+            );
+        });
+
+        let dir = TempDir::new("subshell");
+        let path = dir.join("out.txt");
+        let mut shell = Shell::new();
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                &format!(
+                    "(SUBSHELL_VAR=1; echo a; sh -c 'echo b >&2') > {} 2>&1; echo ${{SUBSHELL_VAR:-unset}}",
+                    path.display()
+                )
+            ),
+            "unset\n"
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a\nb\n");
+
+        assert_eq!(run_captured(&mut shell, "(exit 3); echo $?"), "3\n");
+        assert_eq!(shell.exit_code, None);
+
+        // A subshell can be a stage of a pipeline, or a job of its own
+        std::fs::write(dir.join("a"), "").unwrap();
+        assert_eq!(
+            run_captured(&mut shell, &format!("(cd {}; ls) | wc -l", dir.display())).trim(),
+            "2"
+        );
+        assert_eq!(
+            run_captured(&mut shell, "echo b | (read x; echo $x$x) | tr b c"),
+            "cc\n"
+        );
+        assert_eq!(
+            run_captured(&mut shell, "echo x | (exit 4); echo $?"),
+            "4\n"
+        );
+        run(
+            &mut shell,
+            &format!("(sleep 0.1; echo x > {}) &", path.display()),
+        );
+        assert_eq!(
+            run_captured(&mut shell, "jobs"),
+            format!(
+                "[1]+  {:<24}(sleep 0.1; echo x > {}) &\n",
+                "Running",
+                path.display()
+            )
+        );
+        run(&mut shell, "wait");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "x\n");
+    }
+
+    #[test]
+    fn test_brace_group() {
+        with_cwd_restored(|| {
+            let mut shell = Shell::new();
+            assert_eq!(run_captured(&mut shell, "{ cd /; }; pwd"), "/\n");
+        });
+
+        let dir = TempDir::new("group");
+        let path = dir.join("out.txt");
+        let mut shell = Shell::new();
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                &format!(
+                    "{{ echo a; sh -c 'echo b'; echo c >&2; }} > {} 2>&1; echo after",
+                    path.display()
+                )
+            ),
+            "after\n"
+        );
+        // Other tests may write to the same streams meanwhile
+        let output = std::fs::read_to_string(&path).unwrap();
+        assert!(output.contains("a\nb\nc\n"), "{}", output);
+
+        assert_eq!(
+            run_captured(&mut shell, "false || { echo x; echo y; } && echo z"),
+            "x\ny\nz\n"
+        );
+
+        // In a pipeline the group runs apart from the shell
+        assert_eq!(
+            run_captured(&mut shell, "{ echo a; echo b; } | sort -r"),
+            "b\na\n"
+        );
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "echo x | { read GROUP_VAR; echo $GROUP_VAR; }; echo ${GROUP_VAR:-unset}"
+            ),
+            "x\nunset\n"
+        );
+    }
+
+    #[test]
+    fn test_and_or_lists() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run_captured(&mut shell, "false || echo a && echo b"),
+            "a\nb\n"
+        );
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "true && false || echo c; false && echo no; echo $?"
+            ),
+            "c\n1\n"
+        );
+        run(&mut shell, "true || false");
+        assert_eq!(shell.last_status, 0);
+        run(&mut shell, "true && sh -c 'exit 3'");
+        assert_eq!(shell.last_status, 3);
+
+        // Only the last command of the list can make `set -e` exit
+        run(&mut shell, "set -e; false && echo no; false || true");
+        assert_eq!(shell.exit_code, None);
+        run(&mut shell, "true && false");
+        assert_eq!(shell.exit_code, Some(1));
+
+        // Removed only once the shell has left it
+        let dir = TempDir::new("and_or");
+        let made = dir.join("made");
+        with_cwd_restored(|| {
+            let mut shell = Shell::new();
+            run(
+                &mut shell,
+                &format!("mkdir {0} && cd {0} || echo failed", made.display()),
+            );
+            assert_eq!(std::env::current_dir().unwrap(), made);
+        });
+    }
+
+    #[test]
+    fn test_command_sequence_and_if() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run_captured(&mut shell, "echo a; echo b\necho c"),
+            "a\nb\nc\n"
+        );
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "if false; then echo no; elif true; then echo yes; fi"
+            ),
+            "yes\n"
+        );
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "if sh -c 'exit 3'\nthen echo no\nelse echo $?\nfi"
+            ),
+            "3\n"
+        );
+        run(&mut shell, "if false; then echo no; fi");
+        assert_eq!(shell.last_status, 0);
+        run(&mut shell, "if true; then sh -c 'exit 4'; fi");
+        assert_eq!(shell.last_status, 4);
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "if true; then if false; then :; else echo inner; fi; fi"
+            ),
+            "inner\n"
+        );
+
+        // Redirections and pipes take the output of the whole command
+        let dir = TempDir::new("if");
+        let out = dir.join("out");
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                &format!(
+                    "if true; then echo a; sh -c 'echo b'; fi > {}; echo after",
+                    out.display()
+                )
+            ),
+            "after\n"
+        );
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "a\nb\n");
+        assert_eq!(
+            run_captured(&mut shell, "if true; then echo a; echo b; fi | sort -r"),
+            "b\na\n"
+        );
+    }
+
+    #[test]
+    fn test_empty_quoted_arguments() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "/usr/bin/printf '[%s]' \"\" a'' '' \"$EMPTY_UNSET\""
+            ),
+            "[][a][][]"
+        );
+        assert_eq!(run_captured(&mut shell, "echo \"\" end"), " end\n");
+    }
+
+    #[test]
+    fn test_bare_assignments() {
+        let mut shell = Shell::new();
+        run(&mut shell, "ASSIGN_A=1 ASSIGN_B=\"two words\"");
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(
+            run_captured(&mut shell, "echo $ASSIGN_A \"$ASSIGN_B\"; echo ASSIGN_A=x"),
+            "1 two words\nASSIGN_A=x\n"
+        );
+    }
+
+    #[test]
+    fn test_only_exported_variables_reach_children() {
+        let mut shell = Shell::new();
+        let script = "sh -c 'echo \"<$CHILD_VAR>\"'";
+        run(&mut shell, "CHILD_VAR=bar");
+        assert_eq!(run_captured(&mut shell, script), "<>\n");
+        run(&mut shell, "export CHILD_VAR");
+        assert_eq!(run_captured(&mut shell, script), "<bar>\n");
+        run(&mut shell, "declare +x CHILD_VAR");
+        assert_eq!(run_captured(&mut shell, script), "<>\n");
+        assert_eq!(shell.var("CHILD_VAR"), Some("bar"));
+    }
+
+    #[test]
+    fn test_assignments_scoped_to_command() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "PREFIX_VAR='a b' env | grep PREFIX_VAR; echo ${PREFIX_VAR-unset}"
+            ),
+            "PREFIX_VAR=a b\nunset\n"
+        );
+        assert!(shell.var("PREFIX_VAR").is_none());
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "PREFIX_VAR=1 PREFIX_VAR=2 sh -c 'echo $PREFIX_VAR'"
+            ),
+            "2\n"
+        );
+
+        // Builtins and functions see the value while they run
+        run(
+            &mut shell,
+            "prefix_fn() { echo \"$PREFIX_VAR\"; }; PREFIX_VAR=old",
+        );
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "PREFIX_VAR=new prefix_fn; PREFIX_VAR=x eval 'echo $PREFIX_VAR'; echo $PREFIX_VAR"
+            ),
+            "new\nx\nold\n"
+        );
+        // ...and so do the commands they start, but not the shell afterwards
+        run(
+            &mut shell,
+            "prefix_child() { sh -c 'echo \"<$PREFIX_VAR>\"'; }",
+        );
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "PREFIX_VAR=new prefix_child; prefix_child; PREFIX_VAR=e eval prefix_child"
+            ),
+            "<new>\n<>\n<e>\n"
+        );
+        assert!(!shell.exported.contains("PREFIX_VAR"));
+        run(&mut shell, "readonly PREFIX_RO=1; PREFIX_RO=2 true");
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn test_crlf_input() {
+        let mut shell = Shell::new();
+        assert_eq!(run_captured(&mut shell, "echo hi\r\n"), "hi\n");
+
+        let dir = TempDir::new("crlf");
+        let path = dir.join("script.sh");
+        std::fs::write(&path, "cat <<EOF\r\nbody\r\nEOF\r\necho \\\r\ndone\r").unwrap();
+        let output = run_captured(&mut shell, &format!("source {}", path.display()));
+        assert_eq!(output, "body\ndone\n");
+    }
+
+    #[test]
+    fn test_while_and_until_loops() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "export LOOP_I=0; while test $LOOP_I -lt 3; do echo $LOOP_I; export LOOP_I=$((LOOP_I + 1)); done"
+            ),
+            "0\n1\n2\n"
+        );
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "until test $LOOP_I -eq 0\ndo export LOOP_I=$((LOOP_I - 1)); echo $LOOP_I\ndone"
+            ),
+            "2\n1\n0\n"
+        );
+        run(&mut shell, "while false; do echo never; done");
+        assert_eq!(shell.last_status, 0);
+
+        // Redirections after `done` apply to the whole loop
+        let dir = TempDir::new("loop");
+        let input = dir.join("in");
+        std::fs::write(&input, "a\nb c\n").unwrap();
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                &format!("while read l; do echo \"<$l>\"; done < {}", input.display())
+            ),
+            "<a>\n<b c>\n"
+        );
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "printf '1\\n2\\n' | while read l; do echo $((l * 2)); done"
+            ),
+            "2\n4\n"
+        );
+        let out = dir.join("out");
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                &format!(
+                    "for x in a b; do echo $x; done > {}; echo after",
+                    out.display()
+                )
+            ),
+            "after\n"
+        );
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "a\nb\n");
+    }
+
+    #[test]
+    fn test_for_loop() {
+        let dir = TempDir::new("for");
+        std::fs::write(dir.join("a.txt"), "").unwrap();
+        std::fs::write(dir.join("b.txt"), "").unwrap();
+        let mut shell = Shell::new();
+        shell.set_var("FOR_DIR", &dir.to_string_lossy());
+
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "for f in one 'two three' $FOR_DIR/*.txt; do echo \"<$f>\"; done"
+            ),
+            format!(
+                "<one>\n<two three>\n<{0}/a.txt>\n<{0}/b.txt>\n",
+                dir.display()
+            )
+        );
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "for i in 1 2 3\ndo for j in a b c; do if test $j = b; then continue 2; fi; echo $i$j; done\ndone"
+            ),
+            "1a\n2a\n3a\n"
+        );
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "for i in 1 2 3; do echo $i; break; done; echo $i"
+            ),
+            "1\n1\n"
+        );
+        run(&mut shell, "for x in; do sh -c 'exit 3'; done");
+        assert_eq!(shell.last_status, 0);
+        run(&mut shell, "for 1x in a; do :; done");
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn test_select_loop() {
+        let mut shell = Shell::new();
+        let (reader, mut writer) = std::io::pipe().unwrap();
+        writer.write_all(b"2\n\nx\n").unwrap();
+        drop(writer);
+        shell.stdin = Some(reader.into());
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "select SELECT_ITEM in a b; do echo \"[$SELECT_ITEM] $REPLY\"; done"
+            ),
+            "[b] 2\n[] x\n"
+        );
+        assert_eq!(shell.last_status, 0);
+    }
+
+    #[test]
+    fn test_case() {
+        let mut shell = Shell::new();
+        let script = "case $CASE_WORD in\n  a|b) echo ab;;\n  *.rs) echo rust;;\n  '*') echo star;;\n  ?x) echo \"?x\";;\n  *) echo other\nesac";
+        for (word, expected) in [
+            ("b", "ab\n"),
+            ("main.rs", "rust\n"),
+            ("*", "star\n"),
+            ("zx", "?x\n"),
+            ("a b", "other\n"),
+        ] {
+            shell.set_var("CASE_WORD", word);
+            assert_eq!(run_captured(&mut shell, script), expected, "{}", word);
+        }
+
+        shell.set_var("CASE_PATTERN", "m*");
+        assert_eq!(
+            run_captured(&mut shell, "case main in $CASE_PATTERN) echo yes;; esac"),
+            "yes\n"
+        );
+        run(&mut shell, "case x in y) sh -c 'exit 3';; x) ;; esac");
+        assert_eq!(shell.last_status, 0);
+        run(&mut shell, "case x in x) sh -c 'exit 3';; esac");
+        assert_eq!(shell.last_status, 3);
+
+        let dir = TempDir::new("case");
+        let out = dir.join("out");
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                &format!(
+                    "case x in x) echo hit;; esac > {}; echo after",
+                    out.display()
+                )
+            ),
+            "after\n"
+        );
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "hit\n");
+    }
+
+    #[test]
+    fn test_functions() {
+        let mut shell = Shell::new();
+        run(
+            &mut shell,
+            "greet() { echo \"hello $1\" $#; }\nfunction count {\nfor arg; do echo \"<$arg>\"; done\n}",
+        );
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "greet 'big world' x; count a 'b c'; echo \"[$1]\""
+            ),
+            "hello big world 2\n<a>\n<b c>\n[]\n"
+        );
+        // Output redirections and pipes take everything the function writes
+        assert_eq!(
+            run_captured(&mut shell, "greet you | tr a-z A-Z; count x y | wc -l"),
+            "HELLO YOU 1\n2\n"
+        );
+        assert_eq!(
+            run_captured(&mut shell, "type greet"),
+            "greet is a function\n"
+        );
+    }
+
+    #[test]
+    fn test_function_redirections() {
+        let mut shell = Shell::new();
+        let dir = TempDir::new("fn_redirect");
+        let (input, output) = (dir.join("in.txt"), dir.join("out.txt"));
+        std::fs::write(&input, "from file\n").unwrap();
+        run(
+            &mut shell,
+            "fn_both() { echo out; sh -c 'echo err >&2'; }; fn_cat() { cat; read x; echo \"<$x>\"; }",
+        );
+        // Every command in the body sees the redirections, external ones included
+        run(&mut shell, &format!("fn_both 2> {}", output.display()));
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "err\n");
+        assert_eq!(
+            run_captured(&mut shell, &format!("fn_both > {} 2>&1", output.display())),
+            ""
+        );
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "out\nerr\n");
+        assert_eq!(
+            run_captured(&mut shell, &format!("fn_cat < {}", input.display())),
+            "from file\n<>\n"
+        );
+        assert_eq!(
+            run_captured(&mut shell, "echo piped | fn_cat"),
+            "piped\n<>\n"
+        );
+    }
+
+    #[test]
+    fn test_redirections_on_function_definitions() {
+        let mut shell = Shell::new();
+        let mut lines = ["body $1", "E", "echo after"].map(String::from).into_iter();
+        run_line(&mut shell, "fn_doc() { cat; } <<E", &mut || lines.next());
+        // The body is read with the definition, and every call reads it again
+        assert_eq!(lines.next().as_deref(), Some("echo after"));
+        assert_eq!(
+            run_captured(&mut shell, "fn_doc x; fn_doc y"),
+            "body x\nbody y\n"
+        );
+
+        let dir = TempDir::new("fn_definition");
+        let path = dir.join("out.txt");
+        run(
+            &mut shell,
+            &format!(
+                "fn_out() {{ echo out; sh -c 'echo err >&2'; }} > {} 2>&1",
+                path.display()
+            ),
+        );
+        assert_eq!(run_captured(&mut shell, "fn_out"), "");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "out\nerr\n");
+    }
+
+    #[test]
+    fn test_return() {
+        let mut shell = Shell::new();
+        run(
+            &mut shell,
+            "check() { while true; do if test $1 = yes; then return; fi; return 3; done; echo unreachable; }",
+        );
+        assert_eq!(run_captured(&mut shell, "check yes; echo $?"), "0\n");
+        assert_eq!(run_captured(&mut shell, "check no; echo $?"), "3\n");
+        // `break` in a function cannot reach the caller's loop
+        run(&mut shell, "leave() { break; }");
+        assert_eq!(
+            run_captured(&mut shell, "for i in 1 2; do leave; echo $i; done"),
+            "1\n2\n"
+        );
+        run(&mut shell, "return 1");
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn test_local_variables() {
+        let mut shell = Shell::new();
+        run(
+            &mut shell,
+            "inner() { echo \"inner $LOCAL_A\"; LOCAL_A=changed; }\n\
+             outer() { local LOCAL_A=1 LOCAL_B; local -i LOCAL_N=2+3; inner; \
+             echo \"$LOCAL_A ${LOCAL_B-unset} $LOCAL_N\"; LOCAL_C=global; }",
+        );
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "LOCAL_A=outside; LOCAL_B=b; outer; echo \"$LOCAL_A $LOCAL_B ${LOCAL_N-unset} $LOCAL_C\""
+            ),
+            "inner 1\nchanged unset 5\noutside b unset global\n"
+        );
+        // Locals are arrays too, and each call gets its own
+        run(
+            &mut shell,
+            "count() { local -a LOCAL_L; LOCAL_L+=(x); echo ${#LOCAL_L[@]}; }",
+        );
+        assert_eq!(run_captured(&mut shell, "count; count"), "1\n1\n");
+
+        run(&mut shell, "local LOCAL_OUT=1");
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(
+            run_captured(&mut shell, "echo ${LOCAL_OUT-unset}"),
+            "unset\n"
+        );
+    }
+
+    #[test]
+    fn test_set_options() {
+        let mut shell = Shell::new();
+        run(&mut shell, "set -eu");
+        assert_eq!(run_captured(&mut shell, "echo $-"), "eu\n");
+        assert_eq!(
+            run_captured(&mut shell, "echo ${SET_UNSET:-default}"),
+            "default\n"
+        );
+        assert_eq!(run_captured(&mut shell, "echo $SET_UNSET; echo after"), "");
+
+        // A failing condition is not an error, but a failing command exits the shell
+        run(&mut shell, "set +u");
+        assert_eq!(
+            run_captured(&mut shell, "if false; then :; fi; echo yes; false; echo no"),
+            "yes\n"
+        );
+        run(&mut shell, "sh -c 'exit 3'");
+        assert_eq!(shell.exit_code, Some(3));
+        shell.exit_code = None;
+        run(&mut shell, "set +e");
+        run(&mut shell, "false");
+        assert_eq!(shell.exit_code, None);
+
+        run(&mut shell, "set -f -- 'a b' c");
+        assert_eq!(run_captured(&mut shell, "echo /* $#"), "/* 2\n");
+        run(&mut shell, "set -o noclobber");
+        assert_eq!(run_captured(&mut shell, "echo $-"), "fC\n");
+        assert!(
+            run_captured(&mut shell, "set -o")
+                .lines()
+                .any(|line| line == "noclobber      \ton")
+        );
+        run(&mut shell, "set +f +C; set -q");
+        assert_eq!(shell.last_status, 2);
+        assert_eq!(shell.options, ShellOptions::default());
+        assert_eq!(shell.positional, vec!["a b", "c"]);
+    }
+
+    #[test]
+    fn test_trace_quotes_arguments() {
+        let args = ["echo", "a b", "", "it's", "x=1"].map(String::from);
+        assert_eq!(trace(&args), "echo 'a b' '' 'it'\\''s' x=1");
+    }
+
+    #[test]
+    fn test_break_and_continue() {
+        let mut shell = Shell::new();
+        let counter = "export LOOP_J=$((LOOP_J + 1))";
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                &format!(
+                    "export LOOP_J=0; while true; do {}; if test $LOOP_J -eq 2; then continue; fi; echo $LOOP_J; if test $LOOP_J -ge 3; then break; fi; done",
+                    counter
+                )
+            ),
+            "1\n3\n"
+        );
+        // `break 2` leaves both loops, `continue 2` goes on with the outer one
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "while true; do while true; do echo in; break 2; done; echo skipped; done; echo out"
+            ),
+            "in\nout\n"
+        );
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                &format!(
+                    "export LOOP_J=0; while test $LOOP_J -lt 2; do {}; while true; do continue 2; done; echo skipped; done; echo $LOOP_J",
+                    counter
+                )
+            ),
+            "2\n"
+        );
+        // A count beyond the number of loops stops at the outermost one
+        assert_eq!(
+            run_captured(&mut shell, "while true; do break 5; done; echo after"),
+            "after\n"
+        );
+        run(&mut shell, "break");
+        assert_eq!(shell.last_status, 0);
+        run(&mut shell, "break 0");
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn test_combined_redirect_keeps_both_streams() {
+        let dir = TempDir::new("both");
+        let path = dir.join("both.log");
+        let file = path.to_string_lossy();
+        let mut shell = Shell::new();
+        run(
+            &mut shell,
+            &format!("sh -c 'echo out; echo err >&2; echo out2' &> {}", file),
+        );
+        run(&mut shell, &format!("cd /nonexistent &>> {}", file));
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            content,
+            format!(
+                "out\nerr\nout2\n{}: cd: /nonexistent: No such file or directory\n",
+                shell.name()
+            )
+        );
+    }
+
+    #[test]
+    fn test_external_stderr_redirect() {
+        let dir = TempDir::new("stderr");
+        let base = dir.to_string_lossy();
+        let mut shell = Shell::new();
+
+        // The file is created even when nothing is written to it
+        run(&mut shell, &format!("true 2> {}/empty", base));
+        assert_eq!(
+            std::fs::read_to_string(format!("{}/empty", base)).unwrap(),
+            ""
+        );
+
+        for n in 1..=2 {
+            run(
+                &mut shell,
+                &format!("sh -c 'echo err{} >&2' 2>> {}/log", n, base),
+            );
+        }
+        assert_eq!(
+            std::fs::read_to_string(format!("{}/log", base)).unwrap(),
+            "err1\nerr2\n"
+        );
+
+        run(
+            &mut shell,
+            &format!("sh -c 'echo out; echo err >&2' > {0}/out 2> {0}/err", base),
+        );
+        assert_eq!(
+            std::fs::read_to_string(format!("{}/out", base)).unwrap(),
+            "out\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(format!("{}/err", base)).unwrap(),
+            "err\n"
+        );
+    }
+
+    #[test]
+    fn test_builtin_write_error() {
+        let mut shell = Shell::new();
+        run(&mut shell, "echo hi > /dev/full");
+        assert_eq!(shell.last_status, 1);
+        run(&mut shell, "f() { echo hi; }; f > /dev/full");
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn test_external_output_is_written_byte_for_byte() {
+        let dir = TempDir::new("binary");
+        let path = dir.join("binary.out");
+        let mut shell = Shell::new();
+        run(
+            &mut shell,
+            &format!(
+                "/usr/bin/printf 'a\\377\\000b' > {}",
+                path.to_string_lossy()
+            ),
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), b"a\xFF\0b");
+    }
+
+    #[test]
+    fn test_combined_redirect_in_pipeline_and_with_noclobber() {
+        let dir = TempDir::new("both_pipe");
+        let path = dir.join("both.log");
+        let file = path.to_string_lossy();
+        let mut shell = Shell::new();
+        run(
+            &mut shell,
+            &format!("echo in | sh -c 'cat; echo err >&2' &> {}", file),
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "in\nerr\n");
+
+        run(&mut shell, "set -C");
+        run(&mut shell, &format!("sh -c 'echo again' &> {}", file));
+        assert_eq!(shell.last_status, 1);
+        run(&mut shell, &format!("sh -c 'echo more >&2' &>> {}", file));
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "in\nerr\nmore\n");
+    }
+
+    #[test]
+    fn test_duplicate_and_close_streams() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run_captured(&mut shell, "sh -c 'echo out; echo err >&2' 2>&1 | cat"),
+            "out\nerr\n"
+        );
+        assert_eq!(
+            run_captured(&mut shell, "cd /nonexistent 2>&1"),
+            format!(
+                "{}: cd: /nonexistent: No such file or directory\n",
+                shell.name()
+            )
+        );
+        assert_eq!(run_captured(&mut shell, "echo hi >&-"), "");
+        assert_eq!(run_captured(&mut shell, "cat <&-"), "");
+    }
+
+    #[test]
+    fn test_duplicate_follows_redirection_order() {
+        let mut shell = Shell::new();
+        let name = shell.name();
+        let dir = TempDir::new("dup");
+        let path = dir.join("out.txt");
+        let file = path.to_string_lossy();
+        let mut redirected = |command: &str| {
+            let output = run_captured(&mut shell, &command.replace("FILE", &file));
+            (output, std::fs::read_to_string(&path).unwrap())
+        };
+        let both = "sh -c 'echo out; echo err >&2'";
+        assert_eq!(
+            redirected(&format!("{} > FILE 2>&1", both)),
+            (String::new(), "out\nerr\n".to_string())
+        );
+        assert_eq!(
+            redirected(&format!("{} 2>&1 > FILE", both)),
+            ("err\n".to_string(), "out\n".to_string())
+        );
+
+        // A builtin's captured output follows the same order
+        let error = format!("{}: cd: /nonexistent: No such file or directory\n", name);
+        assert_eq!(
+            redirected("cd /nonexistent > FILE 2>&1"),
+            (String::new(), error.clone())
+        );
+        assert_eq!(
+            redirected("cd /nonexistent 2>&1 > FILE"),
+            (error, String::new())
+        );
+    }
+
+    #[test]
+    fn test_noclobber_blocks_plain_output_redirect() {
+        let dir = TempDir::new("clobber");
+        let path = dir.join("out.txt");
+        let file = path.to_string_lossy();
+        std::fs::write(&path, "keep\n").unwrap();
+        let mut shell = Shell::new();
+        run(&mut shell, "set -C");
+
+        run(&mut shell, &format!("echo new > {}", file));
+        assert_eq!(shell.last_status, 1);
+        run(&mut shell, &format!("sh -c 'echo new' > {}", file));
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep\n");
+
+        run(&mut shell, &format!("echo more >> {}", file));
+        run(&mut shell, &format!("sh -c 'echo forced' >| {}", file));
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "forced\n");
+
+        run(&mut shell, "set +C");
+        run(&mut shell, &format!("echo plain > {}", file));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "plain\n");
+    }
+
+    #[test]
+    fn test_pipeline_status_is_last_stage() {
+        let mut shell = Shell::new();
+        run(&mut shell, "sh -c 'exit 4' | sh -c 'exit 5'");
+        assert_eq!(shell.last_status, 5);
+        run(&mut shell, "sh -c 'exit 4' | cd /");
+        assert_eq!(shell.last_status, 0);
+    }
+
+    #[test]
+    fn test_pipes_between_stages() {
+        let mut shell = Shell::new();
+        // `yes` dies of SIGPIPE once `head` exits, which must leave the shell alone
+        assert_eq!(run_captured(&mut shell, "yes | head -1"), "y\n");
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(run_captured(&mut shell, "echo hi | cat | cat"), "hi\n");
+        assert_eq!(run_captured(&mut shell, "echo hello | wc -c").trim(), "6");
+        // A function stage streams its output, and ends when the reader is gone
+        run(&mut shell, "yes_forever() { while :; do echo y; done; }");
+        assert_eq!(run_captured(&mut shell, "yes_forever | head -1"), "y\n");
+        assert_eq!(
+            run_captured(&mut shell, "yes_forever | cat | head -2"),
+            "y\ny\n"
+        );
+    }
+
+    #[test]
+    fn test_capture_output_of_builtins_and_pipelines() {
+        let mut shell = Shell::new();
+        assert_eq!(capture_output(&mut shell, "echo hi"), "hi\n");
+        assert_eq!(capture_output(&mut shell, "echo a | tr a b"), "b\n");
+        assert_eq!(
+            capture_output(&mut shell, "echo $(echo $(echo deep))"),
+            "deep\n"
+        );
+        assert_eq!(capture_output(&mut shell, "exit 3"), "");
+        assert_eq!(shell.exit_code, None);
+    }
+
+    #[test]
+    fn test_assignment_status_comes_from_command_substitution() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run_captured(&mut shell, "x=$(false); echo $?; x=$(exit 3) y=1; echo $?"),
+            "1\n3\n"
+        );
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "x=$(true)$(false); echo $?; false; x=1; echo $?"
+            ),
+            "1\n0\n"
+        );
+        assert_eq!(
+            run_captured(&mut shell, "if x=$(false); then echo yes; else echo no; fi"),
+            "no\n"
+        );
+        // The status of a command with a name is its own
+        assert_eq!(run_captured(&mut shell, "x=$(false) true; echo $?"), "0\n");
+    }
+
+    #[test]
+    fn test_command_substitution_runs_in_a_subshell() {
+        with_cwd_restored(|| {
+            let mut shell = Shell::new();
+            let cwd = std::env::current_dir().unwrap();
+            let output = capture_output(
+                &mut shell,
+                "cd /; SUBST_LEAK=1; subst_fn() { :; }; set -f; pwd",
+            );
+            assert_eq!(output, "/\n");
+            assert_eq!(std::env::current_dir().unwrap(), cwd);
+            assert_eq!(shell.var("SUBST_LEAK"), None);
+            assert!(!shell.functions.contains_key("subst_fn"));
+            assert!(!shell.options.contains(ShellOptions::NOGLOB));
+            assert_eq!(capture_output(&mut shell, "exit 4"), "");
+            assert_eq!(shell.last_status, 4);
+        });
+    }
+
+    #[test]
+    fn test_background_command_sets_last_pid() {
+        let mut shell = Shell::new();
+        run(&mut shell, "sh -c 'exit 6' &");
+        assert_eq!(shell.last_status, 0);
+        assert!(shell.last_background_pid.is_some());
+        run(&mut shell, "wait");
+        assert_eq!(shell.jobs.wait_all(), 0);
+    }
+
+    #[test]
+    fn test_background_pipelines_builtins_and_functions() {
+        let dir = TempDir::new("background");
+        let mut shell = Shell::new();
+        // A pipeline is one job, with the status of its last stage
+        run(&mut shell, "sleep 0.2 | sh -c 'exit 5' &");
+        assert_eq!(run_captured(&mut shell, "wait %1; echo $?"), "5\n");
+        run(&mut shell, "sleep 5 | cat &");
+        assert_eq!(
+            run_captured(&mut shell, "jobs"),
+            format!("[1]+  {:<24}sleep 5 | cat &\n", "Running")
+        );
+        // Signalling the job reaches every process in its group
+        run(&mut shell, "kill %1; wait");
+        assert_eq!(shell.last_status, 143);
+
+        // Builtins and functions in the background run apart from the shell
+        run(&mut shell, "export BG_EXPORTED=1 &");
+        let out = dir.join("out");
+        run(
+            &mut shell,
+            &format!("bg_write() {{ echo $1 > {}; }}", out.display()),
+        );
+        run(&mut shell, "bg_write hi &");
+        run(&mut shell, "wait");
+        assert!(shell.var("BG_EXPORTED").is_none());
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "hi\n");
+    }
+
+    #[test]
+    fn test_stopped_foreground_job_is_kept() {
+        let mut shell = Shell::new();
+        // Without a terminal to hand over, each job still gets a process group
+        shell.job_control = true;
+        run(&mut shell, "sh -c 'kill -STOP $$; exit 3'");
+        assert_eq!(shell.last_status, 128 + libc::SIGSTOP);
+        assert_eq!(
+            run_captured(&mut shell, "jobs"),
+            format!("[1]+  {:<24}sh -c kill -STOP $$; exit 3 &\n", "Stopped")
+        );
+        run(&mut shell, "fg > /dev/null");
+        assert_eq!(shell.last_status, 3);
+        assert_eq!(run_captured(&mut shell, "jobs"), "");
+    }
+
+    #[test]
+    fn test_heredoc_and_herestring_feed_stdin() {
+        let dir = TempDir::new("heredoc");
+        let path = dir.join("out.txt");
+        let file = path.to_string_lossy();
+        let mut shell = Shell::new();
+        let mut lines = ["one", "  two", "EOF", "echo after"]
+            .map(String::from)
+            .into_iter();
+        run_line(
+            &mut shell,
+            &format!("tr a-z A-Z << EOF > {}", file),
+            &mut || lines.next(),
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "ONE\n  TWO\n");
+        assert_eq!(lines.next().as_deref(), Some("echo after"));
+
+        assert_eq!(
+            run_captured(&mut shell, "tr a-z A-Z <<< 'some text' | cat"),
+            "SOME TEXT\n"
+        );
+        assert_eq!(run_captured(&mut shell, "cat <<< $((6 * 7))"), "42\n");
+    }
+
+    #[test]
+    fn test_heredoc_on_compound_commands() {
+        let mut shell = Shell::new();
+        let mut heredoc = |command: &str| {
+            let mut lines = ["one $((1 + 1))", "two", "EOF", "echo after"]
+                .map(String::from)
+                .into_iter();
+            let output = capture(&mut shell, |shell| {
+                run_line(shell, command, &mut || lines.next())
+            });
+            // The body is taken from the lines, and nothing after it
+            assert_eq!(lines.next().as_deref(), Some("echo after"), "{}", command);
+            output
+        };
+        assert_eq!(
+            heredoc("while read l; do echo \"<$l>\"; done <<EOF"),
+            "<one 2>\n<two>\n"
+        );
+        assert_eq!(heredoc("{ cat; } <<EOF"), "one 2\ntwo\n");
+        assert_eq!(heredoc("( cat ) <<'EOF'"), "one $((1 + 1))\ntwo\n");
+        assert_eq!(heredoc("{ cat; } <<EOF | tr a-z A-Z"), "ONE 2\nTWO\n");
+        assert_eq!(heredoc("if true; then cat; fi <<EOF | wc -l"), "2\n");
+    }
+
+    #[test]
+    fn test_several_heredocs_are_read_in_order() {
+        let mut shell = Shell::new();
+        let mut heredocs = |command: &str| {
+            let mut lines = ["a $((1 + 1))", "A", "b", "B", "echo after"]
+                .map(String::from)
+                .into_iter();
+            let output = capture(&mut shell, |shell| {
+                run_line(shell, command, &mut || lines.next())
+            });
+            // Each body ends at its own delimiter, and nothing after the last is taken
+            assert_eq!(lines.next().as_deref(), Some("echo after"), "{}", command);
+            output
+        };
+        // Only the last here-document becomes stdin
+        assert_eq!(heredocs("cat <<A <<'B'"), "b\n");
+        assert_eq!(heredocs("cat <<A | cat - <<B"), "b\n");
+        assert_eq!(heredocs("{ cat; } <<A <<B"), "b\n");
+        assert_eq!(heredocs("cat <<A; cat <<B"), "a 2\nb\n");
+    }
+
+    #[test]
+    fn test_heredoc_expansion_depends_on_delimiter_quoting() {
+        let mut shell = Shell::new();
+        shell.set_var("HEREDOC_NAME", "world");
+        let mut heredoc = |command: &str, body: &[&str]| {
+            let mut lines = body.iter().map(|line| line.to_string());
+            capture(&mut shell, |shell| {
+                run_line(shell, command, &mut || lines.next())
+            })
+        };
+        let body = ["hello $HEREDOC_NAME $((1 + 2)) \\$HOME", "X"];
+        assert_eq!(heredoc("cat <<X", &body), "hello world 3 $HOME\n");
+        assert_eq!(
+            heredoc("cat <<'X'", &body),
+            "hello $HEREDOC_NAME $((1 + 2)) \\$HOME\n"
+        );
+        assert_eq!(
+            heredoc("cat <<\"X\"", &body),
+            "hello $HEREDOC_NAME $((1 + 2)) \\$HOME\n"
+        );
+    }
+
+    #[test]
+    fn test_declare_builtin() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "declare -i DECL_N=42; DECL_N+=5; DECL_S=a; DECL_S+=b; echo $DECL_N $DECL_S"
+            ),
+            "47 ab\n"
+        );
+        run(&mut shell, "declare -r DECL_R=1; DECL_R=2");
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(run_captured(&mut shell, "echo $DECL_R"), "1\n");
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "declare -a DECL_A; DECL_A+=(x 'y z'); declare -p DECL_A"
+            ),
+            "declare -a DECL_A=([0]=\"x\" [1]=\"y z\")\n"
+        );
+        run(&mut shell, "declare -p DECL_MISSING");
+        assert_eq!(shell.last_status, 1);
+
+        run(&mut shell, "readonly DECL_RO=1; DECL_RO=2");
+        assert_eq!(shell.last_status, 1);
+        run(&mut shell, "UID=0");
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(
+            run_captured(&mut shell, "echo $DECL_RO $UID"),
+            format!("1 {}\n", nix::unistd::getuid())
+        );
+    }
+
+    #[test]
+    fn test_every_redirection_target_is_created() {
+        let mut shell = Shell::new();
+        let dir = TempDir::new("replaced");
+        let base = dir.to_string_lossy();
+        std::fs::write(dir.join("a"), "old\n").unwrap();
+
+        for command in ["echo hi", "sh -c 'echo hi'"] {
+            run(&mut shell, &format!("{1} > {0}/a > {0}/b", base, command));
+            assert_eq!(std::fs::read_to_string(dir.join("a")).unwrap(), "");
+            assert_eq!(std::fs::read_to_string(dir.join("b")).unwrap(), "hi\n");
+        }
+        run(
+            &mut shell,
+            &format!("sh -c 'echo no >&2' 2> {0}/e1 2> {0}/e2", base),
+        );
+        assert_eq!(std::fs::read_to_string(dir.join("e1")).unwrap(), "");
+        assert_eq!(std::fs::read_to_string(dir.join("e2")).unwrap(), "no\n");
+
+        // A replaced target that cannot be opened fails the command
+        run(
+            &mut shell,
+            &format!("echo hi > {0}/missing/x > {0}/c", base),
+        );
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn test_exec_failures_keep_shell_running() {
+        let mut shell = Shell::new();
+        for (line, status) in [
+            ("exec no_such_command_xyz", 127),
+            ("exec -a", 2),
+            ("exec -q true", 2),
+            ("exec > /no/such/dir/out", 1),
+            ("exec", 0),
+        ] {
+            run(&mut shell, line);
+            assert_eq!(shell.last_status, status, "{}", line);
+        }
+        assert_eq!(run_captured(&mut shell, "echo still here"), "still here\n");
+        // In a pipeline the command runs as the stage, under the name from `-a`
+        assert_eq!(
+            run_captured(&mut shell, "exec -a custom sh -c 'echo $0' | cat"),
+            "custom\n"
+        );
+    }
+
+    #[test]
+    fn test_exec_redirects_the_shell() {
+        let dir = TempDir::new("exec");
+        let path = dir.join("exec.log");
+        let saved_stdout = nix::unistd::dup(std::io::stdout()).unwrap();
+        let saved_stderr = nix::unistd::dup(std::io::stderr()).unwrap();
+        let mut shell = Shell::new();
+        run(&mut shell, &format!("exec > {} 2>&1", path.display()));
+        assert_eq!(shell.last_status, 0);
+        run(&mut shell, "echo exec-out; sh -c 'echo exec-err >&2'");
+        nix::unistd::dup2_stdout(saved_stdout).unwrap();
+        nix::unistd::dup2_stderr(saved_stderr).unwrap();
+
+        // Other tests may write to the same streams meanwhile
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert!(
+            log.contains("exec-out\n") && log.contains("exec-err\n"),
+            "{}",
+            log
+        );
+    }
+
+    #[test]
+    fn test_getopts() {
+        let mut shell = Shell::new();
+        let parse = "OPTIND=1; while getopts :ab:c opt \"$@\"; do echo \"$opt $OPTARG\"; done; echo $OPTIND";
+        run(&mut shell, &format!("f() {{ {}; }}", parse));
+        assert_eq!(
+            run_captured(&mut shell, "f -a -b val -cx -bjoined rest -c"),
+            "a \nb val\nc \n? x\nb joined\n6\n"
+        );
+        assert_eq!(run_captured(&mut shell, "f -a -- -c"), "a \n3\n");
+        assert_eq!(run_captured(&mut shell, "f -b"), ": b\n2\n");
+
+        // Without the leading `:` problems are reported and the letter is `?`
+        run(&mut shell, "OPTIND=1; getopts b: opt -b 2>/dev/null");
+        assert_eq!(shell.last_status, 0);
+        assert_eq!(
+            run_captured(&mut shell, "echo \"$opt\" ${OPTARG:-unset}"),
+            "? unset\n"
+        );
+        run(&mut shell, "getopts b: opt -b");
+        assert_eq!(shell.last_status, 1);
+        run(&mut shell, "getopts b:");
+        assert_eq!(shell.last_status, 2);
+    }
+
+    #[test]
+    fn test_kill_builtin() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run_captured(&mut shell, "kill -l TERM; kill -l sigkill; kill -l 15 143"),
+            "15\n9\nTERM\nTERM\n"
+        );
+        assert!(run_captured(&mut shell, "kill -l").contains(" 9) SIGKILL\n"));
+
+        run(&mut shell, "sh -c 'exit 4' &");
+        assert_eq!(run_captured(&mut shell, "wait $!; echo $?"), "4\n");
+        assert_eq!(
+            run_captured(&mut shell, "wait 1 2>&1; echo $?"),
+            "wait: pid 1 is not a child of this shell\n127\n"
+        );
+        run(&mut shell, "sleep 5 &");
+        run(&mut shell, "kill -s HUP %1; wait");
+        assert_eq!(shell.last_status, 129);
+        run(&mut shell, "sleep 5 &");
+        run(&mut shell, "kill -9 $!; wait");
+        assert_eq!(shell.last_status, 137);
+
+        assert_eq!(
+            run_captured(&mut shell, "kill 999999 %7 abc -BOGUS 2>&1"),
+            "kill: (999999) - No such process\nkill: %7: no such job\n\
+             kill: abc: arguments must be process or job IDs\n\
+             kill: -BOGUS: arguments must be process or job IDs\n"
+        );
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(
+            run_captured(&mut shell, "kill -BOGUS 1 2>&1"),
+            "kill: BOGUS: invalid signal specification\n"
+        );
+        run(&mut shell, "kill");
+        assert_eq!(shell.last_status, 2);
+    }
+
+    #[test]
+    fn test_failed_redirection_skips_command() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut shell = Shell::new();
+        let dir = TempDir::new("denied");
+        let read_only = dir.join("ro");
+        std::fs::create_dir_all(&read_only).unwrap();
+        std::fs::set_permissions(&read_only, std::fs::Permissions::from_mode(0o555)).unwrap();
+        // Root may write anywhere, so aim below a directory that does not exist instead
+        let bad = if nix::unistd::geteuid().is_root() {
+            read_only.join("missing/x")
+        } else {
+            read_only.join("x")
+        };
+        let bad = bad.to_string_lossy();
+        let base = dir.to_string_lossy();
+
+        run(
+            &mut shell,
+            &format!("touch {0}/made > {1} 2> {0}/err", base, bad),
+        );
+        assert_eq!(shell.last_status, 1);
+        assert!(!dir.join("made").exists());
+        // The stderr redirection comes after the failed one, so it is never opened
+        assert!(!dir.join("err").exists());
+        run(&mut shell, &format!("export REDIR_SIDE=1 > {}", bad));
+        assert_eq!(shell.last_status, 1);
+        assert!(shell.var("REDIR_SIDE").is_none());
+        run(&mut shell, &format!("echo hi | read REDIR_SIDE > {}", bad));
+        assert!(shell.var("REDIR_SIDE").is_none());
+
+        std::fs::set_permissions(&read_only, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        // A bad fd fails like a file that cannot be opened, not like a syntax error
+        for input in ["echo hi 2>&5", "echo hi 3> x", "{ echo hi; } 2>&5"] {
+            assert_eq!(run_captured(&mut shell, input), "", "{}", input);
+            assert_eq!(shell.last_status, 1, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_traps() {
+        let mut shell = Shell::new();
+        run(&mut shell, "trap 'echo failed $?' ERR");
+        assert_eq!(
+            run_captured(&mut shell, "false; true; echo $?"),
+            "failed 1\n0\n"
+        );
+        // Conditions are allowed to fail
+        assert_eq!(run_captured(&mut shell, "if false; then :; fi"), "");
+        run(&mut shell, "trap - ERR");
+        assert_eq!(run_captured(&mut shell, "false"), "");
+
+        // The signal's trap runs after a command once the signal has arrived, which
+        // need not be before `sh` exits, so keep running commands until it does
+        let mut output = run_captured(
+            &mut shell,
+            "trap 'echo got usr1' USR1; sh -c \"kill -USR1 $$\"",
+        );
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while output.is_empty() && std::time::Instant::now() < deadline {
+            output = run_captured(&mut shell, ":");
+        }
+        assert_eq!(output, "got usr1\n");
+        assert_eq!(
+            run_captured(&mut shell, "trap \"echo 'bye'\" EXIT; trap -p"),
+            "trap -- 'echo '\\''bye'\\''' EXIT\ntrap -- 'echo got usr1' SIGUSR1\n"
+        );
+        run(&mut shell, "exit 3");
+        let output = capture(&mut shell, crate::trap::run_exit);
+        assert_eq!(output, "bye\n");
+        assert_eq!(shell.exit_code, Some(3));
+
+        run(&mut shell, "trap 'echo x' NOPE");
+        assert_eq!(shell.last_status, 1);
+        run(&mut shell, "trap - USR1");
+    }
+
+    #[test]
+    fn test_directory_stack() {
+        let mut shell = Shell::new();
+        let temp = TempDir::new("dir_stack");
+        for name in ["a", "b", "c"] {
+            std::fs::create_dir_all(temp.join(name)).unwrap();
+        }
+        let dir = temp.canonicalize().unwrap();
+        let base = dir.to_string_lossy();
+
+        with_cwd_restored(|| {
+            run(&mut shell, &format!("cd {}/a", base));
+            assert_eq!(
+                run_captured(&mut shell, "popd 2>&1"),
+                "popd: directory stack empty\n"
+            );
+            assert_eq!(
+                run_captured(&mut shell, &format!("pushd {0}/b; pushd {0}/c", base)),
+                format!("{0}/b {0}/a\n{0}/c {0}/b {0}/a\n", base)
+            );
+            assert_eq!(
+                run_captured(&mut shell, "dirs -v"),
+                format!(" 0  {0}/c\n 1  {0}/b\n 2  {0}/a\n", base)
+            );
+            // Rotating brings the entry to the top and changes to it
+            assert_eq!(
+                run_captured(&mut shell, "pushd +2; pwd"),
+                format!("{0}/a {0}/c {0}/b\n{0}/a\n", base)
+            );
+            assert_eq!(
+                run_captured(&mut shell, "pushd; dirs -1"),
+                format!("{0}/c {0}/a {0}/b\n{0}/a\n", base)
+            );
+            assert_eq!(
+                run_captured(&mut shell, "popd +1"),
+                format!("{0}/c {0}/b\n", base)
+            );
+            assert_eq!(
+                run_captured(&mut shell, "popd; pwd"),
+                format!("{0}/b\n{0}/b\n", base)
+            );
+            assert_eq!(
+                run_captured(&mut shell, "pushd +5 2>&1"),
+                "pushd: +5: directory stack index out of range\n"
+            );
+            run(&mut shell, &format!("pushd {}/a; dirs -c", base));
+            assert_eq!(run_captured(&mut shell, "dirs -p"), format!("{}/a\n", base));
+        });
+    }
+
+    #[test]
+    fn test_read_write_redirection() {
+        let mut shell = Shell::new();
+        let dir = TempDir::new("read_write_exec");
+        let file = dir.join("f").to_string_lossy().into_owned();
+
+        // Created when missing, and read from when it exists
+        assert_eq!(run_captured(&mut shell, &format!("cat <> {}", file)), "");
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "");
+        std::fs::write(&file, "abcdef\n").unwrap();
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                &format!("cat <> {0}; read RW_LINE <> {0}; echo $RW_LINE", file)
+            ),
+            "abcdef\nabcdef\n"
+        );
+        // Written over from the start, by external commands and builtins alike
+        run(&mut shell, &format!("printf xy 1<> {}", file));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "xycdef\n");
+        run(&mut shell, &format!("sh -c 'printf z' 1<> {}", file));
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "zycdef\n");
+    }
+
+    #[test]
+    fn test_non_utf8_filenames() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut shell = Shell::new();
+        let dir = TempDir::new("non_utf8");
+        let raw = dir.join(OsStr::from_bytes(b"d\xFF"));
+        std::fs::create_dir_all(&raw).unwrap();
+        let base = dir.to_string_lossy();
+        let name = encoding::decode(raw.as_os_str());
+
+        // Globs, `$'\xff'` and external commands all name the same directory
+        assert_eq!(
+            run_captured(&mut shell, &format!("echo {}/d*", base)),
+            format!("{}\n", name)
+        );
+        assert_eq!(
+            run_captured(&mut shell, &format!("ls {}", base)),
+            "d\u{10FFFF}\n"
+        );
+        run(&mut shell, &format!("echo hi > {}/d$'\\xff'/f", base));
+        assert_eq!(std::fs::read_to_string(raw.join("f")).unwrap(), "hi\n");
+        assert_eq!(
+            run_captured(&mut shell, &format!("cat {}/d*/f", base)),
+            "hi\n"
+        );
+
+        let pwd = with_cwd_restored(|| run_captured(&mut shell, &format!("cd {}/d*; pwd", base)));
+        assert_eq!(pwd, format!("{}\n", name));
+    }
+
+    #[test]
+    fn test_cd_previous_directory() {
+        let mut shell = Shell::new();
+        let temp = TempDir::new("cd_previous");
+        std::fs::create_dir_all(temp.join("a")).unwrap();
+        std::fs::create_dir_all(temp.join("b")).unwrap();
+        let dir = temp.canonicalize().unwrap();
+        let base = dir.to_string_lossy();
+
+        with_cwd_restored(|| {
+            shell.unset_var("OLDPWD");
+            assert_eq!(
+                run_captured(&mut shell, "cd - 2>&1"),
+                format!("{}: cd: OLDPWD not set\n", shell.name())
+            );
+            assert_eq!(shell.last_status, 1);
+
+            run(&mut shell, &format!("cd {0}/a; cd {0}/b", base));
+            assert_eq!(run_captured(&mut shell, "cd -"), format!("{}/a\n", base));
+            assert_eq!(
+                run_captured(&mut shell, "echo $PWD $OLDPWD"),
+                format!("{0}/a {0}/b\n", base)
+            );
+            assert_eq!(
+                run_captured(&mut shell, "cd -; pwd"),
+                format!("{0}/b\n{0}/b\n", base)
+            );
+        });
+    }
+
+    #[test]
+    fn test_read_builtin() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "read READ_A READ_B <<< 'one two  three'; echo \"$READ_A|$READ_B\""
+            ),
+            "one|two  three\n"
+        );
+        assert_eq!(
+            run_captured(&mut shell, "read <<< 'a\\tb\\\\'; echo \"$REPLY\""),
+            "atb\\\n"
+        );
+        assert_eq!(
+            run_captured(&mut shell, "read -r READ_R <<< 'a\\tb'; echo \"$READ_R\""),
+            "a\\tb\n"
+        );
+        assert_eq!(
+            run_captured(&mut shell, "echo x y | read READ_P; echo $READ_P"),
+            "x y\n"
+        );
+        run(&mut shell, "read READ_A < /dev/null");
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn test_test_builtin_in_conditions() {
+        let mut shell = Shell::new();
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "if [ -d / -a \"\" = '' ]; then echo yes; fi; if test 2 -lt 1; then echo no; fi"
+            ),
+            "yes\n"
+        );
+        run(&mut shell, "[ -n x");
+        assert_eq!(shell.last_status, 2);
+        run(&mut shell, "test -e /nonexistent");
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn test_conditional_command() {
+        let mut shell = Shell::new();
+        shell.set_var("COND_VALUE", "a b");
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "if [[ $COND_VALUE == a* && $COND_VALUE != \"a*\" ]]; then echo glob; fi"
+            ),
+            "glob\n"
+        );
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "if [[ v12 =~ ^v([0-9])+$ ]]; then echo $BASH_REMATCH; fi; [[ x.y =~ x\".\"z ]]"
+            ),
+            "v12\n"
+        );
+        assert_eq!(shell.last_status, 1);
+    }
+
+    #[test]
+    fn test_arrays() {
+        let mut shell = Shell::new();
+        run(&mut shell, "ARRAY_T=(a 'b c'); ARRAY_T+=(d)");
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "echo ${ARRAY_T[1]} ${#ARRAY_T[@]} $ARRAY_T; for x in \"${ARRAY_T[@]}\"; do echo \"<$x>\"; done"
+            ),
+            "b c 3 a\n<a>\n<b c>\n<d>\n"
+        );
+        assert!(shell.var("ARRAY_T").is_none());
+        run(&mut shell, "ARRAY_T=z");
+        assert_eq!(run_captured(&mut shell, "echo ${ARRAY_T[@]}"), "z b c d\n");
+
+        // `"$@"` keeps each argument a word of its own, and nothing at all without any
+        run(&mut shell, "count() { echo $#; }; args() { count \"$@\"; }");
+        assert_eq!(run_captured(&mut shell, "args 'a b' c; args"), "2\n0\n");
+
+        // Elements can be assigned and unset one at a time, leaving gaps
+        run(
+            &mut shell,
+            "ARRAY_E=(a b c); ARRAY_E[5]=f; ARRAY_E[1]+=2; i=0; ARRAY_E[i-1]=last",
+        );
+        assert_eq!(
+            run_captured(
+                &mut shell,
+                "echo ${ARRAY_E[@]} ${#ARRAY_E[@]} ${ARRAY_E[4]-unset}; declare -p ARRAY_E"
+            ),
+            "a b2 c last 4 unset\ndeclare -a ARRAY_E=([0]=\"a\" [1]=\"b2\" [2]=\"c\" [5]=\"last\")\n"
+        );
+        run(&mut shell, "unset 'ARRAY_E[1]' 'ARRAY_E[-1]'; ARRAY_E+=(g)");
+        assert_eq!(
+            run_captured(&mut shell, "echo ${ARRAY_E[@]} ${ARRAY_E[3]}"),
+            "a c g g\n"
+        );
+        run(&mut shell, "ARRAY_S=x; ARRAY_S[2]=z; ARRAY_E[-9]=no");
+        assert_eq!(shell.last_status, 1);
+        assert_eq!(run_captured(&mut shell, "echo ${ARRAY_S[@]}"), "x z\n");
+
+        run(&mut shell, "[[ v12 =~ ^v([0-9])([0-9]) ]]");
+        assert_eq!(
+            run_captured(&mut shell, "echo ${BASH_REMATCH[@]}"),
+            "v12 1 2\n"
+        );
+    }
+}
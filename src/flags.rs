@@ -0,0 +1,39 @@
+/// Defines a set of flags kept as the bits of a `u8`, such as `ShellOptions` and
+/// `Attributes`. Each `const NAME = bit;` becomes an associated constant, and the type
+/// gets `contains` and `set`.
+macro_rules! bit_flags {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$flag_meta:meta])*
+                const $flag:ident = $bit:expr;
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+        $vis struct $name(u8);
+
+        impl $name {
+            $(
+                $(#[$flag_meta])*
+                pub const $flag: $name = $name(1 << $bit);
+            )*
+
+            pub fn contains(self, flags: $name) -> bool {
+                self.0 & flags.0 == flags.0
+            }
+
+            pub fn set(&mut self, flags: $name, enabled: bool) {
+                if enabled {
+                    self.0 |= flags.0;
+                } else {
+                    self.0 &= !flags.0;
+                }
+            }
+        }
+    };
+}
+
+pub(crate) use bit_flags;
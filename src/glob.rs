@@ -0,0 +1,273 @@
+use crate::encoding;
+use crate::tokenize::{Token, TokenKind};
+use std::fs;
+
+/// Replaces every word that has a glob pattern with the filenames it matches.
+/// Words whose pattern matches nothing are left as they are.
+pub fn expand_globs(tokens: Vec<Token>) -> Vec<Token> {
+    let mut expanded = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        let Some(pattern) = &token.glob else {
+            expanded.push(token);
+            continue;
+        };
+        expanded.extend(glob_expand(pattern).into_iter().map(|path| Token {
+            kind: TokenKind::Word(path.into()),
+            span: token.span.clone(),
+            glob: None,
+            quoted: token.quoted,
+        }));
+    }
+    expanded
+}
+
+/// Expands a filename pattern using `*`, `?` and `[...]`, plus `**` for any number
+/// of directories, to the sorted list of matching paths. A backslash makes the next
+/// character literal. Returns the pattern itself (without escapes) if nothing matches.
+pub fn glob_expand(pattern: &str) -> Vec<String> {
+    let (prefix, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => ("/", rest),
+        None => ("", pattern),
+    };
+    let components: Vec<&str> = rest.split('/').collect();
+
+    let mut matches = Vec::new();
+    expand_components(prefix, &components, &mut matches);
+    matches.retain(|path| !path.is_empty());
+    matches.sort();
+    matches.dedup();
+
+    if matches.is_empty() {
+        vec![unescape(pattern)]
+    } else {
+        matches
+    }
+}
+
+/// Matches `components` one directory level at a time below `prefix`.
+fn expand_components(prefix: &str, components: &[&str], matches: &mut Vec<String>) {
+    let Some((&component, rest)) = components.split_first() else {
+        matches.push(prefix.to_string());
+        return;
+    };
+
+    if !has_glob(component) {
+        let path = join(prefix, &unescape(component));
+        if encoding::path(&path).exists() || path.is_empty() {
+            expand_components(&path, rest, matches);
+        }
+        return;
+    }
+
+    let dir = if prefix.is_empty() { "." } else { prefix };
+    let Ok(entries) = fs::read_dir(encoding::path(dir)) else {
+        return;
+    };
+    let mut names: Vec<String> = entries
+        .flatten()
+        .map(|entry| encoding::decode(&entry.file_name()))
+        .collect();
+    names.sort();
+
+    if component == "**" {
+        // `**` matches zero directories here, or descends into each subdirectory
+        expand_components(prefix, rest, matches);
+        for name in names.iter().filter(|name| !name.starts_with('.')) {
+            let path = join(prefix, name);
+            if encoding::path(&path).is_dir() {
+                expand_components(&path, components, matches);
+            } else if rest.is_empty() {
+                matches.push(path);
+            }
+        }
+        return;
+    }
+
+    let pattern: Vec<char> = component.chars().collect();
+    for name in names {
+        // Hidden files only match a pattern that starts with a literal dot
+        if name.starts_with('.') && !component.starts_with('.') {
+            continue;
+        }
+        let chars: Vec<char> = name.chars().collect();
+        if matches_pattern(&pattern, &chars) {
+            let path = join(prefix, &name);
+            if rest.is_empty() || encoding::path(&path).is_dir() {
+                expand_components(&path, rest, matches);
+            }
+        }
+    }
+}
+
+/// Matches a whole word against a pattern such as a `case` pattern, where `/` is
+/// an ordinary character.
+pub fn match_pattern(pattern: &str, word: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let word: Vec<char> = word.chars().collect();
+    matches_pattern(&pattern, &word)
+}
+
+/// Backslash-escapes the glob characters in `text`, so it matches only itself.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn join(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() || prefix.ends_with('/') {
+        format!("{}{}", prefix, name)
+    } else {
+        format!("{}/{}", prefix, name)
+    }
+}
+
+/// Returns true if `component` contains an unescaped `*`, `?` or `[`.
+fn has_glob(component: &str) -> bool {
+    let mut chars = component.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn unescape(pattern: &str) -> String {
+    let mut text = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.extend(chars.next()),
+            c => text.push(c),
+        }
+    }
+    text
+}
+
+/// Matches a whole file name against a single-component pattern.
+fn matches_pattern(pattern: &[char], name: &[char]) -> bool {
+    match pattern {
+        [] => name.is_empty(),
+        ['*', rest @ ..] => (0..=name.len()).any(|skip| matches_pattern(rest, &name[skip..])),
+        ['?', rest @ ..] => !name.is_empty() && matches_pattern(rest, &name[1..]),
+        ['[', rest @ ..] => match split_class(rest) {
+            Some((class, after)) => {
+                name.first().is_some_and(|&c| class_matches(class, c))
+                    && matches_pattern(after, &name[1..])
+            }
+            None => name.first() == Some(&'[') && matches_pattern(rest, &name[1..]),
+        },
+        ['\\', c, rest @ ..] | [c, rest @ ..] => {
+            name.first() == Some(c) && matches_pattern(rest, &name[1..])
+        }
+    }
+}
+
+/// Splits the body of a `[...]` class from the rest of the pattern. A `]` right after
+/// the opening bracket (or after `!`/`^`) is part of the class. Returns `None` if the
+/// class is never closed, in which case the `[` is literal.
+fn split_class(pattern: &[char]) -> Option<(&[char], &[char])> {
+    let skip = match pattern {
+        ['!' | '^', ']', ..] => 2,
+        ['!' | '^', ..] | [']', ..] => 1,
+        _ => 0,
+    };
+    let close = skip + pattern[skip..].iter().position(|&c| c == ']')?;
+    Some((&pattern[..close], &pattern[close + 1..]))
+}
+
+fn class_matches(class: &[char], c: char) -> bool {
+    let (negated, mut class) = match class {
+        ['!' | '^', rest @ ..] => (true, rest),
+        _ => (false, class),
+    };
+    let mut found = false;
+    while let Some((&first, rest)) = class.split_first() {
+        match rest {
+            ['-', last, after @ ..] => {
+                found |= (first..=*last).contains(&c);
+                class = after;
+            }
+            _ => {
+                found |= first == c;
+                class = rest;
+            }
+        }
+    }
+    found != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_pattern_matching() {
+        assert!(match_pattern("*.rs", "main.rs"));
+        assert!(!match_pattern("*.rs", "main.rsx"));
+        assert!(match_pattern("?at", "cat"));
+        assert!(match_pattern("[a-c]at", "bat"));
+        assert!(!match_pattern("[!a-c]at", "bat"));
+        assert!(match_pattern("[]x]", "]"));
+        assert!(match_pattern("a\\*", "a*"));
+        assert!(!match_pattern("a\\*", "ab"));
+        assert!(match_pattern("[unclosed", "[unclosed"));
+        assert!(match_pattern("*/*", "src/main.rs"));
+        assert!(match_pattern(&escape("a*[b]\\"), "a*[b]\\"));
+        assert!(!match_pattern(&escape("a*"), "ab"));
+    }
+
+    #[test]
+    fn test_glob_expand_in_directory() {
+        let dir = TempDir::new("glob");
+        fs::create_dir_all(dir.join("sub/deep")).unwrap();
+        for file in [
+            "b.txt",
+            "a.txt",
+            ".hidden.txt",
+            "c.md",
+            "sub/d.txt",
+            "sub/deep/e.txt",
+        ] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let base = dir.to_string_lossy();
+
+        assert_eq!(
+            glob_expand(&format!("{}/*.txt", base)),
+            vec![format!("{}/a.txt", base), format!("{}/b.txt", base)]
+        );
+        assert_eq!(
+            glob_expand(&format!("{}/*/*.txt", base)),
+            vec![format!("{}/sub/d.txt", base)]
+        );
+        assert_eq!(
+            glob_expand(&format!("{}/**/*.txt", base)),
+            vec![
+                format!("{}/a.txt", base),
+                format!("{}/b.txt", base),
+                format!("{}/sub/d.txt", base),
+                format!("{}/sub/deep/e.txt", base),
+            ]
+        );
+        assert_eq!(
+            glob_expand(&format!("{}/*.none", base)),
+            vec![format!("{}/*.none", base)]
+        );
+        assert_eq!(
+            glob_expand(&format!("{}/\\*.txt", base)),
+            vec![format!("{}/*.txt", base)]
+        );
+    }
+}
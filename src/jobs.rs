@@ -0,0 +1,519 @@
+use nix::errno::Errno;
+use nix::sys::signal::{SigHandler, SigSet, Signal, kill, signal};
+use nix::sys::wait::{WaitPidFlag, WaitStatus, waitpid};
+use nix::unistd::{Pid, getpgrp, setpgid, tcsetpgrp};
+
+/// Signals the terminal sends to its foreground process group, which a shell with job
+/// control ignores so that they only reach the job running in the foreground.
+const TERMINAL_SIGNALS: [Signal; 4] = [
+    Signal::SIGINT,
+    Signal::SIGTSTP,
+    Signal::SIGTTIN,
+    Signal::SIGTTOU,
+];
+
+/// What a job was doing when the shell last checked on it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobState {
+    Running,
+    Stopped,
+    /// Finished with the given exit status.
+    Done(i32),
+}
+
+/// A pipeline started in the background with `&`, which may be a single command.
+#[derive(Debug)]
+pub struct Job {
+    pub id: usize,
+    /// The job's processes in pipeline order, with the status of each once it has
+    /// finished. The first leads the process group they all belong to.
+    processes: Vec<(u32, Option<i32>)>,
+    pub state: JobState,
+    /// Command line as typed, without the trailing `&`.
+    pub command: String,
+}
+
+impl Job {
+    /// Process id of the job's last process, whose status is the job's, as `$!` holds it.
+    pub fn pid(&self) -> u32 {
+        self.processes.last().map_or(0, |(pid, _)| *pid)
+    }
+
+    fn process_group(&self) -> Pid {
+        Pid::from_raw(self.processes[0].0 as i32)
+    }
+
+    /// Polls the job without blocking and records any change of state.
+    fn refresh(&mut self) {
+        if matches!(self.state, JobState::Done(_)) {
+            return;
+        }
+        let flags = WaitPidFlag::WNOHANG | WaitPidFlag::WUNTRACED | WaitPidFlag::WCONTINUED;
+        for index in 0..self.processes.len() {
+            let (pid, status) = self.processes[index];
+            if status.is_some() {
+                continue;
+            }
+            match waitpid(Pid::from_raw(pid as i32), Some(flags)) {
+                Ok(status) => self.update(index, status),
+                Err(_) => self.finish(index, 127),
+            }
+        }
+    }
+
+    /// Waits for every process of the job to exit, or for one to stop, returning the
+    /// shell status for what happened.
+    fn wait(&mut self, flags: Option<WaitPidFlag>) -> i32 {
+        for index in 0..self.processes.len() {
+            while self.processes[index].1.is_none() {
+                match waitpid(Pid::from_raw(self.processes[index].0 as i32), flags) {
+                    Ok(WaitStatus::Stopped(_, signal)) => {
+                        self.state = JobState::Stopped;
+                        return 128 + signal as i32;
+                    }
+                    Ok(status) => self.update(index, status),
+                    Err(Errno::EINTR) => {}
+                    Err(_) => self.finish(index, 127),
+                }
+            }
+        }
+        match self.state {
+            JobState::Done(code) => code,
+            _ => 127,
+        }
+    }
+
+    /// Records what `waitpid` reported for the process at `index`.
+    fn update(&mut self, index: usize, status: WaitStatus) {
+        match status {
+            WaitStatus::Exited(_, code) => self.finish(index, code),
+            WaitStatus::Signaled(_, signal, _) => self.finish(index, 128 + signal as i32),
+            WaitStatus::Stopped(..) => self.state = JobState::Stopped,
+            WaitStatus::Continued(_) => self.state = JobState::Running,
+            _ => {}
+        }
+    }
+
+    /// Records that the process at `index` finished with `code`. Once all of them have,
+    /// the job is done with the status of the last.
+    fn finish(&mut self, index: usize, code: i32) {
+        self.processes[index].1 = Some(code);
+        if let [.., (_, Some(last))] = self.processes[..]
+            && self.processes.iter().all(|(_, status)| status.is_some())
+        {
+            self.state = JobState::Done(last);
+        }
+    }
+
+    /// Hands the terminal to the job, continuing it first if it is stopped, and waits
+    /// until it finishes or stops again before taking the terminal back. A job that
+    /// stopped is reported. Returns the shell status for what happened.
+    fn wait_in_foreground(&mut self) -> i32 {
+        give_terminal(self.process_group());
+        if self.state == JobState::Stopped {
+            let _ = kill(
+                Pid::from_raw(-self.process_group().as_raw()),
+                Signal::SIGCONT,
+            );
+            self.state = JobState::Running;
+        }
+        let status = self.wait(Some(WaitPidFlag::WUNTRACED));
+        give_terminal(getpgrp());
+
+        if self.state == JobState::Stopped {
+            println!(
+                "\n[{}]+  {:<24}{}",
+                self.id,
+                self.describe_state(),
+                self.command
+            );
+        }
+        status
+    }
+
+    fn describe_state(&self) -> String {
+        match self.state {
+            JobState::Running => "Running".to_string(),
+            JobState::Stopped => "Stopped".to_string(),
+            JobState::Done(0) => "Done".to_string(),
+            JobState::Done(code) => format!("Exit {}", code),
+        }
+    }
+}
+
+/// Background jobs started by the shell, in the order they were started.
+#[derive(Debug, Default)]
+pub struct JobTable {
+    jobs: Vec<Job>,
+}
+
+impl JobTable {
+    /// Records the processes of a newly started background pipeline as one job,
+    /// assigning it the next free job number. They must all have been put in the
+    /// process group of the first.
+    pub fn add(&mut self, pids: &[u32], command: String) -> &Job {
+        let job = self.new_job(pids, command);
+        self.jobs.push(job);
+        self.jobs.last().unwrap()
+    }
+
+    /// Waits for a pipeline started in the foreground, with its processes in the
+    /// process group of the first, handing it the terminal while it runs. If it stops
+    /// it is added to the table, where `fg` and `bg` can continue it. Returns the
+    /// status of its last process, or 128 + the signal that stopped it.
+    pub fn wait_foreground(&mut self, pids: &[u32], command: String) -> i32 {
+        let mut job = self.new_job(pids, command);
+        let status = job.wait_in_foreground();
+        if job.state == JobState::Stopped {
+            self.jobs.push(job);
+        }
+        status
+    }
+
+    /// A running job of the processes `pids`, with the next free job number.
+    fn new_job(&self, pids: &[u32], command: String) -> Job {
+        Job {
+            id: self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1,
+            processes: pids.iter().map(|&pid| (pid, None)).collect(),
+            state: JobState::Running,
+            command,
+        }
+    }
+
+    /// Renders the table in the format of `jobs`, then forgets jobs that have finished.
+    /// With `pids_only` only process ids are listed; `long` adds them to each line.
+    pub fn list(&mut self, pids_only: bool, long: bool) -> String {
+        let mut output = String::new();
+        for index in 0..self.jobs.len() {
+            let marker = self.marker(index);
+            let job = &mut self.jobs[index];
+            job.refresh();
+
+            if pids_only {
+                output.push_str(&format!("{}\n", job.process_group()));
+                continue;
+            }
+            let pid = if long {
+                format!("{} ", job.process_group())
+            } else {
+                " ".to_string()
+            };
+            output.push_str(&format!(
+                "[{}]{} {}{:<24}{} &\n",
+                job.id,
+                marker,
+                pid,
+                job.describe_state(),
+                job.command
+            ));
+        }
+
+        self.jobs
+            .retain(|job| !matches!(job.state, JobState::Done(_)));
+        output
+    }
+
+    /// Checks on every job without waiting, and forgets the ones that have finished.
+    /// Returns a notice for each of those, as the shell prints before its next prompt.
+    pub fn reap(&mut self) -> String {
+        let mut output = String::new();
+        for index in 0..self.jobs.len() {
+            let marker = self.marker(index);
+            let job = &mut self.jobs[index];
+            job.refresh();
+            if matches!(job.state, JobState::Done(_)) {
+                output.push_str(&format!(
+                    "[{}]{}  {:<24}{}\n",
+                    job.id,
+                    marker,
+                    job.describe_state(),
+                    job.command
+                ));
+            }
+        }
+
+        self.jobs
+            .retain(|job| !matches!(job.state, JobState::Done(_)));
+        output
+    }
+
+    /// Continues the job named by `spec` in the foreground, handing it the terminal,
+    /// and returns its exit status once it finishes or stops again.
+    pub fn foreground(&mut self, spec: Option<&str>) -> Result<i32, String> {
+        let index = self.find_live(spec)?;
+        let job = &mut self.jobs[index];
+        println!("{}", job.command);
+        let status = job.wait_in_foreground();
+        if job.state != JobState::Stopped {
+            self.jobs.remove(index);
+        }
+        Ok(status)
+    }
+
+    /// Resumes the stopped job named by `spec` in the background.
+    pub fn background(&mut self, spec: Option<&str>) -> Result<String, String> {
+        let index = self.find_live(spec)?;
+        let marker = self.marker(index);
+        let job = &mut self.jobs[index];
+        if job.state == JobState::Running {
+            return Err(format!("job {} already in background", job.id));
+        }
+
+        kill(
+            Pid::from_raw(-job.process_group().as_raw()),
+            Signal::SIGCONT,
+        )
+        .map_err(|e| e.desc().to_string())?;
+        job.state = JobState::Running;
+        Ok(format!("[{}]{} {} &\n", job.id, marker, job.command))
+    }
+
+    /// Sends `signal` to every process of the job named by `spec`, or only checks that
+    /// the job is alive when `signal` is `None`. A stopped job is continued so that
+    /// it can act on the signal.
+    pub fn signal(&mut self, spec: &str, signal: Option<Signal>) -> Result<(), String> {
+        let index = self.find_live(Some(spec))?;
+        let job = &mut self.jobs[index];
+        let group = Pid::from_raw(-job.process_group().as_raw());
+        kill(group, signal).map_err(|e| e.desc().to_string())?;
+        if job.state == JobState::Stopped
+            && matches!(signal, Some(Signal::SIGTERM | Signal::SIGHUP))
+        {
+            let _ = kill(group, Signal::SIGCONT);
+        }
+        Ok(())
+    }
+
+    /// Blocks until every job has finished, emptying the table.
+    /// Returns the exit status of the last job, or 0 if there were none.
+    pub fn wait_all(&mut self) -> i32 {
+        self.jobs.drain(..).fold(0, |_, mut job| match job.state {
+            JobState::Done(code) => code,
+            _ => job.wait(None),
+        })
+    }
+
+    /// Blocks until the job named by `target` finishes, removing it from the table, and
+    /// returns its exit status. `target` is a job spec such as `%1`, or a process id.
+    pub fn wait_for(&mut self, target: &str) -> Result<i32, String> {
+        let index = if target.starts_with('%') {
+            self.find(target)
+                .ok_or_else(|| format!("{}: no such job", target))?
+        } else {
+            let pid: u32 = target
+                .parse()
+                .map_err(|_| format!("`{}': not a pid or valid job spec", target))?;
+            self.jobs
+                .iter()
+                .position(|job| job.processes.iter().any(|(process, _)| *process == pid))
+                .ok_or_else(|| format!("pid {} is not a child of this shell", pid))?
+        };
+        let mut job = self.jobs.remove(index);
+        Ok(match job.state {
+            JobState::Done(code) => code,
+            _ => job.wait(None),
+        })
+    }
+
+    /// `+` marks the current (most recent) job and `-` the one before it.
+    fn marker(&self, index: usize) -> char {
+        match self.jobs.len() - index {
+            1 => '+',
+            2 => '-',
+            _ => ' ',
+        }
+    }
+
+    /// Resolves a job spec (`%N`, `%+`, `%%`, `%-` or a bare number, defaulting
+    /// to the current job) to a job that has not yet finished.
+    fn find_live(&mut self, spec: Option<&str>) -> Result<usize, String> {
+        let spec = spec.unwrap_or("%+");
+        let Some(index) = self.find(spec) else {
+            let name = if spec == "%+" { "current" } else { spec };
+            return Err(format!("{}: no such job", name));
+        };
+
+        self.jobs[index].refresh();
+        if let JobState::Done(_) = self.jobs[index].state {
+            self.jobs.remove(index);
+            return Err("job has terminated".to_string());
+        }
+        Ok(index)
+    }
+
+    /// The index of the job a job spec names, finished or not.
+    fn find(&self, spec: &str) -> Option<usize> {
+        match spec.strip_prefix('%').unwrap_or(spec) {
+            "" | "+" | "%" => self.jobs.len().checked_sub(1),
+            "-" => self.jobs.len().checked_sub(2),
+            n => n
+                .parse::<usize>()
+                .ok()
+                .and_then(|id| self.jobs.iter().position(|job| job.id == id)),
+        }
+    }
+}
+
+/// Turns on job control: the shell takes a process group of its own, which it makes
+/// the terminal's foreground group, and ignores the signals of the terminal.
+pub fn start_job_control() {
+    let _ = setpgid(Pid::from_raw(0), Pid::from_raw(0));
+    give_terminal(getpgrp());
+    for terminal_signal in TERMINAL_SIGNALS {
+        // SAFETY: ignoring a signal installs no handler code.
+        let _ = unsafe { signal(terminal_signal, SigHandler::SigIgn) };
+    }
+}
+
+/// Gives the signals of the terminal their default action back, in a child of a shell
+/// with job control that is about to run a command. An ignored signal would stay
+/// ignored across `exec`. Safe to call between `fork` and `exec`.
+pub fn restore_terminal_signals() {
+    for terminal_signal in TERMINAL_SIGNALS {
+        // SAFETY: restoring the default action installs no handler code.
+        let _ = unsafe { signal(terminal_signal, SigHandler::SigDfl) };
+    }
+}
+
+/// Makes `group` the terminal's foreground process group, if the shell has a terminal.
+fn give_terminal(group: Pid) {
+    // Taking the terminal back from a background group raises SIGTTOU unless it is blocked
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGTTOU);
+    let _ = mask.thread_block();
+    let _ = tcsetpgrp(std::io::stdin(), group);
+    let _ = mask.thread_unblock();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    fn spawn(program: &str, args: &[&str]) -> u32 {
+        Command::new(program)
+            .args(args)
+            .process_group(0)
+            .spawn()
+            .unwrap()
+            .id()
+    }
+
+    #[test]
+    fn test_wait_for_job_or_pid() {
+        let mut jobs = JobTable::default();
+        jobs.add(&[spawn("sh", &["-c", "exit 3"])], "a".to_string());
+        let pid = jobs
+            .add(
+                &[spawn("sh", &["-c", "sleep 0.1; exit 5"])],
+                "b".to_string(),
+            )
+            .pid();
+
+        assert_eq!(jobs.wait_for("%1"), Ok(3));
+        assert_eq!(jobs.wait_for(&pid.to_string()), Ok(5));
+        assert_eq!(jobs.wait_for("%1"), Err("%1: no such job".to_string()));
+        assert_eq!(
+            jobs.wait_for("1"),
+            Err("pid 1 is not a child of this shell".to_string())
+        );
+    }
+
+    #[test]
+    fn test_pipeline_job_ends_with_its_last_process() {
+        let mut jobs = JobTable::default();
+        let first = spawn("sh", &["-c", "sleep 0.2; exit 2"]);
+        let last = Command::new("sh")
+            .args(["-c", "exit 7"])
+            .process_group(first as i32)
+            .spawn()
+            .unwrap()
+            .id();
+        assert_eq!(jobs.add(&[first, last], "a | b".to_string()).pid(), last);
+        // Either process names the job, which is done once both are
+        assert_eq!(jobs.wait_for(&first.to_string()), Ok(7));
+        assert_eq!(jobs.list(false, false), "");
+    }
+
+    #[test]
+    fn test_job_numbers_and_wait() {
+        let mut jobs = JobTable::default();
+        let first = spawn("sh", &["-c", "exit 0"]);
+        let second = spawn("sh", &["-c", "exit 3"]);
+        assert_eq!(jobs.add(&[first], "first".to_string()).id, 1);
+        assert_eq!(jobs.add(&[second], "second".to_string()).id, 2);
+        assert_eq!(jobs.wait_all(), 3);
+        assert_eq!(jobs.wait_all(), 0);
+    }
+
+    #[test]
+    fn test_list_reports_and_forgets_finished_jobs() {
+        let mut jobs = JobTable::default();
+        jobs.add(
+            &[spawn("sh", &["-c", "exit 2"])],
+            "sh -c 'exit 2'".to_string(),
+        );
+        let pid = jobs
+            .add(&[spawn("sleep", &["5"])], "sleep 5".to_string())
+            .pid();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+
+        assert_eq!(
+            jobs.list(false, false),
+            format!(
+                "[1]-  {:<24}sh -c 'exit 2' &\n[2]+  {:<24}sleep 5 &\n",
+                "Exit 2", "Running"
+            )
+        );
+        assert_eq!(jobs.list(true, false), format!("{}\n", pid));
+        assert_eq!(
+            jobs.list(false, true),
+            format!("[2]+ {} {:<24}sleep 5 &\n", pid, "Running")
+        );
+        let _ = kill(Pid::from_raw(pid as i32), Signal::SIGKILL);
+        assert_eq!(jobs.wait_all(), 128 + Signal::SIGKILL as i32);
+    }
+
+    #[test]
+    fn test_reap_reports_finished_jobs_once() {
+        let mut jobs = JobTable::default();
+        jobs.add(&[spawn("sleep", &["0.1"])], "sleep 0.1".to_string());
+        assert_eq!(jobs.reap(), "");
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        let mut notice = String::new();
+        while notice.is_empty() && std::time::Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            notice = jobs.reap();
+        }
+        assert_eq!(notice, format!("[1]+  {:<24}sleep 0.1\n", "Done"));
+        assert_eq!(jobs.reap(), "");
+        assert_eq!(jobs.list(false, false), "");
+    }
+
+    #[test]
+    fn test_bg_and_fg_resume_stopped_job() {
+        let mut jobs = JobTable::default();
+        let child = spawn("sh", &["-c", "sleep 0.5; exit 4"]);
+        let pid = jobs.add(&[child], "sh".to_string()).pid();
+        let _ = kill(Pid::from_raw(-(pid as i32)), Signal::SIGSTOP);
+        jobs.jobs[0].wait(Some(WaitPidFlag::WUNTRACED));
+        assert_eq!(jobs.jobs[0].state, JobState::Stopped);
+
+        assert_eq!(jobs.background(Some("%1")), Ok("[1]+ sh &\n".to_string()));
+        assert_eq!(
+            jobs.background(None),
+            Err("job 1 already in background".to_string())
+        );
+        assert_eq!(
+            jobs.foreground(Some("%2")),
+            Err("%2: no such job".to_string())
+        );
+        assert_eq!(jobs.foreground(None), Ok(4));
+        assert_eq!(
+            jobs.foreground(None),
+            Err("current: no such job".to_string())
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use crate::flags::bit_flags;
+
+bit_flags! {
+    /// Shell options turned on with `set -e`, `-x`, `-u`, `-f` and `-C`, one bit each.
+    pub struct ShellOptions {
+        /// `-e`: exit as soon as a command fails outside a condition.
+        const ERREXIT = 0;
+        /// `-x`: print each command to stderr before running it.
+        const XTRACE = 1;
+        /// `-u`: expanding an unset variable is an error.
+        const NOUNSET = 2;
+        /// `-f`: no filename expansion.
+        const NOGLOB = 3;
+        /// `-C`: `>` refuses to overwrite existing files, though `>|` still can.
+        const NOCLOBBER = 4;
+    }
+}
+
+impl ShellOptions {
+    /// Each option with its `set` flag letter and its `set -o` name.
+    const ALL: [(ShellOptions, char, &'static str); 5] = [
+        (Self::ERREXIT, 'e', "errexit"),
+        (Self::XTRACE, 'x', "xtrace"),
+        (Self::NOUNSET, 'u', "nounset"),
+        (Self::NOGLOB, 'f', "noglob"),
+        (Self::NOCLOBBER, 'C', "noclobber"),
+    ];
+
+    /// Looks up the option for a flag letter, as in `set -e`.
+    pub fn from_flag(flag: char) -> Option<ShellOptions> {
+        Self::ALL
+            .iter()
+            .find(|(_, c, _)| *c == flag)
+            .map(|(option, _, _)| *option)
+    }
+
+    /// Looks up the option for a long name, as in `set -o errexit`.
+    pub fn from_name(name: &str) -> Option<ShellOptions> {
+        Self::ALL
+            .iter()
+            .find(|(_, _, n)| *n == name)
+            .map(|(option, _, _)| *option)
+    }
+
+    /// The flag letters of the options that are on, as reported by `$-`.
+    pub fn flags(self) -> String {
+        Self::ALL
+            .iter()
+            .filter(|(option, _, _)| self.contains(*option))
+            .map(|(_, c, _)| *c)
+            .collect()
+    }
+
+    /// Lists every option with whether it is on, in the format of `set -o`.
+    pub fn describe(self) -> String {
+        Self::ALL
+            .iter()
+            .map(|(option, _, name)| {
+                let state = if self.contains(*option) { "on" } else { "off" };
+                format!("{:<15}\t{}\n", name, state)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_clear() {
+        let mut options = ShellOptions::default();
+        options.set(ShellOptions::ERREXIT, true);
+        options.set(ShellOptions::NOGLOB, true);
+        assert!(options.contains(ShellOptions::ERREXIT));
+        assert!(!options.contains(ShellOptions::XTRACE));
+        assert_eq!(options.flags(), "ef");
+
+        options.set(ShellOptions::ERREXIT, false);
+        assert!(!options.contains(ShellOptions::ERREXIT));
+        assert_eq!(options.flags(), "f");
+    }
+
+    #[test]
+    fn test_lookup_by_flag_and_name() {
+        assert_eq!(ShellOptions::from_flag('u'), Some(ShellOptions::NOUNSET));
+        assert_eq!(ShellOptions::from_flag('C'), Some(ShellOptions::NOCLOBBER));
+        assert_eq!(ShellOptions::from_flag('q'), None);
+        assert_eq!(
+            ShellOptions::from_name("xtrace"),
+            Some(ShellOptions::XTRACE)
+        );
+        assert_eq!(ShellOptions::from_name("bogus"), None);
+        assert!(
+            ShellOptions::default()
+                .describe()
+                .starts_with("errexit        \toff\n")
+        );
+    }
+}
@@ -0,0 +1,500 @@
+use crate::encoding;
+
+/// Formats `args` according to `format` as the `printf` builtin does. The format is
+/// reused until every argument has been consumed; conversions with no argument left
+/// see an empty string or zero. Arguments that are not valid numbers are reported in
+/// the returned errors and count as zero, like in other shells.
+pub fn format(format: &str, args: &[String]) -> Result<(String, Vec<String>), String> {
+    let mut printer = Printer {
+        args,
+        next: 0,
+        output: String::new(),
+        errors: Vec::new(),
+    };
+    loop {
+        let start = printer.next;
+        if printer.run(format)? == Flow::Stop {
+            break;
+        }
+        if printer.next >= args.len() || printer.next == start {
+            break;
+        }
+    }
+    Ok((printer.output, printer.errors))
+}
+
+#[derive(Debug, PartialEq)]
+enum Flow {
+    Continue,
+    /// `\c` in a `%b` argument ends all output.
+    Stop,
+}
+
+struct Printer<'a> {
+    args: &'a [String],
+    next: usize,
+    output: String,
+    errors: Vec<String>,
+}
+
+/// The flags, width and precision of one conversion, as in `%-8.3s`.
+#[derive(Debug, Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl Printer<'_> {
+    /// Writes `format` once, consuming arguments for its conversions.
+    fn run(&mut self, format: &str) -> Result<Flow, String> {
+        let mut chars = format.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    let rest: String = chars.clone().collect();
+                    let (text, used, _) = unescape_one(&rest, false);
+                    self.output.push_str(&text);
+                    for _ in 0..used {
+                        chars.next();
+                    }
+                }
+                '%' if chars.peek() == Some(&'%') => {
+                    chars.next();
+                    self.output.push('%');
+                }
+                '%' => {
+                    let mut spec = Spec::default();
+                    while let Some(&flag) = chars.peek() {
+                        match flag {
+                            '-' => spec.left = true,
+                            '+' => spec.plus = true,
+                            ' ' => spec.space = true,
+                            '#' => spec.alternate = true,
+                            '0' => spec.zero = true,
+                            _ => break,
+                        }
+                        chars.next();
+                    }
+                    if chars.next_if_eq(&'*').is_some() {
+                        let width = self.integer_arg();
+                        spec.left |= width < 0;
+                        spec.width = in_range(&width.unsigned_abs().to_string())?;
+                    } else {
+                        spec.width = digits(&mut chars)?;
+                    }
+                    if chars.next_if_eq(&'.').is_some() {
+                        spec.precision = Some(if chars.next_if_eq(&'*').is_some() {
+                            in_range(&self.integer_arg().max(0).to_string())?
+                        } else {
+                            digits(&mut chars)?
+                        });
+                    }
+                    let Some(conversion) = chars.next() else {
+                        return Err("`%': missing format character".to_string());
+                    };
+                    if self.convert(conversion, &spec)? == Flow::Stop {
+                        return Ok(Flow::Stop);
+                    }
+                }
+                c => self.output.push(c),
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn convert(&mut self, conversion: char, spec: &Spec) -> Result<Flow, String> {
+        let text = match conversion {
+            's' => {
+                let arg = self.arg();
+                match spec.precision {
+                    Some(precision) => arg.chars().take(precision).collect(),
+                    None => arg.to_string(),
+                }
+            }
+            'b' => {
+                let (text, flow) = unescape(self.arg(), true);
+                if flow == Flow::Stop {
+                    self.output.push_str(&text);
+                    return Ok(Flow::Stop);
+                }
+                match spec.precision {
+                    Some(precision) => text.chars().take(precision).collect(),
+                    None => text,
+                }
+            }
+            'c' => self.arg().chars().take(1).collect(),
+            'd' | 'i' => {
+                let value = self.integer_arg();
+                let digits = with_precision(value.unsigned_abs().to_string(), spec.precision);
+                let sign = sign(value < 0, spec);
+                return Ok(self.pad_number(sign, &digits, spec));
+            }
+            'o' | 'x' | 'X' | 'u' => {
+                let value = self.integer_arg() as u64;
+                let digits = match conversion {
+                    'o' => format!("{:o}", value),
+                    'x' => format!("{:x}", value),
+                    'X' => format!("{:X}", value),
+                    _ => value.to_string(),
+                };
+                let mut digits = with_precision(digits, spec.precision);
+                let prefix = match conversion {
+                    'o' if spec.alternate && !digits.starts_with('0') => {
+                        digits.insert(0, '0');
+                        ""
+                    }
+                    'x' if spec.alternate && value != 0 => "0x",
+                    'X' if spec.alternate && value != 0 => "0X",
+                    _ => "",
+                };
+                return Ok(self.pad_number(prefix, &digits, spec));
+            }
+            'f' | 'F' | 'e' | 'E' | 'g' | 'G' => {
+                let value = self.float_arg();
+                let precision = spec.precision.unwrap_or(6);
+                let digits = match conversion {
+                    _ if !value.is_finite() => {
+                        let text = if value.is_nan() { "nan" } else { "inf" };
+                        return Ok(self.pad(sign(value < 0.0, spec), text, spec));
+                    }
+                    'f' | 'F' => format!("{:.*}", precision, value.abs()),
+                    'e' | 'E' => exponential(value.abs(), precision),
+                    _ => general(value.abs(), precision, spec.alternate),
+                };
+                let digits = if conversion.is_ascii_uppercase() {
+                    digits.to_uppercase()
+                } else {
+                    digits
+                };
+                let sign = sign(value.is_sign_negative() && value != 0.0, spec);
+                return Ok(self.pad_number(
+                    sign,
+                    &digits,
+                    &Spec {
+                        precision: None,
+                        ..*spec
+                    },
+                ));
+            }
+            other => return Err(format!("`{}': invalid format character", other)),
+        };
+        Ok(self.pad("", &text, spec))
+    }
+
+    /// Writes `prefix` and `text` padded with spaces to the width of `spec`.
+    fn pad(&mut self, prefix: &str, text: &str, spec: &Spec) -> Flow {
+        let len = prefix.chars().count() + text.chars().count();
+        let fill = " ".repeat(spec.width.saturating_sub(len));
+        if spec.left {
+            self.output.push_str(&format!("{}{}{}", prefix, text, fill));
+        } else {
+            self.output.push_str(&format!("{}{}{}", fill, prefix, text));
+        }
+        Flow::Continue
+    }
+
+    /// Like `pad`, but the `0` flag pads with zeros between the sign and the digits.
+    fn pad_number(&mut self, prefix: &str, digits: &str, spec: &Spec) -> Flow {
+        if spec.zero && !spec.left && spec.precision.is_none() {
+            let zeros = spec.width.saturating_sub(prefix.len() + digits.len());
+            let digits = format!("{}{}", "0".repeat(zeros), digits);
+            return self.pad(prefix, &digits, spec);
+        }
+        self.pad(prefix, digits, spec)
+    }
+
+    fn arg(&mut self) -> &str {
+        let arg = self.args.get(self.next).map(String::as_str).unwrap_or("");
+        self.next += 1;
+        arg
+    }
+
+    fn integer_arg(&mut self) -> i64 {
+        let arg = self.arg().to_string();
+        parse_integer(&arg).unwrap_or_else(|| {
+            self.errors.push(format!("{}: invalid number", arg));
+            0
+        })
+    }
+
+    fn float_arg(&mut self) -> f64 {
+        let arg = self.arg().to_string();
+        let value = match arg.trim() {
+            "" => Some(0.0),
+            text => text
+                .parse()
+                .ok()
+                .or_else(|| parse_integer(text).map(|n| n as f64)),
+        };
+        value.unwrap_or_else(|| {
+            self.errors.push(format!("{}: invalid number", arg));
+            0.0
+        })
+    }
+}
+
+fn digits(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<usize, String> {
+    let mut text = String::new();
+    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+        text.push(digit);
+    }
+    match text.as_str() {
+        "" => Ok(0),
+        text => in_range(text),
+    }
+}
+
+/// Parses a width or precision, refusing one above `i32::MAX` as bash does, so that a
+/// huge one is reported instead of filling memory with padding.
+fn in_range(text: &str) -> Result<usize, String> {
+    match text.parse::<i32>() {
+        Ok(value) => Ok(value as usize),
+        Err(_) => Err(format!("{}: Numerical result out of range", text)),
+    }
+}
+
+fn sign(negative: bool, spec: &Spec) -> &'static str {
+    match negative {
+        true => "-",
+        false if spec.plus => "+",
+        false if spec.space => " ",
+        false => "",
+    }
+}
+
+/// Pads `digits` with leading zeros to at least `precision` digits.
+fn with_precision(digits: String, precision: Option<usize>) -> String {
+    match precision {
+        Some(0) if digits == "0" => String::new(),
+        Some(precision) if precision > digits.len() => {
+            format!("{}{}", "0".repeat(precision - digits.len()), digits)
+        }
+        _ => digits,
+    }
+}
+
+/// Parses a numeric argument: decimal, `0x` hex or `0` octal with an optional sign,
+/// or a quote followed by a character, which stands for its code point.
+fn parse_integer(text: &str) -> Option<i64> {
+    let text = text.trim_start();
+    if let Some(quoted) = text.strip_prefix(['\'', '"']) {
+        return Some(quoted.chars().next().map_or(0, |c| c as i64));
+    }
+    let (negative, unsigned) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let magnitude = if let Some(hex) = unsigned
+        .strip_prefix("0x")
+        .or_else(|| unsigned.strip_prefix("0X"))
+    {
+        u64::from_str_radix(hex, 16).ok()?
+    } else if unsigned.len() > 1 && unsigned.starts_with('0') {
+        u64::from_str_radix(&unsigned[1..], 8).ok()?
+    } else if unsigned.is_empty() {
+        0
+    } else {
+        unsigned.parse().ok()?
+    };
+    let value = magnitude as i64;
+    Some(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+/// Formats like C's `%e`: one digit before the point and an exponent of at least two digits.
+fn exponential(value: f64, precision: usize) -> String {
+    let text = format!("{:.*e}", precision, value);
+    let (mantissa, exponent) = text.split_once('e').unwrap_or((&text, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    let sign = if exponent < 0 { '-' } else { '+' };
+    format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+}
+
+/// Formats like C's `%g`: `%e` for very large or small values and `%f` otherwise,
+/// with `precision` significant digits and trailing zeros removed unless `alternate`.
+fn general(value: f64, precision: usize, alternate: bool) -> String {
+    let precision = precision.max(1);
+    let exponent = if value == 0.0 {
+        0
+    } else {
+        // Round first, so that 9.9999995 counts as 1e+01
+        let text = format!("{:.*e}", precision - 1, value);
+        text.split_once('e')
+            .and_then(|(_, e)| e.parse::<i32>().ok())
+            .unwrap_or(0)
+    };
+    let text = if exponent < -4 || exponent >= precision as i32 {
+        exponential(value, precision - 1)
+    } else {
+        format!("{:.*}", (precision as i32 - 1 - exponent) as usize, value)
+    };
+    if alternate {
+        return text;
+    }
+    let (mantissa, exponent) = match text.find('e') {
+        Some(index) => text.split_at(index),
+        None => (text.as_str(), ""),
+    };
+    let mantissa = match mantissa.contains('.') {
+        true => mantissa.trim_end_matches('0').trim_end_matches('.'),
+        false => mantissa,
+    };
+    format!("{}{}", mantissa, exponent)
+}
+
+/// Interprets the backslash escapes of `text`. `argument` selects the rules of a `%b`
+/// argument, where octal escapes are written `\0NNN` and `\c` stops all output.
+fn unescape(text: &str, argument: bool) -> (String, Flow) {
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some(index) = rest.find('\\') {
+        output.push_str(&rest[..index]);
+        let (escaped, used, flow) = unescape_one(&rest[index + 1..], argument);
+        if flow == Flow::Stop {
+            return (output, Flow::Stop);
+        }
+        output.push_str(&escaped);
+        rest = &rest[index + 1 + used..];
+    }
+    output.push_str(rest);
+    (output, Flow::Continue)
+}
+
+/// Decodes the escape sequence at the start of `text`, just after a backslash, and
+/// returns its value with the number of characters it used.
+fn unescape_one(text: &str, argument: bool) -> (String, usize, Flow) {
+    let Some(c) = text.chars().next() else {
+        return ("\\".to_string(), 0, Flow::Continue);
+    };
+    let simple = match c {
+        'a' => Some('\x07'),
+        'b' => Some('\x08'),
+        'e' | 'E' => Some('\x1b'),
+        'f' => Some('\x0c'),
+        'n' => Some('\n'),
+        'r' => Some('\r'),
+        't' => Some('\t'),
+        'v' => Some('\x0b'),
+        '\\' => Some('\\'),
+        '"' if !argument => Some('"'),
+        '\'' if !argument => Some('\''),
+        _ => None,
+    };
+    if let Some(simple) = simple {
+        return (simple.to_string(), 1, Flow::Continue);
+    }
+    let number = |digits: &str, radix: u32, max: usize| {
+        let len = digits
+            .chars()
+            .take(max)
+            .take_while(|c| c.is_digit(radix))
+            .count();
+        let value = u32::from_str_radix(&digits[..len], radix).unwrap_or(0);
+        (encoding::raw_byte(value as u8), len)
+    };
+    match c {
+        'c' if argument => (String::new(), 1, Flow::Stop),
+        'x' if text[1..].starts_with(|c: char| c.is_ascii_hexdigit()) => {
+            let (value, len) = number(&text[1..], 16, 2);
+            (value.to_string(), 1 + len, Flow::Continue)
+        }
+        '0' => {
+            let (value, len) = number(&text[1..], 8, 3);
+            (value.to_string(), 1 + len, Flow::Continue)
+        }
+        '1'..='7' if !argument => {
+            let (value, len) = number(text, 8, 3);
+            (value.to_string(), len, Flow::Continue)
+        }
+        other => (format!("\\{}", other), other.len_utf8(), Flow::Continue),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn printf(format_string: &str, args: &[&str]) -> String {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        format(format_string, &args).unwrap().0
+    }
+
+    #[test]
+    fn test_strings_and_integers() {
+        assert_eq!(printf("%-10s %d\\n", &["foo", "42"]), "foo        42\n");
+        assert_eq!(printf("[%5.2s]", &["abc"]), "[   ab]");
+        assert_eq!(printf("%05d|%+d|%.3i", &["-42", "7", "5"]), "-0042|+7|005");
+        assert_eq!(
+            printf("%o %x %X %#x", &["8", "255", "255", "255"]),
+            "10 ff FF 0xff"
+        );
+        assert_eq!(printf("%d %c%%", &["'A", "xyz"]), "65 x%");
+    }
+
+    #[test]
+    fn test_floating_point() {
+        assert_eq!(printf("%.2f %f", &["3.14159", "2"]), "3.14 2.000000");
+        assert_eq!(
+            printf("%e %.1E", &["1234.5", "0.00012"]),
+            "1.234500e+03 1.2E-04"
+        );
+        assert_eq!(
+            printf("%g %g %g", &["100000", "1000000", "0.0001"]),
+            "100000 1e+06 0.0001"
+        );
+        assert_eq!(printf("%8.3f|", &["-1.5"]), "  -1.500|");
+    }
+
+    #[test]
+    fn test_format_is_reused_for_remaining_arguments() {
+        assert_eq!(printf("%s=%s\\n", &["a", "1", "b"]), "a=1\nb=\n");
+        assert_eq!(printf("x\\n", &["ignored"]), "x\n");
+        assert_eq!(printf("%d\\n", &[]), "0\n");
+    }
+
+    #[test]
+    fn test_escapes_and_errors() {
+        assert_eq!(printf("\\t\\x41\\0101\\101\\a", &[]), "\tAAA\x07");
+        assert_eq!(printf("%b|", &["a\\tb\\0101"]), "a\tbA|");
+        assert_eq!(printf("%b%s", &["stop\\chere", "more"]), "stop");
+
+        let args = vec!["abc".to_string()];
+        let (output, errors) = format("%d", &args).unwrap();
+        assert_eq!(output, "0");
+        assert_eq!(errors, ["abc: invalid number"]);
+        assert!(format("%z", &args).is_err());
+    }
+
+    #[test]
+    fn test_huge_width_and_precision() {
+        let out_of_range = |format_string: &str, args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+            format(format_string, &args).unwrap_err()
+        };
+        assert_eq!(
+            out_of_range("%9999999999d", &["1"]),
+            "9999999999: Numerical result out of range"
+        );
+        assert_eq!(
+            out_of_range("%.99999999999999999999f", &["1"]),
+            "99999999999999999999: Numerical result out of range"
+        );
+        assert_eq!(
+            out_of_range("%*d", &["-3000000000", "1"]),
+            "3000000000: Numerical result out of range"
+        );
+        assert_eq!(
+            out_of_range("%.*f", &["2147483648", "1"]),
+            "2147483648: Numerical result out of range"
+        );
+        assert_eq!(printf("%*d|", &["-3", "1"]), "1  |");
+    }
+}
@@ -1,185 +1,1336 @@
+use crate::commands::is_valid_name;
+use crate::encoding;
+use crate::tokenize::{Op, Token, TokenKind, split_subscript};
+use std::fmt;
+use std::fs::{File, OpenOptions};
 use std::io::Write;
+use std::iter::Peekable;
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
 
 /// Represents a redirection operator.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Redirection {
-    pub file: String,
+    /// The file descriptor being redirected: 0 for an input redirection, and 1 for an
+    /// output one unless the operator names another.
+    pub fd: u32,
+    pub target: RedirTarget,
     pub append: bool,
+    /// Set for `>|`, which overwrites the file even when `noclobber` is set.
+    pub force: bool,
+    /// Set for `<>`, which opens the file for reading too and writes over it from the
+    /// start without truncating it.
+    pub read_write: bool,
+}
+
+/// Where a redirection points its file descriptor.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RedirTarget {
+    /// The file named by the word after the operator, as in `> file` or `< file`, with
+    /// any bytes that are not valid UTF-8 restored.
+    File(PathBuf),
+    /// `>&N`: the stream that fd N, 1 or 2, would be without any redirection, such as
+    /// a pipe. A copy of a stream that was redirected to a file is that file instead.
+    Fd(u32),
+    /// `>&-` or `<&-`: the fd is closed. Anything written to it is discarded, and
+    /// reading it gives end of file.
+    Closed,
+    /// `<< DELIM` or `<<- DELIM`
+    HereDoc(HereDoc),
+    /// `<<< word`, fed to the command followed by a newline
+    HereString(String),
+}
+
+impl fmt::Display for RedirTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RedirTarget::File(file) => f.write_str(&encoding::decode(file.as_os_str())),
+            RedirTarget::Fd(fd) => write!(f, "&{}", fd),
+            RedirTarget::Closed => f.write_str("&-"),
+            RedirTarget::HereDoc(heredoc) => f.write_str(&heredoc.delimiter),
+            RedirTarget::HereString(word) => f.write_str(word),
+        }
+    }
+}
+
+impl Redirection {
+    /// Whether an existing file may be truncated, given the shell's `noclobber` option.
+    pub fn clobber(&self, noclobber: bool) -> bool {
+        self.force || !noclobber
+    }
+
+    /// Opens the file read from stdin, or written to; see `open_output` and
+    /// `open_read_write`. Only a redirection to a file has one to open.
+    pub fn open(&self, noclobber: bool) -> Result<File, std::io::Error> {
+        match &self.target {
+            RedirTarget::File(file) if self.read_write => open_read_write(file),
+            RedirTarget::File(file) if self.fd == 0 => File::open(file),
+            RedirTarget::File(file) => open_output(file, self.append, self.clobber(noclobber)),
+            _ => Err(std::io::Error::from(std::io::ErrorKind::InvalidInput)),
+        }
+    }
+
+    /// Describes a failure to open the file, bash-style.
+    pub fn error_message(&self, e: &std::io::Error) -> String {
+        format!("{}: {}", self.target, io_error_message(e))
+    }
+}
+
+/// A here-document whose body is read from the lines following the command.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HereDoc {
+    pub delimiter: String,
+    /// Set for `<<-`, which strips leading tabs from body lines and the delimiter line.
+    pub strip_tabs: bool,
+    /// Set when no part of the delimiter was quoted, so the body undergoes `$` expansion.
+    pub expand: bool,
+    pub body: String,
 }
 
 /// A parsed command with arguments and redirections.
 #[derive(Debug, Default)]
 pub struct ParsedCommand {
+    /// `NAME=value` and `NAME+=value` words written before the command name.
+    pub assignments: Vec<Assignment>,
+    /// `NAME=(word...)` and `NAME+=(word...)` array assignments before the command name.
+    pub arrays: Vec<ArrayAssignment>,
     pub args: Vec<String>,
-    pub redirect_stdout: Option<Redirection>,
-    pub redirect_stderr: Option<Redirection>,
+    /// Redirections in the order written, which is the order they are applied in when
+    /// the command runs; see `OutputFiles::open`.
+    pub redirects: Vec<Redirection>,
+}
+
+/// An assignment of a value to a variable, or with `index` to an element of an array.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assignment {
+    pub name: String,
+    /// The subscript of `NAME[index]=value`, an arithmetic expression.
+    pub index: Option<String>,
+    /// Set for `+=`, which adds to the existing value instead of replacing it.
+    pub append: bool,
+    pub value: String,
+}
+
+/// An assignment of words to an array variable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArrayAssignment {
+    pub name: String,
+    /// Set for `+=`, which adds to the existing elements instead of replacing them.
+    pub append: bool,
+    pub values: Vec<String>,
+}
+
+/// Returns the name of the array a word like `NAME=` or `NAME+=` assigns to, if the
+/// `(` of an array assignment follows it, and whether it appends.
+pub fn array_assignment_name(word: &str) -> Option<(&str, bool)> {
+    let name = word.strip_suffix('=')?;
+    let (name, append) = match name.strip_suffix('+') {
+        Some(name) => (name, true),
+        None => (name, false),
+    };
+    is_valid_name(name).then_some((name, append))
 }
 
 /// Parses tokens into a ParsedCommand, extracting redirection operators.
-pub fn parse_command(tokens: Vec<String>) -> ParsedCommand {
+/// Fails if a redirection operator is not followed by a word.
+/// Redirections apply left to right, so `> file 2>&1` sends both streams to `file`
+/// while `2>&1 > file` sends stderr to the original stdout.
+pub fn parse_command(tokens: Vec<Token>) -> Result<ParsedCommand, ParseError> {
+    let mut assignments = Vec::new();
+    let mut arrays = Vec::new();
     let mut args = Vec::new();
-    let mut redirect_stdout = None;
-    let mut redirect_stderr = None;
-    let mut i = 0;
-
-    while i < tokens.len() {
-        match tokens[i].as_str() {
-            ">" | "1>" => {
-                redirect_stdout = tokens.get(i + 1).map(|f| Redirection {
-                    file: f.clone(),
-                    append: false,
-                });
-                i += 2;
+    let mut redirects = Vec::new();
+    let mut tokens = tokens.into_iter().peekable();
+
+    while let Some(token) = tokens.next() {
+        let op = match token.kind {
+            TokenKind::Word(word) => {
+                if args.is_empty()
+                    && let Some((name, append)) = array_assignment_name(&word)
+                    && let Some(paren) = tokens.next_if(|next| {
+                        next.kind == TokenKind::Operator(Op::LeftParen)
+                            && next.span.start == token.span.end
+                    })
+                {
+                    arrays.push(ArrayAssignment {
+                        name: name.to_string(),
+                        append,
+                        values: array_values(&mut tokens, &paren)?,
+                    });
+                    continue;
+                }
+                // Once the command name is seen, `NAME=value` words are ordinary arguments
+                match word.split_once('=') {
+                    Some((name, value)) if args.is_empty() => {
+                        let (name, append) = match name.strip_suffix('+') {
+                            Some(name) => (name, true),
+                            None => (name, false),
+                        };
+                        let (name, index) = match split_subscript(name) {
+                            Some((name, index, "")) => (name, Some(index.to_string())),
+                            _ => (name, None),
+                        };
+                        if is_valid_name(name) {
+                            assignments.push(Assignment {
+                                name: name.to_string(),
+                                index,
+                                append,
+                                value: value.to_string(),
+                            });
+                        } else {
+                            args.push(word.into_owned());
+                        }
+                    }
+                    _ => args.push(word.into_owned()),
+                }
+                continue;
             }
-            ">>" | "1>>" => {
-                redirect_stdout = tokens.get(i + 1).map(|f| Redirection {
-                    file: f.clone(),
-                    append: true,
-                });
-                i += 2;
+            TokenKind::Operator(op) => op,
+            TokenKind::ProcessSubstitution { .. } => return Err(ParseError::ProcessSubstitution),
+        };
+        match op {
+            Op::Input { fd: None | Some(0) } => {
+                redirects.push(to_file(&mut tokens, &token, Some(0))?);
             }
-            "2>" => {
-                redirect_stderr = tokens.get(i + 1).map(|f| Redirection {
-                    file: f.clone(),
-                    append: false,
+            Op::ReadWrite { fd } => redirects.push(Redirection {
+                read_write: true,
+                ..to_file(&mut tokens, &token, Some(fd.unwrap_or(0)))?
+            }),
+            Op::HereDoc {
+                fd: None | Some(0),
+                strip_tabs,
+            } => {
+                let expand = tokens.peek().is_some_and(|delimiter| !delimiter.quoted);
+                let heredoc = HereDoc {
+                    delimiter: target(&mut tokens, &token)?,
+                    strip_tabs,
+                    expand,
+                    body: String::new(),
+                };
+                redirects.push(Redirection {
+                    target: RedirTarget::HereDoc(heredoc),
+                    ..copy(0, 0)
                 });
-                i += 2;
             }
-            "2>>" => {
-                redirect_stderr = tokens.get(i + 1).map(|f| Redirection {
-                    file: f.clone(),
-                    append: true,
+            Op::HereString { fd: None | Some(0) } => redirects.push(Redirection {
+                target: RedirTarget::HereString(target(&mut tokens, &token)?),
+                ..copy(0, 0)
+            }),
+            Op::Output { fd, append } => redirects.push(Redirection {
+                append,
+                ..to_file(&mut tokens, &token, fd)?
+            }),
+            Op::Clobber { fd } => redirects.push(Redirection {
+                force: true,
+                ..to_file(&mut tokens, &token, fd)?
+            }),
+            Op::OutputAll { append } => {
+                // Both streams go to the same file, as with `> file 2>&1`
+                redirects.push(Redirection {
+                    append,
+                    ..to_file(&mut tokens, &token, None)?
                 });
-                i += 2;
+                redirects.push(copy(2, 1));
+            }
+            Op::DuplicateOutput { fd } => {
+                let word = target(&mut tokens, &token)?;
+                let redirection = match word.as_str() {
+                    "-" => Redirection {
+                        target: RedirTarget::Closed,
+                        ..copy(1, 1)
+                    },
+                    _ => match word.parse() {
+                        Ok(source @ (1 | 2)) => copy(1, source),
+                        Ok(source) => return Err(ParseError::UnsupportedFd(source)),
+                        // `>& file` is another way to write `&> file`
+                        Err(_) if fd.is_none() => {
+                            redirects.push(Redirection {
+                                target: RedirTarget::File(encoding::path(&word)),
+                                ..copy(1, 1)
+                            });
+                            redirects.push(copy(2, 1));
+                            continue;
+                        }
+                        Err(_) => return Err(ParseError::AmbiguousRedirect(word)),
+                    },
+                };
+                match fd {
+                    None | Some(1 | 2) => redirects.push(Redirection {
+                        fd: fd.unwrap_or(1),
+                        ..redirection
+                    }),
+                    Some(fd) => return Err(ParseError::UnsupportedFd(fd)),
+                }
             }
-            "|" => {
+            Op::DuplicateInput { fd: None | Some(0) } => {
+                let word = target(&mut tokens, &token)?;
+                match word.as_str() {
+                    "-" => redirects.push(Redirection {
+                        target: RedirTarget::Closed,
+                        ..copy(0, 0)
+                    }),
+                    "0" => {}
+                    _ => match word.parse() {
+                        Ok(fd) => return Err(ParseError::UnsupportedFd(fd)),
+                        Err(_) => return Err(ParseError::AmbiguousRedirect(word)),
+                    },
+                }
+            }
+            Op::Pipe => {
                 // Pipeline operator - stop parsing this command
                 break;
             }
-            _ => {
-                args.push(tokens[i].clone());
-                i += 1;
-            }
+            Op::Input { fd: Some(fd) }
+            | Op::DuplicateInput { fd: Some(fd) }
+            | Op::HereDoc { fd: Some(fd), .. }
+            | Op::HereString { fd: Some(fd) } => return Err(ParseError::UnsupportedFd(fd)),
+            _ => return Err(ParseError::unexpected(&token)),
         }
     }
 
-    ParsedCommand {
+    Ok(ParsedCommand {
+        assignments,
+        arrays,
         args,
-        redirect_stdout,
-        redirect_stderr,
+        redirects,
+    })
+}
+
+/// A redirection making `fd` a copy of stream `source`, as `fd>&source` does.
+fn copy(fd: u32, source: u32) -> Redirection {
+    Redirection {
+        fd,
+        target: RedirTarget::Fd(source),
+        append: false,
+        force: false,
+        read_write: false,
+    }
+}
+
+/// Takes the words of an array assignment up to its closing `)`.
+fn array_values<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    paren: &Token,
+) -> Result<Vec<String>, ParseError> {
+    let mut values = Vec::new();
+    loop {
+        match tokens.next() {
+            Some(Token {
+                kind: TokenKind::Word(word),
+                ..
+            }) => values.push(word.into_owned()),
+            Some(Token {
+                kind: TokenKind::Operator(Op::Newline),
+                ..
+            }) => {}
+            Some(Token {
+                kind: TokenKind::Operator(Op::RightParen),
+                ..
+            }) => return Ok(values),
+            Some(token) => return Err(ParseError::unexpected(&token)),
+            None => {
+                return Err(ParseError::UnexpectedToken {
+                    token: "newline".to_string(),
+                    column: paren.span.end + 1,
+                });
+            }
+        }
+    }
+}
+
+/// Takes the file a redirection of `fd`, stdout unless given, reads or writes.
+fn to_file<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    operator: &Token,
+    fd: Option<u32>,
+) -> Result<Redirection, ParseError> {
+    let file = encoding::path(&target(tokens, operator)?);
+    Ok(Redirection {
+        target: RedirTarget::File(file),
+        ..copy(fd.unwrap_or(1), fd.unwrap_or(1))
+    })
+}
+
+/// Takes the word a redirection operator applies to.
+fn target<'a>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'a>>>,
+    operator: &Token,
+) -> Result<String, ParseError> {
+    match tokens.next_if(|t| matches!(t.kind, TokenKind::Word(_))) {
+        Some(Token {
+            kind: TokenKind::Word(word),
+            ..
+        }) => Ok(word.into_owned()),
+        _ => Err(match tokens.peek() {
+            Some(Token {
+                kind: TokenKind::ProcessSubstitution { .. },
+                ..
+            }) => ParseError::ProcessSubstitution,
+            Some(next) => ParseError::unexpected(next),
+            None => ParseError::UnexpectedToken {
+                token: "newline".to_string(),
+                column: operator.span.end + 1,
+            },
+        }),
+    }
+}
+
+/// Errors produced while turning tokens into commands.
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ParseError {
+    /// `column` counts bytes from 1 at the start of the input, for pointing out the token.
+    #[error("syntax error near unexpected token `{token}'")]
+    UnexpectedToken { token: String, column: usize },
+    /// Input that ends inside a compound command.
+    #[error("syntax error: unexpected end of file")]
+    UnexpectedEof,
+    /// A redirection of a file descriptor other than stdin, stdout or stderr, or
+    /// one in the wrong direction (such as `0>`).
+    #[error("{0}: bad file descriptor")]
+    UnsupportedFd(u32),
+    /// A `>&` or `<&` target that is neither a file descriptor nor `-`.
+    #[error("{0}: ambiguous redirect")]
+    AmbiguousRedirect(String),
+    /// A `<(...)` or `>(...)` word, which cannot be run yet.
+    #[error("process substitution not supported yet")]
+    ProcessSubstitution,
+}
+
+impl ParseError {
+    pub fn unexpected(token: &Token) -> Self {
+        ParseError::UnexpectedToken {
+            token: token.to_string(),
+            column: token.span.start + 1,
+        }
+    }
+
+    /// The column of the token the error is about, if it is about one.
+    pub fn column(&self) -> Option<usize> {
+        match self {
+            ParseError::UnexpectedToken { column, .. } => Some(*column),
+            _ => None,
+        }
     }
 }
 
-/// Parses tokens into a list of commands separated by |
-pub fn parse_pipeline(tokens: Vec<String>) -> Vec<ParsedCommand> {
+/// Formats a syntax error the way bash reports it, as `name: message`. When `column` is
+/// given, the line of `input` holding it follows, with a caret under that column.
+pub fn render_syntax_error(
+    name: &str,
+    message: &str,
+    input: &str,
+    column: Option<usize>,
+) -> String {
+    let mut rendered = format!("{}: {}", name, message);
+    if let Some(offset) = column.and_then(|column| column.checked_sub(1))
+        && let Some(before) = input.get(..offset)
+    {
+        let start = before.rfind('\n').map_or(0, |i| i + 1);
+        let end = input[offset..]
+            .find('\n')
+            .map_or(input.len(), |i| offset + i);
+        let indent = input[start..offset].chars().count();
+        rendered.push_str(&format!(
+            "\n{}\n{}^",
+            &input[start..end],
+            " ".repeat(indent)
+        ));
+    }
+    rendered
+}
+
+/// A sequence of commands connected by `|`, each stage's stdout feeding the next stage's stdin.
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    pub commands: Vec<ParsedCommand>,
+    /// Set when the pipeline ends with `&`, to run it in the background as one job.
+    pub background: bool,
+}
+
+/// Parses tokens into a pipeline of commands separated by |
+pub fn parse_pipeline(tokens: Vec<Token>) -> Result<Pipeline, ParseError> {
     let mut commands = Vec::new();
     let mut current_tokens = Vec::new();
+    let mut after_pipe = false;
+    let mut background = false;
 
-    for token in tokens {
-        if token == "|" {
-            if !current_tokens.is_empty() {
-                commands.push(parse_command(current_tokens));
-                current_tokens = Vec::new();
+    let len = tokens.len();
+    for (i, token) in tokens.into_iter().enumerate() {
+        let TokenKind::Operator(op) = token.kind else {
+            after_pipe = false;
+            current_tokens.push(token);
+            continue;
+        };
+        // The next stage may start on a later line
+        if op == Op::Newline && after_pipe {
+            continue;
+        }
+        after_pipe = op == Op::Pipe;
+        // Nor may it stand for a command, or for the word a redirection needs
+        let misplaced_background = op == Op::Background
+            && (i + 1 < len
+                || current_tokens.last().is_none_or(
+                    |last| matches!(last.kind, TokenKind::Operator(op) if op != Op::RightParen),
+                ));
+        if misplaced_background
+            || matches!(op, Op::Semicolon | Op::DoubleSemicolon | Op::And | Op::Or)
+        {
+            // Lists have been split up by `ast::parse_list`; `&` may only end the pipeline
+            return Err(ParseError::unexpected(&token));
+        } else if op == Op::Background {
+            background = true;
+        } else if op == Op::Pipe {
+            if current_tokens.is_empty() {
+                return Err(ParseError::unexpected(&token));
             }
+            // The `|` ends the command, and is what a dangling redirection reports
+            current_tokens.push(token);
+            commands.push(parse_command(std::mem::take(&mut current_tokens))?);
         } else {
             current_tokens.push(token);
         }
     }
 
+    // A `|` needs a command after it
+    if after_pipe {
+        return Err(ParseError::UnexpectedEof);
+    }
     // Don't forget the last command
     if !current_tokens.is_empty() {
-        commands.push(parse_command(current_tokens));
+        commands.push(parse_command(current_tokens)?);
     }
 
-    commands
+    Ok(Pipeline {
+        commands,
+        background,
+    })
 }
 
-/// Writes content to a file, with optional append mode.
-pub fn write_to_file(file: &str, content: &str, append: bool) -> Result<(), std::io::Error> {
+/// Opens a file for output redirection, creating it if needed. Unless appending,
+/// the file is truncated; without `clobber` an existing regular file is refused
+/// instead, which `io_error_message` reports as "cannot overwrite existing file".
+pub fn open_output(path: &Path, append: bool, clobber: bool) -> Result<File, std::io::Error> {
+    let mut options = OpenOptions::new();
     if append {
-        std::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(file)
-            .and_then(|mut f| f.write_all(content.as_bytes()))
+        options.create(true).append(true);
+    } else if clobber {
+        options.create(true).write(true).truncate(true);
     } else {
-        std::fs::write(file, content)
+        match OpenOptions::new().write(true).create_new(true).open(path) {
+            // Like bash, noclobber only protects regular files, so `> /dev/null` still works
+            Err(e)
+                if e.kind() == std::io::ErrorKind::AlreadyExists
+                    && !path.metadata().is_ok_and(|m| m.is_file()) =>
+            {
+                options.write(true);
+            }
+            result => return result,
+        }
     }
+    options.open(path)
 }
 
-/// Creates or truncates a file.
-pub fn create_file(file: &str, append: bool) -> Result<(), std::io::Error> {
-    if append {
-        std::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(file)
-            .map(|_| ())
-    } else {
-        std::fs::File::create(file).map(|_| ())
+/// Opens a file for `<>`, for both reading and writing, creating it if needed but
+/// never truncating it. `noclobber` does not apply.
+pub fn open_read_write(path: &Path) -> Result<File, std::io::Error> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+/// Fills in a here-document body from `next_line` until the delimiter line.
+/// Returns bash's warning if input ends before the delimiter; the body read so far is kept.
+pub fn read_heredoc_body(
+    heredoc: &mut HereDoc,
+    mut next_line: impl FnMut() -> Option<String>,
+) -> Result<(), String> {
+    while let Some(line) = next_line() {
+        let line = if heredoc.strip_tabs {
+            line.trim_start_matches('\t')
+        } else {
+            line.as_str()
+        };
+        if line == heredoc.delimiter {
+            return Ok(());
+        }
+        heredoc.body.push_str(line);
+        heredoc.body.push('\n');
     }
+
+    Err(format!(
+        "warning: here-document delimited by end-of-file (wanted `{}')",
+        heredoc.delimiter
+    ))
 }
 
-/// Handles output redirection for command results.
-pub fn handle_output(result: &Result<String, String>, parsed: &ParsedCommand) {
-    use crate::commands::BUILTINS;
-    use std::io::{self, Write};
+/// Opens a file for use as a command's stdin, describing any failure bash-style.
+pub fn open_input(path: &str) -> Result<std::fs::File, String> {
+    std::fs::File::open(encoding::path(path))
+        .map_err(|e| format!("{}: {}", path, io_error_message(&e)))
+}
 
-    // Handle stdout redirection
-    if let Some(ref redirection) = parsed.redirect_stdout {
-        let output = result.as_ref().ok().map(|s| s.as_str()).unwrap_or("");
-        if !output.is_empty() {
-            let _ = write_to_file(&redirection.file, output, redirection.append);
-        } else {
-            let _ = create_file(&redirection.file, redirection.append);
+/// Describes an I/O error the way the C library does, without Rust's `(os error N)` suffix.
+pub fn io_error_message(e: &std::io::Error) -> String {
+    use std::io::ErrorKind;
+
+    match e.kind() {
+        ErrorKind::NotFound => "No such file or directory".to_string(),
+        ErrorKind::PermissionDenied => "Permission denied".to_string(),
+        ErrorKind::IsADirectory => "Is a directory".to_string(),
+        ErrorKind::AlreadyExists => "cannot overwrite existing file".to_string(),
+        _ => match e.raw_os_error() {
+            Some(code) => nix::errno::Errno::from_raw(code).desc().to_string(),
+            None => e.to_string(),
+        },
+    }
+}
+
+/// Where a command's stdin comes from once its redirections are applied.
+#[derive(Debug)]
+pub enum Input {
+    /// The stdin the command would have without any redirection, such as a pipe.
+    Stream,
+    File(File),
+    /// The text of a here-document or here-string, fed to the command through a pipe.
+    Text(String),
+    /// Closed with `<&-`: the command reads end of file.
+    Closed,
+}
+
+/// Where a command's stdout or stderr goes once its redirections are applied.
+#[derive(Debug)]
+pub enum Output {
+    /// Where stream 1 or 2 went before the redirections, such as the terminal or a pipe.
+    Stream(u32),
+    File(File),
+    /// Closed with `>&-`: anything written to it is discarded.
+    Closed,
+}
+
+impl Output {
+    fn try_clone(&self) -> std::io::Result<Output> {
+        Ok(match self {
+            Output::Stream(fd) => Output::Stream(*fd),
+            Output::File(file) => Output::File(file.try_clone()?),
+            Output::Closed => Output::Closed,
+        })
+    }
+
+    /// What to make the shell's own stream `fd`, or `None` to leave it as it is.
+    fn into_shell_fd(self, fd: u32) -> std::io::Result<Option<OwnedFd>> {
+        use std::os::fd::AsFd;
+
+        match self {
+            Output::Stream(source) if source == fd => Ok(None),
+            Output::Stream(1) => std::io::stdout().as_fd().try_clone_to_owned().map(Some),
+            Output::Stream(_) => std::io::stderr().as_fd().try_clone_to_owned().map(Some),
+            Output::File(file) => Ok(Some(file.into())),
+            Output::Closed => Ok(Some(File::create("/dev/null")?.into())),
         }
-    } else if let Ok(output) = result
-        && !output.is_empty()
-    {
-        print!("{}", output);
-        // Flush stdout for commands like `clear` that need immediate effect
-        if parsed.args.first().is_some_and(|a| a == "clear") {
-            let _ = io::stdout().flush();
+    }
+}
+
+/// Where a command's stdin, stdout and stderr go. The files are opened before the
+/// command runs, so that a redirection that cannot be applied stops the command.
+#[derive(Debug)]
+pub struct OutputFiles {
+    pub stdin: Input,
+    pub stdout: Output,
+    pub stderr: Output,
+}
+
+impl OutputFiles {
+    /// Applies `parsed`'s redirections left to right, opening each file as it comes,
+    /// as bash does: `> a > b` creates both files but writes to `b`, with `> f 2>&1`
+    /// stderr shares stdout's open file, and `< missing < file` fails at `missing`.
+    /// Fails with a message at the first redirection that cannot be applied, leaving
+    /// the ones after it alone.
+    pub fn open(parsed: &ParsedCommand, noclobber: bool) -> Result<OutputFiles, String> {
+        let mut stdin = Input::Stream;
+        let mut streams = [Output::Stream(1), Output::Stream(2)];
+        for redirection in &parsed.redirects {
+            let index = match redirection.fd {
+                0 => {
+                    stdin = match &redirection.target {
+                        RedirTarget::File(_) => Input::File(
+                            redirection
+                                .open(noclobber)
+                                .map_err(|e| redirection.error_message(&e))?,
+                        ),
+                        RedirTarget::HereDoc(heredoc) => Input::Text(heredoc.body.clone()),
+                        RedirTarget::HereString(word) => Input::Text(format!("{}\n", word)),
+                        RedirTarget::Closed => Input::Closed,
+                        RedirTarget::Fd(fd) => return Err(format!("{}: bad file descriptor", fd)),
+                    };
+                    continue;
+                }
+                1 | 2 => redirection.fd as usize - 1,
+                fd => return Err(format!("{}: bad file descriptor", fd)),
+            };
+            streams[index] = match redirection.target {
+                RedirTarget::File(_) => Output::File(
+                    redirection
+                        .open(noclobber)
+                        .map_err(|e| redirection.error_message(&e))?,
+                ),
+                RedirTarget::Fd(fd @ (1 | 2)) => streams[fd as usize - 1]
+                    .try_clone()
+                    .map_err(|e| io_error_message(&e))?,
+                RedirTarget::Fd(fd) => return Err(format!("{}: bad file descriptor", fd)),
+                RedirTarget::Closed => Output::Closed,
+                RedirTarget::HereDoc(_) | RedirTarget::HereString(_) => {
+                    return Err(format!("{}: bad file descriptor", redirection.fd));
+                }
+            };
         }
+        let [stdout, stderr] = streams;
+        Ok(OutputFiles {
+            stdin,
+            stdout,
+            stderr,
+        })
     }
 
-    // Handle stderr redirection for builtins
-    if let Some(ref redirection) = parsed.redirect_stderr {
-        let is_external = !BUILTINS.contains(&parsed.args[0].as_str());
-        if !is_external {
-            if let Err(e) = result {
-                let _ = write_to_file(&redirection.file, e, redirection.append);
-            } else {
-                let _ = create_file(&redirection.file, redirection.append);
-            }
+    /// Makes the outputs the shell's own stdout and stderr, as `exec` does with no
+    /// command. A closed stream is pointed at `/dev/null`, so later writes to it go
+    /// nowhere.
+    pub fn replace_shell_streams(self) -> std::io::Result<()> {
+        use nix::unistd::{dup2_stderr, dup2_stdout};
+
+        // Output the shell has buffered belongs to the old target
+        std::io::stdout().flush()?;
+        // Copies are of the streams as they were, so both are found before either changes
+        let stdout = self.stdout.into_shell_fd(1)?;
+        let stderr = self.stderr.into_shell_fd(2)?;
+        if let Some(fd) = stdout {
+            dup2_stdout(fd)?;
+        }
+        if let Some(fd) = stderr {
+            dup2_stderr(fd)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where a builtin writes its output and its error messages. Raw bytes in the text
+/// are restored as it is written.
+pub struct Io<'a> {
+    out: &'a mut dyn Write,
+    /// `None` when error messages go to `out` as well, as with `2>&1`
+    err: Option<&'a mut dyn Write>,
+    /// The first write to `out` that failed
+    error: Option<std::io::Error>,
+}
+
+impl<'a> Io<'a> {
+    /// An `Io` writing errors to `err`, or to `out` as well without one.
+    pub fn new(out: &'a mut dyn Write, err: Option<&'a mut dyn Write>) -> Io<'a> {
+        Io {
+            out,
+            err,
+            error: None,
         }
-    } else if let Err(e) = result {
-        eprintln!("{}", e);
     }
+
+    /// Writes `text` to the output. A failure is kept for [`Io::finish`] to return.
+    pub fn print(&mut self, text: &str) {
+        if self.error.is_none()
+            && let Err(e) = self.out.write_all(&encoding::to_bytes(text))
+        {
+            self.error = Some(e);
+        }
+    }
+
+    /// Writes `message` and a newline to the error output.
+    pub fn eprintln(&mut self, message: &str) {
+        let err = match self.err.as_mut() {
+            Some(err) => err,
+            None => &mut self.out,
+        };
+        let _ = err.write_all(&encoding::to_bytes(&format!("{}\n", message)));
+    }
+
+    /// Flushes the output, so that something like `clear` takes effect at once, and
+    /// returns the first write to it that failed.
+    pub fn finish(&mut self) -> Option<std::io::Error> {
+        let flushed = self.out.flush();
+        self.error.take().or(flushed.err())
+    }
+}
+
+/// Calls `run` with an [`Io`] that writes where the `files` opened for a command send
+/// its output. Output that is not redirected goes to `stdout`, and errors to the
+/// shell's stderr.
+pub fn with_io<T>(files: OutputFiles, stdout: &mut dyn Write, run: impl FnOnce(&mut Io) -> T) -> T {
+    let OutputFiles {
+        stdout: mut out_file,
+        stderr: mut err_file,
+        ..
+    } = files;
+    let (mut out_sink, mut err_sink) = (std::io::sink(), std::io::sink());
+    let (mut out_stderr, mut stderr) = (std::io::stderr(), std::io::stderr());
+
+    let (out, unused_stdout): (&mut dyn Write, Option<&mut dyn Write>) = match &mut out_file {
+        Output::File(file) => (file, Some(stdout)),
+        Output::Closed => (&mut out_sink, Some(stdout)),
+        Output::Stream(2) => (&mut out_stderr, Some(stdout)),
+        Output::Stream(_) => (stdout, None),
+    };
+    let err: Option<&mut dyn Write> = match &mut err_file {
+        Output::File(file) => Some(file),
+        Output::Closed => Some(&mut err_sink),
+        // With `2>&1` and the output on stdout too, errors share its writer
+        Output::Stream(1) => unused_stdout,
+        Output::Stream(_) => Some(&mut stderr),
+    };
+    run(&mut Io::new(out, err))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::TempDir;
+    use crate::tokenize::tokenize;
+
+    fn command(input: &str) -> ParsedCommand {
+        parse_command(tokenize(input).unwrap()).unwrap()
+    }
+
+    fn pipeline(input: &str) -> Result<Pipeline, ParseError> {
+        parse_pipeline(tokenize(input).unwrap())
+    }
+
+    /// The output redirections of `parsed` in order, written as `fd>target`, with
+    /// `>>`, `>|` or `<>` in place of `>` where they were used.
+    fn redirects(parsed: &ParsedCommand) -> Vec<String> {
+        let operator = |r: &Redirection| match (r.append, r.force, r.read_write, &r.target) {
+            (true, _, _, _) => ">>",
+            (_, true, _, _) => ">|",
+            (_, _, true, _) => "<>",
+            (_, _, _, RedirTarget::HereDoc(_)) => "<<",
+            (_, _, _, RedirTarget::HereString(_)) => "<<<",
+            _ if r.fd == 0 => "<",
+            _ => ">",
+        };
+        parsed
+            .redirects
+            .iter()
+            .map(|r| format!("{}{}{}", r.fd, operator(r), r.target))
+            .collect()
+    }
 
     #[test]
     fn test_parse_stdout_redirect() {
-        let tokens = vec![
-            "echo".to_string(),
-            "hi".to_string(),
-            ">".to_string(),
-            "out.txt".to_string(),
-        ];
-        let parsed = parse_command(tokens);
+        let parsed = command("echo hi > out.txt");
         assert_eq!(parsed.args, vec!["echo", "hi"]);
-        assert!(parsed.redirect_stdout.is_some());
-        assert_eq!(parsed.redirect_stdout.unwrap().file, "out.txt");
+        assert_eq!(redirects(&parsed), vec!["1>out.txt"]);
+    }
+
+    #[test]
+    fn test_parse_assignments() {
+        let assignment = |name: &str, append, value: &str| Assignment {
+            name: name.to_string(),
+            index: None,
+            append,
+            value: value.to_string(),
+        };
+        let parsed = command("A=1 _b=\"x y\" C+=2 2c=3 cmd D=4");
+        assert_eq!(
+            parsed.assignments,
+            vec![
+                assignment("A", false, "1"),
+                assignment("_b", false, "x y"),
+                assignment("C", true, "2"),
+            ]
+        );
+        assert_eq!(parsed.args, vec!["2c=3", "cmd", "D=4"]);
+
+        let parsed = command("EMPTY= > out.txt");
+        assert_eq!(parsed.assignments, vec![assignment("EMPTY", false, "")]);
+        assert!(parsed.args.is_empty());
+        assert_eq!(command("ls a=b").args, vec!["ls", "a=b"]);
+
+        let parsed = command("A[1]=x A[i+1]+=y 1[2]=z");
+        let index = |index: &str| Some(index.to_string());
+        assert_eq!(parsed.assignments[0].index, index("1"));
+        assert_eq!(parsed.assignments[1].index, index("i+1"));
+        assert!(parsed.assignments[1].append);
+        assert_eq!(parsed.args, vec!["1[2]=z"]);
     }
 
     #[test]
     fn test_parse_stderr_redirect() {
-        let tokens = vec!["ls".to_string(), "2>".to_string(), "err.txt".to_string()];
-        let parsed = parse_command(tokens);
-        assert!(parsed.redirect_stderr.is_some());
+        let parsed = command("ls 2> err.txt");
+        assert_eq!(redirects(&parsed), vec!["2>err.txt"]);
+    }
+
+    #[test]
+    fn test_parse_pipeline_stages() {
+        let pipeline = pipeline("cat f | sort | uniq > out.txt").unwrap();
+        assert_eq!(pipeline.commands.len(), 3);
+        assert_eq!(pipeline.commands[0].args, vec!["cat", "f"]);
+        assert_eq!(pipeline.commands[1].args, vec!["sort"]);
+        assert_eq!(redirects(&pipeline.commands[2]), vec!["1>out.txt"]);
+    }
+
+    #[test]
+    fn test_parse_pipeline_segments() {
+        let split = pipeline("echo 'a|b' \"|\" | tr a b").unwrap();
+        assert_eq!(split.commands.len(), 2);
+        assert_eq!(split.commands[0].args, vec!["echo", "a|b", "|"]);
+
+        // Each stage keeps its own redirections, which win over the pipe
+        let middle = pipeline("cat < in | sort 2> err > out | wc -l &").unwrap();
+        assert_eq!(middle.commands.len(), 3);
+        assert_eq!(middle.commands[1].args, vec!["sort"]);
+        assert_eq!(redirects(&middle.commands[1]), vec!["2>err", "1>out"]);
+        assert_eq!(redirects(&middle.commands[0]), vec!["0<in"]);
+        assert!(middle.background);
+
+        for input in ["a | | b", "| a"] {
+            assert_eq!(
+                pipeline(input).unwrap_err().to_string(),
+                "syntax error near unexpected token `|'"
+            );
+        }
+        assert_eq!(pipeline("a |").unwrap_err(), ParseError::UnexpectedEof);
+        assert_eq!(pipeline("a |\n\n b").unwrap().commands[1].args, vec!["b"]);
+    }
+
+    #[test]
+    fn test_parse_pipeline_rejects_semicolon() {
+        let err = pipeline("pwd; ls").unwrap_err();
+        assert_eq!(err.to_string(), "syntax error near unexpected token `;'");
+        assert_eq!(err.column(), Some(4));
+    }
+
+    #[test]
+    fn test_parse_pipeline_rejects_process_substitution() {
+        for input in ["diff <(sort a) <(sort b)", "cat < <(ls)", "ls > >(cat)"] {
+            assert_eq!(
+                pipeline(input).unwrap_err(),
+                ParseError::ProcessSubstitution
+            );
+        }
+    }
+
+    #[test]
+    fn test_operator_after_redirection_is_an_error() {
+        let cases = [
+            (">>>file", ">"),
+            ("echo a>>>>b", ">>"),
+            ("echo a > > f", ">"),
+            ("echo a >> | b", "|"),
+            ("echo a < > f", ">"),
+            ("echo a 2> &> f", "&>"),
+        ];
+        for (input, token) in cases {
+            match pipeline(input) {
+                Err(ParseError::UnexpectedToken { token: t, .. }) => {
+                    assert_eq!(t, token, "{}", input)
+                }
+                other => panic!("{}: {:?}", input, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_redirections_keep_their_order() {
+        // Redirections of the same fd are all kept, to be applied in turn
+        let parsed = command("echo hi > a 2> e >> b 2>&1 > c");
+        assert_eq!(
+            redirects(&parsed),
+            vec!["1>a", "2>e", "1>>b", "2>&1", "1>c"]
+        );
+        assert_eq!(redirects(&command("echo hi > a >&2")), vec!["1>a", "1>&2"]);
+    }
+
+    #[test]
+    fn test_open_applies_redirections_in_order() {
+        let dir = TempDir::new("redirect_order");
+        let file = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let open = |input: String| OutputFiles::open(&command(&input), false);
+
+        // Every file is created, and the last redirection of each fd wins
+        let files = open(format!(
+            "echo > {} 2> {} > {}",
+            file("a"),
+            file("e"),
+            file("b")
+        ))
+        .unwrap();
+        assert!(matches!(files.stdout, Output::File(_)));
+        assert!(matches!(files.stderr, Output::File(_)));
+        for name in ["a", "e", "b"] {
+            assert!(dir.join(name).exists(), "{}", name);
+        }
+
+        let files = open(format!("echo 2>&1 > {}", file("a"))).unwrap();
+        assert!(matches!(files.stdout, Output::File(_)));
+        assert!(matches!(files.stderr, Output::Stream(1)));
+        let files = open("echo 2>&1 1>&2".to_string()).unwrap();
+        assert!(matches!(files.stdout, Output::Stream(1)));
+        assert!(matches!(files.stderr, Output::Stream(1)));
+        let files = open("echo >&- 2>&1".to_string()).unwrap();
+        assert!(matches!(files.stderr, Output::Closed));
+
+        // Nothing after a failed redirection is opened
+        let err = open(format!("echo > {}/missing/x > {}", file(""), file("c"))).unwrap_err();
+        assert!(err.ends_with("No such file or directory"), "{}", err);
+        assert!(!dir.join("c").exists());
+        assert_eq!(
+            open(format!("exec 3> {} 10> {}", file("c"), file("d"))).unwrap_err(),
+            "3: bad file descriptor"
+        );
+    }
+
+    #[test]
+    fn test_open_applies_input_redirections_in_order() {
+        let dir = TempDir::new("input_order");
+        let input = dir.join("in").to_string_lossy().into_owned();
+        std::fs::write(&input, "text\n").unwrap();
+        let out = dir.join("out").to_string_lossy().into_owned();
+        let open = |line: String| OutputFiles::open(&command(&line), false);
+
+        // A missing input stops the command, even with a later one that exists
+        let err = open(format!("cat < /nonexistent < {} > {}", input, out)).unwrap_err();
+        assert_eq!(err, "/nonexistent: No such file or directory");
+        assert!(!dir.join("out").exists());
+        let err = open(format!("cat < {} < /nonexistent", input)).unwrap_err();
+        assert_eq!(err, "/nonexistent: No such file or directory");
+
+        // The last input redirection is the one read
+        let files = open(format!("cat <<< word < {}", input)).unwrap();
+        assert!(matches!(files.stdin, Input::File(_)));
+        let files = open(format!("cat < {} <<< word", input)).unwrap();
+        assert!(matches!(files.stdin, Input::Text(text) if text == "word\n"));
+        let files = open(format!("cat < {} <&-", input)).unwrap();
+        assert!(matches!(files.stdin, Input::Closed));
+    }
+
+    #[test]
+    fn test_escaped_spaces_in_arguments_and_targets() {
+        let parsed = command("cat my\\ file > my\\ out");
+        assert_eq!(parsed.args, vec!["cat", "my file"]);
+        assert_eq!(redirects(&parsed), vec!["1>my out"]);
+    }
+
+    #[test]
+    fn test_array_assignments() {
+        let parsed = command("list=(a 'b c'\nd) more+=() x=1");
+        assert_eq!(
+            parsed.arrays,
+            vec![
+                ArrayAssignment {
+                    name: "list".to_string(),
+                    append: false,
+                    values: vec!["a".to_string(), "b c".to_string(), "d".to_string()],
+                },
+                ArrayAssignment {
+                    name: "more".to_string(),
+                    append: true,
+                    values: vec![],
+                },
+            ]
+        );
+        assert_eq!(parsed.assignments.len(), 1);
+        assert_eq!(parsed.assignments[0].value, "1");
+        assert!(parsed.args.is_empty());
+
+        assert!(parse_command(tokenize("list=(a >b)").unwrap()).is_err());
+        // Only directly after `NAME=` does `(` start an array
+        assert!(parse_command(tokenize("list= (a)").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_parse_pipeline_rejects_logical_operators() {
+        let err = pipeline("make && ./run").unwrap_err();
+        assert_eq!(
+            err,
+            ParseError::UnexpectedToken {
+                token: "&&".to_string(),
+                column: 6
+            }
+        );
+    }
+
+    #[test]
+    fn test_trailing_ampersand_sets_background() {
+        let background = pipeline("sleep 10 &").unwrap();
+        assert_eq!(background.commands[0].args, vec!["sleep", "10"]);
+        assert!(background.background);
+        for input in ["a | &", "a > &"] {
+            assert_eq!(
+                pipeline(input).unwrap_err().to_string(),
+                "syntax error near unexpected token `&'"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_combined_redirect() {
+        let parsed = command("cmd &>> all.log");
+        assert_eq!(redirects(&parsed), vec!["1>>all.log", "2>&1"]);
+    }
+
+    #[test]
+    fn test_parse_stdin_redirect() {
+        let parsed = command("wc < in.txt");
+        assert_eq!(parsed.args, vec!["wc"]);
+        assert_eq!(redirects(&parsed), vec!["0<in.txt"]);
+    }
+
+    #[test]
+    fn test_parse_herestring() {
+        let parsed = command("wc -c <<< hello > out");
+        assert_eq!(parsed.args, vec!["wc", "-c"]);
+        assert_eq!(redirects(&parsed), vec!["0<<<hello", "1>out"]);
+        let files = OutputFiles::open(&command("wc -c <<< hello"), false).unwrap();
+        assert!(matches!(files.stdin, Input::Text(text) if text == "hello\n"));
+    }
+
+    #[test]
+    fn test_fd_prefix_must_be_a_separate_number() {
+        let parsed = command("cat file2>out");
+        assert_eq!(parsed.args, vec!["cat", "file2"]);
+        assert_eq!(redirects(&parsed), vec!["1>out"]);
+
+        let parsed = command("foo 2>out");
+        assert_eq!(parsed.args, vec!["foo"]);
+        assert_eq!(redirects(&parsed), vec!["2>out"]);
+        assert_eq!(redirects(&command("foo 2> out")), vec!["2>out"]);
+
+        let parsed = command("echo 2 > out");
+        assert_eq!(parsed.args, vec!["echo", "2"]);
+        assert_eq!(redirects(&parsed), vec!["1>out"]);
+    }
+
+    #[test]
+    fn test_other_fds_are_kept() {
+        let parsed = command("exec 10> trace.log 3>> out 3> again");
+        assert_eq!(parsed.args, vec!["exec"]);
+        assert_eq!(
+            redirects(&parsed),
+            vec!["10>trace.log", "3>>out", "3>again"]
+        );
+    }
+
+    #[test]
+    fn test_unsupported_fd_is_an_error() {
+        assert_eq!(
+            pipeline("cat 1< in").unwrap_err(),
+            ParseError::UnsupportedFd(1)
+        );
+    }
+
+    #[test]
+    fn test_quoted_operator_is_an_argument() {
+        let parsed = command("echo '>' \"|\" x");
+        assert_eq!(parsed.args, vec!["echo", ">", "|", "x"]);
+        assert!(parsed.redirects.is_empty());
+
+        let parsed = command("grep '>' file.txt");
+        assert_eq!(parsed.args, vec!["grep", ">", "file.txt"]);
+        assert!(parsed.redirects.is_empty());
+    }
+
+    #[test]
+    fn test_redirect_without_target() {
+        let err = pipeline("echo hi >").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "syntax error near unexpected token `newline'"
+        );
+        assert_eq!(err.column(), Some(10));
+        let err = pipeline("cat < | wc").unwrap_err();
+        assert_eq!(err.to_string(), "syntax error near unexpected token `|'");
+        assert_eq!(err.column(), Some(7));
+        let err = pipeline("| foo").unwrap_err();
+        assert_eq!(err.to_string(), "syntax error near unexpected token `|'");
+        assert_eq!(err.column(), Some(1));
+        let err = pipeline("echo hi 2>>").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "syntax error near unexpected token `newline'"
+        );
+
+        let parsed = command("echo hi >> file");
+        assert_eq!(parsed.args, vec!["echo", "hi"]);
+        assert_eq!(redirects(&parsed), vec!["1>>file"]);
+    }
+
+    #[test]
+    fn test_render_syntax_error() {
+        let render = |input: &str, caret| {
+            let err = pipeline(input).unwrap_err();
+            let column = if caret { err.column() } else { None };
+            render_syntax_error("myshell", &err.to_string(), input, column)
+        };
+        assert_eq!(
+            render("echo >", false),
+            "myshell: syntax error near unexpected token `newline'"
+        );
+        assert_eq!(
+            render("echo a > | b", true),
+            "myshell: syntax error near unexpected token `|'\necho a > | b\n         ^"
+        );
+        assert_eq!(
+            render_syntax_error("sh", "oops", "if x\nthen é ;; y\nfi", Some(14)),
+            "sh: oops\nthen é ;; y\n       ^"
+        );
+        assert_eq!(render_syntax_error("sh", "oops", "x", None), "sh: oops");
+    }
+
+    fn lines(input: &[&str]) -> impl FnMut() -> Option<String> {
+        let mut lines: Vec<String> = input.iter().rev().map(|s| s.to_string()).collect();
+        move || lines.pop()
+    }
+
+    /// The first here-document of the command `input`.
+    fn heredoc(input: &str) -> HereDoc {
+        command(input)
+            .redirects
+            .into_iter()
+            .find_map(|redirection| match redirection.target {
+                RedirTarget::HereDoc(heredoc) => Some(heredoc),
+                _ => None,
+            })
+            .expect("expected a here-document")
+    }
+
+    #[test]
+    fn test_read_heredoc_body() {
+        let mut heredoc = heredoc("cat <<- EOF");
+        read_heredoc_body(&mut heredoc, lines(&["\tone", "two", "\tEOF", "after"])).unwrap();
+        assert_eq!(heredoc.body, "one\ntwo\n");
+        assert!(heredoc.expand);
+    }
+
+    #[test]
+    fn test_every_heredoc_is_kept() {
+        let parsed = command("cat <<A <<-'B' > out");
+        assert_eq!(redirects(&parsed), vec!["0<<A", "0<<B", "1>out"]);
+    }
+
+    #[test]
+    fn test_quoted_heredoc_delimiter() {
+        for input in [
+            "cat <<'EOF'",
+            "cat <<\"EOF\"",
+            "cat <<E\"O\"F",
+            "cat <<\\EOF",
+        ] {
+            let heredoc = heredoc(input);
+            assert_eq!(heredoc.delimiter, "EOF");
+            assert!(!heredoc.expand, "{}", input);
+        }
+
+        // The delimiter line must match exactly; only `<<-` strips leading tabs
+        let mut heredoc = HereDoc {
+            delimiter: "EOF".to_string(),
+            ..HereDoc::default()
+        };
+        read_heredoc_body(&mut heredoc, lines(&[" EOF", "\tEOF", "EOF "])).unwrap_err();
+        assert_eq!(heredoc.body, " EOF\n\tEOF\nEOF \n");
+    }
+
+    #[test]
+    fn test_heredoc_missing_delimiter() {
+        let mut heredoc = HereDoc {
+            delimiter: "EOF".to_string(),
+            ..HereDoc::default()
+        };
+        let err = read_heredoc_body(&mut heredoc, lines(&["one", " EOF"])).unwrap_err();
+        assert_eq!(
+            err,
+            "warning: here-document delimited by end-of-file (wanted `EOF')"
+        );
+        assert_eq!(heredoc.body, "one\n EOF\n");
+    }
+
+    #[test]
+    fn test_duplicate_follows_redirection_order() {
+        // stderr copies stdout after it went to the file...
+        let parsed = command("cmd > out.txt 2>&1");
+        assert_eq!(redirects(&parsed), vec!["1>out.txt", "2>&1"]);
+
+        // ...or before, keeping the stdout the command had
+        let parsed = command("cmd 2>&1 > out.txt");
+        assert_eq!(redirects(&parsed), vec!["2>&1", "1>out.txt"]);
+        let parsed = command("cmd 2>&1 1>&2");
+        assert_eq!(redirects(&parsed), vec!["2>&1", "1>&2"]);
+    }
+
+    #[test]
+    fn test_parse_duplicate_redirects() {
+        let parsed = command("cmd 1>&2 2>&- <&-");
+        assert_eq!(redirects(&parsed), vec!["1>&2", "2>&-", "0<&-"]);
+
+        let parsed = command("cmd >& all.log");
+        assert_eq!(redirects(&parsed), vec!["1>all.log", "2>&1"]);
+    }
+
+    #[test]
+    fn test_bad_duplicate_targets() {
+        assert_eq!(
+            pipeline("cmd 2>&5").unwrap_err(),
+            ParseError::UnsupportedFd(5)
+        );
+        assert_eq!(
+            pipeline("cmd 2>& file").unwrap_err(),
+            ParseError::AmbiguousRedirect("file".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_clobber_redirect() {
+        let parsed = command("echo hi >| out.txt 2>| err.txt");
+        assert_eq!(redirects(&parsed), vec!["1>|out.txt", "2>|err.txt"]);
+    }
+
+    #[test]
+    fn test_open_output_without_clobber() {
+        let dir = TempDir::new("noclobber");
+        let path = dir.join("out.txt");
+        let file = path.as_path();
+        let write_to_file = |file: &Path, content: &str, append, clobber| {
+            open_output(file, append, clobber).and_then(|mut f| f.write_all(content.as_bytes()))
+        };
+
+        // A file that does not exist yet is created either way
+        write_to_file(file, "new\n", false, false).unwrap();
+        let err = write_to_file(file, "again\n", false, false).unwrap_err();
+        assert_eq!(io_error_message(&err), "cannot overwrite existing file");
+        // Appending is never blocked
+        write_to_file(file, "more\n", true, false).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new\nmore\n");
+
+        write_to_file(file, "forced\n", false, true).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "forced\n");
+        open_output(Path::new("/dev/null"), false, false).unwrap();
+    }
+
+    #[test]
+    fn test_read_write_redirection() {
+        let parsed = command("cat <> in 1<>out 3<> other");
+        assert_eq!(redirects(&parsed), vec!["0<>in", "1<>out", "3<>other"]);
+
+        let dir = TempDir::new("read_write");
+        let path = dir.join("file.txt");
+        // A missing file is created, and an existing one written over without truncating
+        open_read_write(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        std::fs::write(&path, "abcdef").unwrap();
+        open_read_write(&path).unwrap().write_all(b"xy").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "xycdef");
+    }
+
+    #[test]
+    fn test_open_missing_input() {
+        let err = open_input("/nonexistent/input.txt").unwrap_err();
+        assert_eq!(err, "/nonexistent/input.txt: No such file or directory");
     }
 }
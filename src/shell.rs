@@ -0,0 +1,464 @@
+use crate::alias::AliasMap;
+use crate::arith;
+use crate::ast::List;
+use crate::attributes::Attributes;
+use crate::encoding;
+use crate::execute::capture_output;
+use crate::jobs::JobTable;
+use crate::options::ShellOptions;
+use crate::tokenize::Expander;
+use crate::trap::Traps;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::ffi::OsString;
+use std::io::PipeWriter;
+use std::os::fd::OwnedFd;
+use std::path::PathBuf;
+
+/// The elements of an indexed array by index. There can be gaps: after `a[5]=x` on an
+/// empty array, `a` has the single element 5.
+pub type Array = BTreeMap<usize, String>;
+
+/// Indexed arrays by name, as assigned with `name=(word...)` or `name[index]=value`.
+pub type ArrayStore = HashMap<String, Array>;
+
+/// What a variable was before `local` or an assignment in front of a command replaced
+/// it, restored when the function returns or the command is done.
+#[derive(Debug, Default)]
+pub struct SavedVariable {
+    pub value: Option<String>,
+    pub array: Option<Array>,
+    pub attributes: Option<Attributes>,
+    pub exported: bool,
+}
+
+/// A function defined with `name() { ... }`, as a call runs it.
+#[derive(Debug, Clone, Default)]
+pub struct Function {
+    pub body: List,
+    /// Source text of the redirections after the body, applied on every call.
+    pub redirects: String,
+    /// The input lines holding the bodies of the here-documents among `redirects`,
+    /// read once where the function is defined and read again by every call.
+    pub heredoc_lines: Vec<String>,
+}
+
+/// State that persists across commands for the lifetime of the shell.
+#[derive(Debug, Default)]
+pub struct Shell {
+    /// Variables by name, starting with the inherited environment. Only those named in
+    /// `exported` reach the environment of child processes.
+    pub variables: HashMap<String, String>,
+    /// Names of variables marked for export to child processes.
+    pub exported: HashSet<String>,
+    /// Aliases defined with `alias`.
+    pub aliases: AliasMap,
+    /// Command lines entered so far, including those loaded from the history file.
+    pub history: Vec<String>,
+    /// Number of history entries already persisted by `history -a`/`-w`.
+    pub history_written: usize,
+    /// Set when `history` rewrites the list, so the line editor can resync.
+    pub history_modified: bool,
+    /// Set by `exit`; the shell stops reading input once it is present.
+    pub exit_code: Option<i32>,
+    /// Exit status of the most recently executed command, reported by `$?`.
+    pub last_status: i32,
+    /// Status set by a builtin such as `fg` that reports something other than 0 or 1.
+    pub builtin_status: Option<i32>,
+    /// Status of the last command substitution in the command being expanded, which
+    /// is the status of a command made only of assignments.
+    pub substitution_status: Option<i32>,
+    /// The shell's own process id, reported by `$$`.
+    pub pid: u32,
+    /// Process id of the most recent background command, reported by `$!`.
+    pub last_background_pid: Option<u32>,
+    /// Name of the shell or of the script being sourced, reported by `$0`.
+    pub arg0: String,
+    /// Commands started in the background with `&`.
+    pub jobs: JobTable,
+    /// Where command output goes while it is captured in place of the shell's stdout.
+    pub stdout: Option<PipeWriter>,
+    /// Redirected input of the builtin being run, for `read`; unset means the shell's stdin.
+    pub stdin: Option<OwnedFd>,
+    /// Options turned on with `set`.
+    pub options: ShellOptions,
+    /// Number of `if`, `while` and `until` conditions running, where `set -e` does not apply.
+    pub condition_depth: usize,
+    /// Number of `while`/`until` loops currently running, which bounds `break N`.
+    pub loop_depth: usize,
+    /// Set by `break`, `continue` or `return` for the running loops and function to act on.
+    pub loop_control: Option<LoopControl>,
+    /// Functions defined with `name() { ... }` or `function name { ... }`.
+    pub functions: HashMap<String, Function>,
+    /// Number of function calls currently running, which `return` needs at least one of
+    /// outside a sourced file.
+    pub function_depth: usize,
+    /// Number of files being run by `source` or `.`, which `return` can also leave.
+    pub source_depth: usize,
+    /// For each running function call, the variables it made local with what they were
+    /// before, innermost call last.
+    pub locals: Vec<HashMap<String, SavedVariable>>,
+    /// Arguments of the running function, reported by `$1`, `$2`, ..., `$@` and `$#`.
+    pub positional: Vec<String>,
+    /// Array variables. `$name` of an array is its first element.
+    pub arrays: ArrayStore,
+    /// Attributes given to variables with `declare`, such as integer and readonly.
+    pub attributes: HashMap<String, Attributes>,
+    /// Set when commands are read from a terminal, where syntax errors show the column.
+    pub interactive: bool,
+    /// Set in an interactive shell, which runs each foreground job in a process group
+    /// of its own that gets the terminal. Subshells and command substitutions go without.
+    pub job_control: bool,
+    /// Directories saved by `pushd`, the top of the stack last. The current directory
+    /// is not part of it.
+    pub dir_stack: Vec<PathBuf>,
+    /// Commands set with `trap` to run on signals, on exit and after failures.
+    pub traps: Traps,
+    /// Where `getopts` stopped inside a group of options like `-abc`: the `OPTIND` it
+    /// left and the index of the next letter, which only counts while `OPTIND` is unchanged.
+    pub getopts_position: (usize, usize),
+}
+
+/// A request from `break N` or `continue N` to leave the innermost N loops,
+/// or to go on with the next iteration of the Nth, or from `return` to leave the
+/// running function along with any loops inside it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoopControl {
+    Break(usize),
+    Continue(usize),
+    Return,
+}
+
+impl LoopControl {
+    /// What is left for the enclosing loop once the innermost one has handled its part.
+    pub fn outer(self) -> Option<LoopControl> {
+        match self {
+            LoopControl::Break(n) if n > 1 => Some(LoopControl::Break(n - 1)),
+            LoopControl::Continue(n) if n > 1 => Some(LoopControl::Continue(n - 1)),
+            LoopControl::Return => Some(LoopControl::Return),
+            _ => None,
+        }
+    }
+}
+
+impl Shell {
+    /// Creates the shell state, treating every inherited environment variable as exported.
+    /// `PPID` and `UID` are set from the process and made readonly, and `OPTIND` starts
+    /// at 1 unless it is inherited.
+    pub fn new() -> Self {
+        let variables: HashMap<String, String> = env_vars().collect();
+        let mut shell = Self {
+            exported: variables.keys().cloned().collect(),
+            variables,
+            aliases: AliasMap::new(),
+            pid: std::process::id(),
+            arg0: env::args().next().unwrap_or_default(),
+            ..Self::default()
+        };
+        let ppid = nix::unistd::getppid().to_string();
+        let uid = nix::unistd::getuid().to_string();
+        for (name, value) in [("PPID", ppid), ("UID", uid)] {
+            shell.set_var(name, &value);
+            shell
+                .attributes
+                .insert(name.to_string(), Attributes::READONLY);
+        }
+        if shell.var("OPTIND").is_none() {
+            shell.set_var("OPTIND", "1");
+        }
+        shell
+    }
+
+    /// The name the shell reports errors under: the file name of `$0`.
+    pub fn name(&self) -> String {
+        std::path::Path::new(&self.arg0).file_name().map_or_else(
+            || self.arg0.clone(),
+            |name| name.to_string_lossy().into_owned(),
+        )
+    }
+
+    /// The value of the variable `name`, leaving out arrays and special parameters.
+    pub fn var(&self, name: &str) -> Option<&str> {
+        self.variables.get(name).map(String::as_str)
+    }
+
+    /// Sets the variable `name` to `value` as is, without checking its attributes.
+    pub fn set_var(&mut self, name: &str, value: &str) {
+        self.variables.insert(name.to_string(), value.to_string());
+    }
+
+    /// Removes the value of the variable `name`, keeping its attributes.
+    pub fn unset_var(&mut self, name: &str) {
+        self.variables.remove(name);
+    }
+
+    /// The environment child processes get: the exported variables that have a value.
+    pub fn environment(&self) -> impl Iterator<Item = (OsString, OsString)> {
+        self.exported.iter().filter_map(|name| {
+            let value = self.variables.get(name)?;
+            Some((encoding::encode(name), encoding::encode(value)))
+        })
+    }
+
+    /// Removes the variable `name` with its attributes, returning what it was.
+    pub fn take_variable(&mut self, name: &str) -> SavedVariable {
+        let saved = self.save_variable(name);
+        self.arrays.remove(name);
+        self.attributes.remove(name);
+        self.exported.remove(name);
+        self.variables.remove(name);
+        saved
+    }
+
+    /// What the variable `name` is now, to be put back with `restore_variable`.
+    pub fn save_variable(&self, name: &str) -> SavedVariable {
+        SavedVariable {
+            value: self.variables.get(name).cloned(),
+            array: self.arrays.get(name).cloned(),
+            attributes: self.attributes.get(name).copied(),
+            exported: self.exported.contains(name),
+        }
+    }
+
+    /// Puts back a variable removed by `take_variable`, replacing any new value.
+    pub fn restore_variable(&mut self, name: &str, saved: SavedVariable) {
+        self.take_variable(name);
+        if let Some(value) = saved.value {
+            self.set_var(name, &value);
+        }
+        if let Some(array) = saved.array {
+            self.arrays.insert(name.to_string(), array);
+        }
+        if let Some(attributes) = saved.attributes {
+            self.attributes.insert(name.to_string(), attributes);
+        }
+        if saved.exported {
+            self.exported.insert(name.to_string());
+        }
+    }
+
+    /// Whether the `noclobber` option keeps `>` from overwriting existing files.
+    pub fn noclobber(&self) -> bool {
+        self.options.contains(ShellOptions::NOCLOBBER)
+    }
+
+    /// Returns the attributes of the variable `name`, if any have been given.
+    pub fn attributes(&self, name: &str) -> Attributes {
+        self.attributes.get(name).copied().unwrap_or_default()
+    }
+
+    /// Assigns `value` to the variable `name`, or with `append` adds it to the current
+    /// value. Integer variables take the value of `value` as an arithmetic expression, and
+    /// an array has its first element set. Fails for a readonly variable.
+    pub fn set_variable(&mut self, name: &str, value: &str, append: bool) -> Result<(), String> {
+        let current = if append { self.parameter(name) } else { None };
+        let value = self.assigned_value(name, current, value)?;
+        match self.arrays.get_mut(name) {
+            Some(elements) => {
+                elements.insert(0, value);
+            }
+            None => self.set_var(name, &value),
+        }
+        Ok(())
+    }
+
+    /// Assigns `value` to element `index` of the array `name`, as `set_variable` assigns
+    /// a variable. `index` is an arithmetic expression, negative to count back from the
+    /// end. A variable that is not an array becomes one, its value the element 0.
+    pub fn set_element(
+        &mut self,
+        name: &str,
+        index: &str,
+        value: &str,
+        append: bool,
+    ) -> Result<(), String> {
+        let index = self.array_index(name, index)?;
+        let current = match self.arrays.get(name) {
+            _ if !append => None,
+            Some(elements) => elements.get(&index).cloned(),
+            None => self.var(name).filter(|_| index == 0).map(str::to_string),
+        };
+        let value = self.assigned_value(name, current, value)?;
+        let elements = self.arrays.entry(name.to_string()).or_insert_with(|| {
+            let value = self.variables.remove(name);
+            value.map(|value| (0, value)).into_iter().collect()
+        });
+        elements.insert(index, value);
+        Ok(())
+    }
+
+    /// Removes element `index` of the array `name`, or the variable itself for an index
+    /// of 0 if it is not an array. Fails for a readonly variable.
+    pub fn unset_element(&mut self, name: &str, index: &str) -> Result<(), String> {
+        if self.attributes(name).contains(Attributes::READONLY) {
+            return Err(format!("{}: cannot unset: readonly variable", name));
+        }
+        let index = self.array_index(name, index)?;
+        match self.arrays.get_mut(name) {
+            Some(elements) => {
+                elements.remove(&index);
+            }
+            None if index == 0 => self.unset_var(name),
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Evaluates the subscript `index` of the array `name`. A negative index counts back
+    /// from one past the highest index in use.
+    fn array_index(&mut self, name: &str, index: &str) -> Result<usize, String> {
+        let bad_subscript = || format!("{}[{}]: bad array subscript", name, index);
+        let number = arith::evaluate(index, |name| self.parameter(name))
+            .map_err(|message| format!("{}: {}", index.trim(), message))?;
+        let end = match self.arrays.get(name) {
+            Some(elements) => elements.keys().next_back().map_or(0, |last| last + 1),
+            None => usize::from(self.variables.contains_key(name)),
+        };
+        let number = if number < 0 {
+            i64::try_from(end).map_err(|_| bad_subscript())? + number
+        } else {
+            number
+        };
+        usize::try_from(number).map_err(|_| bad_subscript())
+    }
+
+    /// The value an assignment of `value` gives the variable `name`, which is `current`
+    /// now if it is being appended to. Fails for a readonly variable.
+    fn assigned_value(
+        &mut self,
+        name: &str,
+        current: Option<String>,
+        value: &str,
+    ) -> Result<String, String> {
+        let attributes = self.attributes(name);
+        if attributes.contains(Attributes::READONLY) {
+            return Err(format!("{}: readonly variable", name));
+        }
+        Ok(if attributes.contains(Attributes::INTEGER) {
+            let number = arith::evaluate(value, |name| self.parameter(name))
+                .map_err(|message| format!("{}: {}", value.trim(), message))?;
+            let current = current.and_then(|current| current.parse::<i64>().ok());
+            current.unwrap_or(0).wrapping_add(number).to_string()
+        } else {
+            current.unwrap_or_default() + value
+        })
+    }
+
+    /// Loads entries from the history file, if it exists.
+    pub fn load_history(&mut self) {
+        if let Some(path) = self.history_file()
+            && let Ok(content) = std::fs::read_to_string(path)
+        {
+            self.append_history_lines(&content);
+            self.history_written = self.history.len();
+        }
+    }
+
+    /// Writes the full history list to the history file.
+    pub fn save_history(&self) {
+        if let Some(path) = self.history_file() {
+            let _ = std::fs::write(path, self.history_content(0));
+        }
+    }
+
+    /// Appends every non-empty line of `content` to the history list.
+    pub fn append_history_lines(&mut self, content: &str) {
+        self.history.extend(
+            content
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(str::to_string),
+        );
+    }
+
+    /// Returns the file history persists to: `$HISTFILE`, or `~/.shell_history` by default.
+    pub fn history_file(&self) -> Option<PathBuf> {
+        match self.var("HISTFILE") {
+            Some(path) => Some(encoding::path(path)),
+            None => self
+                .var("HOME")
+                .map(|home| encoding::path(home).join(".shell_history")),
+        }
+    }
+
+    /// Renders history entries from `start` onwards in history-file format.
+    pub fn history_content(&self, start: usize) -> String {
+        self.history
+            .iter()
+            .skip(start)
+            .map(|entry| format!("{}\n", entry))
+            .collect()
+    }
+}
+
+impl Expander for Shell {
+    fn parameter(&mut self, name: &str) -> Option<String> {
+        match name {
+            "?" => Some(self.last_status.to_string()),
+            "$" => Some(self.pid.to_string()),
+            "!" => self.last_background_pid.map(|pid| pid.to_string()),
+            "0" => Some(self.arg0.clone()),
+            "@" | "*" => Some(self.positional.join(" ")),
+            "#" => Some(self.positional.len().to_string()),
+            "-" => Some(self.options.flags()),
+            name if name.starts_with(|c: char| c.is_ascii_digit()) => name
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| self.positional.get(n.checked_sub(1)?).cloned()),
+            name => match self.arrays.get(name) {
+                Some(elements) => elements.get(&0).cloned(),
+                None => self.variables.get(name).cloned(),
+            },
+        }
+    }
+
+    fn array(&mut self, name: &str) -> Option<Vec<String>> {
+        match name {
+            "@" => Some(self.positional.clone()),
+            name => self
+                .arrays
+                .get(name)
+                .map(|elements| elements.values().cloned().collect()),
+        }
+    }
+
+    fn element(&mut self, name: &str, index: i64) -> Option<String> {
+        if name == "@" {
+            let elements = self.positional.clone();
+            let index = if index < 0 {
+                elements.len() as i64 + index
+            } else {
+                index
+            };
+            return elements.get(usize::try_from(index).ok()?).cloned();
+        }
+        let elements = self.arrays.get(name)?;
+        let index = if index < 0 {
+            let end = elements.keys().next_back().map_or(0, |last| last + 1);
+            i64::try_from(end).ok()? + index
+        } else {
+            index
+        };
+        elements.get(&usize::try_from(index).ok()?).cloned()
+    }
+
+    fn assign(&mut self, name: &str, value: &str) {
+        if let Err(e) = self.set_variable(name, value, false) {
+            eprintln!("{}: {}", self.name(), e);
+        }
+    }
+
+    fn nounset(&self) -> bool {
+        self.options.contains(ShellOptions::NOUNSET)
+    }
+
+    fn command_output(&mut self, command: &str) -> String {
+        capture_output(self, command)
+    }
+}
+
+/// The variables in the process environment. Names and values that are not valid
+/// UTF-8 are decoded with `encoding` instead of being rejected.
+pub fn env_vars() -> impl Iterator<Item = (String, String)> {
+    env::vars_os().map(|(name, value)| (encoding::decode(&name), encoding::decode(&value)))
+}
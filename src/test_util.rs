@@ -0,0 +1,29 @@
+use std::path::{Path, PathBuf};
+
+/// A directory of the test's own under the system's temp directory, created empty
+/// and removed again when it is dropped, even when an assertion fails.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates an empty `{name}_{pid}`, clearing whatever an earlier run left there.
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -1,86 +1,2065 @@
-/// Tokenizes shell input into a vector of strings.
-/// Handles quotes, escapes, redirection operators, and pipelines.
-pub fn tokenize(input: &str) -> Vec<String> {
-    let mut tokens = Vec::new();
-    let mut current = String::new();
+use crate::arith;
+use crate::commands::is_valid_name;
+use crate::encoding;
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
+
+/// Supplies values for the `$` expansions performed while tokenizing.
+pub trait Expander {
+    /// Returns the value of a variable or of a special parameter such as `?`,
+    /// or `None` if it is unset.
+    fn parameter(&mut self, name: &str) -> Option<String>;
+    /// Sets a variable, for `${NAME:=default}`.
+    fn assign(&mut self, name: &str, value: &str);
+    /// Runs `command` for a `$(...)` or backquote substitution and returns its output.
+    fn command_output(&mut self, command: &str) -> String;
+    /// Returns true if expanding an unset variable is an error, as with `set -u`.
+    fn nounset(&self) -> bool {
+        false
+    }
+    /// Returns the elements of the array `name`, with `@` for the positional parameters,
+    /// or `None` if there is no such array.
+    fn array(&mut self, _name: &str) -> Option<Vec<String>> {
+        None
+    }
+    /// Returns element `index` of the array `name`, where a negative index counts back
+    /// from the end. Arrays with gaps between their indices need to look it up themselves.
+    fn element(&mut self, name: &str, index: i64) -> Option<String> {
+        let elements = self.array(name)?;
+        let index = if index < 0 {
+            elements.len() as i64 + index
+        } else {
+            index
+        };
+        elements.get(usize::try_from(index).ok()?).cloned()
+    }
+}
+
+/// Input that cannot be tokenized. The variants for input that ends before its tokens
+/// are complete carry the byte offset of the opening quote or of the dangling backslash.
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum TokenizeError {
+    #[error("syntax error: unexpected end of file")]
+    UnterminatedSingleQuote(usize),
+    #[error("syntax error: unexpected end of file")]
+    UnterminatedDoubleQuote(usize),
+    #[error("syntax error: unexpected end of file")]
+    TrailingBackslash(usize),
+    #[error("syntax error: unexpected end of file")]
+    UnterminatedSubstitution(usize),
+    /// A `${...}` expansion that is not one of the supported forms.
+    #[error("{0}: bad substitution")]
+    BadSubstitution(String),
+    /// An unset variable expanded while `set -u` is on.
+    #[error("{0}: unbound variable")]
+    Unbound(String),
+    /// A `$(( ... ))` expression that could not be evaluated.
+    #[error("{expression}: {message}")]
+    Arithmetic { expression: String, message: String },
+}
+
+/// A control or redirection operator. Redirections carry the fd number written
+/// directly before them, if any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// `|`
+    Pipe,
+    /// `||`
+    Or,
+    /// `&&`
+    And,
+    /// `&`
+    Background,
+    /// `;`
+    Semicolon,
+    /// `;;`
+    DoubleSemicolon,
+    /// An unquoted line break, which separates commands like `;`
+    Newline,
+    /// `(`
+    LeftParen,
+    /// `)`
+    RightParen,
+    /// `>`, or `>>` when appending
+    Output { fd: Option<u32>, append: bool },
+    /// `>|`, which truncates the file even when `noclobber` is set
+    Clobber { fd: Option<u32> },
+    /// `&>` or `&>>`, redirecting stdout and stderr together
+    OutputAll { append: bool },
+    /// `<`
+    Input { fd: Option<u32> },
+    /// `<>`, opening a file for both reading and writing
+    ReadWrite { fd: Option<u32> },
+    /// `>&`, followed by the fd to copy or `-` to close the stream
+    DuplicateOutput { fd: Option<u32> },
+    /// `<&`, followed by the fd to copy or `-` to close the stream
+    DuplicateInput { fd: Option<u32> },
+    /// `<<`, or `<<-` which strips leading tabs
+    HereDoc { fd: Option<u32>, strip_tabs: bool },
+    /// `<<<`
+    HereString { fd: Option<u32> },
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (fd, operator) = match *self {
+            Op::Pipe => (None, "|"),
+            Op::Or => (None, "||"),
+            Op::And => (None, "&&"),
+            Op::Background => (None, "&"),
+            Op::Semicolon => (None, ";"),
+            Op::DoubleSemicolon => (None, ";;"),
+            // Named the way bash's syntax errors refer to it
+            Op::Newline => (None, "newline"),
+            Op::LeftParen => (None, "("),
+            Op::RightParen => (None, ")"),
+            Op::Output { fd, append } => (fd, if append { ">>" } else { ">" }),
+            Op::Clobber { fd } => (fd, ">|"),
+            Op::OutputAll { append } => (None, if append { "&>>" } else { "&>" }),
+            Op::Input { fd } => (fd, "<"),
+            Op::ReadWrite { fd } => (fd, "<>"),
+            Op::DuplicateOutput { fd } => (fd, ">&"),
+            Op::DuplicateInput { fd } => (fd, "<&"),
+            Op::HereDoc { fd, strip_tabs } => (fd, if strip_tabs { "<<-" } else { "<<" }),
+            Op::HereString { fd } => (fd, "<<<"),
+        };
+        if let Some(fd) = fd {
+            write!(f, "{}", fd)?;
+        }
+        f.write_str(operator)
+    }
+}
+
+/// What a token is: a word (with quotes and escapes already removed) or an operator.
+/// A word that was read exactly as written borrows its text from the input.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind<'a> {
+    Word(Cow<'a, str>),
+    Operator(Op),
+    /// `<(command)`, or `>(command)` when `output` is set, carrying the inner command.
+    ProcessSubstitution {
+        command: String,
+        output: bool,
+    },
+}
+
+/// A token together with the byte range of the input it was read from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token<'a> {
+    pub kind: TokenKind<'a>,
+    pub span: Range<usize>,
+    /// For words containing unquoted `*`, `?` or `[`: the word as a filename pattern,
+    /// with the quoted glob characters backslash-escaped.
+    pub glob: Option<String>,
+    /// Set for words that were quoted or escaped, in whole or in part.
+    pub quoted: bool,
+}
+
+impl fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            TokenKind::Word(word) => f.write_str(word),
+            TokenKind::Operator(op) => write!(f, "{}", op),
+            TokenKind::ProcessSubstitution { command, output } => {
+                let direction = if *output { '>' } else { '<' };
+                write!(f, "{}({})", direction, command)
+            }
+        }
+    }
+}
+
+/// Tokenizes shell input into words and operators.
+/// Handles quotes (including `$'...'` with C-style escapes), escapes, redirection operators,
+/// pipelines, command separators, the `&&` / `||` list operators, the background operator `&`,
+/// and `(` / `)`.
+/// An unquoted `#` at the start of a word begins a comment that runs to the end of the line.
+/// `$` parameters and substitutions are left as literal text; see `tokenize_expanded`.
+pub fn tokenize(input: &str) -> Result<Vec<Token<'_>>, TokenizeError> {
+    Tokenizer::new(input, None).collect()
+}
+
+/// Tokenizes shell input like `tokenize`, expanding variables (`$NAME` and the
+/// `${...}` forms), the special parameters `$?`, `$$`, `$!`, `$0`, `$1`..`$9`, `$@`,
+/// `$*` and `$#`, the command substitutions `$(...)` and `` `...` `` and arithmetic
+/// `$((...))` outside single quotes, using values from `expander`.
+pub fn tokenize_expanded<'a>(
+    input: &'a str,
+    expander: &mut dyn Expander,
+) -> Result<Vec<Token<'a>>, TokenizeError> {
+    Tokenizer::new(input, Some(expander)).collect()
+}
+
+/// Reads tokens from shell input one at a time, stopping at the first error. Words
+/// borrow their text from `input` unless quote removal or an expansion changed it.
+pub struct Tokenizer<'a, 'e> {
+    input: &'a str,
+    chars: CharStream<'a>,
+    expander: Option<&'e mut dyn Expander>,
+    word: WordBuilder<'a>,
+    in_single_quote: bool,
+    in_double_quote: bool,
+    /// Byte offset of the quote that is currently open.
+    quote_start: usize,
+    /// Tokens completed but not yet returned; one character can complete several.
+    pending: Vec<Token<'a>>,
+    finished: bool,
+    /// Set while expanding a here-document body, which reads like one double-quoted
+    /// word in which `"` is an ordinary character.
+    heredoc: bool,
+}
+
+impl<'a, 'e> Tokenizer<'a, 'e> {
+    /// Tokenizes `input`, performing `$` expansions with `expander` if there is one.
+    pub fn new(input: &'a str, expander: Option<&'e mut dyn Expander>) -> Self {
+        Self {
+            input,
+            chars: input.char_indices().peekable(),
+            expander,
+            word: WordBuilder::default(),
+            in_single_quote: false,
+            in_double_quote: false,
+            quote_start: 0,
+            pending: Vec::new(),
+            finished: false,
+            heredoc: false,
+        }
+    }
+
+    /// Consumes the character `c` at `position`, along with any that follow it as part
+    /// of the same quote, expansion or operator.
+    fn step(&mut self, position: usize, c: char) -> Result<(), TokenizeError> {
+        let quoted = self.in_single_quote || self.in_double_quote;
+        let operator = if c == '\\' && !self.in_single_quote {
+            self.word.begin(position);
+            // A backslash-newline pair is a line continuation and disappears entirely
+            match self.chars.next() {
+                Some((_, '\n')) => {}
+                // Inside double quotes only these characters can be escaped,
+                // and in a here-document body not even `"`
+                Some((_, next))
+                    if self.in_double_quote
+                        && !matches!(next, '$' | '`' | '\\')
+                        && (next != '"' || self.heredoc) =>
+                {
+                    self.word.push('\\', true);
+                    self.word.push(next, true);
+                }
+                Some((_, next)) => self.word.push(next, true),
+                None if !self.in_double_quote => {
+                    return Err(TokenizeError::TrailingBackslash(position));
+                }
+                None => {}
+            }
+            None
+        } else if c == '$' && !quoted && self.chars.next_if(|&(_, n)| n == '\'').is_some() {
+            self.word.begin(position);
+            self.word.quoted = true;
+            let text = scan_ansi_c(&mut self.chars)
+                .ok_or(TokenizeError::UnterminatedSingleQuote(position))?;
+            self.word.push_str(&text, true);
+            None
+        } else if c == '$' && !self.in_single_quote && self.input[position..].starts_with("$((") {
+            self.word.begin(position);
+            self.chars.next();
+            self.chars.next();
+            // The expression runs to the `)` matching the inner `(`, which must be doubled
+            let expression = scan_parenthesized(&mut self.chars)
+                .filter(|_| self.chars.next_if(|&(_, n)| n == ')').is_some())
+                .ok_or(TokenizeError::UnterminatedSubstitution(position))?;
+            if let Some(expander) = self.expander.as_deref_mut() {
+                let value = arith::evaluate(&expression, |name| expander.parameter(name)).map_err(
+                    |message| TokenizeError::Arithmetic {
+                        expression: expression.trim().to_string(),
+                        message,
+                    },
+                )?;
+                self.word.push_str(&value.to_string(), self.in_double_quote);
+                self.word.expanded = true;
+            } else {
+                self.word.push_str(&format!("$(({}))", expression), true);
+            }
+            None
+        } else if !self.in_single_quote
+            && (c == '`' || c == '$' && self.chars.next_if(|&(_, n)| n == '(').is_some())
+        {
+            self.word.begin(position);
+            let command = if c == '`' {
+                scan_backquoted(&mut self.chars)
+            } else {
+                scan_parenthesized(&mut self.chars)
+            }
+            .ok_or(TokenizeError::UnterminatedSubstitution(position))?;
+            let end = offset(&mut self.chars, self.input);
+
+            match self.expander.as_deref_mut() {
+                Some(expander) => {
+                    let output = expander.command_output(&command);
+                    let output = output.trim_end_matches('\n');
+                    self.word.push_fields(
+                        &mut self.pending,
+                        output,
+                        self.in_double_quote,
+                        position,
+                        end,
+                    );
+                }
+                None if c == '`' => self.word.push_str(&format!("`{}`", command), true),
+                None => self.word.push_str(&format!("$({})", command), true),
+            }
+            None
+        } else if c == '$'
+            && !self.in_single_quote
+            && self.expander.is_none()
+            && self.chars.next_if(|&(_, n)| n == '{').is_some()
+        {
+            // Kept as written for the expansion pass, operators and blanks included
+            self.word.begin(position);
+            let body = scan_braced(&mut self.chars)
+                .ok_or(TokenizeError::UnterminatedSubstitution(position))?;
+            self.word.push_str(&format!("${{{}}}", body), true);
+            None
+        } else if c == '$'
+            && !self.in_single_quote
+            && let Some(expander) = self.expander.as_deref_mut()
+            && let Some(&(_, next)) = self.chars.peek()
+            && (next == '{' || next == '_' || next.is_ascii_alphabetic())
+        {
+            let value = if self.chars.next_if(|&(_, n)| n == '{').is_some() {
+                let body = scan_braced(&mut self.chars)
+                    .ok_or(TokenizeError::UnterminatedSubstitution(position))?;
+                let all_elements = match body.as_str() {
+                    "@" => Some("@"),
+                    body => body.strip_suffix("[@]"),
+                };
+                if self.in_double_quote
+                    && let Some(name) = all_elements
+                    && let Some(elements) = expander.array(name)
+                {
+                    self.push_elements(&elements, position);
+                    return Ok(());
+                }
+                expand_braced(&body, expander)?
+            } else {
+                let mut name = String::new();
+                while let Some((_, n)) = self
+                    .chars
+                    .next_if(|&(_, n)| n == '_' || n.is_ascii_alphanumeric())
+                {
+                    name.push(n);
+                }
+                lookup(expander, &name)?
+            };
+            let end = offset(&mut self.chars, self.input);
+            self.word.begin(position);
+            self.word.push_fields(
+                &mut self.pending,
+                &value,
+                self.in_double_quote,
+                position,
+                end,
+            );
+            None
+        } else if c == '$'
+            && !self.in_single_quote
+            && let Some(expander) = self.expander.as_deref_mut()
+            && let Some((_, name)) = self.chars.next_if(|&(_, n)| {
+                matches!(n, '?' | '$' | '!' | '@' | '*' | '#' | '-') || n.is_ascii_digit()
+            })
+        {
+            if name == '@'
+                && self.in_double_quote
+                && let Some(elements) = expander.array("@")
+            {
+                self.push_elements(&elements, position);
+                return Ok(());
+            }
+            self.word.begin(position);
+            let value = lookup(expander, &name.to_string())?;
+            let end = offset(&mut self.chars, self.input);
+            self.word.push_fields(
+                &mut self.pending,
+                &value,
+                self.in_double_quote,
+                position,
+                end,
+            );
+            None
+        } else if c == '#' && self.word.start.is_none() && !quoted {
+            // Skip the comment but keep any later lines, as in a sourced script
+            while self.chars.next_if(|&(_, n)| n != '\n').is_some() {}
+            None
+        } else if c == '\'' && !self.in_double_quote {
+            self.word.begin(position);
+            self.word.quoted = true;
+            self.in_single_quote = !self.in_single_quote;
+            self.quote_start = position;
+            None
+        } else if c == '"' && !self.in_single_quote && !self.heredoc {
+            self.word.begin(position);
+            self.word.quoted = true;
+            self.in_double_quote = !self.in_double_quote;
+            self.quote_start = position;
+            None
+        } else if matches!(c, '<' | '>')
+            && !quoted
+            && self.chars.next_if(|&(_, n)| n == '(').is_some()
+        {
+            self.word.finish(&mut self.pending, position);
+            let command = scan_parenthesized(&mut self.chars)
+                .ok_or(TokenizeError::UnterminatedSubstitution(position))?;
+            self.pending.push(Token {
+                kind: TokenKind::ProcessSubstitution {
+                    command,
+                    output: c == '>',
+                },
+                span: position..offset(&mut self.chars, self.input),
+                glob: None,
+                quoted: false,
+            });
+            None
+        } else if c == '>' && !quoted {
+            let (fd, start) = take_fd(&mut self.pending, &mut self.word, position);
+            if self.chars.next_if(|&(_, n)| n == '|').is_some() {
+                Some((Op::Clobber { fd }, start))
+            } else if self.chars.next_if(|&(_, n)| n == '&').is_some() {
+                Some((Op::DuplicateOutput { fd }, start))
+            } else {
+                let append = self.chars.next_if(|&(_, n)| n == '>').is_some();
+                Some((Op::Output { fd, append }, start))
+            }
+        } else if c == '<' && !quoted {
+            let (fd, start) = take_fd(&mut self.pending, &mut self.word, position);
+            let op = if self.chars.next_if(|&(_, n)| n == '&').is_some() {
+                Op::DuplicateInput { fd }
+            } else if self.chars.next_if(|&(_, n)| n == '<').is_some() {
+                // `<<<` is a here-string, `<<-` a tab-stripping here-document
+                if self.chars.next_if(|&(_, n)| n == '<').is_some() {
+                    Op::HereString { fd }
+                } else {
+                    let strip_tabs = self.chars.next_if(|&(_, n)| n == '-').is_some();
+                    Op::HereDoc { fd, strip_tabs }
+                }
+            } else if self.chars.next_if(|&(_, n)| n == '>').is_some() {
+                Op::ReadWrite { fd }
+            } else {
+                Op::Input { fd }
+            };
+            Some((op, start))
+        } else if c == '|' && !quoted {
+            self.word.finish(&mut self.pending, position);
+            if self.chars.next_if(|&(_, n)| n == '|').is_some() {
+                Some((Op::Or, position))
+            } else {
+                Some((Op::Pipe, position))
+            }
+        } else if c == '&' && !quoted {
+            // Handle `&&`, `&>`, and the background operator `&`
+            self.word.finish(&mut self.pending, position);
+            if self.chars.next_if(|&(_, n)| n == '&').is_some() {
+                Some((Op::And, position))
+            } else if self.chars.next_if(|&(_, n)| n == '>').is_some() {
+                // `&>` / `&>>` redirect stdout and stderr together
+                let append = self.chars.next_if(|&(_, n)| n == '>').is_some();
+                Some((Op::OutputAll { append }, position))
+            } else {
+                Some((Op::Background, position))
+            }
+        } else if c == ';' && !quoted {
+            self.word.finish(&mut self.pending, position);
+            // `;;` terminates a case arm, so it is a single operator
+            if self.chars.next_if(|&(_, n)| n == ';').is_some() {
+                Some((Op::DoubleSemicolon, position))
+            } else {
+                Some((Op::Semicolon, position))
+            }
+        } else if c == '\n' && !quoted {
+            self.word.finish(&mut self.pending, position);
+            Some((Op::Newline, position))
+        } else if matches!(c, '(' | ')') && !quoted {
+            self.word.finish(&mut self.pending, position);
+            let op = if c == '(' {
+                Op::LeftParen
+            } else {
+                Op::RightParen
+            };
+            Some((op, position))
+        } else if c.is_whitespace() && !quoted {
+            self.word.finish(&mut self.pending, position);
+            None
+        } else if c == '~'
+            && !quoted
+            && (self.word.start.is_none() || self.word.at_assignment_value())
+            && let Some(expander) = self.expander.as_deref_mut()
+            && let Some((prefix, home)) = tilde_prefix(&self.input[position + 1..], expander)
+        {
+            for _ in prefix.chars() {
+                self.chars.next();
+            }
+            self.word.begin(position);
+            self.word.push_str(&home, true);
+            self.word.expanded = true;
+            None
+        } else if quoted {
+            self.word.begin(position);
+            self.word.push(c, true);
+            None
+        } else {
+            self.word.begin(position);
+            self.word.push_source(self.input, position, c);
+            None
+        };
+
+        if let Some((op, start)) = operator {
+            let end = offset(&mut self.chars, self.input);
+            self.pending.push(Token {
+                kind: TokenKind::Operator(op),
+                span: start..end,
+                glob: None,
+                quoted: false,
+            });
+            // The fd after `>&` or `<&` ends there, so `2>&1>file` is two redirections
+            if matches!(op, Op::DuplicateOutput { .. } | Op::DuplicateInput { .. }) {
+                if self.chars.next_if(|&(_, n)| n == '-').is_some() {
+                    self.word.begin(end);
+                    self.word.push_source(self.input, end, '-');
+                } else {
+                    while let Some((position, n)) = self.chars.next_if(|&(_, n)| n.is_ascii_digit())
+                    {
+                        self.word.begin(position);
+                        self.word.push_source(self.input, position, n);
+                    }
+                }
+                self.word
+                    .finish(&mut self.pending, offset(&mut self.chars, self.input));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the elements of a quoted `"$@"` or `"${name[@]}"` as separate words, the first
+    /// joined to the text before it and the last to the text after it.
+    fn push_elements(&mut self, elements: &[String], position: usize) {
+        let end = offset(&mut self.chars, self.input);
+        self.word.begin(position);
+        self.word.expanded = true;
+        // With no elements there is no word, unless something else is quoted with them
+        self.word.vanish_if_empty = elements.is_empty();
+        for (i, element) in elements.iter().enumerate() {
+            if i > 0 {
+                self.word.finish(&mut self.pending, end);
+                self.word.begin(position);
+                self.word.expanded = true;
+            }
+            self.word.push_str(element, true);
+        }
+    }
+
+    /// Checks for unclosed quotes and completes the last word once the input runs out.
+    fn finish(&mut self) -> Result<(), TokenizeError> {
+        if self.in_single_quote {
+            return Err(TokenizeError::UnterminatedSingleQuote(self.quote_start));
+        }
+        if self.in_double_quote && !self.heredoc {
+            return Err(TokenizeError::UnterminatedDoubleQuote(self.quote_start));
+        }
+        self.word.finish(&mut self.pending, self.input.len());
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a, '_> {
+    type Item = Result<Token<'a>, TokenizeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() && !self.finished {
+            let result = match self.chars.next() {
+                Some((position, c)) => self.step(position, c),
+                None => {
+                    self.finished = true;
+                    self.finish()
+                }
+            };
+            if let Err(e) = result {
+                self.finished = true;
+                self.pending.clear();
+                return Some(Err(e));
+            }
+        }
+        (!self.pending.is_empty()).then(|| Ok(self.pending.remove(0)))
+    }
+}
+
+/// The word the tokenizer is in the middle of reading.
+#[derive(Default)]
+struct WordBuilder<'a> {
+    /// The word so far, borrowed from the input for as long as it is a slice of it.
+    text: Cow<'a, str>,
+    /// `text` with quoted glob characters escaped, for filename expansion. It borrows
+    /// the same slice as `text` while that is borrowed.
+    pattern: Cow<'a, str>,
+    /// Set once an unquoted `*`, `?` or `[` has been added.
+    has_glob: bool,
+    /// Set once any quote or quoted character has been added.
+    quoted: bool,
+    /// Set once the result of an expansion has been added.
+    expanded: bool,
+    /// Set when a quoted `"$@"` expanded to nothing, so the word is dropped if it is
+    /// empty even though it was quoted.
+    vanish_if_empty: bool,
+    /// Byte offset where the word began.
+    start: Option<usize>,
+}
+
+impl<'a> WordBuilder<'a> {
+    fn begin(&mut self, position: usize) {
+        self.start.get_or_insert(position);
+    }
+
+    /// Appends a character; glob characters only stay active when it was not `quoted`.
+    fn push(&mut self, c: char, quoted: bool) {
+        if matches!(c, '*' | '?' | '[') && !quoted {
+            self.has_glob = true;
+        } else if matches!(c, '*' | '?' | '[' | '\\') {
+            self.pattern.to_mut().push('\\');
+        }
+        self.text.to_mut().push(c);
+        self.pattern.to_mut().push(c);
+        self.quoted |= quoted;
+    }
+
+    /// Appends the unquoted character `c` read at `position` of `source`. A word made
+    /// only of such characters, one after another, stays a slice of `source`.
+    fn push_source(&mut self, source: &'a str, position: usize, c: char) {
+        let start = self.start.unwrap_or(position);
+        // A new word starts out owned and empty, and can start borrowing here
+        let contiguous = match &self.text {
+            Cow::Borrowed(text) => start + text.len() == position,
+            Cow::Owned(text) => text.is_empty() && start == position,
+        };
+        if contiguous && c != '\\' {
+            self.has_glob |= matches!(c, '*' | '?' | '[');
+            let text = &source[start..position + c.len_utf8()];
+            self.text = Cow::Borrowed(text);
+            self.pattern = Cow::Borrowed(text);
+        } else {
+            self.push(c, false);
+        }
+    }
+
+    fn push_str(&mut self, s: &str, quoted: bool) {
+        s.chars().for_each(|c| self.push(c, quoted));
+    }
+
+    /// Whether the word so far is the `NAME=` of an assignment, where a `~` may follow.
+    fn at_assignment_value(&self) -> bool {
+        !self.quoted && self.text.strip_suffix('=').is_some_and(is_valid_name)
+    }
+
+    /// Appends the result of an expansion that started at `start` and ends at `end`.
+    /// Unquoted results are split into words at whitespace; quoted ones stay in one word.
+    fn push_fields(
+        &mut self,
+        tokens: &mut Vec<Token<'a>>,
+        value: &str,
+        quoted: bool,
+        start: usize,
+        end: usize,
+    ) {
+        self.expanded = true;
+        for c in value.chars() {
+            if c.is_whitespace() && !quoted {
+                self.finish(tokens, end);
+            } else {
+                self.begin(start);
+                self.push(c, quoted);
+            }
+        }
+    }
+
+    /// Moves the word into `tokens`. Words that came out empty are dropped, unless they
+    /// were quoted like `""`, which is an empty argument.
+    fn finish(&mut self, tokens: &mut Vec<Token<'a>>, end: usize) {
+        let word = std::mem::take(self);
+        if !word.text.is_empty() || word.quoted && !word.vanish_if_empty {
+            tokens.push(Token {
+                kind: TokenKind::Word(word.text),
+                span: word.start.unwrap_or(end)..end,
+                glob: word.has_glob.then(|| word.pattern.into_owned()),
+                quoted: word.quoted,
+            });
+        }
+    }
+}
+
+/// Ends the word before a redirection operator at `position`. A word of literal digits
+/// directly before the operator is the fd being redirected and becomes part of it; any
+/// other word, including quoted or expanded digits, is kept as an ordinary word.
+/// Returns the fd and where the operator starts.
+fn take_fd<'a>(
+    tokens: &mut Vec<Token<'a>>,
+    word: &mut WordBuilder<'a>,
+    position: usize,
+) -> (Option<u32>, usize) {
+    if !word.quoted
+        && !word.expanded
+        && word.text.chars().all(|c| c.is_ascii_digit())
+        && let Ok(fd) = word.text.parse()
+    {
+        let start = word.start.unwrap_or(position);
+        *word = WordBuilder::default();
+        return (Some(fd), start);
+    }
+    word.finish(tokens, position);
+    (None, position)
+}
+
+/// Reads the tilde-prefix at the start of `rest`, the text after a `~`, and returns it
+/// with the directory it stands for: `~` is `$HOME`, `~+` is `$PWD`, `~-` is `$OLDPWD`
+/// and `~user` is that user's home directory. The prefix must end the word or be
+/// followed by `/`; returns `None` when it does not, or names nothing.
+fn tilde_prefix<'a>(rest: &'a str, expander: &mut dyn Expander) -> Option<(&'a str, String)> {
+    let end = rest
+        .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '+')))
+        .unwrap_or(rest.len());
+    let (prefix, after) = rest.split_at(end);
+    let ends_word = after
+        .chars()
+        .next()
+        .is_none_or(|c| c == '/' || c.is_whitespace() || ";|&<>()".contains(c));
+    if !ends_word {
+        return None;
+    }
+    let home = match prefix {
+        "" => expander.parameter("HOME"),
+        "+" => expander.parameter("PWD"),
+        "-" => expander.parameter("OLDPWD"),
+        user => nix::unistd::User::from_name(user)
+            .ok()
+            .flatten()
+            .map(|user| user.dir.to_string_lossy().into_owned()),
+    };
+    home.map(|home| (prefix, home))
+}
+
+/// Expands the body of a `${...}`: `NAME`, `#NAME` for the length of the value,
+/// `NAME:-word` for `word` when the variable is unset or empty, and `NAME:=word`,
+/// which also assigns it. Without the colon, `-` and `=` only apply when unset.
+/// `NAME[index]` in place of `NAME` is an array element, `NAME[@]` all of them, and
+/// `#NAME[@]` their number.
+fn expand_braced(body: &str, expander: &mut dyn Expander) -> Result<String, TokenizeError> {
+    let bad_substitution = || TokenizeError::BadSubstitution(format!("${{{}}}", body));
+
+    if let Some(name) = body.strip_prefix('#') {
+        if let Some((array, "@" | "*", "")) = split_subscript(name) {
+            let count = expander.array(array).map_or(0, |elements| elements.len());
+            return Ok(count.to_string());
+        }
+        if let Some((array, index, "")) = split_subscript(name) {
+            let value = element(expander, array, index)?.unwrap_or_default();
+            return Ok(value.chars().count().to_string());
+        }
+        if let (name, "") = split_name(name)
+            && !name.is_empty()
+        {
+            let value = lookup(expander, name)?;
+            return Ok(value.chars().count().to_string());
+        }
+    }
+
+    let (name, value, rest) = match split_subscript(body) {
+        Some((name, index, rest)) => (name, element(expander, name, index)?, rest),
+        None => {
+            let (name, rest) = split_name(body);
+            if name.is_empty() {
+                return Err(bad_substitution());
+            }
+            (name, expander.parameter(name), rest)
+        }
+    };
+    let (colon, rest) = match rest.strip_prefix(':') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    let use_default = match &value {
+        None => true,
+        Some(value) => colon && value.is_empty(),
+    };
+
+    match (rest.chars().next(), &value) {
+        (None, None) if !colon && expander.nounset() => {
+            Err(TokenizeError::Unbound(name.to_string()))
+        }
+        (None, _) if !colon => Ok(value.unwrap_or_default()),
+        (Some('-'), _) if use_default => Ok(rest[1..].to_string()),
+        (Some('='), _) if use_default => {
+            expander.assign(name, &rest[1..]);
+            Ok(rest[1..].to_string())
+        }
+        (Some('-' | '='), value) => Ok(value.clone().unwrap_or_default()),
+        _ => Err(bad_substitution()),
+    }
+}
+
+/// Splits `NAME[index]` off the front of `s`, returning the name, the index and the rest.
+pub fn split_subscript(s: &str) -> Option<(&str, &str, &str)> {
+    let (name, rest) = split_name(s);
+    let (index, rest) = rest.strip_prefix('[')?.split_once(']')?;
+    let valid = name.starts_with(|c: char| c == '_' || c.is_ascii_alphabetic());
+    valid.then_some((name, index, rest))
+}
+
+/// Looks up element `index` of the array `name`, where a variable that is not an array
+/// counts as one with a single element. An index of `@` or `*` joins all elements.
+fn element(
+    expander: &mut dyn Expander,
+    name: &str,
+    index: &str,
+) -> Result<Option<String>, TokenizeError> {
+    let elements = expander.array(name);
+    if matches!(index, "@" | "*") {
+        let elements = elements.unwrap_or_else(|| expander.parameter(name).into_iter().collect());
+        return Ok((!elements.is_empty()).then(|| elements.join(" ")));
+    }
+    let index = arith::evaluate(index, |name| expander.parameter(name)).map_err(|message| {
+        TokenizeError::Arithmetic {
+            expression: index.trim().to_string(),
+            message,
+        }
+    })?;
+    Ok(match elements {
+        Some(_) => expander.element(name, index),
+        None => expander.parameter(name).filter(|_| matches!(index, 0 | -1)),
+    })
+}
+
+/// Returns the value of the parameter `name`; unset counts as empty unless `set -u` is on.
+/// `$@` and `$*` may always be empty.
+fn lookup(expander: &mut dyn Expander, name: &str) -> Result<String, TokenizeError> {
+    match expander.parameter(name) {
+        Some(value) => Ok(value),
+        None if expander.nounset() && !matches!(name, "@" | "*") => {
+            Err(TokenizeError::Unbound(name.to_string()))
+        }
+        None => Ok(String::new()),
+    }
+}
+
+/// Splits a parameter name (a variable name or one special parameter) off the front of `s`.
+fn split_name(s: &str) -> (&str, &str) {
+    let end = match s.chars().next() {
+        Some('?' | '$' | '!' | '@' | '*' | '#' | '-') => 1,
+        Some(c) if c.is_ascii_digit() => s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()),
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => s
+            .find(|c: char| c != '_' && !c.is_ascii_alphanumeric())
+            .unwrap_or(s.len()),
+        _ => 0,
+    };
+    s.split_at(end)
+}
+
+/// Returns the byte offset of the next unread character.
+fn offset(chars: &mut CharStream, input: &str) -> usize {
+    chars.peek().map_or(input.len(), |&(position, _)| position)
+}
+
+type CharStream<'a> = std::iter::Peekable<std::str::CharIndices<'a>>;
+
+/// Consumes the body of a `$(...)` substitution up to its matching `)`, skipping
+/// over quoted text and nested parentheses. Returns `None` if it is never closed.
+fn scan_parenthesized(chars: &mut CharStream) -> Option<String> {
+    let mut command = String::new();
+    let mut depth = 1;
+    let mut quote = None;
+
+    while let Some((_, c)) = chars.next() {
+        match (c, quote) {
+            ('\\', q) if q != Some('\'') => {
+                command.push(c);
+                command.push(chars.next()?.1);
+                continue;
+            }
+            ('\'' | '"', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            ('(', None) => depth += 1,
+            (')', None) => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(command);
+                }
+            }
+            _ => {}
+        }
+        command.push(c);
+    }
+    None
+}
+
+/// Consumes the body of a `${...}` expansion up to its matching `}`, skipping over
+/// quoted text, nested braces and `$(...)` substitutions. Returns `None` if it is
+/// never closed.
+fn scan_braced(chars: &mut CharStream) -> Option<String> {
+    let mut body = String::new();
+    let mut depth = 1;
+    let mut quote = None;
+
+    while let Some((_, c)) = chars.next() {
+        match (c, quote) {
+            ('\\', q) if q != Some('\'') => {
+                body.push(c);
+                body.push(chars.next()?.1);
+                continue;
+            }
+            ('$', q) if q != Some('\'') && chars.next_if(|&(_, n)| n == '(').is_some() => {
+                body.push_str(&format!("$({})", scan_parenthesized(chars)?));
+                continue;
+            }
+            ('\'' | '"', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            ('{', None) => depth += 1,
+            ('}', None) => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(body);
+                }
+            }
+            _ => {}
+        }
+        body.push(c);
+    }
+    None
+}
+
+/// Consumes the body of a `` `...` `` substitution up to the closing backquote.
+/// A backslash before `` ` ``, `$` or `\` is removed, which is how backquotes nest.
+fn scan_backquoted(chars: &mut CharStream) -> Option<String> {
+    let mut command = String::new();
+    while let Some((_, c)) = chars.next() {
+        match c {
+            '`' => return Some(command),
+            '\\' => {
+                let (_, next) = chars.next()?;
+                if !matches!(next, '`' | '$' | '\\') {
+                    command.push(c);
+                }
+                command.push(next);
+            }
+            _ => command.push(c),
+        }
+    }
+    None
+}
+
+/// Consumes the body of a `$'...'` string up to the closing quote, decoding the
+/// C-style escapes bash supports; unknown escapes are kept as written. As in bash,
+/// a NUL character ends the string. Returns `None` if it is never closed.
+fn scan_ansi_c(chars: &mut CharStream) -> Option<String> {
+    let mut text = String::new();
+    let mut ended = false;
+    while let Some((_, c)) = chars.next() {
+        let decoded = match c {
+            '\'' => return Some(text),
+            '\\' => match chars.next()?.1 {
+                'a' => Some('\x07'),
+                'b' => Some('\x08'),
+                'e' | 'E' => Some('\x1b'),
+                'f' => Some('\x0c'),
+                'n' => Some('\n'),
+                'r' => Some('\r'),
+                't' => Some('\t'),
+                'v' => Some('\x0b'),
+                e @ ('\\' | '\'' | '"' | '?') => Some(e),
+                // Octal and hex escapes are bytes, which need not be valid UTF-8
+                e @ '0'..='7' => scan_code(chars, 8, 2, e.to_digit(8)).map(raw_byte),
+                'x' if chars.peek().is_some_and(|(_, n)| n.is_ascii_hexdigit()) => {
+                    scan_code(chars, 16, 2, None).map(raw_byte)
+                }
+                e @ ('u' | 'U') if chars.peek().is_some_and(|(_, n)| n.is_ascii_hexdigit()) => {
+                    let digits = if e == 'u' { 4 } else { 8 };
+                    match scan_code(chars, 16, digits, None) {
+                        // Not to be confused with the raw bytes those characters stand for
+                        Some(c) if encoding::is_reserved(c) => {
+                            if !ended {
+                                text.push_str(&encoding::escape(c));
+                            }
+                            continue;
+                        }
+                        c => c,
+                    }
+                }
+                // Unknown escapes, and `\x` without digits, stand for themselves
+                e => {
+                    if !ended {
+                        text.push('\\');
+                        text.push(e);
+                    }
+                    continue;
+                }
+            },
+            c => Some(c),
+        };
+        match decoded {
+            Some('\0') => ended = true,
+            Some(c) if !ended => text.push(c),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Reads up to `max_digits` digits in `radix` after an escape, continuing from `value`
+/// if a digit was already read, and returns the character with that code.
+fn scan_code(
+    chars: &mut CharStream,
+    radix: u32,
+    max_digits: usize,
+    mut value: Option<u32>,
+) -> Option<char> {
+    for _ in 0..max_digits {
+        let Some((_, c)) = chars.next_if(|&(_, c)| c.is_digit(radix)) else {
+            break;
+        };
+        value = Some(value.unwrap_or(0) * radix + c.to_digit(radix)?);
+    }
+    char::from_u32(value?)
+}
+
+/// The byte an octal or hex escape stands for, keeping only its low eight bits.
+fn raw_byte(code: char) -> char {
+    encoding::raw_byte(code as u32 as u8)
+}
+
+/// Returns true if `line` ends with an unquoted backslash, so the command continues
+/// on the next line.
+pub fn needs_continuation(line: &str) -> bool {
     let mut in_single_quote = false;
     let mut in_double_quote = false;
-    let mut chars = input.chars().peekable();
-
+    let mut chars = line.chars();
     while let Some(c) = chars.next() {
         if c == '\\' && !in_single_quote {
-            if let Some(&next) = chars.peek() {
-                chars.next();
-                current.push(next);
+            if chars.next().is_none() {
+                return true;
             }
         } else if c == '\'' && !in_double_quote {
             in_single_quote = !in_single_quote;
         } else if c == '"' && !in_single_quote {
             in_double_quote = !in_double_quote;
-        } else if c == '>' && !in_single_quote && !in_double_quote {
-            let mut redirect_token = String::new();
+        }
+    }
+    false
+}
 
-            let has_fd = !current.is_empty() && current.chars().last().unwrap().is_ascii_digit();
-            if has_fd {
-                redirect_token = current.clone();
-                current.clear();
-            }
+/// Performs the `$` expansions and command substitutions in the body of a here-document
+/// whose delimiter was not quoted. Backslashes only escape `$`, `` ` ``, `\` and newlines.
+pub fn expand_heredoc(body: &str, expander: &mut dyn Expander) -> Result<String, TokenizeError> {
+    let mut tokenizer = Tokenizer::new(body, Some(expander));
+    tokenizer.in_double_quote = true;
+    tokenizer.heredoc = true;
+    let words = tokenizer
+        .map(|token| token.map(|token| token.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(words.concat())
+}
+
+/// Removes the `\r` that a line read from a file with CRLF line endings still ends in.
+pub fn strip_carriage_return(mut line: String) -> String {
+    if line.ends_with('\r') {
+        line.pop();
+    }
+    line
+}
 
-            redirect_token.push(c);
+/// Appends lines from `next_line` to `line` for as long as it ends in a line continuation.
+pub fn join_continued_lines(
+    mut line: String,
+    next_line: &mut dyn FnMut() -> Option<String>,
+) -> String {
+    while needs_continuation(&line) {
+        let Some(more) = next_line() else {
+            break;
+        };
+        line.push('\n');
+        line.push_str(&more);
+    }
+    line
+}
 
-            if let Some(&next) = chars.peek()
-                && next == '>'
-            {
-                chars.next();
-                redirect_token.push(next);
-            }
+/// Performs history expansion of `!!` (previous command), `!N` (entry N) and
+/// `!-N` (Nth previous command) on a raw input line, before it is tokenized.
+/// Single quotes and a preceding backslash suppress expansion, but double quotes do not;
+/// any other `!` is literal.
+pub fn expand_history(line: &str, history: &[String]) -> Result<String, String> {
+    let mut expanded = String::with_capacity(line.len());
+    let mut in_single_quote = false;
+    let mut in_double_quote = false;
+    let mut chars = line.chars().peekable();
 
-            if !has_fd && !current.is_empty() {
-                tokens.push(current.clone());
-                current.clear();
+    while let Some(c) = chars.next() {
+        if c == '\\' && !in_single_quote {
+            expanded.push(c);
+            if let Some(next) = chars.next() {
+                expanded.push(next);
             }
+            continue;
+        }
+        if c == '\'' && !in_double_quote {
+            in_single_quote = !in_single_quote;
+        } else if c == '"' && !in_single_quote {
+            in_double_quote = !in_double_quote;
+        }
+        if c != '!' || in_single_quote {
+            expanded.push(c);
+            continue;
+        }
 
-            tokens.push(redirect_token);
-        } else if c == '|' && !in_single_quote && !in_double_quote {
-            // Handle pipeline operator
-            if !current.is_empty() {
-                tokens.push(current.clone());
-                current.clear();
+        let event = if chars.next_if_eq(&'!').is_some() {
+            "!".to_string()
+        } else {
+            let mut digits = String::new();
+            if chars.peek() == Some(&'-') {
+                digits.push('-');
+                chars.next();
             }
-            tokens.push("|".to_string());
-        } else if c.is_whitespace() && !in_single_quote && !in_double_quote {
-            if !current.is_empty() {
-                tokens.push(current.clone());
-                current.clear();
+            while let Some(d) = chars.next_if(|d| d.is_ascii_digit()) {
+                digits.push(d);
             }
-        } else {
-            current.push(c);
-        }
-    }
+            digits
+        };
 
-    if !current.is_empty() {
-        tokens.push(current);
+        let entry = match event.as_str() {
+            "" => {
+                expanded.push('!');
+                continue;
+            }
+            "-" => None,
+            "!" => history.last(),
+            n => n
+                .parse::<isize>()
+                .ok()
+                .and_then(|n| match n {
+                    n if n < 0 => history.len().checked_sub(n.unsigned_abs()),
+                    n => (n as usize).checked_sub(1),
+                })
+                .and_then(|index| history.get(index)),
+        };
+        match entry {
+            Some(entry) => expanded.push_str(entry),
+            None => return Err(format!("!{}: event not found", event)),
+        }
     }
 
-    tokens
+    Ok(expanded)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    fn texts(tokens: Vec<Token>) -> Vec<String> {
+        tokens.iter().map(Token::to_string).collect()
+    }
+
+    fn words(input: &str) -> Vec<String> {
+        texts(tokenize(input).unwrap())
+    }
 
     #[test]
     fn test_simple_command() {
-        assert_eq!(tokenize("echo hello"), vec!["echo", "hello"]);
+        assert_eq!(words("echo hello"), vec!["echo", "hello"]);
     }
 
     #[test]
     fn test_quoted_string() {
-        assert_eq!(tokenize("echo \"hello world\""), vec!["echo", "hello world"]);
+        assert_eq!(words("echo \"hello world\""), vec!["echo", "hello world"]);
+    }
+
+    #[test]
+    fn test_tokenizer_yields_tokens_up_to_the_first_error() {
+        let mut tokenizer = Tokenizer::new("echo a|b 'open", None);
+        let words: Vec<String> = tokenizer
+            .by_ref()
+            .take(4)
+            .map(|token| token.unwrap().to_string())
+            .collect();
+        assert_eq!(words, vec!["echo", "a", "|", "b"]);
+        assert_eq!(
+            tokenizer.next(),
+            Some(Err(TokenizeError::UnterminatedSingleQuote(9)))
+        );
+        assert_eq!(tokenizer.next(), None);
+
+        // Input after the tokens taken is never looked at
+        let mut status = Status::new(0);
+        let first = Tokenizer::new("echo ${%x}", Some(&mut status)).next();
+        assert_eq!(first.unwrap().unwrap().to_string(), "echo");
+        assert!(tokenize_expanded("echo ${%x}", &mut status).is_err());
+    }
+
+    #[test]
+    fn test_words_borrow_the_input_unless_changed() {
+        let mut status = Status::new(0);
+        let tokens =
+            tokenize_expanded("ls -la *.rs 2>&1 'a b' c\\d e\\\nf ~ x$?", &mut status).unwrap();
+        let borrowed: Vec<(String, bool)> = tokens
+            .iter()
+            .filter_map(|token| match &token.kind {
+                TokenKind::Word(word) => Some((word.to_string(), matches!(word, Cow::Borrowed(_)))),
+                _ => None,
+            })
+            .collect();
+        let home = std::env::var("HOME").unwrap_or_default();
+        assert_eq!(
+            borrowed,
+            vec![
+                ("ls".to_string(), true),
+                ("-la".to_string(), true),
+                ("*.rs".to_string(), true),
+                ("1".to_string(), true),
+                ("a b".to_string(), false),
+                ("cd".to_string(), false),
+                ("ef".to_string(), false),
+                (home, false),
+                ("x0".to_string(), false),
+            ]
+        );
+        assert_eq!(tokens[2].glob.as_deref(), Some("*.rs"));
+    }
+
+    #[test]
+    fn test_expand_heredoc() {
+        let mut status = Status::new(2);
+        status.set("HEREDOC_TEST_VAR", "a  b");
+        assert_eq!(
+            expand_heredoc(
+                "$HEREDOC_TEST_VAR \"$?\" '$(echo x)'\n\\$HOME \\\"q\\\" \\n\n",
+                &mut status
+            ),
+            Ok("a  b \"2\" 'x'\n$HOME \\\"q\\\" \\n\n".to_string())
+        );
+        assert_eq!(expand_heredoc("", &mut status), Ok(String::new()));
+    }
+
+    #[test]
+    fn test_empty_quoted_words() {
+        assert_eq!(words("echo \"\""), vec!["echo", ""]);
+        assert_eq!(words("echo '' ''"), vec!["echo", "", ""]);
+        assert_eq!(words("echo a\"\" b"), vec!["echo", "a", "b"]);
+        assert_eq!(words("echo $'' end"), vec!["echo", "", "end"]);
+        assert_eq!(words("echo   \t end"), vec!["echo", "end"]);
+
+        let mut status = Status::new(0);
+        assert_eq!(
+            texts(
+                tokenize_expanded("echo $EMPTY_UNSET \"$EMPTY_UNSET\" end", &mut status).unwrap()
+            ),
+            vec!["echo", "", "end"]
+        );
     }
 
     #[test]
     fn test_redirection() {
-        assert_eq!(tokenize("echo hi > file.txt"), vec!["echo", "hi", ">", "file.txt"]);
+        assert_eq!(
+            words("echo hi > file.txt"),
+            vec!["echo", "hi", ">", "file.txt"]
+        );
+        assert_eq!(
+            tokenize("echo hi 2>|file.txt").unwrap()[2].kind,
+            TokenKind::Operator(Op::Clobber { fd: Some(2) })
+        );
+        assert_eq!(words("a >| b > |c"), vec!["a", ">|", "b", ">", "|", "c"]);
+    }
+
+    #[test]
+    fn test_fd_prefix_only_for_all_digit_words() {
+        assert_eq!(words("cat file2>out"), vec!["cat", "file2", ">", "out"]);
+        assert_eq!(words("foo 2>out"), vec!["foo", "2>", "out"]);
+        assert_eq!(words("foo 2> out"), vec!["foo", "2>", "out"]);
+        assert_eq!(words("foo 2 > out"), vec!["foo", "2", ">", "out"]);
+        assert_eq!(
+            tokenize("exec 10>>trace.log").unwrap()[1].kind,
+            TokenKind::Operator(Op::Output {
+                fd: Some(10),
+                append: true
+            })
+        );
+    }
+
+    #[test]
+    fn test_glued_redirection_operators() {
+        // Each operator is the longest one that matches, as in bash
+        let cases: &[(&str, &[&str])] = &[
+            ("echo hi>>file", &["echo", "hi", ">>", "file"]),
+            ("echo hi>file", &["echo", "hi", ">", "file"]),
+            ("echo hi2>x", &["echo", "hi2", ">", "x"]),
+            ("echo 2>x", &["echo", "2>", "x"]),
+            ("echo 2>>x", &["echo", "2>>", "x"]),
+            ("echo \"2\">x", &["echo", "2", ">", "x"]),
+            ("echo 2\\>x", &["echo", "2>x"]),
+            ("echo a>|b", &["echo", "a", ">|", "b"]),
+            ("echo a 2>&1>f", &["echo", "a", "2>&", "1", ">", "f"]),
+            ("echo a>&2", &["echo", "a", ">&", "2"]),
+            ("echo a&>f", &["echo", "a", "&>", "f"]),
+            ("echo a&>>f", &["echo", "a", "&>>", "f"]),
+            (">>>file", &[">>", ">", "file"]),
+            ("echo a>>>>b", &["echo", "a", ">>", ">>", "b"]),
+            ("echo a>>|b", &["echo", "a", ">>", "|", "b"]),
+            ("cat<<<x", &["cat", "<<<", "x"]),
+            ("cat<<<<x", &["cat", "<<<", "<", "x"]),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(&words(input), expected, "tokenizing {:?}", input);
+        }
+        // Only digits written literally name an fd
+        let tokens = tokenize_expanded("echo $#>f", &mut Status::new(0)).unwrap();
+        assert_eq!(texts(tokens), vec!["echo", "1", ">", "f"]);
+    }
+
+    #[test]
+    fn test_escaped_space_joins_words() {
+        let cases: &[(&str, &[&str])] = &[
+            ("ls My\\ Documents", &["ls", "My Documents"]),
+            ("echo \\ leading", &["echo", " leading"]),
+            ("echo trailing\\ ", &["echo", "trailing "]),
+            ("echo a\\  b", &["echo", "a ", "b"]),
+            ("echo \\ ", &["echo", " "]),
+            ("echo 'a'\\ \"b\"", &["echo", "a b"]),
+            ("echo a\\\tb", &["echo", "a\tb"]),
+            ("cat my\\ file > out", &["cat", "my file", ">", "out"]),
+            ("cat my\\ file>my\\ out", &["cat", "my file", ">", "my out"]),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(&words(input), expected, "tokenizing {:?}", input);
+        }
+        assert!(tokenize("echo a\\ b").unwrap()[1].quoted);
+    }
+
+    #[test]
+    fn test_pipe_splits_words() {
+        assert_eq!(words("a|b"), vec!["a", "|", "b"]);
+        assert_eq!(words("a | b"), vec!["a", "|", "b"]);
+    }
+
+    #[test]
+    fn test_quoted_pipe_is_literal() {
+        assert_eq!(words("echo \"a|b\""), vec!["echo", "a|b"]);
+        assert_eq!(words("echo 'a|b'"), vec!["echo", "a|b"]);
+    }
+
+    #[test]
+    fn test_semicolon_separates_commands() {
+        assert_eq!(words("cd /tmp; pwd"), vec!["cd", "/tmp", ";", "pwd"]);
+        assert_eq!(words("pwd;ls"), vec!["pwd", ";", "ls"]);
+        assert_eq!(words(";ls"), vec![";", "ls"]);
+        assert_eq!(words("ls;"), vec!["ls", ";"]);
+    }
+
+    #[test]
+    fn test_repeated_semicolons() {
+        assert_eq!(words("a;;b"), vec!["a", ";;", "b"]);
+        assert_eq!(words("a;;;b"), vec!["a", ";;", ";", "b"]);
+        assert_eq!(words("a; ;b"), vec!["a", ";", ";", "b"]);
+    }
+
+    #[test]
+    fn test_quoted_semicolon_is_literal() {
+        assert_eq!(words("echo \"a;b\" 'c;d'"), vec!["echo", "a;b", "c;d"]);
+    }
+
+    #[test]
+    fn test_escaped_operators_are_words() {
+        for (input, word) in [
+            ("echo \\>x", ">x"),
+            ("echo a\\>b", "a>b"),
+            ("echo \\|", "|"),
+            ("echo \\&", "&"),
+            ("echo \\;", ";"),
+            ("echo \\<", "<"),
+        ] {
+            let tokens = tokenize(input).unwrap();
+            assert_eq!(tokens.len(), 2, "{}", input);
+            assert_eq!(tokens[1].kind, TokenKind::Word(word.into()), "{}", input);
+        }
+        // An escaped backslash leaves the `>` to start a redirection
+        let tokens = tokenize("echo \\\\> f").unwrap();
+        assert_eq!(tokens[1].kind, TokenKind::Word("\\".into()));
+        assert!(matches!(
+            tokens[2].kind,
+            TokenKind::Operator(Op::Output { .. })
+        ));
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        assert_eq!(words("make && ./run"), vec!["make", "&&", "./run"]);
+        assert_eq!(words("true&&echo ok"), vec!["true", "&&", "echo", "ok"]);
+        assert_eq!(words("false||echo ok"), vec!["false", "||", "echo", "ok"]);
+    }
+
+    #[test]
+    fn test_logical_operators_maximal_munch() {
+        assert_eq!(words("a&&&b"), vec!["a", "&&", "&", "b"]);
+        assert_eq!(words("a|||b"), vec!["a", "||", "|", "b"]);
+        assert_eq!(words("echo '&&' \"||\""), vec!["echo", "&&", "||"]);
+    }
+
+    #[test]
+    fn test_background_operator() {
+        assert_eq!(words("sleep 10 &"), vec!["sleep", "10", "&"]);
+        assert_eq!(words("cmd&"), vec!["cmd", "&"]);
+        assert_eq!(words("echo \"a & b\""), vec!["echo", "a & b"]);
+    }
+
+    #[test]
+    fn test_parentheses_and_braces() {
+        assert_eq!(words("(ls)"), vec!["(", "ls", ")"]);
+        assert_eq!(words("( ls )"), vec!["(", "ls", ")"]);
+        assert_eq!(words("echo (x)"), vec!["echo", "(", "x", ")"]);
+        assert_eq!(words("echo '(' \"a)\" \\("), vec!["echo", "(", "a)", "("]);
+        // Braces are ordinary characters; only the parser treats `{` and `}` as reserved
+        assert_eq!(words("echo {a,b} { }"), vec!["echo", "{a,b}", "{", "}"]);
+        assert_eq!(
+            tokenize("(ls)").unwrap()[0].kind,
+            TokenKind::Operator(Op::LeftParen)
+        );
+    }
+
+    #[test]
+    fn test_process_substitution() {
+        assert_eq!(
+            words("diff <(sort a) >( tee b )"),
+            vec!["diff", "<(sort a)", ">( tee b )"]
+        );
+        let tokens = tokenize("cat <(grep x <(cat 'f)'))").unwrap();
+        assert_eq!(
+            tokens[1].kind,
+            TokenKind::ProcessSubstitution {
+                command: "grep x <(cat 'f)')".to_string(),
+                output: false,
+            }
+        );
+        assert_eq!(tokens[1].span, 4..25);
+        // Quoted, the characters are ordinary
+        assert_eq!(
+            words("echo \"(not special)\" 'a<(b'"),
+            vec!["echo", "(not special)", "a<(b"]
+        );
+        assert_eq!(
+            tokenize("cat <(ls"),
+            Err(TokenizeError::UnterminatedSubstitution(4))
+        );
+    }
+
+    #[test]
+    fn test_combined_output_redirection() {
+        assert_eq!(words("cmd &> all.log"), vec!["cmd", "&>", "all.log"]);
+        assert_eq!(words("cmd&>>all.log"), vec!["cmd", "&>>", "all.log"]);
+    }
+
+    #[test]
+    fn test_duplicate_operators() {
+        assert_eq!(
+            words("cmd 2>&1>out 1>&2 >&- <&- >& all"),
+            vec![
+                "cmd", "2>&", "1", ">", "out", "1>&", "2", ">&", "-", "<&", "-", ">&", "all"
+            ]
+        );
+        assert_eq!(
+            tokenize("cmd 2>&1").unwrap()[1].kind,
+            TokenKind::Operator(Op::DuplicateOutput { fd: Some(2) })
+        );
+    }
+
+    #[test]
+    fn test_input_redirection() {
+        assert_eq!(
+            words("wc -l < input.txt"),
+            vec!["wc", "-l", "<", "input.txt"]
+        );
+        assert_eq!(words("wc<file"), vec!["wc", "<", "file"]);
+        assert_eq!(words("cat 0<file"), vec!["cat", "0<", "file"]);
+        assert_eq!(words("echo '<' \"a<b\""), vec!["echo", "<", "a<b"]);
+        assert_eq!(
+            tokenize("cat <>f 3<> g").unwrap()[1].kind,
+            TokenKind::Operator(Op::ReadWrite { fd: None })
+        );
+        assert_eq!(words("cat <>f 3<> g"), vec!["cat", "<>", "f", "3<>", "g"]);
+    }
+
+    #[test]
+    fn test_heredoc_operators() {
+        assert_eq!(words("cat <<EOF"), vec!["cat", "<<", "EOF"]);
+        assert_eq!(words("cat<<-'EOF'"), vec!["cat", "<<-", "EOF"]);
+    }
+
+    #[test]
+    fn test_herestring_operator() {
+        assert_eq!(words("wc -c <<< hello"), vec!["wc", "-c", "<<<", "hello"]);
+        assert_eq!(words("cat<<<\"a b\""), vec!["cat", "<<<", "a b"]);
+        assert_eq!(words("cat <<<<x"), vec!["cat", "<<<", "<", "x"]);
+    }
+
+    #[test]
+    fn test_unterminated_quotes() {
+        assert_eq!(
+            tokenize("echo 'abc"),
+            Err(TokenizeError::UnterminatedSingleQuote(5))
+        );
+        assert_eq!(
+            tokenize("echo \"ok\" \"abc"),
+            Err(TokenizeError::UnterminatedDoubleQuote(10))
+        );
+        assert_eq!(
+            tokenize("echo \"it's\" 'say \"hi\""),
+            Err(TokenizeError::UnterminatedSingleQuote(12))
+        );
+    }
+
+    #[test]
+    fn test_ansi_c_quoting() {
+        assert_eq!(
+            words(r#"printf $'%s\n' $'a\tb' x$'\x41\102'y $'it\'s' "$'no'""#),
+            vec!["printf", "%s\n", "a\tb", "xABy", "it's", "$'no'"]
+        );
+        assert_eq!(
+            words(r"echo $'\u00e9\e[0m' $'\q\x' $'cut\0here'"),
+            vec!["echo", "\u{e9}\x1b[0m", "\\q\\x", "cut"]
+        );
+        assert_eq!(
+            words(r"echo $'\U0010FFAA' $'\xAA'")
+                .iter()
+                .map(|w| encoding::encode(w))
+                .collect::<Vec<_>>(),
+            vec![
+                "echo".into(),
+                "\u{10FFAA}".into(),
+                OsStr::from_bytes(b"\xAA").to_os_string()
+            ]
+        );
+        assert_eq!(
+            tokenize("echo $'abc"),
+            Err(TokenizeError::UnterminatedSingleQuote(5))
+        );
+        assert_eq!(
+            tokenize(r"echo $'abc\'"),
+            Err(TokenizeError::UnterminatedSingleQuote(5))
+        );
+    }
+
+    #[test]
+    fn test_trailing_backslash() {
+        assert_eq!(
+            tokenize("echo abc\\"),
+            Err(TokenizeError::TrailingBackslash(8))
+        );
+        assert_eq!(words("echo abc\\\\"), vec!["echo", "abc\\"]);
+    }
+
+    #[test]
+    fn test_backslash_in_double_quotes() {
+        assert_eq!(
+            words(r#"echo "\$HOME" "\\" "\z" "a\nb" \z"#),
+            vec!["echo", "$HOME", "\\", "\\z", "a\\nb", "z"]
+        );
+        assert_eq!(words(r#"echo "end\\""#), vec!["echo", "end\\"]);
+        assert_eq!(
+            tokenize(r#"echo "end\""#),
+            Err(TokenizeError::UnterminatedDoubleQuote(5))
+        );
+    }
+
+    #[test]
+    fn test_operators_are_typed_and_spanned() {
+        let tokens = tokenize("cat f2 2>err '>' <<-EOF").unwrap();
+        let kinds: Vec<_> = tokens.iter().map(|t| t.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Word("cat".into()),
+                TokenKind::Word("f2".into()),
+                TokenKind::Operator(Op::Output {
+                    fd: Some(2),
+                    append: false
+                }),
+                TokenKind::Word("err".into()),
+                TokenKind::Word(">".into()),
+                TokenKind::Operator(Op::HereDoc {
+                    fd: None,
+                    strip_tabs: true
+                }),
+                TokenKind::Word("EOF".into()),
+            ]
+        );
+        let spans: Vec<_> = tokens.iter().map(|t| t.span.clone()).collect();
+        assert_eq!(spans, vec![0..3, 4..6, 7..9, 9..12, 13..16, 17..20, 20..23]);
+    }
+
+    #[test]
+    fn test_glob_patterns_respect_quoting() {
+        let globs: Vec<_> = tokenize(r#"ls *.rs "*.md" '?' a\[b "x"*[ab] plain"#)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.glob)
+            .collect();
+        assert_eq!(
+            globs,
+            vec![
+                None,
+                Some("*.rs".to_string()),
+                None,
+                None,
+                None,
+                Some("x*[ab]".to_string()),
+                None,
+            ]
+        );
+        assert_eq!(
+            tokenize(r#""a*"*"#).unwrap()[0].glob.as_deref(),
+            Some("a\\**")
+        );
+    }
+
+    #[test]
+    fn test_quoted_flag() {
+        let quoted: Vec<_> = tokenize(r#"echo '>' a"b" \| plain > out"#)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.quoted)
+            .collect();
+        assert_eq!(quoted, vec![false, true, true, true, false, false, false]);
+    }
+
+    #[test]
+    fn test_comments() {
+        assert_eq!(words("# just a comment"), Vec::<String>::new());
+        assert_eq!(words("echo hello # greet"), vec!["echo", "hello"]);
+        assert_eq!(words("echo hi;# done"), vec!["echo", "hi", ";"]);
+    }
+
+    #[test]
+    fn test_hash_inside_word_or_quotes_is_literal() {
+        assert_eq!(words("echo foo#bar"), vec!["echo", "foo#bar"]);
+        assert_eq!(
+            words("echo \"#notacomment\" '#nor this'"),
+            vec!["echo", "#notacomment", "#nor this"]
+        );
+    }
+
+    #[test]
+    fn test_line_continuation() {
+        assert!(needs_continuation("echo a \\"));
+        assert!(!needs_continuation("echo a \\\\"));
+        assert!(!needs_continuation("echo 'a \\'"));
+        assert!(needs_continuation("echo \"don't\" a \\"));
+        assert!(!needs_continuation("echo \"a \\\""));
+        assert_eq!(
+            words("echo hel\\\nlo \\\nworld"),
+            vec!["echo", "hello", "world"]
+        );
+        assert_eq!(words("echo 'a\\\nb'"), vec!["echo", "a\\\nb"]);
+    }
+
+    #[test]
+    fn test_join_continued_lines() {
+        let mut rest = vec!["b \\".to_string(), "c".to_string(), "d".to_string()].into_iter();
+        let joined = join_continued_lines("echo a \\".to_string(), &mut || rest.next());
+        assert_eq!(joined, "echo a \\\nb \\\nc");
+        assert_eq!(words(&joined), vec!["echo", "a", "b", "c"]);
+        assert_eq!(rest.next().as_deref(), Some("d"));
+    }
+
+    #[test]
+    fn test_tabs_and_carriage_returns() {
+        assert_eq!(words("echo\ta\t\tb"), vec!["echo", "a", "b"]);
+        assert_eq!(words("echo hi\r\n"), vec!["echo", "hi", "newline"]);
+        assert_eq!(words("echo 'a\tb\r'"), vec!["echo", "a\tb\r"]);
+        assert_eq!(strip_carriage_return("echo hi\r".to_string()), "echo hi");
+        assert_eq!(strip_carriage_return("echo hi".to_string()), "echo hi");
+    }
+
+    /// Stands in for the shell: `$?` is the status given, and variables live in a map
+    /// of their own, apart from `HOME`, which comes from the environment.
+    struct Status {
+        status: i32,
+        variables: HashMap<String, String>,
+    }
+
+    impl Status {
+        fn new(status: i32) -> Self {
+            Self {
+                status,
+                variables: HashMap::new(),
+            }
+        }
+
+        fn set(&mut self, name: &str, value: &str) {
+            self.variables.insert(name.to_string(), value.to_string());
+        }
+    }
+
+    impl Expander for Status {
+        fn parameter(&mut self, name: &str) -> Option<String> {
+            match name {
+                "?" => Some(self.status.to_string()),
+                "$" => Some("4242".to_string()),
+                "1" | "@" => Some("first arg".to_string()),
+                "#" => Some("1".to_string()),
+                // Fixed, since `cd` tests running alongside change the real ones
+                "PWD" => Some("/cwd".to_string()),
+                "OLDPWD" => Some("/old".to_string()),
+                "HOME" => std::env::var(name).ok(),
+                name => self.variables.get(name).cloned(),
+            }
+        }
+
+        fn assign(&mut self, name: &str, value: &str) {
+            self.set(name, value);
+        }
+
+        fn array(&mut self, name: &str) -> Option<Vec<String>> {
+            (name == "list").then(|| vec!["a".to_string(), "b c".to_string()])
+        }
+
+        fn command_output(&mut self, command: &str) -> String {
+            // Stands in for running an `echo` command
+            let words = texts(tokenize_expanded(command, self).unwrap());
+            format!("{}\n", words[1..].join(" "))
+        }
+    }
+
+    #[test]
+    fn test_tilde_in_redirection_targets() {
+        let mut status = Status::new(0);
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(
+            texts(
+                tokenize_expanded("echo hi > ~/x >> ~/y 2> ~nobody-such-user 2>~", &mut status)
+                    .unwrap()
+            ),
+            vec![
+                "echo".to_string(),
+                "hi".to_string(),
+                ">".to_string(),
+                format!("{}/x", home),
+                ">>".to_string(),
+                format!("{}/y", home),
+                "2>".to_string(),
+                "~nobody-such-user".to_string(),
+                "2>".to_string(),
+                home.clone(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tilde_expansion() {
+        let mut status = Status::new(0);
+        let home = std::env::var("HOME").unwrap();
+        let root = nix::unistd::User::from_name("root").unwrap().unwrap().dir;
+        let root = root.to_string_lossy();
+        assert_eq!(
+            texts(
+                tokenize_expanded(
+                    "echo ~ ~/a ~root/b A=~/c ~nobody-such-user x~ '~' \\~ ~\"/d\" ~;",
+                    &mut status
+                )
+                .unwrap()
+            ),
+            vec![
+                "echo".to_string(),
+                home.clone(),
+                format!("{}/a", home),
+                format!("{}/b", root),
+                format!("A={}/c", home),
+                "~nobody-such-user".to_string(),
+                "x~".to_string(),
+                "~".to_string(),
+                "~".to_string(),
+                // The slash is quoted, so it is part of the prefix
+                "~/d".to_string(),
+                home.clone(),
+                ";".to_string(),
+            ]
+        );
+        assert_eq!(
+            texts(tokenize_expanded("echo ~- ~+/x", &mut status).unwrap()),
+            vec!["echo".to_string(), "/old".to_string(), "/cwd/x".to_string()]
+        );
+        // The structural pass leaves it for expansion
+        assert_eq!(words("cd ~/x"), vec!["cd", "~/x"]);
+    }
+
+    #[test]
+    fn test_array_expansion() {
+        let mut status = Status::new(0);
+        assert_eq!(
+            texts(
+                tokenize_expanded(
+                    "echo ${list[1]} ${#list[@]} \"x${list[@]}y\" ${list[@]} ${list[-1+1]}",
+                    &mut status
+                )
+                .unwrap()
+            ),
+            vec!["echo", "b", "c", "2", "xa", "b cy", "a", "b", "c", "a"]
+        );
+        assert_eq!(
+            texts(
+                tokenize_expanded(
+                    "echo ${#list[1]} ${list[5]:-none} \"${list[7]}\" ${HOME[0]}",
+                    &mut status
+                )
+                .unwrap()
+            ),
+            vec!["echo", "3", "none", "", &std::env::var("HOME").unwrap()]
+        );
+        assert_eq!(
+            tokenize_expanded("echo ${list[1/0]}", &mut status),
+            Err(TokenizeError::Arithmetic {
+                expression: "1/0".to_string(),
+                message: "division by 0".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_last_status_expansion() {
+        let mut status = Status::new(1);
+        assert_eq!(
+            texts(tokenize_expanded("echo $? \"$?\" '$?' x$?y", &mut status).unwrap()),
+            vec!["echo", "1", "1", "$?", "x1y"]
+        );
+        assert_eq!(words("echo $?"), vec!["echo", "$?"]);
+    }
+
+    #[test]
+    fn test_special_parameter_expansion() {
+        let mut status = Status::new(0);
+        assert_eq!(
+            texts(tokenize_expanded("echo $$ $! a$ $10 \"$@\" ${#} $2", &mut status).unwrap()),
+            vec!["echo", "4242", "a$", "first", "arg0", "first arg", "1"]
+        );
+    }
+
+    #[test]
+    fn test_variable_expansion() {
+        let mut status = Status::new(0);
+        status.set("TOKENIZE_TEST_VAR", "a  b");
+        assert_eq!(
+            texts(
+                tokenize_expanded(
+                    "echo $TOKENIZE_TEST_VAR \"$TOKENIZE_TEST_VAR\" '$TOKENIZE_TEST_VAR' \
+                     x${TOKENIZE_TEST_VAR}y ${#TOKENIZE_TEST_VAR} $TOKENIZE_TEST_UNSET.",
+                    &mut status
+                )
+                .unwrap()
+            ),
+            vec![
+                "echo",
+                "a",
+                "b",
+                "a  b",
+                "$TOKENIZE_TEST_VAR",
+                "xa",
+                "by",
+                "4",
+                "."
+            ]
+        );
+        assert_eq!(words("echo $HOME"), vec!["echo", "$HOME"]);
+    }
+
+    #[test]
+    fn test_variable_defaults() {
+        let mut status = Status::new(0);
+        status.set("TOKENIZE_TEST_EMPTY", "");
+        assert_eq!(
+            texts(
+                tokenize_expanded(
+                    "echo ${TOKENIZE_TEST_EMPTY:-d1} ${TOKENIZE_TEST_EMPTY-d2}. \
+                     ${TOKENIZE_TEST_NEW:=set} $TOKENIZE_TEST_NEW ${TOKENIZE_TEST_NEW:-other}",
+                    &mut status
+                )
+                .unwrap()
+            ),
+            vec!["echo", "d1", ".", "set", "set", "set"]
+        );
+        assert_eq!(
+            tokenize_expanded("echo ${1x}", &mut status),
+            Err(TokenizeError::BadSubstitution("${1x}".to_string()))
+        );
+        assert_eq!(
+            tokenize_expanded("echo ${HOME", &mut status),
+            Err(TokenizeError::UnterminatedSubstitution(5))
+        );
+    }
+
+    #[test]
+    fn test_command_substitution() {
+        let mut status = Status::new(0);
+        assert_eq!(
+            texts(
+                tokenize_expanded("echo $(echo a  b) \"$(echo c  d)\" x`echo y`z", &mut status)
+                    .unwrap()
+            ),
+            vec!["echo", "a", "b", "c d", "xyz"]
+        );
+        assert_eq!(
+            texts(tokenize_expanded("echo $(echo \"(a)\" ')') '$(echo no)'", &mut status).unwrap()),
+            vec!["echo", "(a)", ")", "$(echo no)"]
+        );
+        assert_eq!(words("echo $(a | b) `c`"), vec!["echo", "$(a | b)", "`c`"]);
+    }
+
+    #[test]
+    fn test_arithmetic_expansion() {
+        let mut status = Status::new(0);
+        assert_eq!(
+            texts(
+                tokenize_expanded(
+                    "echo $((2 + 3 * 4)) x$(( (1+1) ))y \"$((7/2))\"",
+                    &mut status
+                )
+                .unwrap()
+            ),
+            vec!["echo", "14", "x2y", "3"]
+        );
+        assert_eq!(words("echo $((1 + 2))"), vec!["echo", "$((1 + 2))"]);
+        assert_eq!(
+            tokenize_expanded("echo $(( 1 / 0 ))", &mut status),
+            Err(TokenizeError::Arithmetic {
+                expression: "1 / 0".to_string(),
+                message: "division by 0".to_string()
+            })
+        );
+        assert_eq!(
+            tokenize("echo $((1 + 2)"),
+            Err(TokenizeError::UnterminatedSubstitution(5))
+        );
+    }
+
+    #[test]
+    fn test_unexpanded_substitution_is_one_word() {
+        assert_eq!(words("echo $(ls -l)"), vec!["echo", "$(ls -l)"]);
+        assert_eq!(words("x$(a $(b \")\"))y"), vec!["x$(a $(b \")\"))y"]);
+        assert_eq!(
+            words("echo `a b` '$(c' d)"),
+            vec!["echo", "`a b`", "$(c", "d", ")"]
+        );
+        // The text is kept as written, quoted, so later expansion sees the substitution
+        assert!(tokenize("echo $(a *)").unwrap()[1].glob.is_none());
+    }
+
+    #[test]
+    fn test_operators_inside_braced_expansion() {
+        assert_eq!(words("echo ${A:->}"), vec!["echo", "${A:->}"]);
+        assert_eq!(
+            words("echo ${A:-a b;c|d} x"),
+            vec!["echo", "${A:-a b;c|d}", "x"]
+        );
+        assert_eq!(
+            words("echo ${A:-'}'} ${A:-{x}} ${A:-$(echo })}"),
+            vec!["echo", "${A:-'}'}", "${A:-{x}}", "${A:-$(echo })}"]
+        );
+        let tokens = tokenize("echo ${A} > f").unwrap();
+        assert_eq!(texts(tokens.clone()), vec!["echo", "${A}", ">", "f"]);
+        assert_eq!(
+            tokens[2].kind,
+            TokenKind::Operator(Op::Output {
+                fd: None,
+                append: false
+            })
+        );
+        assert_eq!(
+            tokenize("echo ${A:-x"),
+            Err(TokenizeError::UnterminatedSubstitution(5))
+        );
+
+        let mut status = Status::new(0);
+        assert_eq!(
+            texts(
+                tokenize_expanded(
+                    "echo ${TOKENIZE_UNSET:->} ${TOKENIZE_UNSET:-a;b}",
+                    &mut status
+                )
+                .unwrap()
+            ),
+            vec!["echo", ">", "a;b"]
+        );
+    }
+
+    #[test]
+    fn test_nested_and_unterminated_substitution() {
+        let mut status = Status::new(0);
+        assert_eq!(
+            texts(
+                tokenize_expanded("echo $(echo $(echo in)) `echo \\`echo bq\\``", &mut status)
+                    .unwrap()
+            ),
+            vec!["echo", "in", "bq"]
+        );
+        assert_eq!(
+            tokenize("echo $(pwd"),
+            Err(TokenizeError::UnterminatedSubstitution(5))
+        );
+    }
+
+    #[test]
+    fn test_history_expansion() {
+        let history = vec!["echo one".to_string(), "echo two".to_string()];
+        assert_eq!(expand_history("!!", &history), Ok("echo two".to_string()));
+        assert_eq!(
+            expand_history("!1 | wc", &history),
+            Ok("echo one | wc".to_string())
+        );
+        assert_eq!(expand_history("!-2", &history), Ok("echo one".to_string()));
+        assert_eq!(
+            expand_history("echo hi!", &history),
+            Ok("echo hi!".to_string())
+        );
+        // A `!` not followed by `!`, a digit or `-` is left alone
+        for literal in ["find . ! -name x", "! true", "[ a != b ]", "echo $! \"a!\""] {
+            assert_eq!(expand_history(literal, &history), Ok(literal.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_history_expansion_suppressed() {
+        let history = vec!["ls".to_string()];
+        assert_eq!(
+            expand_history("echo '!!'", &history),
+            Ok("echo '!!'".to_string())
+        );
+        assert_eq!(
+            expand_history("echo \\!!", &history),
+            Ok("echo \\!!".to_string())
+        );
+    }
+
+    #[test]
+    fn test_history_expansion_inside_double_quotes() {
+        let history = vec!["ls".to_string()];
+        assert_eq!(
+            expand_history("echo \"it's\" !!", &history),
+            Ok("echo \"it's\" ls".to_string())
+        );
+        assert_eq!(
+            expand_history("echo \"!!\"", &history),
+            Ok("echo \"ls\"".to_string())
+        );
+    }
+
+    #[test]
+    fn test_history_expansion_out_of_range() {
+        let history = vec!["ls".to_string()];
+        assert_eq!(
+            expand_history("!42", &history),
+            Err("!42: event not found".to_string())
+        );
+        assert_eq!(
+            expand_history("!!", &[]),
+            Err("!!: event not found".to_string())
+        );
     }
 }
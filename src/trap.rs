@@ -0,0 +1,228 @@
+use crate::execute::run_line;
+use crate::shell::Shell;
+use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Signals that have arrived but whose trap has not run yet, one bit per signal number.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn record_signal(signal: libc::c_int) {
+    PENDING.fetch_or(1 << signal, Ordering::SeqCst);
+}
+
+/// What a trap is set on: a signal, or one of the shell's own events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Condition {
+    /// Just before the shell exits
+    Exit,
+    Signal(Signal),
+    /// After a command fails
+    Err,
+}
+
+impl Condition {
+    /// Parses a condition as `trap` takes it: `EXIT`, `ERR`, a signal name with or
+    /// without `SIG`, in any case, or a signal number.
+    pub fn parse(name: &str) -> Option<Condition> {
+        let upper = name.to_ascii_uppercase();
+        match upper.strip_prefix("SIG").unwrap_or(&upper) {
+            "0" | "EXIT" => Some(Condition::Exit),
+            "ERR" => Some(Condition::Err),
+            _ => parse_signal(name).map(Condition::Signal),
+        }
+    }
+
+    /// The name `trap -p` lists the condition under.
+    pub fn name(self) -> &'static str {
+        match self {
+            Condition::Exit => "EXIT",
+            Condition::Signal(signal) => signal.as_str(),
+            Condition::Err => "ERR",
+        }
+    }
+}
+
+/// Parses a signal as `trap` and `kill` take it: a name with or without `SIG`, in any
+/// case, or a number.
+pub fn parse_signal(name: &str) -> Option<Signal> {
+    if let Ok(number) = name.parse::<i32>() {
+        return Signal::try_from(number).ok();
+    }
+    let name = name.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    format!("SIG{}", name).parse().ok()
+}
+
+/// Every signal with its number, one per line, as `trap -l` and `kill -l` list them.
+pub fn signal_list() -> String {
+    Signal::iterator()
+        .map(|signal| format!("{:2}) {}\n", signal as i32, signal))
+        .collect()
+}
+
+/// The commands set with `trap`, and whether one is running now.
+#[derive(Debug, Default)]
+pub struct Traps {
+    commands: BTreeMap<Condition, String>,
+    running: bool,
+}
+
+impl Traps {
+    /// Sets the command for `condition`. For a signal, an empty command ignores it and
+    /// any other command runs once the signal arrives.
+    pub fn set(&mut self, condition: Condition, command: &str) -> Result<(), String> {
+        if let Condition::Signal(signal) = condition {
+            let handler = if command.is_empty() {
+                SigHandler::SigIgn
+            } else {
+                SigHandler::Handler(record_signal)
+            };
+            install(signal, handler)?;
+        }
+        self.commands.insert(condition, command.to_string());
+        Ok(())
+    }
+
+    /// Removes the trap on `condition`, giving a signal its default action back.
+    pub fn reset(&mut self, condition: Condition) -> Result<(), String> {
+        if let Condition::Signal(signal) = condition {
+            install(signal, SigHandler::SigDfl)?;
+        }
+        self.commands.remove(&condition);
+        Ok(())
+    }
+
+    /// The traps that are set, in the order `trap -p` lists them.
+    pub fn iter(&self) -> impl Iterator<Item = (Condition, &str)> {
+        self.commands
+            .iter()
+            .map(|(condition, command)| (*condition, command.as_str()))
+    }
+
+    pub fn get(&self, condition: Condition) -> Option<&str> {
+        self.commands.get(&condition).map(String::as_str)
+    }
+}
+
+fn install(signal: Signal, handler: SigHandler) -> Result<(), String> {
+    let action = SigAction::new(handler, SaFlags::SA_RESTART, SigSet::empty());
+    // SAFETY: `record_signal` only touches an atomic, which is async-signal-safe.
+    unsafe { sigaction(signal, &action) }
+        .map(|_| ())
+        .map_err(|e| format!("trap: {}: {}", signal, e.desc()))
+}
+
+/// Runs the traps of the signals that arrived since the last call. Signals this
+/// shell has no trap for are left pending.
+pub fn run_pending(shell: &mut Shell) {
+    if PENDING.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+    let trapped: Vec<(Signal, String)> = shell
+        .traps
+        .iter()
+        .filter_map(|(condition, command)| match condition {
+            Condition::Signal(signal) => Some((signal, command.to_string())),
+            _ => None,
+        })
+        .collect();
+    let mask = trapped
+        .iter()
+        .fold(0, |mask, (signal, _)| mask | 1 << *signal as i32);
+    let arrived = PENDING.fetch_and(!mask, Ordering::SeqCst) & mask;
+    for (signal, command) in trapped {
+        if arrived & 1 << signal as i32 != 0 {
+            run(shell, &command);
+        }
+    }
+}
+
+/// Runs the trap on `signal` as if the signal had arrived, for a Ctrl-C that the line
+/// editor read as a key rather than the signal it would otherwise send.
+pub fn run_signal(shell: &mut Shell, signal: Signal) {
+    if let Some(command) = shell.traps.get(Condition::Signal(signal)) {
+        let command = command.to_string();
+        run(shell, &command);
+    }
+}
+
+/// Runs the `ERR` trap after a command failed, unless a trap is already running.
+pub fn run_err(shell: &mut Shell) {
+    if let Some(command) = shell.traps.get(Condition::Err) {
+        let command = command.to_string();
+        run(shell, &command);
+    }
+}
+
+/// Runs the `EXIT` trap, once, as the shell is about to exit. The shell exits with the
+/// status it was going to, unless the trap runs `exit` itself.
+pub fn run_exit(shell: &mut Shell) {
+    let Some(command) = shell.traps.commands.remove(&Condition::Exit) else {
+        return;
+    };
+    let exit_code = shell.exit_code.take();
+    run(shell, &command);
+    shell.exit_code = shell.exit_code.or(exit_code);
+}
+
+/// Runs a trap's command. `$?` is left as it was before the trap.
+fn run(shell: &mut Shell, command: &str) {
+    if shell.traps.running || command.is_empty() {
+        return;
+    }
+    shell.traps.running = true;
+    let status = shell.last_status;
+    run_line(shell, command, &mut || None);
+    shell.last_status = status;
+    shell.traps.running = false;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_condition() {
+        for name in ["INT", "SIGINT", "int", "2"] {
+            assert_eq!(
+                Condition::parse(name),
+                Some(Condition::Signal(Signal::SIGINT))
+            );
+        }
+        assert_eq!(Condition::parse("EXIT"), Some(Condition::Exit));
+        assert_eq!(Condition::parse("0"), Some(Condition::Exit));
+        assert_eq!(Condition::parse("err"), Some(Condition::Err));
+        assert_eq!(Condition::parse("NOPE"), None);
+        assert_eq!(Condition::parse("99"), None);
+    }
+
+    #[test]
+    fn test_listing_order() {
+        let mut traps = Traps::default();
+        traps.set(Condition::Err, "e").unwrap();
+        traps.set(Condition::Exit, "x").unwrap();
+        let names: Vec<&str> = traps.iter().map(|(c, _)| c.name()).collect();
+        assert_eq!(names, vec!["EXIT", "ERR"]);
+        traps.reset(Condition::Exit).unwrap();
+        assert_eq!(traps.get(Condition::Exit), None);
+    }
+
+    #[test]
+    fn test_run_signal_without_the_signal() {
+        let mut shell = Shell::new();
+        run_signal(&mut shell, Signal::SIGUSR2);
+        shell
+            .traps
+            .set(Condition::Signal(Signal::SIGUSR2), "TRAP_RAN=1")
+            .unwrap();
+        shell.last_status = 130;
+        run_signal(&mut shell, Signal::SIGUSR2);
+        shell
+            .traps
+            .reset(Condition::Signal(Signal::SIGUSR2))
+            .unwrap();
+        assert_eq!(shell.var("TRAP_RAN"), Some("1"));
+        assert_eq!(shell.last_status, 130);
+    }
+}
@@ -0,0 +1,120 @@
+use nix::sys::resource::{Resource, getrlimit, rlim_t, setrlimit};
+
+const INFINITY: rlim_t = libc::RLIM_INFINITY;
+
+/// A resource limit `ulimit` can show and change.
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub option: char,
+    description: &'static str,
+    unit: Option<&'static str>,
+    /// Bytes in one unit that `ulimit` counts in
+    scale: rlim_t,
+    resource: Resource,
+}
+
+/// Every limit, in the order `ulimit -a` lists them.
+#[rustfmt::skip]
+pub const LIMITS: &[Limit] = &[
+    limit('c', "core file size", Some("blocks"), 512, Resource::RLIMIT_CORE),
+    limit('d', "data seg size", Some("kbytes"), 1024, Resource::RLIMIT_DATA),
+    limit('f', "file size", Some("blocks"), 512, Resource::RLIMIT_FSIZE),
+    limit('n', "open files", None, 1, Resource::RLIMIT_NOFILE),
+    limit('s', "stack size", Some("kbytes"), 1024, Resource::RLIMIT_STACK),
+    limit('t', "cpu time", Some("seconds"), 1, Resource::RLIMIT_CPU),
+    limit('u', "max user processes", None, 1, Resource::RLIMIT_NPROC),
+    limit('v', "virtual memory", Some("kbytes"), 1024, Resource::RLIMIT_AS),
+];
+
+const fn limit(
+    option: char,
+    description: &'static str,
+    unit: Option<&'static str>,
+    scale: rlim_t,
+    resource: Resource,
+) -> Limit {
+    Limit {
+        option,
+        description,
+        unit,
+        scale,
+        resource,
+    }
+}
+
+impl Limit {
+    pub fn from_option(option: char) -> Option<Limit> {
+        LIMITS.iter().find(|limit| limit.option == option).copied()
+    }
+
+    /// The current soft limit, or the hard one, in the limit's units.
+    pub fn get(&self, hard: bool) -> Result<String, String> {
+        let (soft_value, hard_value) = getrlimit(self.resource).map_err(|e| self.error(e))?;
+        Ok(self.format(if hard { hard_value } else { soft_value }))
+    }
+
+    /// Sets the soft limit, the hard one, or both, to `value`: a number of the limit's
+    /// units, `unlimited`, or `soft`/`hard` for the current limit of that kind.
+    pub fn set(&self, value: &str, soft: bool, hard: bool) -> Result<(), String> {
+        let (soft_value, hard_value) = getrlimit(self.resource).map_err(|e| self.error(e))?;
+        let new = match value {
+            "unlimited" => INFINITY,
+            "soft" => soft_value,
+            "hard" => hard_value,
+            _ => value
+                .parse::<rlim_t>()
+                .ok()
+                .and_then(|n| n.checked_mul(self.scale))
+                .ok_or_else(|| format!("{}: invalid number", value))?,
+        };
+        let soft_value = if soft { new } else { soft_value };
+        let hard_value = if hard { new } else { hard_value };
+        setrlimit(self.resource, soft_value, hard_value)
+            .map_err(|e| format!("{}: cannot modify limit: {}", self.description, e.desc()))
+    }
+
+    /// The line `ulimit -a` shows the limit on, without the value, as in bash.
+    pub fn label(&self) -> String {
+        let unit = match self.unit {
+            Some(unit) => format!("({}, -{}) ", unit, self.option),
+            None => format!("(-{}) ", self.option),
+        };
+        format!("{:<20} {:>20}", self.description, unit)
+    }
+
+    fn format(&self, value: rlim_t) -> String {
+        if value == INFINITY {
+            "unlimited".to_string()
+        } else {
+            (value / self.scale).to_string()
+        }
+    }
+
+    fn error(&self, e: nix::Error) -> String {
+        format!("{}: cannot get limit: {}", self.description, e.desc())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labels_line_up() {
+        let core = Limit::from_option('c').unwrap();
+        assert_eq!(core.label(), "core file size              (blocks, -c) ");
+        let files = Limit::from_option('n').unwrap();
+        assert_eq!(files.label(), "open files                          (-n) ");
+        assert!(Limit::from_option('z').is_none());
+    }
+
+    #[test]
+    fn test_set_soft_limit() {
+        let core = Limit::from_option('c').unwrap();
+        let original = core.get(false).unwrap();
+        core.set("0", true, false).unwrap();
+        assert_eq!(core.get(false).unwrap(), "0");
+        assert!(core.set("many", true, false).is_err());
+        core.set(&original, true, false).unwrap();
+    }
+}